@@ -0,0 +1,140 @@
+//! Encode throughput on a megabyte of text, with a SentencePiece-style
+//! vocabulary and a byte-level `tokenizer.json` built from the same words.
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use nxml::rng::Rng;
+use nxml::tokenizer::{BatchOptions, EncodeOptions, Token, TokenType, Tokenizer, Vocab};
+use nxml::tokenizer_json::bytes_to_unicode;
+use serde_json::{json, Map};
+
+const SYLLABLES: &[&str] = &[
+    "ka", "to", "ri", "ne", "mo", "la", "su", "vi", "de", "po", "an", "el", "is", "or", "un",
+    "ché", "ßa", "ño",
+];
+
+/// `n` words of one to four syllables, drawn so some are much more common
+/// than others, like in real text.
+fn words(rng: &mut Rng, n: usize) -> Vec<String> {
+    let lexicon = (0..4000)
+        .map(|_| {
+            (0..1 + rng.usize(0..4))
+                .map(|_| SYLLABLES[rng.usize(0..SYLLABLES.len())])
+                .collect::<String>()
+        })
+        .collect::<Vec<_>>();
+    (0..n)
+        .map(|_| {
+            let common = 1 + rng.usize(0..lexicon.len());
+            lexicon[rng.usize(0..common)].clone()
+        })
+        .collect()
+}
+
+/// About a megabyte of words with some punctuation and line breaks.
+fn text() -> String {
+    let mut rng = Rng(42);
+    let mut text = String::new();
+    for (i, word) in words(&mut rng, 160_000).into_iter().enumerate() {
+        text.push_str(&word);
+        text.push_str(match i % 13 {
+            0 => ", ",
+            7 => ".\n",
+            _ => " ",
+        });
+    }
+    text
+}
+
+/// A LLaMA-style vocabulary: special and byte tokens, and then every prefix
+/// of `▁` and a common word, scored so longer pieces win.
+fn pieces() -> Vec<Token> {
+    let token = |token: &str, score, ty| Token {
+        token: token.into(),
+        score,
+        ty,
+    };
+    let mut tokens = vec![
+        token("<unk>", 0.0, TokenType::Unknown),
+        token("<s>", 0.0, TokenType::Control),
+        token("</s>", 0.0, TokenType::Control),
+    ];
+    tokens.extend((0..=255u8).map(|b| token(&format!("<0x{b:02X}>"), 0.0, TokenType::Byte)));
+    for word in words(&mut Rng(7), 20_000) {
+        let piece = format!("▁{word}");
+        for (end, _) in piece.char_indices().skip(1).chain([(piece.len(), ' ')]) {
+            tokens.push(token(&piece[..end], end as f32 - 100.0, TokenType::Normal));
+        }
+    }
+    for c in ",.\n".chars() {
+        tokens.push(token(&c.to_string(), -100.0, TokenType::Normal));
+    }
+    tokens
+}
+
+/// A GPT-2-style byte-level BPE, with merges building each common word one
+/// character at a time.
+fn byte_level() -> Tokenizer {
+    let chars = bytes_to_unicode();
+    let mut vocab = Map::new();
+    for c in &chars {
+        vocab.insert(c.to_string(), json!(vocab.len()));
+    }
+    let mut merges = Vec::new();
+    for word in words(&mut Rng(7), 20_000) {
+        let word = format!(" {word}")
+            .bytes()
+            .map(|b| chars[b as usize])
+            .collect::<Vec<_>>();
+        let mut prefix = word[0].to_string();
+        for c in &word[1..] {
+            let merged = format!("{prefix}{c}");
+            if !vocab.contains_key(&merged) {
+                vocab.insert(merged.clone(), json!(vocab.len()));
+                merges.push(format!("{prefix} {c}"));
+            }
+            prefix = merged;
+        }
+    }
+    let json = json!({
+        "added_tokens": [{"id": vocab.len(), "content": "<|endoftext|>", "special": true}],
+        "pre_tokenizer": {"type": "ByteLevel", "add_prefix_space": false, "use_regex": true},
+        "model": {"type": "BPE", "vocab": vocab, "merges": merges},
+    });
+    Tokenizer::from_json_str(&json.to_string()).unwrap()
+}
+
+fn encode(c: &mut Criterion) {
+    let text = text();
+    let mut group = c.benchmark_group("encode");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(text.len() as u64));
+
+    let tokenizer = Tokenizer::new(Vocab::new(pieces()));
+    group.bench_function("sentencepiece", |b| {
+        b.iter(|| tokenizer.encode_with(&text, EncodeOptions::default()))
+    });
+    let lines = text.lines().collect::<Vec<_>>();
+    group.bench_function("sentencepiece_batch", |b| {
+        b.iter(|| tokenizer.encode_batch(&lines, BatchOptions::default()))
+    });
+
+    // A fresh tokenizer each time, so the word cache starts empty.
+    group.bench_function("byte_level", |b| {
+        let encode = |tokenizer: Tokenizer| tokenizer.encode_with(&text, EncodeOptions::default());
+        b.iter_batched(byte_level, encode, BatchSize::PerIteration)
+    });
+    let tokenizer = byte_level();
+    group.bench_function("byte_level_batch", |b| {
+        b.iter(|| tokenizer.encode_batch(&lines, BatchOptions::default()))
+    });
+    group.finish();
+}
+
+fn vocab(c: &mut Criterion) {
+    c.bench_function("vocab/build", |b| {
+        b.iter_batched(pieces, Vocab::new, BatchSize::LargeInput)
+    });
+}
+
+criterion_group!(benches, encode, vocab);
+criterion_main!(benches);
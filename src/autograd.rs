@@ -0,0 +1,737 @@
+use crate::ops::{self, Grad};
+use crate::tensor::Tensor;
+
+#[derive(Clone)]
+enum Op {
+    Leaf,
+    /// inputs: [w, x]
+    MatMul(usize, usize),
+    /// `b` is either the same shape as `a`, or a single row broadcast over it.
+    Add(usize, usize),
+    Mul(usize, usize),
+    Silu(usize),
+    RmsNorm(usize, f32),
+    Rope {
+        x: usize,
+        n_heads: usize,
+        theta: f32,
+    },
+    Softmax(usize),
+    Attention {
+        q: usize,
+        k: usize,
+        v: usize,
+        causal: bool,
+    },
+    GetRows(usize, Tensor<usize, 1>),
+    /// Columns of `x`, starting at `offset`.
+    Columns {
+        x: usize,
+        offset: usize,
+    },
+    /// Values with the same number of rows, side by side.
+    Concat(Vec<usize>),
+    CrossEntropy(usize, Tensor<usize, 1>),
+    Sum(usize),
+}
+
+struct Entry {
+    value: Tensor<f32, 2>,
+    op: Op,
+}
+
+/// A handle to a value recorded on a [`Tape`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Var {
+    id: usize,
+    shape: [usize; 2],
+}
+
+impl Var {
+    pub fn shape(&self) -> [usize; 2] {
+        self.shape
+    }
+}
+
+/// A gradient tape for reverse-mode automatic differentiation. Each op is
+/// computed eagerly in `f32` and recorded, so that [`Tape::backward`] can
+/// later walk the tape in reverse and accumulate gradients with the backward
+/// kernels in `ops`.
+///
+/// Values are 2D, with rows laid out like the eager ops expect: a vector is a
+/// single row, a sequence of activations is `[tokens, features]` and a weight
+/// matrix is `[out, in]`.
+#[derive(Default)]
+pub struct Tape {
+    entries: Vec<Entry>,
+}
+
+/// The gradients of a scalar with respect to every value on a tape.
+pub struct Gradients {
+    grads: Vec<Option<Tensor<f32, 2>>>,
+}
+
+impl Gradients {
+    /// The gradient with respect to `x`, or `None` if the scalar doesn't
+    /// depend on it.
+    pub fn get(&self, x: Var) -> Option<&Tensor<f32, 2>> {
+        self.grads[x.id].as_ref()
+    }
+
+    pub fn get_mut(&mut self, x: Var) -> Option<&mut Tensor<f32, 2>> {
+        self.grads[x.id].as_mut()
+    }
+}
+
+/// Adds `g` into the gradient accumulated so far for `id`.
+fn accumulate(grads: &mut [Option<Tensor<f32, 2>>], id: usize, g: Tensor<f32, 2>) {
+    match &mut grads[id] {
+        Some(acc) => {
+            assert_eq!(acc.shape(), g.shape());
+            let acc = acc.make_mut();
+            unsafe {
+                ops::add_raw_f32(
+                    acc.as_ptr(),
+                    g.as_slice().as_ptr(),
+                    acc.as_mut_ptr(),
+                    acc.len(),
+                )
+            };
+        }
+        None => grads[id] = Some(g),
+    }
+}
+
+impl Tape {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn push(&mut self, value: Tensor<f32, 2>, op: Op) -> Var {
+        let shape = value.shape();
+        self.entries.push(Entry { value, op });
+        Var {
+            id: self.entries.len() - 1,
+            shape,
+        }
+    }
+
+    /// Records a value to differentiate with respect to, such as a parameter
+    /// or an input.
+    pub fn leaf(&mut self, value: Tensor<f32, 2>) -> Var {
+        self.push(value, Op::Leaf)
+    }
+
+    pub fn value(&self, x: Var) -> &Tensor<f32, 2> {
+        &self.entries[x.id].value
+    }
+
+    /// Forgets every recorded op, keeping nothing but the leaves, e.g. to start
+    /// the next training step. Handles to the leaves stay valid as long as
+    /// they were all recorded before any op.
+    pub fn clear_ops(&mut self) {
+        let leaves = self
+            .entries
+            .iter()
+            .take_while(|e| matches!(e.op, Op::Leaf))
+            .count();
+        self.entries.truncate(leaves);
+    }
+
+    /// Replaces the value of a leaf, e.g. after an optimizer step.
+    pub fn set(&mut self, x: Var, value: Tensor<f32, 2>) {
+        assert!(matches!(self.entries[x.id].op, Op::Leaf));
+        assert_eq!(value.shape(), x.shape);
+        self.entries[x.id].value = value;
+    }
+
+    /// The value of a leaf, to update in place, e.g. in an optimizer step.
+    pub fn value_mut(&mut self, x: Var) -> &mut Tensor<f32, 2> {
+        assert!(matches!(self.entries[x.id].op, Op::Leaf));
+        &mut self.entries[x.id].value
+    }
+
+    /// `x · wᵀ`, like [`Tensor::<f16, 2>::matmul`]. `w` is `[m, k]` and `x` is
+    /// `[n, k]`, giving `[n, m]`.
+    pub fn matmul(&mut self, w: Var, x: Var) -> Var {
+        let [m, k] = w.shape;
+        let [n, k2] = x.shape;
+        assert_eq!(k, k2);
+
+        let mut y = Tensor::zeros([n, m]);
+        unsafe {
+            ops::generic_dot_f32(
+                self.value(x).as_slice().as_ptr(),
+                self.value(w).as_slice().as_ptr(),
+                y.make_mut().as_mut_ptr(),
+                [1, 1, n, k],
+                [1, 1, m, k],
+            );
+        }
+        self.push(y, Op::MatMul(w.id, x.id))
+    }
+
+    /// `b`, broadcast to the shape of `a` if it's a single row.
+    fn broadcast(&self, a: Var, b: Var) -> Tensor<f32, 2> {
+        if b.shape == a.shape {
+            self.value(b).clone()
+        } else {
+            assert_eq!(b.shape, [1, a.shape[1]]);
+            self.value(b).repeat(a.shape)
+        }
+    }
+
+    /// Element-wise `a + b`, where `b` may be a single row added to every row.
+    pub fn add(&mut self, a: Var, b: Var) -> Var {
+        let b_full = self.broadcast(a, b);
+        let mut y = Tensor::zeros(a.shape);
+        unsafe {
+            ops::add_raw_f32(
+                self.value(a).as_slice().as_ptr(),
+                b_full.as_slice().as_ptr(),
+                y.make_mut().as_mut_ptr(),
+                b_full.as_slice().len(),
+            );
+        }
+        self.push(y, Op::Add(a.id, b.id))
+    }
+
+    /// Element-wise `a * b`, where `b` may be a single row multiplied with
+    /// every row, e.g. the weights of a norm.
+    pub fn mul(&mut self, a: Var, b: Var) -> Var {
+        let b_full = self.broadcast(a, b);
+        let mut y = Tensor::zeros(a.shape);
+        unsafe {
+            ops::mul_raw_f32(
+                self.value(a).as_slice().as_ptr(),
+                b_full.as_slice().as_ptr(),
+                y.make_mut().as_mut_ptr(),
+                b_full.as_slice().len(),
+            );
+        }
+        self.push(y, Op::Mul(a.id, b.id))
+    }
+
+    pub fn silu(&mut self, x: Var) -> Var {
+        let mut y = Tensor::zeros(x.shape);
+        let x_data = self.value(x).as_slice();
+        unsafe { ops::silu_raw_f32(x_data.as_ptr(), y.make_mut().as_mut_ptr(), x_data.len()) };
+        self.push(y, Op::Silu(x.id))
+    }
+
+    /// Normalizes each row by its root mean square, with `eps` added to the
+    /// mean square.
+    pub fn rms_norm(&mut self, x: Var, eps: f32) -> Var {
+        let [rows, n] = x.shape;
+        let mut y = Tensor::zeros(x.shape);
+        unsafe {
+            ops::rms_norm_f32(
+                self.value(x).as_slice().as_ptr(),
+                y.make_mut().as_mut_ptr(),
+                rows,
+                n,
+                eps,
+            )
+        };
+        self.push(y, Op::RmsNorm(x.id, eps))
+    }
+
+    /// Interleaved rotary position embeddings over rows of `n_heads` whole
+    /// heads, where row `i` is at position `i`, like
+    /// [`Tensor::<f16, 2>::rope_inplace`] with [`RopeStyle::Interleaved`].
+    ///
+    /// [`RopeStyle::Interleaved`]: crate::tensor::RopeStyle::Interleaved
+    pub fn rope(&mut self, x: Var, n_heads: usize, theta: f32) -> Var {
+        let [rows, n] = x.shape;
+        assert_eq!(n % n_heads, 0);
+
+        let mut y = Tensor::zeros(x.shape);
+        unsafe {
+            let x = self.value(x).as_slice().as_ptr();
+            ops::rope_f32(
+                x,
+                y.make_mut().as_mut_ptr(),
+                rows,
+                n_heads,
+                n / n_heads,
+                theta,
+                false,
+            );
+        }
+        self.push(
+            y,
+            Op::Rope {
+                x: x.id,
+                n_heads,
+                theta,
+            },
+        )
+    }
+
+    /// Softmax over each row.
+    pub fn softmax(&mut self, x: Var) -> Var {
+        let [rows, n] = x.shape;
+        let mut y = Tensor::zeros(x.shape);
+        unsafe {
+            ops::softmax_rows_f32(
+                self.value(x).as_slice().as_ptr(),
+                y.make_mut().as_mut_ptr(),
+                rows,
+                n,
+            )
+        };
+        self.push(y, Op::Softmax(x.id))
+    }
+
+    /// Scaled dot-product attention over `[n, d]` queries, keys and values.
+    /// When `causal`, each position only attends to itself and earlier ones.
+    pub fn attention(&mut self, q: Var, k: Var, v: Var, causal: bool) -> Var {
+        let [n, d] = q.shape;
+        assert_eq!(k.shape, [n, d]);
+        assert_eq!(v.shape, [n, d]);
+
+        let mut y = Tensor::zeros([n, d]);
+        unsafe {
+            ops::attention_f32(
+                self.value(q).as_slice().as_ptr(),
+                self.value(k).as_slice().as_ptr(),
+                self.value(v).as_slice().as_ptr(),
+                y.make_mut().as_mut_ptr(),
+                n,
+                d,
+                causal,
+            );
+        }
+        self.push(
+            y,
+            Op::Attention {
+                q: q.id,
+                k: k.id,
+                v: v.id,
+                causal,
+            },
+        )
+    }
+
+    /// Gathers rows of `w`, e.g. token embeddings. See [`Tensor::get_rows`].
+    pub fn get_rows(&mut self, w: Var, idxs: &Tensor<usize, 1>) -> Var {
+        let y = self.value(w).get_rows(idxs);
+        self.push(y, Op::GetRows(w.id, idxs.clone()))
+    }
+
+    /// Splits the columns of `x` into consecutive groups of the given widths,
+    /// e.g. the heads of multi-head attention.
+    pub fn split_columns(&mut self, x: Var, sizes: &[usize]) -> Vec<Var> {
+        let parts = self.value(x).split(sizes, 1);
+        let mut offset = 0;
+        parts
+            .into_iter()
+            .map(|part| {
+                let var = self.push(part, Op::Columns { x: x.id, offset });
+                offset += var.shape[1];
+                var
+            })
+            .collect()
+    }
+
+    /// Joins values with the same number of rows side by side, undoing
+    /// [`Tape::split_columns`].
+    pub fn concat_columns(&mut self, xs: &[Var]) -> Var {
+        let values = xs.iter().map(|&x| self.value(x)).collect::<Vec<_>>();
+        let y = Tensor::concat(&values, 1);
+        self.push(y, Op::Concat(xs.iter().map(|x| x.id).collect()))
+    }
+
+    /// The mean cross-entropy of the softmax of each row of `logits` against
+    /// the class in `targets`, as a `[1, 1]` scalar.
+    pub fn cross_entropy(&mut self, logits: Var, targets: &Tensor<usize, 1>) -> Var {
+        let [rows, n] = logits.shape;
+        assert_eq!(targets.shape(), [rows]);
+
+        let loss = unsafe {
+            ops::cross_entropy_f32(
+                self.value(logits).as_slice().as_ptr(),
+                targets.as_slice().as_ptr(),
+                rows,
+                n,
+            )
+        };
+        self.push(
+            Tensor::new(vec![loss], [1, 1]),
+            Op::CrossEntropy(logits.id, targets.clone()),
+        )
+    }
+
+    /// The sum of every element, as a `[1, 1]` scalar.
+    pub fn sum(&mut self, x: Var) -> Var {
+        let y = self.value(x).sum(1).sum(0);
+        self.push(y, Op::Sum(x.id))
+    }
+
+    /// Computes the gradients of the scalar `loss` with respect to every value
+    /// recorded before it.
+    pub fn backward(&self, loss: Var) -> Gradients {
+        assert_eq!(loss.shape, [1, 1]);
+
+        let mut grads = vec![None; self.entries.len()];
+        grads[loss.id] = Some(Tensor::new(vec![1.0], [1, 1]));
+
+        for i in (0..=loss.id).rev() {
+            let Some(dy) = grads[i].take() else {
+                continue;
+            };
+            self.backward_op(i, &dy, &mut grads);
+            grads[i] = Some(dy);
+        }
+
+        Gradients { grads }
+    }
+
+    fn backward_op(&self, i: usize, dy: &Tensor<f32, 2>, grads: &mut [Option<Tensor<f32, 2>>]) {
+        let value = |id: usize| self.entries[id].value.as_slice();
+        let zeros = |id: usize| Tensor::<f32, 2>::zeros(self.entries[id].value.shape());
+        let dy_data = dy.as_slice();
+        let [rows, n] = dy.shape();
+
+        match &self.entries[i].op {
+            Op::Leaf => {}
+            &Op::MatMul(w, x) => {
+                let [m, k] = self.entries[w].value.shape();
+                let (mut dw, mut dx) = (zeros(w), zeros(x));
+                unsafe {
+                    ops::matmul_backward_f32(
+                        Grad {
+                            x: value(x).as_ptr(),
+                            dx: dx.make_mut().as_mut_ptr(),
+                        },
+                        Grad {
+                            x: value(w).as_ptr(),
+                            dx: dw.make_mut().as_mut_ptr(),
+                        },
+                        dy_data.as_ptr(),
+                        rows,
+                        k,
+                        m,
+                    );
+                }
+                accumulate(grads, w, dw);
+                accumulate(grads, x, dx);
+            }
+            &Op::Add(a, b) => {
+                accumulate(grads, a, dy.clone());
+                accumulate(
+                    grads,
+                    b,
+                    unbroadcast(dy.clone(), self.entries[b].value.shape()),
+                );
+            }
+            &Op::Mul(a, b) => {
+                let b_shape = self.entries[b].value.shape();
+                let b_full = if b_shape == dy.shape() {
+                    self.entries[b].value.clone()
+                } else {
+                    self.entries[b].value.repeat(dy.shape())
+                };
+
+                let (mut da, mut db) = (Tensor::zeros(dy.shape()), Tensor::zeros(dy.shape()));
+                unsafe {
+                    ops::mul_raw_f32(
+                        dy_data.as_ptr(),
+                        b_full.as_slice().as_ptr(),
+                        da.make_mut().as_mut_ptr(),
+                        dy_data.len(),
+                    );
+                    ops::mul_raw_f32(
+                        dy_data.as_ptr(),
+                        value(a).as_ptr(),
+                        db.make_mut().as_mut_ptr(),
+                        dy_data.len(),
+                    );
+                }
+                accumulate(grads, a, da);
+                accumulate(grads, b, unbroadcast(db, b_shape));
+            }
+            &Op::Silu(x) => {
+                let mut dx = zeros(x);
+                unsafe {
+                    ops::silu_backward_f32(
+                        value(x).as_ptr(),
+                        dy_data.as_ptr(),
+                        dx.make_mut().as_mut_ptr(),
+                        dy_data.len(),
+                    )
+                };
+                accumulate(grads, x, dx);
+            }
+            &Op::RmsNorm(x, eps) => {
+                let mut dx = zeros(x);
+                let dx_ptr = dx.make_mut().as_mut_ptr();
+                unsafe {
+                    ops::rms_norm_backward_f32(
+                        value(x).as_ptr(),
+                        dy_data.as_ptr(),
+                        dx_ptr,
+                        rows,
+                        n,
+                        eps,
+                    )
+                };
+                accumulate(grads, x, dx);
+            }
+            &Op::Rope { x, n_heads, theta } => {
+                let mut dx = zeros(x);
+                let dx_ptr = dx.make_mut().as_mut_ptr();
+                unsafe {
+                    ops::rope_f32(
+                        dy_data.as_ptr(),
+                        dx_ptr,
+                        rows,
+                        n_heads,
+                        n / n_heads,
+                        theta,
+                        true,
+                    )
+                };
+                accumulate(grads, x, dx);
+            }
+            &Op::Softmax(x) => {
+                let mut dx = zeros(x);
+                unsafe {
+                    ops::softmax_rows_backward_f32(
+                        value(i).as_ptr(),
+                        dy_data.as_ptr(),
+                        dx.make_mut().as_mut_ptr(),
+                        rows,
+                        n,
+                    )
+                };
+                accumulate(grads, x, dx);
+            }
+            &Op::Attention { q, k, v, causal } => {
+                let (mut dq, mut dk, mut dv) = (zeros(q), zeros(k), zeros(v));
+                unsafe {
+                    ops::attention_backward_f32(
+                        Grad {
+                            x: value(q).as_ptr(),
+                            dx: dq.make_mut().as_mut_ptr(),
+                        },
+                        Grad {
+                            x: value(k).as_ptr(),
+                            dx: dk.make_mut().as_mut_ptr(),
+                        },
+                        Grad {
+                            x: value(v).as_ptr(),
+                            dx: dv.make_mut().as_mut_ptr(),
+                        },
+                        dy_data.as_ptr(),
+                        rows,
+                        n,
+                        causal,
+                    );
+                }
+                accumulate(grads, q, dq);
+                accumulate(grads, k, dk);
+                accumulate(grads, v, dv);
+            }
+            Op::GetRows(w, idxs) => {
+                let mut dw = zeros(*w);
+                unsafe {
+                    ops::get_rows_backward_f32(
+                        idxs.as_slice().as_ptr(),
+                        dy_data.as_ptr(),
+                        dw.make_mut().as_mut_ptr(),
+                        n,
+                        self.entries[*w].value.shape()[0],
+                        idxs.shape()[0],
+                    );
+                }
+                accumulate(grads, *w, dw);
+            }
+            &Op::Columns { x, offset } => {
+                let mut dx = zeros(x);
+                Tensor::concat_into(&mut dx, &[dy], 1, offset);
+                accumulate(grads, x, dx);
+            }
+            Op::Concat(xs) => {
+                let sizes = xs
+                    .iter()
+                    .map(|&x| self.entries[x].value.shape()[1])
+                    .collect::<Vec<_>>();
+                for (&x, dx) in xs.iter().zip(dy.split(&sizes, 1)) {
+                    accumulate(grads, x, dx);
+                }
+            }
+            Op::CrossEntropy(logits, targets) => {
+                let [rows, n] = self.entries[*logits].value.shape();
+                let mut dx = zeros(*logits);
+                unsafe {
+                    ops::cross_entropy_backward_f32(
+                        value(*logits).as_ptr(),
+                        targets.as_slice().as_ptr(),
+                        dy_data[0],
+                        dx.make_mut().as_mut_ptr(),
+                        rows,
+                        n,
+                    );
+                }
+                accumulate(grads, *logits, dx);
+            }
+            &Op::Sum(x) => {
+                let g = dy_data[0];
+                accumulate(
+                    grads,
+                    x,
+                    Tensor::new(vec![g; value(x).len()], self.entries[x].value.shape()),
+                );
+            }
+        }
+    }
+}
+
+/// Sums a gradient over the rows it was broadcast to, if its value was a
+/// single row.
+fn unbroadcast(g: Tensor<f32, 2>, shape: [usize; 2]) -> Tensor<f32, 2> {
+    if g.shape() == shape {
+        g
+    } else {
+        g.sum(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assert_tensor_close;
+    use crate::rng::Rng;
+
+    fn tensor(shape: [usize; 2], seed: u64) -> Tensor<f32, 2> {
+        Tensor::new(
+            Rng(seed * 2654435761 + 1).vec_f32(shape[0] * shape[1]),
+            shape,
+        )
+    }
+
+    /// Checks the gradients of `sum(f(inputs) * r)`, for a fixed random `r`,
+    /// against central finite differences.
+    fn check_gradients(inputs: &[Tensor<f32, 2>], f: impl Fn(&mut Tape, &[Var]) -> Var) {
+        let loss = |inputs: &[Tensor<f32, 2>]| {
+            let mut tape = Tape::new();
+            let vars = inputs
+                .iter()
+                .map(|x| tape.leaf(x.clone()))
+                .collect::<Vec<_>>();
+            let y = f(&mut tape, &vars);
+            let r = tape.leaf(tensor(y.shape(), 99));
+            let yr = tape.mul(y, r);
+            let loss = tape.sum(yr);
+            (tape, vars, loss)
+        };
+
+        let (tape, vars, l) = loss(inputs);
+        let grads = tape.backward(l);
+
+        const EPS: f32 = 1e-2;
+        for (i, x) in inputs.iter().enumerate() {
+            let analytic = grads.get(vars[i]).expect("input has no gradient");
+
+            let numeric = (0..x.as_slice().len())
+                .map(|j| {
+                    let mut perturbed = inputs.to_vec();
+                    perturbed[i].make_mut()[j] += EPS;
+                    let (tape, _, l) = loss(&perturbed);
+                    let plus = tape.value(l).as_slice()[0];
+
+                    perturbed[i].make_mut()[j] -= 2.0 * EPS;
+                    let (tape, _, l) = loss(&perturbed);
+                    let minus = tape.value(l).as_slice()[0];
+
+                    (plus - minus) / (2.0 * EPS)
+                })
+                .collect();
+
+            assert_tensor_close!(
+                analytic,
+                Tensor::new(numeric, x.shape()),
+                rtol = 1e-2,
+                atol = 1e-2
+            );
+        }
+    }
+
+    #[test]
+    fn matmul() {
+        check_gradients(&[tensor([5, 3], 1), tensor([4, 3], 2)], |t, x| {
+            t.matmul(x[0], x[1])
+        });
+    }
+
+    #[test]
+    fn add_and_mul() {
+        let same = [tensor([4, 3], 1), tensor([4, 3], 2)];
+        check_gradients(&same, |t, x| t.add(x[0], x[1]));
+        check_gradients(&same, |t, x| t.mul(x[0], x[1]));
+
+        let broadcast = [tensor([4, 3], 1), tensor([1, 3], 3)];
+        check_gradients(&broadcast, |t, x| t.add(x[0], x[1]));
+        check_gradients(&broadcast, |t, x| t.mul(x[0], x[1]));
+    }
+
+    #[test]
+    fn silu() {
+        check_gradients(&[tensor([4, 5], 1)], |t, x| t.silu(x[0]));
+    }
+
+    #[test]
+    fn rms_norm() {
+        check_gradients(&[tensor([3, 7], 1)], |t, x| t.rms_norm(x[0], 1e-5));
+    }
+
+    #[test]
+    fn rope() {
+        check_gradients(&[tensor([5, 8], 1)], |t, x| t.rope(x[0], 2, 10000.0));
+    }
+
+    #[test]
+    fn softmax() {
+        check_gradients(&[tensor([3, 6], 1)], |t, x| t.softmax(x[0]));
+    }
+
+    #[test]
+    fn attention() {
+        let inputs = [tensor([5, 4], 1), tensor([5, 4], 2), tensor([5, 4], 3)];
+        check_gradients(&inputs, |t, x| t.attention(x[0], x[1], x[2], false));
+        check_gradients(&inputs, |t, x| t.attention(x[0], x[1], x[2], true));
+    }
+
+    #[test]
+    fn get_rows() {
+        let idxs = Tensor::from(vec![2, 0, 2, 3]);
+        check_gradients(&[tensor([4, 3], 1)], |t, x| t.get_rows(x[0], &idxs));
+    }
+
+    #[test]
+    fn columns() {
+        check_gradients(&[tensor([3, 6], 1), tensor([3, 2], 2)], |t, x| {
+            let parts = t.split_columns(x[0], &[2, 4]);
+            let h = t.silu(parts[1]);
+            t.concat_columns(&[x[1], h, parts[0]])
+        });
+    }
+
+    #[test]
+    fn cross_entropy() {
+        let targets = Tensor::from(vec![4, 0, 2]);
+        check_gradients(&[tensor([3, 5], 1)], |t, x| t.cross_entropy(x[0], &targets));
+    }
+
+    #[test]
+    fn reused_values() {
+        // The gradient of a value used twice is the sum of both contributions.
+        check_gradients(&[tensor([3, 4], 1), tensor([5, 4], 2)], |t, x| {
+            let h = t.matmul(x[1], x[0]);
+            let h = t.silu(h);
+            let g = t.rms_norm(h, 1e-5);
+            t.mul(g, h)
+        });
+    }
+}
@@ -0,0 +1,114 @@
+use std::{fs, io, path::PathBuf};
+
+use half::f16;
+use nxml::{
+    ggml::Ggml,
+    imatrix::ActivationStats,
+    scratch::Scratch,
+    tensor::Tensor,
+    tokenizer::Tokenizer,
+    weights::{NameTable, Weights},
+};
+
+use clap::Parser;
+
+/// Runs a model over calibration text, recording how much each input channel
+/// of its weight matrices matters, for `quantize --imatrix`.
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    #[arg(short, long)]
+    model: PathBuf,
+    /// The calibration text.
+    #[arg(short, long)]
+    file: PathBuf,
+    /// Where to save the importance matrix.
+    #[arg(short, long, default_value = "imatrix.dat")]
+    out: PathBuf,
+    /// The number of tokens in each chunk of the text the model sees at once.
+    /// Defaults to the model's context length.
+    #[arg(long)]
+    ctx: Option<usize>,
+    /// Stop after this many chunks.
+    #[arg(long)]
+    chunks: Option<usize>,
+}
+
+/// The mean negative log likelihood of each row's next token.
+fn nll(logits: &Tensor<f16, 2>, tokens: &[usize]) -> f64 {
+    let rows = logits.rows().zip(&tokens[1..]);
+    let sum = rows
+        .map(|(row, &next)| {
+            let max = row
+                .iter()
+                .map(|x| x.to_f32())
+                .fold(f32::NEG_INFINITY, f32::max);
+            let sum = row
+                .iter()
+                .map(|x| ((x.to_f32() - max) as f64).exp())
+                .sum::<f64>();
+            sum.ln() - (row[next].to_f32() - max) as f64
+        })
+        .sum::<f64>();
+    sum / (tokens.len() - 1) as f64
+}
+
+fn main() -> io::Result<()> {
+    let args = Args::parse();
+
+    let (vocab, Ggml { hparams, vars }) = Ggml::load(&args.model)?;
+    let table = NameTable::detect(hparams.arch.name_tables(), vars.keys().map(String::as_str))
+        .ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "unknown tensor naming scheme")
+        })?;
+    let tokenizer = Tokenizer::new(vocab);
+    let weights = Weights::map(vars, &hparams, table)?;
+
+    let ctx = args.ctx.unwrap_or(hparams.n_ctx).min(hparams.n_ctx);
+    if ctx < 2 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "chunks need at least two tokens",
+        ));
+    }
+    let text = fs::read_to_string(&args.file)?;
+    let tokens = tokenizer.encode(&text).to_vec();
+    let n_chunks = (tokens.len() / ctx).min(args.chunks.unwrap_or(usize::MAX));
+    if n_chunks == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "the text is {} tokens, fewer than one chunk of {ctx}",
+                tokens.len()
+            ),
+        ));
+    }
+    println!("{} tokens, {n_chunks} chunks of {ctx}", tokens.len());
+
+    let model = hparams.arch.build(hparams, weights);
+    let mut scratch = Scratch {
+        activations: Some(ActivationStats::new()),
+        ..Scratch::new()
+    };
+    let mut total_nll = 0.0;
+    for (i, chunk) in tokens.chunks_exact(ctx).take(n_chunks).enumerate() {
+        let logits = model.forward(&Tensor::from(chunk.to_vec()), &mut scratch);
+        total_nll += nll(&logits, chunk);
+        println!(
+            "chunk {}/{n_chunks}: perplexity {:.3}",
+            i + 1,
+            (total_nll / (i + 1) as f64).exp()
+        );
+    }
+
+    let stats = scratch.activations.unwrap();
+    let imatrix = stats.to_imatrix(table, n_chunks, &args.file.display().to_string());
+    imatrix.save(&args.out)?;
+    println!(
+        "saved the importance of {} matrices to {}",
+        imatrix.importance.len(),
+        args.out.display()
+    );
+
+    Ok(())
+}
@@ -1,176 +1,101 @@
 use std::{io, path::PathBuf};
 
-use half::f16;
-use nxml::{ggml::Ggml, tensor::Tensor, tokenizer::Tokenizer};
-
-use clap::Parser;
+use nxml::{
+    ggml::Ggml,
+    lora::{self, Lora},
+    safetensors,
+    scratch::Scratch,
+    sentencepiece::SentencePiece,
+    tokenizer::Tokenizer,
+    weights::{NameTable, Weights},
+};
+
+use clap::{Parser, ValueEnum};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Args {
+    /// A GGML or GGUF file, or a safetensors checkpoint with its
+    /// `config.json` and tokenizer beside it.
     #[arg(short, long)]
     model: PathBuf,
+    /// A HuggingFace `tokenizer.json` or SentencePiece `tokenizer.model` to use
+    /// instead of the model's vocabulary.
+    #[arg(long)]
+    tokenizer: Option<PathBuf>,
+    /// A LoRA adapter to apply to the model: a file in llama.cpp's GGML
+    /// format, or a directory saved by HuggingFace's PEFT.
+    #[arg(long)]
+    lora: Option<PathBuf>,
+    #[arg(long, value_enum, default_value_t = LoraMode::Merge, requires = "lora")]
+    lora_mode: LoraMode,
 }
 
-struct Layer {
-    attn_norm: Tensor<f32, 1>,
-
-    wq: Tensor<f16, 2>,
-    wk: Tensor<f16, 2>,
-    wv: Tensor<f16, 2>,
-    wo: Tensor<f16, 2>,
-
-    ffn_norm: Tensor<f32, 1>,
-
-    w1: Tensor<f16, 2>,
-    w2: Tensor<f16, 2>,
-    w3: Tensor<f16, 2>,
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum LoraMode {
+    /// Add the updates into the base weights while loading.
+    Merge,
+    /// Keep the base weights and apply the updates during each matmul.
+    OnTheFly,
 }
 
-struct Model {
-    tok_embeddings: Tensor<f16, 2>,
-    norm: Tensor<f32, 1>,
-    output: Tensor<f16, 2>,
-
-    layers: Vec<Layer>,
+impl From<LoraMode> for lora::LoraMode {
+    fn from(mode: LoraMode) -> Self {
+        match mode {
+            LoraMode::Merge => Self::Merge,
+            LoraMode::OnTheFly => Self::OnTheFly,
+        }
+    }
 }
 
 fn main() -> io::Result<()> {
     let args = Args::parse();
 
-    let (vocab, mut ggml) = Ggml::load(&args.model)?;
-
-    for name in ggml.vars.keys() {
-        println!("{}", name);
-    }
-
-    let tokenizer = Tokenizer::new(vocab);
-
-    println!("{:?}", tokenizer.encode("Hello, world!"));
-
-    let prompt = " Building a website can be done in 10 simple steps:";
+    let (vocab, ggml) = if safetensors::is_checkpoint(&args.model) {
+        (None, safetensors::load_model(&args.model)?)
+    } else {
+        let (vocab, ggml) = Ggml::load(&args.model)?;
+        (Some(vocab), ggml)
+    };
+
+    let tokenizer = match &args.tokenizer {
+        Some(path) if path.extension().is_some_and(|e| e == "model") => {
+            SentencePiece::load(path)?.try_into()?
+        }
+        Some(path) => Tokenizer::from_json(path)?,
+        None => match vocab {
+            Some(vocab) => Tokenizer::new(vocab),
+            None => safetensors::load_tokenizer(&args.model)?,
+        },
+    };
+
+    let prompt = "Building a website can be done in 10 simple steps:";
     let tokens = tokenizer.encode(prompt);
 
-    let model = build_model(ggml);
-
-    compute_model(tokens, &model);
-
-    Ok(())
-}
-
-fn compute_model(tokens: Tensor<usize, 1>, model: &Model) -> Tensor<usize, 1> {
-    let mut input = model.tok_embeddings.get_rows(tokens.clone());
-
-    println!("64");
-
-    for layer in &model.layers {
-        let cur = input.rms_norm();
-        dbg!(&cur);
-        dbg!(&layer.attn_norm);
-        let cur = layer.attn_norm.tile(cur.shape()).matmul(&cur);
-
-        dbg!(&cur);
-        dbg!(&layer.wq);
-
-        let q = dbg!(layer.wq.matmul(&cur));
-        let k = dbg!(layer.wk.matmul(&cur));
-        let v = dbg!(layer.wv.matmul(&cur));
-        let cur = dbg!(v.flash_attn(q, k));
-        let cur = dbg!(layer.wo.matmul(&cur));
-    }
-
-    tokens
-
-    // todo!()
-}
-
-fn build_model(mut ggml: Ggml) -> Model {
-    let mut layers = vec![];
-
-    for i in 0..ggml.hparams.n_layers {
-        let layer = Layer {
-            attn_norm: ggml
-                .vars
-                .remove(&format!("layers.{i}.attention_norm.weight"))
-                .unwrap()
-                .as_tensor_f32()
-                .unwrap(),
-
-            wq: ggml    
-                .vars
-                .remove(&format!("layers.{i}.attention.wq.weight"))
-                .unwrap()
-                .as_tensor_f16()
-                .unwrap(),
-            wk: ggml
-                .vars
-                .remove(&format!("layers.{i}.attention.wk.weight"))
-                .unwrap()
-                .as_tensor_f16()
-                .unwrap(),
-            wv: ggml
-                .vars
-                .remove(&format!("layers.{i}.attention.wv.weight"))
-                .unwrap()
-                .as_tensor_f16()
-                .unwrap(),
-            wo: ggml
-                .vars
-                .remove(&format!("layers.{i}.attention.wo.weight"))
-                .unwrap()
-                .as_tensor_f16()
-                .unwrap(),
-
-            ffn_norm: ggml
-                .vars
-                .remove(&format!("layers.{i}.ffn_norm.weight"))
-                .unwrap()
-                .as_tensor_f32()
-                .unwrap(),
-
-            w1: ggml
-                .vars
-                .remove(&format!("layers.{i}.feed_forward.w1.weight"))
-                .unwrap()
-                .as_tensor_f16()
-                .unwrap(),
-            w2: ggml
-                .vars
-                .remove(&format!("layers.{i}.feed_forward.w2.weight"))
-                .unwrap()
-                .as_tensor_f16()
-                .unwrap(),
-            w3: ggml
-                .vars
-                .remove(&format!("layers.{i}.feed_forward.w3.weight"))
-                .unwrap()
-                .as_tensor_f16()
-                .unwrap(),
+    let Ggml { hparams, vars } = ggml;
+    let table = NameTable::detect(hparams.arch.name_tables(), vars.keys().map(String::as_str))
+        .ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "unknown tensor naming scheme")
+        })?;
+    let mut weights = Weights::map(vars, &hparams, table)?;
+    if let Some(path) = &args.lora {
+        let lora = if path.is_dir() {
+            Lora::load_peft(path)?
+        } else {
+            Lora::load(path)?
         };
-
-        layers.push(layer);
+        weights = weights.with_lora(lora, args.lora_mode.into())?;
     }
 
-    Model {
-        tok_embeddings: ggml
-            .vars
-            .remove(&format!("tok_embeddings.weight"))
-            .unwrap()
-            .as_tensor_f16()
-            .unwrap(),
-        norm: ggml
-            .vars
-            .remove(&format!("norm.weight"))
-            .unwrap()
-            .as_tensor_f32()
-            .unwrap(),
-        output: ggml
-            .vars
-            .remove(&format!("output.weight"))
-            .unwrap()
-            .as_tensor_f16()
-            .unwrap(),
+    let model = hparams.arch.build(hparams, weights);
+    let mut scratch = Scratch::new();
 
-        layers,
-    }
+    let logits = model.forward(&tokens, &mut scratch);
+    let last = logits.rows().last().unwrap();
+    let next = (0..last.len())
+        .max_by(|&a, &b| last[a].total_cmp(&last[b]))
+        .unwrap();
+    println!("next token: {next}");
+
+    Ok(())
 }
@@ -0,0 +1,257 @@
+use std::{collections::HashMap, io, path::PathBuf};
+
+use half::f16;
+use nxml::{
+    buffer::AlignedBuf,
+    ggml::{Data, Format, Ggml, ScalarType, Var},
+    imatrix::Imatrix,
+    quant::Quantized,
+    safetensors,
+    tokenizer::Vocab,
+    weights::{NameTable, Weight},
+};
+
+use clap::{Parser, ValueEnum};
+
+/// Quantizes the matrices of a model, reporting how much each one changed
+/// and how much smaller the model got.
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// The model to quantize, in any format `Ggml::load` reads, or a
+    /// safetensors checkpoint with its `config.json` and tokenizer beside it.
+    #[arg(short, long)]
+    model: PathBuf,
+    /// Where to save the quantized model.
+    #[arg(short, long)]
+    out: PathBuf,
+    /// The type to quantize matrices to.
+    #[arg(short = 't', long = "type", value_enum)]
+    ty: Type,
+    /// The type to keep the token and position embeddings and the output
+    /// projection in.
+    #[arg(long, value_enum, default_value_t = Type::F16)]
+    embedding_type: Type,
+    /// The type to keep norms and biases in.
+    #[arg(long, value_enum, default_value_t = VectorType::F32)]
+    norm_type: VectorType,
+    #[arg(long, value_enum, default_value_t = OutputFormat::Gguf)]
+    format: OutputFormat,
+    /// An importance matrix from the `imatrix` tool, collected with the same
+    /// tensor names, to choose block scales with.
+    #[arg(long)]
+    imatrix: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Type {
+    F32,
+    F16,
+    #[value(name = "q4_0")]
+    Q4_0,
+    #[value(name = "q4_1")]
+    Q4_1,
+    #[value(name = "q5_0")]
+    Q5_0,
+    #[value(name = "q5_1")]
+    Q5_1,
+    #[value(name = "q8_0")]
+    Q8_0,
+}
+
+impl From<Type> for ScalarType {
+    fn from(ty: Type) -> Self {
+        match ty {
+            Type::F32 => Self::F32,
+            Type::F16 => Self::F16,
+            Type::Q4_0 => Self::Q4_0,
+            Type::Q4_1 => Self::Q4_1,
+            Type::Q5_0 => Self::Q5_0,
+            Type::Q5_1 => Self::Q5_1,
+            Type::Q8_0 => Self::Q8_0,
+        }
+    }
+}
+
+/// Vectors are rarely a whole number of blocks, and too small to be worth
+/// quantizing.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum VectorType {
+    F32,
+    F16,
+}
+
+/// The formats that can hold quantized data.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    /// llama.cpp's mmap-able GGML format. Only holds LLaMA models.
+    Ggjt,
+    Gguf,
+}
+
+/// Converts `data` to `ty`, weighting each row's error by `importance`, if
+/// given.
+fn convert(data: &Data, ty: ScalarType, importance: Option<&[f32]>) -> Data {
+    let x = data.to_f32();
+    match ty {
+        ScalarType::F32 => Data::F32(AlignedBuf::from_slice(&x)),
+        ScalarType::F16 => Data::F16(AlignedBuf::from_slice(
+            &x.iter().map(|&x| f16::from_f32(x)).collect::<Vec<_>>(),
+        )),
+        ty => Data::Quantized(match importance {
+            Some(importance) => Quantized::with_importance(ty, &x, importance),
+            None => Quantized::new(ty, &x),
+        }),
+    }
+}
+
+/// The root mean square and largest absolute difference between `a` and `b`.
+fn error(a: &[f32], b: &[f32]) -> (f32, f32) {
+    let (sum, max) = a
+        .iter()
+        .zip(b)
+        .fold((0.0f64, 0.0f32), |(sum, max), (a, b)| {
+            let d = a - b;
+            (sum + (d * d) as f64, max.max(d.abs()))
+        });
+    ((sum / a.len().max(1) as f64).sqrt() as f32, max)
+}
+
+fn mib(bytes: usize) -> f64 {
+    bytes as f64 / (1 << 20) as f64
+}
+
+fn main() -> io::Result<()> {
+    let args = Args::parse();
+
+    let (vocab, Ggml { mut hparams, vars }) = if safetensors::is_checkpoint(&args.model) {
+        let tokenizer = safetensors::load_tokenizer(&args.model)?;
+        let vocab = Vocab {
+            bos: tokenizer.bos_id(),
+            eos: tokenizer.eos_id(),
+            ..tokenizer.vocab().clone()
+        };
+        (vocab, safetensors::load_model(&args.model)?)
+    } else {
+        Ggml::load(&args.model)?
+    };
+    if vocab.id_to_token.len() != hparams.vocab_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "the tokenizer has {} tokens, but the model {}",
+                vocab.id_to_token.len(),
+                hparams.vocab_size
+            ),
+        ));
+    }
+    let table = NameTable::detect(hparams.arch.name_tables(), vars.keys().map(String::as_str))
+        .ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "unknown tensor naming scheme")
+        })?;
+    let embeddings = [Weight::TokEmbeddings, Weight::PosEmbeddings, Weight::Output]
+        .into_iter()
+        .filter_map(|w| table.name(w))
+        .collect::<Vec<_>>();
+    // The importance is per input channel, so it doesn't line up with the
+    // rows of matrices stored as `[in, out]`.
+    let transposed = table
+        .weights(hparams.n_layers)
+        .into_iter()
+        .filter(|w| matches!(w, Weight::Layer(_, w) if table.transposed.contains(w)))
+        .filter_map(|w| table.name(w))
+        .collect::<Vec<_>>();
+    let imatrix = args.imatrix.as_ref().map(Imatrix::load).transpose()?;
+    if let Some(imatrix) = &imatrix {
+        println!(
+            "importance of {} matrices over {} chunks of {}",
+            imatrix.importance.len(),
+            imatrix.chunks,
+            imatrix.dataset
+        );
+    }
+
+    let mut names = vars.keys().cloned().collect::<Vec<_>>();
+    names.sort();
+
+    let (mut size, mut quantized_size) = (0, 0);
+    let mut quantized = HashMap::new();
+    for name in names {
+        let var = &vars[&name];
+        let from = var.data.scalar_type();
+        let mut ty = if var.dims.len() < 2 {
+            match args.norm_type {
+                VectorType::F32 => ScalarType::F32,
+                VectorType::F16 => ScalarType::F16,
+            }
+        } else if embeddings.contains(&name) {
+            args.embedding_type.into()
+        } else {
+            args.ty.into()
+        };
+
+        // Blocks can't span rows.
+        let row_len = var.dims.last().copied().unwrap_or(1);
+        let mut note = String::new();
+        if !row_len.is_multiple_of(ty.block_len()) {
+            note = format!(" (rows of {row_len} aren't whole blocks)");
+            ty = if from.is_quantized() || from == ScalarType::BF16 {
+                ScalarType::F16
+            } else {
+                from
+            };
+        }
+
+        let importance = imatrix
+            .as_ref()
+            .and_then(|imatrix| imatrix.importance.get(&name))
+            .filter(|importance| {
+                ty.is_quantized() && importance.len() == row_len && !transposed.contains(&name)
+            });
+        if importance.is_some() {
+            note += " (importance-weighted)";
+        }
+
+        let data = convert(&var.data, ty, importance.map(Vec::as_slice));
+        let (rmse, max) = error(&var.data.to_f32(), &data.to_f32());
+        let (before, after) = (from.size_of(var.data.len()), ty.size_of(data.len()));
+        println!(
+            "{name:<48} {:>14} {from:?} -> {ty:?}: rmse {rmse:.2e}, max error {max:.2e}, {:.2} -> {:.2} MiB{note}",
+            format!("{:?}", var.dims),
+            mib(before),
+            mib(after),
+        );
+        size += before;
+        quantized_size += after;
+
+        quantized.insert(
+            name.clone(),
+            Var {
+                name,
+                dims: var.dims.clone(),
+                data,
+            },
+        );
+    }
+
+    println!(
+        "{:.2} MiB -> {:.2} MiB ({:.1}% of the original size)",
+        mib(size),
+        mib(quantized_size),
+        100.0 * quantized_size as f64 / size.max(1) as f64
+    );
+
+    hparams.scalar_ty = args.ty.into();
+    let format = match args.format {
+        OutputFormat::Ggjt => Format::Ggjt,
+        OutputFormat::Gguf => Format::Gguf,
+    };
+    Ggml {
+        hparams,
+        vars: quantized,
+    }
+    .save(&vocab, &args.out, format)?;
+    println!("saved {}", args.out.display());
+
+    Ok(())
+}
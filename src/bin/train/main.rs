@@ -0,0 +1,342 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use half::f16;
+use nxml::{
+    autograd::{Tape, Var},
+    buffer::AlignedBuf,
+    ggml::{self, Data, Format, Ggml, HParams, ScalarType},
+    model::llama::GGML_NAMES,
+    optim::{clip_grad_norm, AdamW, CosineSchedule, Optimizer},
+    rng::Rng,
+    tensor::Tensor,
+    tokenizer::{Token, TokenType, Vocab},
+    weights::{LayerWeight, Weight},
+};
+
+use clap::Parser;
+
+/// Trains a small LLaMA-architecture model on the bytes of a text file, and
+/// saves it as a GGML checkpoint the `llama` binary can load.
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// The text file to train on.
+    #[arg(short, long)]
+    data: PathBuf,
+    /// Where to save the checkpoint.
+    #[arg(short, long)]
+    out: PathBuf,
+
+    #[arg(long, default_value_t = 64)]
+    dim: usize,
+    #[arg(long, default_value_t = 4)]
+    n_heads: usize,
+    #[arg(long, default_value_t = 2)]
+    n_layers: usize,
+    #[arg(long, default_value_t = 32)]
+    multiple_of: usize,
+
+    /// The number of tokens in each training sequence.
+    #[arg(long, default_value_t = 64)]
+    seq_len: usize,
+    #[arg(long, default_value_t = 1000)]
+    steps: usize,
+    #[arg(long, default_value_t = 100)]
+    warmup_steps: usize,
+    #[arg(long, default_value_t = 3e-3)]
+    lr: f32,
+    #[arg(long, default_value_t = 3e-4)]
+    min_lr: f32,
+    #[arg(long, default_value_t = 0.1)]
+    weight_decay: f32,
+    /// The largest L2 norm of the gradients of all parameters together.
+    #[arg(long, default_value_t = 1.0)]
+    max_grad_norm: f32,
+    #[arg(long, default_value_t = 1)]
+    seed: u64,
+}
+
+/// `<unk>`, `<s>` and `</s>`, followed by one token per byte, so ids match
+/// the byte fallback of `Tokenizer::encode`.
+const N_SPECIAL: usize = 3;
+const BOS: usize = 1;
+
+struct Layer {
+    attn_norm: Var,
+
+    wq: Var,
+    wk: Var,
+    wv: Var,
+    wo: Var,
+
+    ffn_norm: Var,
+
+    w1: Var,
+    w2: Var,
+    w3: Var,
+}
+
+struct Model {
+    tok_embeddings: Var,
+    norm: Var,
+    output: Var,
+
+    layers: Vec<Layer>,
+}
+
+fn main() -> io::Result<()> {
+    let args = Args::parse();
+    assert_eq!(
+        args.dim % args.n_heads,
+        0,
+        "dim must be a multiple of n_heads"
+    );
+
+    let text = fs::read(&args.data)?;
+    let tokens = std::iter::once(BOS)
+        .chain(text.iter().map(|&b| b as usize + N_SPECIAL))
+        .collect::<Vec<_>>();
+    if tokens.len() <= args.seq_len + 1 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the training data is shorter than a sequence",
+        ));
+    }
+
+    let hparams = HParams::llama(
+        N_SPECIAL + 256,
+        args.dim,
+        args.multiple_of,
+        args.n_heads,
+        args.n_layers,
+    );
+
+    let mut rng = Rng(args.seed.wrapping_mul(2654435761) | 1);
+    let mut tape = Tape::new();
+    let model = init_model(&hparams, &mut tape, &mut rng);
+    let params = named_params(&model)
+        .into_iter()
+        .map(|(_, p)| p)
+        .collect::<Vec<_>>();
+
+    let mut optimizer = AdamW::new(args.weight_decay);
+    let schedule = CosineSchedule {
+        max_lr: args.lr,
+        min_lr: args.min_lr,
+        warmup_steps: args.warmup_steps,
+        total_steps: args.steps,
+    };
+
+    for step in 0..args.steps {
+        let start = rng.usize(0..tokens.len() - args.seq_len);
+        let inputs = Tensor::from(tokens[start..start + args.seq_len].to_vec());
+        let targets = Tensor::from(tokens[start + 1..start + args.seq_len + 1].to_vec());
+
+        let logits = forward(&model, &hparams, &mut tape, &inputs);
+        let loss = tape.cross_entropy(logits, &targets);
+        let mut grads = tape.backward(loss);
+        let grad_norm = clip_grad_norm(&mut grads, &params, args.max_grad_norm);
+
+        let lr = schedule.lr(step);
+        if step % 10 == 0 || step + 1 == args.steps {
+            let loss = tape.value(loss).as_slice()[0];
+            println!("step {step}: loss {loss:.4}, lr {lr:.2e}, grad norm {grad_norm:.3}");
+        }
+
+        optimizer.step(&mut tape, &params, &grads, lr);
+        tape.clear_ops();
+    }
+
+    save(&model, hparams, &tape, &args.out)?;
+    println!("saved {}", args.out.display());
+
+    Ok(())
+}
+
+fn init_model(hparams: &HParams, tape: &mut Tape, rng: &mut Rng) -> Model {
+    let (dim, n_ff) = (hparams.dim, hparams.n_ff);
+
+    // Uniform weights scaled by the fan-in, and norms starting at identity.
+    let mut matrix = |tape: &mut Tape, rows: usize, cols: usize| {
+        let scale = 1.0 / (cols as f32).sqrt();
+        let data = (0..rows * cols).map(|_| rng.float() * scale).collect();
+        tape.leaf(Tensor::new(data, [rows, cols]))
+    };
+    let norm = |tape: &mut Tape| tape.leaf(Tensor::new(vec![1.0; dim], [1, dim]));
+
+    let tok_embeddings = matrix(tape, hparams.vocab_size, dim);
+    let layers = (0..hparams.n_layers)
+        .map(|_| Layer {
+            attn_norm: norm(tape),
+            wq: matrix(tape, dim, dim),
+            wk: matrix(tape, dim, dim),
+            wv: matrix(tape, dim, dim),
+            wo: matrix(tape, dim, dim),
+            ffn_norm: norm(tape),
+            w1: matrix(tape, n_ff, dim),
+            w2: matrix(tape, dim, n_ff),
+            w3: matrix(tape, n_ff, dim),
+        })
+        .collect();
+    let norm = norm(tape);
+    let output = matrix(tape, hparams.vocab_size, dim);
+
+    Model {
+        tok_embeddings,
+        norm,
+        output,
+        layers,
+    }
+}
+
+/// Every parameter, along with the weight of the model it is.
+fn named_params(model: &Model) -> Vec<(Weight, Var)> {
+    let mut params = vec![
+        (Weight::TokEmbeddings, model.tok_embeddings),
+        (Weight::Norm, model.norm),
+        (Weight::Output, model.output),
+    ];
+
+    for (i, layer) in model.layers.iter().enumerate() {
+        for (w, p) in [
+            (LayerWeight::AttnNorm, layer.attn_norm),
+            (LayerWeight::Wq, layer.wq),
+            (LayerWeight::Wk, layer.wk),
+            (LayerWeight::Wv, layer.wv),
+            (LayerWeight::Wo, layer.wo),
+            (LayerWeight::FfnNorm, layer.ffn_norm),
+            (LayerWeight::W1, layer.w1),
+            (LayerWeight::W2, layer.w2),
+            (LayerWeight::W3, layer.w3),
+        ] {
+            params.push((Weight::Layer(i, w), p));
+        }
+    }
+    params
+}
+
+/// Records the forward pass over `tokens` on the tape, returning the logits
+/// for the next token at each position. This is the same computation as
+/// `Llama::forward`, in `f32`, so the saved checkpoint runs as trained.
+fn forward(model: &Model, hparams: &HParams, tape: &mut Tape, tokens: &Tensor<usize, 1>) -> Var {
+    let head_dims = vec![hparams.dim / hparams.n_heads; hparams.n_heads];
+    let mut x = tape.get_rows(model.tok_embeddings, tokens);
+
+    for layer in &model.layers {
+        let h = tape.rms_norm(x, hparams.norm_eps);
+        let h = tape.mul(h, layer.attn_norm);
+
+        let q = tape.matmul(layer.wq, h);
+        let k = tape.matmul(layer.wk, h);
+        let v = tape.matmul(layer.wv, h);
+        let q = tape.rope(q, hparams.n_heads, hparams.rope_theta);
+        let k = tape.rope(k, hparams.n_heads, hparams.rope_theta);
+        let (qs, ks, vs) = (
+            tape.split_columns(q, &head_dims),
+            tape.split_columns(k, &head_dims),
+            tape.split_columns(v, &head_dims),
+        );
+        let heads = (0..hparams.n_heads)
+            .map(|i| tape.attention(qs[i], ks[i], vs[i], true))
+            .collect::<Vec<_>>();
+        let attn = tape.concat_columns(&heads);
+        let attn = tape.matmul(layer.wo, attn);
+        x = tape.add(x, attn);
+
+        let h = tape.rms_norm(x, hparams.norm_eps);
+        let h = tape.mul(h, layer.ffn_norm);
+        let gate = tape.matmul(layer.w1, h);
+        let gate = tape.silu(gate);
+        let up = tape.matmul(layer.w3, h);
+        let ff = tape.mul(gate, up);
+        let ff = tape.matmul(layer.w2, ff);
+        x = tape.add(x, ff);
+    }
+
+    let x = tape.rms_norm(x, hparams.norm_eps);
+    let x = tape.mul(x, model.norm);
+    tape.matmul(model.output, x)
+}
+
+/// Saves the model with a byte-level vocabulary. Norms are saved as `f32`
+/// vectors and matrices as `f16`, like converted LLaMA checkpoints.
+fn save(model: &Model, hparams: HParams, tape: &Tape, path: &Path) -> io::Result<()> {
+    let token = |token: &str, ty| Token {
+        token: token.into(),
+        score: 0.0,
+        ty,
+    };
+    let mut tokens = vec![
+        token("<unk>", TokenType::Unknown),
+        token("<s>", TokenType::Control),
+        token("</s>", TokenType::Control),
+    ];
+    tokens.extend((0..=255u8).map(|b| token(&format!("<0x{b:02X}>"), TokenType::Byte)));
+    let vocab = Vocab::new(tokens);
+
+    let vars = named_params(model)
+        .into_iter()
+        .map(|(weight, p)| {
+            let value = tape.value(p);
+            let data = match weight.scalar_type(&hparams) {
+                ScalarType::F32 => Data::F32(AlignedBuf::from_slice(value.as_slice())),
+                _ => Data::F16(
+                    value
+                        .iter()
+                        .map(|&x| f16::from_f32(x))
+                        .collect::<Vec<_>>()
+                        .into(),
+                ),
+            };
+            let name = GGML_NAMES.name(weight).unwrap();
+            (
+                name.clone(),
+                ggml::Var {
+                    name,
+                    dims: weight.shape(&hparams),
+                    data,
+                },
+            )
+        })
+        .collect::<HashMap<_, _>>();
+
+    Ggml { hparams, vars }.save(&vocab, path, Format::Ggmf)
+}
+
+#[cfg(test)]
+mod tests {
+    use nxml::scratch::Scratch;
+    use nxml::weights::Weights;
+
+    use super::*;
+
+    #[test]
+    fn checkpoints_run_as_trained() {
+        let hparams = HParams::llama(N_SPECIAL + 256, 16, 8, 2, 2);
+        let mut tape = Tape::new();
+        let model = init_model(&hparams, &mut tape, &mut Rng(3));
+        let tokens = std::iter::once(BOS).chain(b"Hello!".iter().map(|&b| b as usize + N_SPECIAL));
+        let tokens = Tensor::from(tokens.collect::<Vec<_>>());
+        let logits = forward(&model, &hparams, &mut tape, &tokens);
+        let expected = tape.value(logits).clone();
+
+        let path = std::env::temp_dir().join(format!("nxml-train-{}", std::process::id()));
+        let loaded = save(&model, hparams, &tape, &path).and_then(|()| Ggml::load(&path));
+        let _ = fs::remove_file(&path);
+        let (_, Ggml { hparams, vars }) = loaded.unwrap();
+
+        let weights = Weights::map(vars, &hparams, &GGML_NAMES).unwrap();
+        let logits = hparams
+            .arch
+            .build(hparams, weights)
+            .forward(&tokens, &mut Scratch::new());
+        assert_eq!(logits.shape(), expected.shape());
+        for (x, y) in logits.to_vec().into_iter().zip(expected.as_slice()) {
+            assert!((x.to_f32() - y).abs() < 2e-2, "{x} != {y}");
+        }
+    }
+}
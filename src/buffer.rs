@@ -0,0 +1,159 @@
+use std::{
+    alloc::{self, Layout},
+    fmt, mem,
+    ops::{Deref, DerefMut},
+    ptr::NonNull,
+    slice,
+};
+
+use crate::{tensor::TensorElement, CHUNK_SIZE};
+
+/// Round a number up to a multiple of another number, which is a power of two.
+fn round_up_to_multiple(n: usize, multiple: usize) -> usize {
+    assert!(multiple.is_power_of_two());
+    (n + multiple - 1) & !(multiple - 1)
+}
+
+/// The number of elements between the starts of consecutive rows of
+/// `row_len` elements, when every row is padded to start on a chunk boundary.
+pub fn padded_row_len<T>(row_len: usize) -> usize {
+    assert_eq!(CHUNK_SIZE % mem::size_of::<T>(), 0);
+    round_up_to_multiple(row_len * mem::size_of::<T>(), CHUNK_SIZE) / mem::size_of::<T>()
+}
+
+/// A fixed-size buffer whose start is aligned to `CHUNK_SIZE` bytes and whose
+/// allocation is padded to a whole number of chunks, so SIMD kernels can load
+/// full chunks without reading past the end of the allocation. The padding is
+/// zeroed, but is not part of the slice the buffer dereferences to.
+pub struct AlignedBuf<T> {
+    ptr: NonNull<T>,
+    len: usize,
+}
+
+// The buffer uniquely owns its elements, like a `Box<[T]>`.
+unsafe impl<T: Send> Send for AlignedBuf<T> {}
+unsafe impl<T: Sync> Sync for AlignedBuf<T> {}
+
+impl<T: TensorElement> AlignedBuf<T> {
+    fn layout(len: usize) -> Layout {
+        assert!(mem::size_of::<T>() > 0);
+        assert!(mem::align_of::<T>() <= CHUNK_SIZE);
+
+        // Always allocate at least one chunk, so there's never a zero-sized
+        // allocation.
+        let bytes = len
+            .checked_mul(mem::size_of::<T>())
+            .expect("buffer size overflow")
+            .max(1);
+        Layout::from_size_align(round_up_to_multiple(bytes, CHUNK_SIZE), CHUNK_SIZE)
+            .expect("buffer size overflow")
+    }
+
+    /// Allocates a buffer of `len` elements, each set to `value`.
+    pub fn filled(len: usize, value: T) -> Self {
+        let layout = Self::layout(len);
+
+        let ptr = unsafe { alloc::alloc_zeroed(layout) } as *mut T;
+        let ptr = NonNull::new(ptr).unwrap_or_else(|| alloc::handle_alloc_error(layout));
+
+        for i in 0..len {
+            unsafe { ptr.as_ptr().add(i).write(value) };
+        }
+
+        Self { ptr, len }
+    }
+
+    pub fn zeroed(len: usize) -> Self {
+        Self::filled(len, T::ZERO)
+    }
+
+    pub fn from_slice(data: &[T]) -> Self {
+        let mut buf = Self::zeroed(data.len());
+        buf.copy_from_slice(data);
+        buf
+    }
+
+    /// Allocates `rows` zeroed rows of `row_len` elements, where each row is
+    /// padded to start on a chunk boundary. Returns the buffer along with the
+    /// distance between rows in elements.
+    pub fn zeroed_rows(rows: usize, row_len: usize) -> (Self, usize) {
+        let stride = padded_row_len::<T>(row_len);
+        (Self::zeroed(rows * stride), stride)
+    }
+}
+
+impl<T> Deref for AlignedBuf<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl<T> DerefMut for AlignedBuf<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl<T: TensorElement> Clone for AlignedBuf<T> {
+    fn clone(&self) -> Self {
+        Self::from_slice(self)
+    }
+}
+
+impl<T: TensorElement> From<Vec<T>> for AlignedBuf<T> {
+    fn from(data: Vec<T>) -> Self {
+        Self::from_slice(&data)
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for AlignedBuf<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl<T> Drop for AlignedBuf<T> {
+    fn drop(&mut self) {
+        // `T` is `Copy`, so there's nothing to drop but the allocation. This
+        // is the same layout `AlignedBuf::layout` computed when allocating.
+        let bytes = (self.len * mem::size_of::<T>()).max(1);
+        unsafe {
+            let layout = Layout::from_size_align_unchecked(
+                round_up_to_multiple(bytes, CHUNK_SIZE),
+                CHUNK_SIZE,
+            );
+            alloc::dealloc(self.ptr.as_ptr() as *mut u8, layout);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use half::f16;
+
+    #[test]
+    fn aligned() {
+        for len in [0, 1, 15, 16, 17, 1000] {
+            let buf = AlignedBuf::<f32>::filled(len, 1.0);
+            assert_eq!(buf.as_ptr() as usize % CHUNK_SIZE, 0);
+            assert_eq!(buf.len(), len);
+            assert!(buf.iter().all(|&x| x == 1.0));
+            assert_eq!(&buf.clone()[..], &buf[..]);
+        }
+    }
+
+    #[test]
+    fn padded_rows() {
+        assert_eq!(padded_row_len::<f32>(1), 16);
+        assert_eq!(padded_row_len::<f32>(16), 16);
+        assert_eq!(padded_row_len::<f32>(17), 32);
+        assert_eq!(padded_row_len::<f16>(33), 64);
+
+        let (buf, stride) = AlignedBuf::<f16>::zeroed_rows(3, 33);
+        assert_eq!(stride, 64);
+        assert_eq!(buf.len(), 3 * 64);
+    }
+}
@@ -0,0 +1,230 @@
+//! Hyperparameters from the `config.json` of a HuggingFace checkpoint.
+
+use std::{fs, io, path::Path};
+
+use serde_json::Value;
+
+use crate::ggml::{HParams, ScalarType};
+use crate::model::Architecture;
+
+impl HParams {
+    /// Reads a HuggingFace `config.json`, choosing the architecture from its
+    /// `model_type`.
+    pub fn from_config(p: impl AsRef<Path>) -> io::Result<Self> {
+        Self::from_config_str(&fs::read_to_string(p)?)
+    }
+
+    pub fn from_config_str(json: &str) -> io::Result<Self> {
+        let config: Value = serde_json::from_str(json)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let config = Config(&config);
+
+        let model_type = config.str("model_type")?;
+        let arch = Architecture::from_name(model_type).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported model_type {model_type}"),
+            )
+        })?;
+
+        let hparams = match arch {
+            Architecture::Llama | Architecture::Mistral => {
+                let n_heads = config.usize("num_attention_heads")?;
+                Self {
+                    arch,
+                    vocab_size: config.usize("vocab_size")?,
+                    dim: config.usize("hidden_size")?,
+                    multiple_of: 1,
+                    n_heads,
+                    n_kv_heads: config.opt_usize("num_key_value_heads")?.unwrap_or(n_heads),
+                    n_layers: config.usize("num_hidden_layers")?,
+                    n_ff: config.usize("intermediate_size")?,
+                    n_ctx: config.usize("max_position_embeddings")?,
+                    norm_eps: config.f32("rms_norm_eps")?,
+                    rope_theta: config.opt_f32("rope_theta")?.unwrap_or(10000.0),
+                    rotary_pct: 1.0,
+                    sliding_window: config.opt_usize("sliding_window")?,
+                    scalar_ty: ScalarType::F16,
+                }
+            }
+            Architecture::Gpt2 => {
+                let (dim, n_heads) = (config.usize("n_embd")?, config.usize("n_head")?);
+                Self {
+                    arch,
+                    vocab_size: config.usize("vocab_size")?,
+                    dim,
+                    multiple_of: 1,
+                    n_heads,
+                    n_kv_heads: n_heads,
+                    n_layers: config.usize("n_layer")?,
+                    n_ff: config.opt_usize("n_inner")?.unwrap_or(4 * dim),
+                    n_ctx: config.usize("n_positions")?,
+                    norm_eps: config.opt_f32("layer_norm_epsilon")?.unwrap_or(1e-5),
+                    rope_theta: 10000.0,
+                    rotary_pct: 0.0,
+                    sliding_window: None,
+                    scalar_ty: ScalarType::F16,
+                }
+            }
+            Architecture::GptNeoX => {
+                if config.0.get("use_parallel_residual") == Some(&Value::Bool(false)) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "GPT-NeoX models without parallel residuals are not supported",
+                    ));
+                }
+                let n_heads = config.usize("num_attention_heads")?;
+                Self {
+                    arch,
+                    vocab_size: config.usize("vocab_size")?,
+                    dim: config.usize("hidden_size")?,
+                    multiple_of: 1,
+                    n_heads,
+                    n_kv_heads: n_heads,
+                    n_layers: config.usize("num_hidden_layers")?,
+                    n_ff: config.usize("intermediate_size")?,
+                    n_ctx: config.usize("max_position_embeddings")?,
+                    norm_eps: config.opt_f32("layer_norm_eps")?.unwrap_or(1e-5),
+                    rope_theta: config.opt_f32("rotary_emb_base")?.unwrap_or(10000.0),
+                    rotary_pct: config.opt_f32("rotary_pct")?.unwrap_or(1.0),
+                    sliding_window: None,
+                    scalar_ty: ScalarType::F16,
+                }
+            }
+        };
+
+        if hparams.n_heads == 0 || hparams.dim % hparams.n_heads != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the hidden size is not a multiple of the number of heads",
+            ));
+        }
+        if hparams.n_kv_heads == 0 || hparams.n_heads % hparams.n_kv_heads != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the number of heads is not a multiple of the number of key and value heads",
+            ));
+        }
+        Ok(hparams)
+    }
+}
+
+/// Typed access to the fields of a config, with errors naming the field.
+struct Config<'a>(&'a Value);
+
+impl Config<'_> {
+    fn missing(key: &str) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, format!("config has no {key}"))
+    }
+
+    fn invalid(key: &str) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("config has an invalid {key}"),
+        )
+    }
+
+    fn str(&self, key: &str) -> io::Result<&str> {
+        self.0
+            .get(key)
+            .ok_or_else(|| Self::missing(key))?
+            .as_str()
+            .ok_or_else(|| Self::invalid(key))
+    }
+
+    fn usize(&self, key: &str) -> io::Result<usize> {
+        self.opt_usize(key)?.ok_or_else(|| Self::missing(key))
+    }
+
+    fn f32(&self, key: &str) -> io::Result<f32> {
+        self.opt_f32(key)?.ok_or_else(|| Self::missing(key))
+    }
+
+    /// A field that may be missing or `null`.
+    fn opt_usize(&self, key: &str) -> io::Result<Option<usize>> {
+        match self.0.get(key) {
+            None | Some(Value::Null) => Ok(None),
+            Some(x) => x
+                .as_u64()
+                .map(|x| Some(x as usize))
+                .ok_or_else(|| Self::invalid(key)),
+        }
+    }
+
+    fn opt_f32(&self, key: &str) -> io::Result<Option<f32>> {
+        match self.0.get(key) {
+            None | Some(Value::Null) => Ok(None),
+            Some(x) => x
+                .as_f64()
+                .map(|x| Some(x as f32))
+                .ok_or_else(|| Self::invalid(key)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_every_architecture() {
+        let mistral = HParams::from_config_str(
+            r#"{
+                "model_type": "mistral", "vocab_size": 32000, "hidden_size": 4096,
+                "intermediate_size": 14336, "num_attention_heads": 32, "num_key_value_heads": 8,
+                "num_hidden_layers": 32, "max_position_embeddings": 32768, "rms_norm_eps": 1e-05,
+                "rope_theta": 10000.0, "sliding_window": 4096, "torch_dtype": "bfloat16"
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(mistral.arch, Architecture::Mistral);
+        assert_eq!(
+            (mistral.n_heads, mistral.n_kv_heads, mistral.head_dim()),
+            (32, 8, 128)
+        );
+        assert_eq!((mistral.n_ff, mistral.sliding_window), (14336, Some(4096)));
+
+        let gpt2 = HParams::from_config_str(
+            r#"{
+                "model_type": "gpt2", "vocab_size": 50257, "n_embd": 768, "n_head": 12,
+                "n_layer": 12, "n_positions": 1024, "n_inner": null, "layer_norm_epsilon": 1e-05
+            }"#,
+        )
+        .unwrap();
+        assert_eq!((gpt2.dim, gpt2.n_ff, gpt2.n_ctx), (768, 3072, 1024));
+
+        let pythia = HParams::from_config_str(
+            r#"{
+                "model_type": "gpt_neox", "vocab_size": 50304, "hidden_size": 512,
+                "intermediate_size": 2048, "num_attention_heads": 8, "num_hidden_layers": 6,
+                "max_position_embeddings": 2048, "layer_norm_eps": 1e-05, "rotary_pct": 0.25,
+                "rotary_emb_base": 10000, "use_parallel_residual": true
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            (pythia.arch, pythia.rotary_pct),
+            (Architecture::GptNeoX, 0.25)
+        );
+    }
+
+    #[test]
+    fn rejects_bad_configs() {
+        for (json, error) in [
+            (r#"{"model_type": "bert"}"#, "unsupported model_type bert"),
+            (
+                r#"{"model_type": "gpt2", "vocab_size": 10}"#,
+                "config has no n_embd",
+            ),
+            (
+                r#"{"model_type": "gpt2", "n_embd": 8, "n_head": 2, "vocab_size": "10"}"#,
+                "config has an invalid vocab_size",
+            ),
+        ] {
+            assert_eq!(
+                HParams::from_config_str(json).unwrap_err().to_string(),
+                error
+            );
+        }
+    }
+}
@@ -2,26 +2,202 @@ use std::{
     collections::HashMap,
     fmt,
     fs::File,
-    io::{self, Read},
+    io::{self, BufWriter, Read, Seek, SeekFrom, Write},
     mem,
+    ops::{Range, RangeInclusive},
     path::Path,
+    sync::Arc,
 };
 
 use bstr::BString;
-use half::f16;
+use half::{bf16, f16};
+use memmap2::Mmap;
 
+use crate::buffer::AlignedBuf;
+use crate::gguf;
+use crate::model::Architecture;
+use crate::quant::Quantized;
 use crate::tensor::Tensor;
-use crate::tokenizer::{Token, Vocab};
+use crate::tokenizer::{Token, TokenType, Vocab};
 
-#[derive(Debug)]
+/// The types GGML stores elements as, numbered as in its files.
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ScalarType {
     F32 = 0,
     F16 = 1,
+    Q4_0 = 2,
+    Q4_1 = 3,
+    Q5_0 = 6,
+    Q5_1 = 7,
+    Q8_0 = 8,
+    Q2_K = 10,
+    Q3_K = 11,
+    Q4_K = 12,
+    Q5_K = 13,
+    Q6_K = 14,
+    BF16 = 30,
+}
+
+impl ScalarType {
+    const ALL: [Self; 13] = [
+        Self::F32,
+        Self::F16,
+        Self::Q4_0,
+        Self::Q4_1,
+        Self::Q5_0,
+        Self::Q5_1,
+        Self::Q8_0,
+        Self::Q2_K,
+        Self::Q3_K,
+        Self::Q4_K,
+        Self::Q5_K,
+        Self::Q6_K,
+        Self::BF16,
+    ];
+
+    pub fn from_id(id: u32) -> Option<Self> {
+        Self::ALL.into_iter().find(|&ty| ty as u32 == id)
+    }
+
+    /// The number llama.cpp gives a model whose matrices are mostly of this
+    /// type, in GGML headers and GGUF's `general.file_type`.
+    pub fn file_type(self) -> u32 {
+        match self {
+            Self::F32 => 0,
+            Self::F16 => 1,
+            Self::Q4_0 => 2,
+            Self::Q4_1 => 3,
+            Self::Q8_0 => 7,
+            Self::Q5_0 => 8,
+            Self::Q5_1 => 9,
+            Self::Q2_K => 10,
+            // The small mixes of k-quants, which are mostly of one type.
+            Self::Q3_K => 11,
+            Self::Q4_K => 14,
+            Self::Q5_K => 16,
+            Self::Q6_K => 18,
+            Self::BF16 => 32,
+        }
+    }
+
+    pub fn from_file_type(file_type: u32) -> Option<Self> {
+        match file_type {
+            12 | 13 => Some(Self::Q3_K),
+            15 => Some(Self::Q4_K),
+            17 => Some(Self::Q5_K),
+            _ => Self::ALL
+                .into_iter()
+                .find(|&ty| ty.file_type() == file_type),
+        }
+    }
 }
 
 pub enum Data {
-    F32(Vec<f32>),
-    F16(Vec<f16>),
+    F32(AlignedBuf<f32>),
+    F16(AlignedBuf<f16>),
+    Quantized(Quantized),
+    Mapped(Mapped),
+}
+
+impl Data {
+    pub fn scalar_type(&self) -> ScalarType {
+        match self {
+            Data::F32(_) => ScalarType::F32,
+            Data::F16(_) => ScalarType::F16,
+            Data::Quantized(data) => data.scalar_type(),
+            Data::Mapped(data) => data.ty,
+        }
+    }
+
+    /// The number of elements.
+    pub fn len(&self) -> usize {
+        match self {
+            Data::F32(data) => data.len(),
+            Data::F16(data) => data.len(),
+            Data::Quantized(data) => data.len(),
+            Data::Mapped(data) => data.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The elements as stored in a file.
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        match self {
+            Data::F32(data) => as_bytes(data),
+            Data::F16(data) => as_bytes(data),
+            Data::Quantized(data) => data.as_bytes(),
+            Data::Mapped(data) => data.as_bytes(),
+        }
+    }
+
+    /// The elements, dequantized if need be.
+    pub fn to_f32(&self) -> Vec<f32> {
+        match self {
+            Data::F32(data) => data.to_vec(),
+            Data::F16(data) => data.iter().map(|x| x.to_f32()).collect(),
+            Data::Quantized(data) => data.dequantize(),
+            Data::Mapped(data) => data.to_f32(),
+        }
+    }
+}
+
+/// `F32`, `F16` or `BF16` elements left in a memory-mapped file, which the
+/// map is kept open for.
+#[derive(Clone)]
+pub struct Mapped {
+    mmap: Arc<Mmap>,
+    range: Range<usize>,
+    ty: ScalarType,
+}
+
+impl Mapped {
+    /// Panics if `range` isn't a whole number of `ty`s in `mmap`.
+    pub(crate) fn new(mmap: Arc<Mmap>, range: Range<usize>, ty: ScalarType) -> Self {
+        assert!(
+            matches!(ty, ScalarType::F32 | ScalarType::F16 | ScalarType::BF16),
+            "can't map {ty:?}"
+        );
+        assert!(range.end <= mmap.len() && range.len().is_multiple_of(ty.size_of(1)));
+        Self { mmap, range, ty }
+    }
+
+    pub fn scalar_type(&self) -> ScalarType {
+        self.ty
+    }
+
+    pub fn len(&self) -> usize {
+        self.range.len() / self.ty.size_of(1)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.range.is_empty()
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.mmap[self.range.clone()]
+    }
+
+    pub fn to_f32(&self) -> Vec<f32> {
+        let bytes = self.as_bytes();
+        match self.ty {
+            ScalarType::F32 => bytes
+                .chunks_exact(4)
+                .map(|x| f32::from_le_bytes(x.try_into().unwrap()))
+                .collect(),
+            ScalarType::F16 => bytes
+                .chunks_exact(2)
+                .map(|x| f16::from_le_bytes([x[0], x[1]]).to_f32())
+                .collect(),
+            _ => bytes
+                .chunks_exact(2)
+                .map(|x| bf16::from_le_bytes([x[0], x[1]]).to_f32())
+                .collect(),
+        }
+    }
 }
 
 pub struct Var {
@@ -39,7 +215,7 @@ impl Var {
         };
 
         if let Data::F16(data) = self.data {
-            Ok(Tensor::<f16, DIMS>::new(data, shape))
+            Ok(Tensor::<f16, DIMS>::from_buf(data, shape))
         } else {
             Err(self)
         }
@@ -53,7 +229,7 @@ impl Var {
         };
 
         if let Data::F32(data) = self.data {
-            Ok(Tensor::<f32, DIMS>::new(data, shape))
+            Ok(Tensor::<f32, DIMS>::from_buf(data, shape))
         } else {
             Err(self)
         }
@@ -69,16 +245,69 @@ impl fmt::Debug for Var {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct HParams {
+    pub arch: Architecture,
     pub vocab_size: usize,
     pub dim: usize,
+    /// Only stored in GGML files, where `n_ff` is derived from it. Checkpoints
+    /// from elsewhere set it to 1.
     pub multiple_of: usize,
     pub n_heads: usize,
+    /// The number of key and value heads, each shared by a group of query
+    /// heads.
+    pub n_kv_heads: usize,
     pub n_layers: usize,
+    /// The hidden size of the feed-forward layers.
+    pub n_ff: usize,
+    /// The longest sequence the model was trained on, and the number of
+    /// learned position embeddings for architectures that have them.
+    pub n_ctx: usize,
+    pub norm_eps: f32,
+    pub rope_theta: f32,
+    /// The fraction of each head that rotary position embeddings rotate.
+    pub rotary_pct: f32,
+    /// The number of positions each position attends to, if attention is
+    /// limited to a sliding window.
+    pub sliding_window: Option<usize>,
     pub scalar_ty: ScalarType,
 }
 
+impl HParams {
+    /// LLaMA, with the hyperparameters a GGML file stores. The feed-forward
+    /// size is two thirds of `4 * dim`, rounded up to a multiple of
+    /// `multiple_of`.
+    pub fn llama(
+        vocab_size: usize,
+        dim: usize,
+        multiple_of: usize,
+        n_heads: usize,
+        n_layers: usize,
+    ) -> Self {
+        let n_ff = (2 * (4 * dim) / 3).div_ceil(multiple_of) * multiple_of;
+        Self {
+            arch: Architecture::Llama,
+            vocab_size,
+            dim,
+            multiple_of,
+            n_heads,
+            n_kv_heads: n_heads,
+            n_layers,
+            n_ff,
+            n_ctx: 2048,
+            norm_eps: 1e-6,
+            rope_theta: 10000.0,
+            rotary_pct: 1.0,
+            sliding_window: None,
+            scalar_ty: ScalarType::F16,
+        }
+    }
+
+    pub fn head_dim(&self) -> usize {
+        self.dim / self.n_heads
+    }
+}
+
 pub struct Ggml {
     pub hparams: HParams,
     pub vars: HashMap<String, Var>,
@@ -99,39 +328,72 @@ fn read_u32(data: &[u8], offset: usize) -> io::Result<u32> {
     ]))
 }
 
+/// The magic number of the original GGML format, "ggmf".
+const GGMF_MAGIC: u32 = 0x67676d66;
+/// The magic number of llama.cpp's mmap-able GGML format, "ggjt", which pads
+/// the start of each variable's data to a multiple of `GGJT_ALIGN` bytes.
+const GGJT_MAGIC: u32 = 0x67676a74;
+const GGJT_ALIGN: u64 = 32;
+/// The version of ggjt with GGML's current layout of quantized blocks.
+const GGJT_VERSION: u32 = 3;
+
+/// The file formats [`Ggml::save`] can write, all of which [`Ggml::load`]
+/// reads back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Ggmf,
+    Ggjt,
+    /// Self-describing, so the only one that can hold architectures other
+    /// than LLaMA.
+    Gguf,
+}
+
 impl Ggml {
+    /// Loads a model in any of the formats of [`Format`], telling them apart
+    /// by their magic number.
     pub fn load(p: impl AsRef<Path>) -> io::Result<(Vocab, Self)> {
         let mut f = File::open(p)?;
 
-        const HEADER_LEN: usize = mem::size_of::<u32>() * 9;
+        let mut magic = [0; 4];
+        f.read_exact(&mut magic)?;
+        match u32::from_le_bytes(magic) {
+            GGMF_MAGIC => Self::load_ggml(f, 1..=1, 1),
+            // Later versions only changed the layout of quantized data, and
+            // only the latest one's is supported.
+            GGJT_MAGIC => Self::load_ggml(f, 1..=GGJT_VERSION, GGJT_ALIGN),
+            gguf::MAGIC => gguf::load(f),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "invalid magic")),
+        }
+    }
+
+    fn load_ggml(
+        mut f: File,
+        versions: RangeInclusive<u32>,
+        align: u64,
+    ) -> io::Result<(Vocab, Self)> {
+        const HEADER_LEN: usize = mem::size_of::<u32>() * 8;
         let mut header = [0; HEADER_LEN];
         f.read_exact(&mut header)?;
 
-        let magic = read_u32(&header, 0)?;
-        if magic != 0x67676d66 {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid magic"));
-        }
-
-        let version = read_u32(&header, 4)?;
-        if version != 1 {
+        let version = read_u32(&header, 0)?;
+        if !versions.contains(&version) {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidData,
                 "invalid version",
             ));
         }
 
-        let vocab_size = read_u32(&header, 8)? as usize;
-        let dim = read_u32(&header, 12)? as usize;
-        let multiple_of = read_u32(&header, 16)? as usize;
-        let n_heads = read_u32(&header, 20)? as usize;
-        let n_layers = read_u32(&header, 24)? as usize;
-        let _unused = read_u32(&header, 28)?;
-        let scalar_type = read_u32(&header, 32)? as usize;
-
-        let scalar_type = match scalar_type {
-            // 0 => ScalarType::F32,
-            1 => ScalarType::F16,
-            _ => {
+        let vocab_size = read_u32(&header, 4)? as usize;
+        let dim = read_u32(&header, 8)? as usize;
+        let multiple_of = read_u32(&header, 12)? as usize;
+        let n_heads = read_u32(&header, 16)? as usize;
+        let n_layers = read_u32(&header, 20)? as usize;
+        let _unused = read_u32(&header, 24)?;
+        let scalar_type = read_u32(&header, 28)?;
+
+        let scalar_type = match ScalarType::from_file_type(scalar_type) {
+            Some(scalar_type) => scalar_type,
+            None => {
                 return Err(io::Error::new(
                     io::ErrorKind::InvalidData,
                     "invalid scalar type in header",
@@ -140,22 +402,12 @@ impl Ggml {
         };
 
         let hparams = HParams {
-            vocab_size,
-            dim,
-            multiple_of,
-            n_heads,
-            n_layers,
             scalar_ty: scalar_type,
+            ..HParams::llama(vocab_size, dim, multiple_of, n_heads, n_layers)
         };
 
-        println!("{hparams:#?}");
-
-        let mut vocab = Vocab {
-            token_to_id: HashMap::new(),
-            id_to_token: Vec::new(),
-        };
-
-        for i in 0..vocab_size {
+        let mut tokens = Vec::with_capacity(vocab_size);
+        for _ in 0..vocab_size {
             let mut buf = [0; 4];
             f.read_exact(&mut buf)?;
             let len = u32::from_le_bytes(buf) as usize;
@@ -167,81 +419,514 @@ impl Ggml {
             f.read_exact(&mut buf)?;
             let score = f32::from_le_bytes(buf);
 
-            vocab.token_to_id.insert(token.clone(), i);
-            vocab.id_to_token.push(Token { token, score });
+            tokens.push(Token {
+                token,
+                score,
+                ty: TokenType::Normal,
+            });
         }
+        let vocab = Vocab::new(tokens);
+
+        let vars = read_vars(&mut f, align)?;
+        if version < GGJT_VERSION
+            && vars
+                .values()
+                .any(|var| var.data.scalar_type().is_quantized())
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "quantized data in an unsupported layout",
+            ));
+        }
+
+        Ok((vocab, Self { hparams, vars }))
+    }
+
+    /// Writes the vocabulary and variables in `format`. Variables are
+    /// written in order of name, so the same model always gives the same
+    /// file.
+    ///
+    /// The GGML formats only store LLaMA's shapes, with the feed-forward size
+    /// derived from `multiple_of`, and leave everything else to defaults.
+    /// Only ggjt and GGUF can hold quantized data, and only GGUF a byte-level
+    /// vocabulary's merges and the ids of BOS and EOS.
+    pub fn save(&self, vocab: &Vocab, p: impl AsRef<Path>, format: Format) -> io::Result<()> {
+        assert_eq!(vocab.id_to_token.len(), self.hparams.vocab_size);
+        let mut f = BufWriter::new(File::create(p)?);
+
+        match format {
+            Format::Ggmf => self.save_ggml(vocab, &mut f, GGMF_MAGIC, 1, 1)?,
+            Format::Ggjt => self.save_ggml(vocab, &mut f, GGJT_MAGIC, GGJT_VERSION, GGJT_ALIGN)?,
+            Format::Gguf => gguf::save(self, vocab, &mut f)?,
+        }
+
+        f.flush()
+    }
 
-        let mut vars = HashMap::new();
-        // While the file has contents
-        let mut var_header = [0; mem::size_of::<u32>() * 3];
-        loop {
-            match f.read_exact(&mut var_header) {
-                Ok(()) => {}
-                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
-                Err(e) => return Err(e),
+    fn save_ggml(
+        &self,
+        vocab: &Vocab,
+        f: &mut BufWriter<File>,
+        magic: u32,
+        version: u32,
+        align: u64,
+    ) -> io::Result<()> {
+        let hparams = &self.hparams;
+        if version < GGJT_VERSION
+            && self
+                .vars
+                .values()
+                .any(|var| var.data.scalar_type().is_quantized())
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "only ggjt and GGUF files can hold quantized data",
+            ));
+        }
+        if !vocab.merges.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "GGML files can't hold byte-level vocabularies",
+            ));
+        }
+        if self
+            .vars
+            .values()
+            .any(|var| var.data.scalar_type() == ScalarType::BF16)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "only GGUF files can hold bf16 data",
+            ));
+        }
+        let llama = HParams::llama(
+            hparams.vocab_size,
+            hparams.dim,
+            hparams.multiple_of,
+            hparams.n_heads,
+            hparams.n_layers,
+        );
+        if hparams.arch != Architecture::Llama
+            || hparams.n_kv_heads != hparams.n_heads
+            || hparams.n_ff != llama.n_ff
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "GGML files can only describe LLaMA models with the original shapes",
+            ));
+        }
+
+        let write_u32 = |f: &mut BufWriter<File>, x: usize| -> io::Result<()> {
+            let x = u32::try_from(x).map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidInput, "value doesn't fit in u32")
+            })?;
+            f.write_all(&x.to_le_bytes())
+        };
+
+        for x in [
+            magic as usize,
+            version as usize,
+            hparams.vocab_size,
+            hparams.dim,
+            hparams.multiple_of,
+            hparams.n_heads,
+            hparams.n_layers,
+            0,
+            hparams.scalar_ty.file_type() as usize,
+        ] {
+            write_u32(f, x)?;
+        }
+
+        for token in &vocab.id_to_token {
+            write_u32(f, token.token.len())?;
+            f.write_all(&token.token)?;
+            f.write_all(&token.score.to_le_bytes())?;
+        }
+
+        let mut names = self.vars.keys().collect::<Vec<_>>();
+        names.sort();
+        for name in names {
+            let var = &self.vars[name];
+            let (len, bytes) = (var.data.len(), var.data.as_bytes());
+            assert_eq!(
+                len,
+                var.dims.iter().product::<usize>(),
+                "{name} has the wrong number of elements"
+            );
+
+            write_u32(f, var.dims.len())?;
+            write_u32(f, name.len())?;
+            write_u32(f, var.data.scalar_type() as usize)?;
+            for &dim in var.dims.iter().rev() {
+                write_u32(f, dim)?;
+            }
+            f.write_all(name.as_bytes())?;
+            if align > 1 {
+                let pos = f.stream_position()?;
+                f.write_all(&vec![0; (pos.next_multiple_of(align) - pos) as usize])?;
             }
+            f.write_all(bytes)?;
+        }
 
-            let n_dims = read_u32(&var_header, 0)? as usize;
-            let name_len = read_u32(&var_header, 4)? as usize;
-            let ftype = read_u32(&var_header, 8)? as usize;
-
-            let mut dims = vec![0; n_dims * mem::size_of::<u32>()];
-            f.read_exact(&mut dims)?;
-            let dims = dims
-                .chunks_exact(mem::size_of::<u32>())
-                .map(|x| u32::from_le_bytes([x[0], x[1], x[2], x[3]]) as usize)
-                .collect::<Vec<_>>();
-
-            let mut name = vec![0; name_len];
-            f.read_exact(&mut name)?;
-            let name = String::from_utf8(name)
-                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid name"))?;
-
-            println!("loading parameters: \"{name}\" ({dims:?})");
-
-            let element_count = dims.iter().product::<usize>();
-
-            let data = match ftype {
-                0 => {
-                    let mut data = vec![0.0; element_count];
-                    {
-                        let mut data_u8 = unsafe {
-                            std::slice::from_raw_parts_mut(
-                                data.as_mut_ptr() as *mut u8,
-                                data.len() * mem::size_of::<f32>(),
-                            )
-                        };
-                        f.read_exact(&mut data_u8)?;
-                    }
-                    Data::F32(data)
-                }
-                1 => {
-                    let mut data = vec![f16::ZERO; element_count];
-                    {
-                        let mut data_u8 = unsafe {
-                            std::slice::from_raw_parts_mut(
-                                data.as_mut_ptr() as *mut u8,
-                                data.len() * mem::size_of::<f16>(),
-                            )
-                        };
-                        f.read_exact(&mut data_u8)?;
-                    }
-                    Data::F16(data)
-                }
-                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid ftype")),
-            };
-
-            if vars
-                .insert(name.clone(), Var { name, dims, data })
-                .is_some()
-            {
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    "duplicate variable name",
-                ));
+        Ok(())
+    }
+}
+
+/// Reads variables until the end of the file. Some formats pad the start of
+/// each variable's data to a multiple of `align` bytes.
+pub(crate) fn read_vars(f: &mut File, align: u64) -> io::Result<HashMap<String, Var>> {
+    let mut vars = HashMap::new();
+    // While the file has contents
+    let mut var_header = [0; mem::size_of::<u32>() * 3];
+    loop {
+        match f.read_exact(&mut var_header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+
+        let n_dims = read_u32(&var_header, 0)? as usize;
+        let name_len = read_u32(&var_header, 4)? as usize;
+        let ftype = read_u32(&var_header, 8)?;
+
+        let mut dims = vec![0; n_dims * mem::size_of::<u32>()];
+        f.read_exact(&mut dims)?;
+        // The file lists dimensions innermost first, so reverse them into
+        // the row-major order tensors use.
+        let dims = dims
+            .chunks_exact(mem::size_of::<u32>())
+            .rev()
+            .map(|x| u32::from_le_bytes([x[0], x[1], x[2], x[3]]) as usize)
+            .collect::<Vec<_>>();
+
+        let mut name = vec![0; name_len];
+        f.read_exact(&mut name)?;
+        let name = String::from_utf8(name)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid name"))?;
+
+        if align > 1 {
+            let pos = f.stream_position()?;
+            f.seek(SeekFrom::Start(pos.next_multiple_of(align)))?;
+        }
+
+        let data = read_data(f, ftype, dims.iter().product())?;
+
+        if vars
+            .insert(name.clone(), Var { name, dims, data })
+            .is_some()
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "duplicate variable name",
+            ));
+        }
+    }
+
+    Ok(vars)
+}
+
+/// Reads `element_count` elements of the scalar type numbered `ftype`.
+pub(crate) fn read_data(f: &mut impl Read, ftype: u32, element_count: usize) -> io::Result<Data> {
+    Ok(match ftype {
+        0 => {
+            let mut data = AlignedBuf::<f32>::zeroed(element_count);
+            f.read_exact(as_bytes_mut(&mut data))?;
+            Data::F32(data)
+        }
+        1 => {
+            let mut data = AlignedBuf::<f16>::zeroed(element_count);
+            f.read_exact(as_bytes_mut(&mut data))?;
+            Data::F16(data)
+        }
+        ftype => match ScalarType::from_id(ftype) {
+            Some(ty) if ty.is_quantized() && element_count.is_multiple_of(ty.block_len()) => {
+                let mut bytes = vec![0; ty.size_of(element_count)];
+                f.read_exact(&mut bytes)?;
+                Data::Quantized(Quantized::from_bytes(ty, element_count, bytes))
             }
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid ftype")),
+        },
+    })
+}
+
+fn as_bytes<T>(data: &[T]) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(data.as_ptr() as *const u8, mem::size_of_val(data)) }
+}
+
+fn as_bytes_mut<T>(data: &mut [T]) -> &mut [u8] {
+    unsafe { std::slice::from_raw_parts_mut(data.as_mut_ptr() as *mut u8, mem::size_of_val(data)) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{llama, neox};
+    use crate::quant::BLOCK_LEN;
+    use crate::weights::NameTable;
+
+    /// A model with the given `(name, dims, scalar type)` tensors, each filled
+    /// with a ramp, and a vocabulary of numbers.
+    fn model(hparams: HParams, names: &[(String, Vec<usize>, ScalarType)]) -> (Vocab, Ggml) {
+        let vars = names
+            .iter()
+            .map(|(name, dims, scalar_type)| {
+                let len = dims.iter().product();
+                let data = match scalar_type {
+                    ScalarType::F32 => Data::F32(AlignedBuf::from_slice(
+                        &(0..len).map(|i| i as f32).collect::<Vec<_>>(),
+                    )),
+                    ScalarType::F16 => Data::F16(AlignedBuf::from_slice(
+                        &(0..len)
+                            .map(|i| f16::from_f32(i as f32 / 8.0))
+                            .collect::<Vec<_>>(),
+                    )),
+                    &ty => Data::Quantized(Quantized::new(
+                        ty,
+                        &(0..len).map(|i| i as f32 / 8.0).collect::<Vec<_>>(),
+                    )),
+                };
+                (
+                    name.clone(),
+                    Var {
+                        name: name.clone(),
+                        dims: dims.clone(),
+                        data,
+                    },
+                )
+            })
+            .collect();
+
+        let tokens = (0..hparams.vocab_size)
+            .map(|i| Token {
+                token: i.to_string().into(),
+                score: -(i as f32),
+                ty: TokenType::Normal,
+            })
+            .collect();
+        (Vocab::new(tokens), Ggml { hparams, vars })
+    }
+
+    fn names(hparams: &HParams, table: &NameTable) -> Vec<(String, Vec<usize>, ScalarType)> {
+        table
+            .weights(hparams.n_layers)
+            .into_iter()
+            .map(|w| {
+                (
+                    table.name(w).unwrap(),
+                    w.shape(hparams),
+                    w.scalar_type(hparams),
+                )
+            })
+            .collect()
+    }
+
+    fn round_trip(vocab: &Vocab, ggml: &Ggml, format: Format) -> io::Result<(Vocab, Ggml)> {
+        let path = std::env::temp_dir().join(format!("nxml-{format:?}-{}", std::process::id()));
+        let result = ggml
+            .save(vocab, &path, format)
+            .and_then(|()| Ggml::load(&path));
+        let _ = std::fs::remove_file(&path);
+        result
+    }
+
+    fn assert_same(a: &(Vocab, Ggml), b: &(Vocab, Ggml)) {
+        let tokens = |vocab: &Vocab| {
+            vocab
+                .id_to_token
+                .iter()
+                .map(|t| (t.token.clone(), t.score))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(tokens(&a.0), tokens(&b.0));
+        assert_eq!(
+            (a.0.bos, a.0.eos, &a.0.merges),
+            (b.0.bos, b.0.eos, &b.0.merges)
+        );
+        assert_eq!(a.1.hparams, b.1.hparams);
+
+        let mut names = a.1.vars.keys().collect::<Vec<_>>();
+        names.sort();
+        let mut other = b.1.vars.keys().collect::<Vec<_>>();
+        other.sort();
+        assert_eq!(names, other);
+        for name in names {
+            let (x, y) = (&a.1.vars[name], &b.1.vars[name]);
+            assert_eq!(
+                (&x.dims, x.data.as_bytes()),
+                (&y.dims, y.data.as_bytes()),
+                "{name}"
+            );
         }
+    }
 
-        Ok((vocab, Self { hparams, vars }))
+    #[test]
+    fn round_trips_every_format() {
+        let hparams = HParams::llama(7, 8, 4, 2, 2);
+        let original = model(hparams.clone(), &names(&hparams, &llama::GGML_NAMES));
+
+        for format in [Format::Ggmf, Format::Ggjt] {
+            assert_same(
+                &round_trip(&original.0, &original.1, format).unwrap(),
+                &original,
+            );
+        }
+
+        // GGUF stores the feed-forward size itself, and not `multiple_of`.
+        let hparams = HParams {
+            multiple_of: 1,
+            n_kv_heads: 1,
+            sliding_window: Some(4),
+            arch: Architecture::Mistral,
+            ..hparams
+        };
+        let mut original = model(hparams.clone(), &names(&hparams, &llama::GGUF_NAMES));
+        (original.0.bos, original.0.eos) = (Some(5), Some(6));
+        assert_same(
+            &round_trip(&original.0, &original.1, Format::Gguf).unwrap(),
+            &original,
+        );
+
+        // Only GGUF can describe other architectures.
+        let hparams = HParams {
+            arch: Architecture::GptNeoX,
+            n_kv_heads: 2,
+            sliding_window: None,
+            norm_eps: 1e-5,
+            rotary_pct: 0.5,
+            ..hparams
+        };
+        let original = model(hparams.clone(), &names(&hparams, &neox::GGUF_NAMES));
+        assert_same(
+            &round_trip(&original.0, &original.1, Format::Gguf).unwrap(),
+            &original,
+        );
+        let error = round_trip(&original.0, &original.1, Format::Ggjt)
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+
+        // Byte-level vocabularies keep their merges, which GGML files can't.
+        let hparams = HParams {
+            multiple_of: 1,
+            ..HParams::llama(7, 8, 4, 2, 2)
+        };
+        let mut original = model(hparams.clone(), &names(&hparams, &llama::GGML_NAMES));
+        original.0.merges = vec!["1 2".into(), "12 3".into()];
+        original.0.eos = Some(0);
+        assert_same(
+            &round_trip(&original.0, &original.1, Format::Gguf).unwrap(),
+            &original,
+        );
+        let error = round_trip(&original.0, &original.1, Format::Ggmf)
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn round_trips_quantized_data() {
+        let hparams = HParams::llama(7, 32, 32, 2, 1);
+        let types = [
+            ScalarType::Q4_0,
+            ScalarType::Q4_1,
+            ScalarType::Q5_0,
+            ScalarType::Q5_1,
+            ScalarType::Q8_0,
+        ];
+        let mut names = names(&hparams, &llama::GGML_NAMES);
+        for (i, (_, dims, ty)) in names
+            .iter_mut()
+            .filter(|(_, dims, _)| dims.len() == 2)
+            .enumerate()
+        {
+            assert_eq!(dims[1] % BLOCK_LEN, 0);
+            *ty = types[i % types.len()];
+        }
+        let mut original = model(
+            HParams {
+                scalar_ty: ScalarType::Q4_0,
+                ..hparams
+            },
+            &names,
+        );
+        // k-quants can only be read, so these are arbitrary bytes.
+        for (i, ty) in [
+            ScalarType::Q2_K,
+            ScalarType::Q3_K,
+            ScalarType::Q4_K,
+            ScalarType::Q5_K,
+            ScalarType::Q6_K,
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let name = format!("{ty:?}");
+            let bytes = (0..ty.size_of(512)).map(|j| (i + j) as u8).collect();
+            let data = Data::Quantized(Quantized::from_bytes(ty, 512, bytes));
+            original.1.vars.insert(
+                name.clone(),
+                Var {
+                    name,
+                    dims: vec![2, 256],
+                    data,
+                },
+            );
+        }
+
+        assert_same(
+            &round_trip(&original.0, &original.1, Format::Ggjt).unwrap(),
+            &original,
+        );
+        let error = round_trip(&original.0, &original.1, Format::Ggmf)
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+
+        let original = (
+            original.0,
+            Ggml {
+                hparams: HParams {
+                    multiple_of: 1,
+                    ..original.1.hparams
+                },
+                ..original.1
+            },
+        );
+        assert_same(
+            &round_trip(&original.0, &original.1, Format::Gguf).unwrap(),
+            &original,
+        );
+    }
+
+    #[test]
+    fn loads_row_major_dims() {
+        // A ggmf file written out by hand: a vocabulary of one token, and a
+        // `[3, 4]` matrix, which GGML lists innermost dimension first.
+        let mut bytes = Vec::new();
+        for x in [GGMF_MAGIC, 1, 1, 4, 1, 1, 0, 0, 0] {
+            bytes.extend(x.to_le_bytes());
+        }
+        bytes.extend(1u32.to_le_bytes());
+        bytes.extend(b"a");
+        bytes.extend(0f32.to_le_bytes());
+        let name = b"tok_embeddings.weight";
+        for x in [2, name.len() as u32, 0, 4, 3] {
+            bytes.extend(x.to_le_bytes());
+        }
+        bytes.extend(name);
+        for i in 0..12 {
+            bytes.extend((i as f32).to_le_bytes());
+        }
+
+        let path = std::env::temp_dir().join(format!("nxml-dims-{}", std::process::id()));
+        std::fs::write(&path, bytes).unwrap();
+        let loaded = Ggml::load(&path);
+        let _ = std::fs::remove_file(&path);
+        let (_, mut ggml) = loaded.unwrap();
+
+        let var = ggml.vars.remove("tok_embeddings.weight").unwrap();
+        assert_eq!(var.dims, [3, 4]);
+        let tensor = var.as_tensor_f32::<2>().unwrap();
+        assert_eq!(tensor.rows().nth(1).unwrap(), [4.0, 5.0, 6.0, 7.0]);
     }
 }
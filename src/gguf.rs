@@ -0,0 +1,593 @@
+//! GGUF, the successor of the GGML formats: typed key-value metadata, then a
+//! table of tensors, then their data, each tensor starting on a multiple of
+//! the alignment.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+};
+
+use bstr::BString;
+
+use crate::ggml::{self, Ggml, HParams, ScalarType, Var};
+use crate::model::Architecture;
+use crate::tokenizer::{Token, TokenType, Vocab};
+
+/// "GGUF", read as a little-endian `u32`.
+pub(crate) const MAGIC: u32 = 0x46554747;
+const VERSION: u32 = 3;
+/// The alignment of tensor data unless `general.alignment` says otherwise.
+const DEFAULT_ALIGN: u64 = 32;
+
+/// A metadata value, tagged with its type number in the file.
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    U8(u8),
+    I8(i8),
+    U16(u16),
+    I16(i16),
+    U32(u32),
+    I32(i32),
+    F32(f32),
+    Bool(bool),
+    String(BString),
+    Array(Vec<Value>),
+    U64(u64),
+    I64(i64),
+    F64(f64),
+}
+
+impl Value {
+    fn type_id(&self) -> u32 {
+        match self {
+            Value::U8(_) => 0,
+            Value::I8(_) => 1,
+            Value::U16(_) => 2,
+            Value::I16(_) => 3,
+            Value::U32(_) => 4,
+            Value::I32(_) => 5,
+            Value::F32(_) => 6,
+            Value::Bool(_) => 7,
+            Value::String(_) => 8,
+            Value::Array(_) => 9,
+            Value::U64(_) => 10,
+            Value::I64(_) => 11,
+            Value::F64(_) => 12,
+        }
+    }
+
+    fn read(r: &mut impl Read, type_id: u32) -> io::Result<Self> {
+        Ok(match type_id {
+            0 => Value::U8(read_array::<1>(r)?[0]),
+            1 => Value::I8(read_array::<1>(r)?[0] as i8),
+            2 => Value::U16(u16::from_le_bytes(read_array(r)?)),
+            3 => Value::I16(i16::from_le_bytes(read_array(r)?)),
+            4 => Value::U32(read_u32(r)?),
+            5 => Value::I32(i32::from_le_bytes(read_array(r)?)),
+            6 => Value::F32(f32::from_le_bytes(read_array(r)?)),
+            7 => Value::Bool(read_array::<1>(r)?[0] != 0),
+            8 => Value::String(read_string(r)?),
+            9 => {
+                let type_id = read_u32(r)?;
+                let len = read_u64(r)?;
+                Value::Array(
+                    (0..len)
+                        .map(|_| Value::read(r, type_id))
+                        .collect::<io::Result<_>>()?,
+                )
+            }
+            10 => Value::U64(read_u64(r)?),
+            11 => Value::I64(i64::from_le_bytes(read_array(r)?)),
+            12 => Value::F64(f64::from_le_bytes(read_array(r)?)),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "invalid metadata type",
+                ))
+            }
+        })
+    }
+
+    fn write(&self, w: &mut impl Write) -> io::Result<()> {
+        match self {
+            Value::U8(x) => w.write_all(&[*x]),
+            Value::I8(x) => w.write_all(&x.to_le_bytes()),
+            Value::U16(x) => w.write_all(&x.to_le_bytes()),
+            Value::I16(x) => w.write_all(&x.to_le_bytes()),
+            Value::U32(x) => w.write_all(&x.to_le_bytes()),
+            Value::I32(x) => w.write_all(&x.to_le_bytes()),
+            Value::F32(x) => w.write_all(&x.to_le_bytes()),
+            Value::Bool(x) => w.write_all(&[*x as u8]),
+            Value::String(x) => write_string(w, x),
+            Value::Array(xs) => {
+                // An empty array's element type doesn't matter.
+                let type_id = xs.first().map_or(Value::U8(0).type_id(), Value::type_id);
+                assert!(xs.iter().all(|x| x.type_id() == type_id), "mixed array");
+                w.write_all(&type_id.to_le_bytes())?;
+                w.write_all(&(xs.len() as u64).to_le_bytes())?;
+                xs.iter().try_for_each(|x| x.write(w))
+            }
+            Value::U64(x) => w.write_all(&x.to_le_bytes()),
+            Value::I64(x) => w.write_all(&x.to_le_bytes()),
+            Value::F64(x) => w.write_all(&x.to_le_bytes()),
+        }
+    }
+
+    fn as_u64(&self) -> Option<u64> {
+        match *self {
+            Value::U8(x) => Some(x.into()),
+            Value::U16(x) => Some(x.into()),
+            Value::U32(x) => Some(x.into()),
+            Value::U64(x) => Some(x),
+            Value::I8(x) => x.try_into().ok(),
+            Value::I16(x) => x.try_into().ok(),
+            Value::I32(x) => x.try_into().ok(),
+            Value::I64(x) => x.try_into().ok(),
+            _ => None,
+        }
+    }
+
+    fn as_f32(&self) -> Option<f32> {
+        match *self {
+            Value::F32(x) => Some(x),
+            Value::F64(x) => Some(x as f32),
+            _ => None,
+        }
+    }
+}
+
+/// The name GGUF gives an architecture in `general.architecture`, which also
+/// prefixes its hyperparameters. Mistral is LLaMA with a sliding window.
+fn arch_name(arch: Architecture) -> &'static str {
+    match arch {
+        Architecture::Llama | Architecture::Mistral => "llama",
+        Architecture::Gpt2 => "gpt2",
+        Architecture::GptNeoX => "gptneox",
+    }
+}
+
+pub(crate) fn load(f: File) -> io::Result<(Vocab, Ggml)> {
+    let mut r = BufReader::new(f);
+
+    if read_u32(&mut r)? != VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid version",
+        ));
+    }
+    let n_tensors = read_u64(&mut r)?;
+    let n_kv = read_u64(&mut r)?;
+
+    let mut metadata = HashMap::new();
+    for _ in 0..n_kv {
+        let key = read_string(&mut r)?.to_string();
+        let type_id = read_u32(&mut r)?;
+        metadata.insert(key, Value::read(&mut r, type_id)?);
+    }
+    let meta = Metadata(&metadata);
+
+    let tokens = meta.get("tokenizer.ggml.tokens")?;
+    let scores = metadata.get("tokenizer.ggml.scores");
+    let types = metadata.get("tokenizer.ggml.token_type");
+    let (Value::Array(tokens), None | Some(Value::Array(_)), None | Some(Value::Array(_))) =
+        (tokens, scores, types)
+    else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid vocabulary",
+        ));
+    };
+    let mut vocab_tokens = Vec::with_capacity(tokens.len());
+    for (i, token) in tokens.iter().enumerate() {
+        let score = match scores {
+            Some(Value::Array(scores)) => scores.get(i).and_then(Value::as_f32),
+            _ => Some(0.0),
+        };
+        let ty = match types {
+            Some(Value::Array(types)) => types
+                .get(i)
+                .and_then(Value::as_u64)
+                .and_then(|x| TokenType::from_id(x as u32)),
+            _ => Some(TokenType::Normal),
+        };
+        let (Value::String(token), Some(score), Some(ty)) = (token, score, ty) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid vocabulary",
+            ));
+        };
+        vocab_tokens.push(Token {
+            token: token.clone(),
+            score,
+            ty,
+        });
+    }
+    let mut vocab = Vocab::new(vocab_tokens);
+    match metadata.get("tokenizer.ggml.model") {
+        None => {}
+        Some(Value::String(model)) if model == "llama" => {}
+        Some(Value::String(model)) if model == "gpt2" => {
+            let Some(Value::Array(merges)) = metadata.get("tokenizer.ggml.merges") else {
+                return Err(Metadata::invalid("tokenizer.ggml.merges"));
+            };
+            for merge in merges {
+                let Value::String(merge) = merge else {
+                    return Err(Metadata::invalid("tokenizer.ggml.merges"));
+                };
+                vocab.merges.push(merge.clone());
+            }
+        }
+        Some(model) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported tokenizer model {model:?}"),
+            ));
+        }
+    }
+    let special_id = |key: &str| match metadata.get(key) {
+        None => Ok(None),
+        Some(id) => match id.as_u64() {
+            Some(id) if (id as usize) < vocab.id_to_token.len() => Ok(Some(id as usize)),
+            _ => Err(Metadata::invalid(key)),
+        },
+    };
+    vocab.bos = special_id("tokenizer.ggml.bos_token_id")?;
+    vocab.eos = special_id("tokenizer.ggml.eos_token_id")?;
+
+    let hparams = meta.hparams(vocab.id_to_token.len())?;
+
+    let mut infos = Vec::new();
+    for _ in 0..n_tensors {
+        let name = String::from_utf8(read_string(&mut r)?.into())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid name"))?;
+        let n_dims = read_u32(&mut r)?;
+        // Innermost first, like the GGML formats.
+        let mut dims = (0..n_dims)
+            .map(|_| Ok(read_u64(&mut r)? as usize))
+            .collect::<io::Result<Vec<_>>>()?;
+        dims.reverse();
+        let ftype = read_u32(&mut r)?;
+        let offset = read_u64(&mut r)?;
+        infos.push((name, dims, ftype, offset));
+    }
+
+    let align = match metadata.get("general.alignment") {
+        Some(align) => align
+            .as_u64()
+            .filter(|x| x.is_power_of_two())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid alignment"))?,
+        None => DEFAULT_ALIGN,
+    };
+    let data_start = r.stream_position()?.next_multiple_of(align);
+
+    let mut vars = HashMap::new();
+    for (name, dims, ftype, offset) in infos {
+        r.seek(SeekFrom::Start(data_start + offset))?;
+        let data = ggml::read_data(&mut r, ftype, dims.iter().product())?;
+        if vars
+            .insert(name.clone(), Var { name, dims, data })
+            .is_some()
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "duplicate variable name",
+            ));
+        }
+    }
+
+    Ok((vocab, Ggml { hparams, vars }))
+}
+
+pub(crate) fn save(ggml: &Ggml, vocab: &Vocab, w: &mut BufWriter<File>) -> io::Result<()> {
+    let metadata = metadata(&ggml.hparams, vocab)?;
+    let mut names = ggml.vars.keys().collect::<Vec<_>>();
+    names.sort();
+
+    w.write_all(&MAGIC.to_le_bytes())?;
+    w.write_all(&VERSION.to_le_bytes())?;
+    w.write_all(&(names.len() as u64).to_le_bytes())?;
+    w.write_all(&(metadata.len() as u64).to_le_bytes())?;
+    for (key, value) in &metadata {
+        write_string(w, key.as_bytes())?;
+        w.write_all(&value.type_id().to_le_bytes())?;
+        value.write(w)?;
+    }
+
+    let mut offset = 0u64;
+    for name in &names {
+        let var = &ggml.vars[*name];
+        assert_eq!(
+            var.data.len(),
+            var.dims.iter().product::<usize>(),
+            "{name} has the wrong number of elements"
+        );
+
+        write_string(w, name.as_bytes())?;
+        w.write_all(&(var.dims.len() as u32).to_le_bytes())?;
+        for &dim in var.dims.iter().rev() {
+            w.write_all(&(dim as u64).to_le_bytes())?;
+        }
+        w.write_all(&(var.data.scalar_type() as u32).to_le_bytes())?;
+        w.write_all(&offset.to_le_bytes())?;
+        offset = (offset + var.data.as_bytes().len() as u64).next_multiple_of(DEFAULT_ALIGN);
+    }
+
+    let mut pos = w.stream_position()?;
+    for name in names {
+        let bytes = ggml.vars[name].data.as_bytes();
+        let padding = pos.next_multiple_of(DEFAULT_ALIGN) - pos;
+        w.write_all(&vec![0; padding as usize])?;
+        w.write_all(bytes)?;
+        pos += padding + bytes.len() as u64;
+    }
+
+    Ok(())
+}
+
+/// The metadata describing `hparams` and `vocab`, in the order it's written.
+fn metadata(hparams: &HParams, vocab: &Vocab) -> io::Result<Vec<(String, Value)>> {
+    let u32 = |x: usize| {
+        u32::try_from(x)
+            .map(Value::U32)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "value doesn't fit in u32"))
+    };
+    let arch = arch_name(hparams.arch);
+    let key = |name: &str| format!("{arch}.{name}");
+
+    let mut metadata = vec![
+        (
+            "general.architecture".to_string(),
+            Value::String(arch.into()),
+        ),
+        (
+            "general.file_type".to_string(),
+            Value::U32(hparams.scalar_ty.file_type()),
+        ),
+        (key("context_length"), u32(hparams.n_ctx)?),
+        (key("embedding_length"), u32(hparams.dim)?),
+        (key("block_count"), u32(hparams.n_layers)?),
+        (key("feed_forward_length"), u32(hparams.n_ff)?),
+        (key("attention.head_count"), u32(hparams.n_heads)?),
+    ];
+    match hparams.arch {
+        Architecture::Llama | Architecture::Mistral => {
+            metadata.extend([
+                (key("attention.head_count_kv"), u32(hparams.n_kv_heads)?),
+                (
+                    key("attention.layer_norm_rms_epsilon"),
+                    Value::F32(hparams.norm_eps),
+                ),
+                (key("rope.dimension_count"), u32(hparams.head_dim())?),
+                (key("rope.freq_base"), Value::F32(hparams.rope_theta)),
+            ]);
+            if let Some(window) = hparams.sliding_window {
+                metadata.push((key("attention.sliding_window"), u32(window)?));
+            }
+        }
+        Architecture::Gpt2 => {
+            metadata.push((
+                key("attention.layer_norm_epsilon"),
+                Value::F32(hparams.norm_eps),
+            ));
+        }
+        Architecture::GptNeoX => {
+            metadata.extend([
+                (
+                    key("attention.layer_norm_epsilon"),
+                    Value::F32(hparams.norm_eps),
+                ),
+                (key("rope.dimension_count"), u32(rotary_dims(hparams))?),
+                (key("rope.freq_base"), Value::F32(hparams.rope_theta)),
+                (key("use_parallel_residual"), Value::Bool(true)),
+            ]);
+        }
+    }
+
+    // Byte-level vocabularies are merged by rank, like GPT-2's, rather than
+    // by score.
+    let model = if vocab.merges.is_empty() {
+        "llama"
+    } else {
+        "gpt2"
+    };
+    metadata.extend([
+        (
+            "tokenizer.ggml.model".to_string(),
+            Value::String(model.into()),
+        ),
+        (
+            "tokenizer.ggml.tokens".to_string(),
+            Value::Array(
+                vocab
+                    .id_to_token
+                    .iter()
+                    .map(|t| Value::String(t.token.clone()))
+                    .collect(),
+            ),
+        ),
+        (
+            "tokenizer.ggml.scores".to_string(),
+            Value::Array(
+                vocab
+                    .id_to_token
+                    .iter()
+                    .map(|t| Value::F32(t.score))
+                    .collect(),
+            ),
+        ),
+        (
+            "tokenizer.ggml.token_type".to_string(),
+            Value::Array(
+                vocab
+                    .id_to_token
+                    .iter()
+                    .map(|t| Value::I32(t.ty as i32))
+                    .collect(),
+            ),
+        ),
+    ]);
+    if !vocab.merges.is_empty() {
+        metadata.push((
+            "tokenizer.ggml.merges".to_string(),
+            Value::Array(
+                vocab
+                    .merges
+                    .iter()
+                    .map(|merge| Value::String(merge.clone()))
+                    .collect(),
+            ),
+        ));
+    }
+    for (key, id) in [
+        ("tokenizer.ggml.bos_token_id", vocab.bos),
+        ("tokenizer.ggml.eos_token_id", vocab.eos),
+    ] {
+        if let Some(id) = id {
+            metadata.push((key.to_string(), u32(id)?));
+        }
+    }
+    Ok(metadata)
+}
+
+fn rotary_dims(hparams: &HParams) -> usize {
+    (hparams.head_dim() as f32 * hparams.rotary_pct) as usize
+}
+
+/// Typed access to the metadata, with errors naming the key.
+struct Metadata<'a>(&'a HashMap<String, Value>);
+
+impl Metadata<'_> {
+    fn get(&self, key: &str) -> io::Result<&Value> {
+        self.0.get(key).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("metadata has no {key}"))
+        })
+    }
+
+    fn usize(&self, key: &str) -> io::Result<usize> {
+        self.get(key)?
+            .as_u64()
+            .map(|x| x as usize)
+            .ok_or_else(|| Self::invalid(key))
+    }
+
+    fn f32(&self, key: &str) -> io::Result<f32> {
+        self.get(key)?.as_f32().ok_or_else(|| Self::invalid(key))
+    }
+
+    fn invalid(key: &str) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("metadata has an invalid {key}"),
+        )
+    }
+
+    fn hparams(&self, vocab_size: usize) -> io::Result<HParams> {
+        let Value::String(arch) = self.get("general.architecture")? else {
+            return Err(Self::invalid("general.architecture"));
+        };
+        let key = |name: &str| format!("{arch}.{name}");
+        let has = |name: &str| self.0.contains_key(&key(name));
+
+        let arch = match arch.as_slice() {
+            b"llama" if has("attention.sliding_window") => Architecture::Mistral,
+            b"llama" => Architecture::Llama,
+            b"gpt2" => Architecture::Gpt2,
+            b"gptneox" => Architecture::GptNeoX,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unsupported architecture {arch}"),
+                ))
+            }
+        };
+
+        let n_heads = self.usize(&key("attention.head_count"))?;
+        let dim = self.usize(&key("embedding_length"))?;
+        let scalar_ty = self
+            .0
+            .get("general.file_type")
+            .and_then(Value::as_u64)
+            .and_then(|x| ScalarType::from_file_type(x as u32))
+            .unwrap_or(ScalarType::F16);
+        let mut hparams = HParams {
+            arch,
+            vocab_size,
+            dim,
+            multiple_of: 1,
+            n_heads,
+            n_kv_heads: n_heads,
+            n_layers: self.usize(&key("block_count"))?,
+            n_ff: self.usize(&key("feed_forward_length"))?,
+            n_ctx: self.usize(&key("context_length"))?,
+            norm_eps: 1e-5,
+            rope_theta: 10000.0,
+            rotary_pct: 1.0,
+            sliding_window: None,
+            scalar_ty,
+        };
+        if n_heads == 0 || dim % n_heads != 0 {
+            return Err(Self::invalid(&key("attention.head_count")));
+        }
+
+        if has("attention.head_count_kv") {
+            hparams.n_kv_heads = self.usize(&key("attention.head_count_kv"))?;
+        }
+        for name in [
+            "attention.layer_norm_rms_epsilon",
+            "attention.layer_norm_epsilon",
+        ] {
+            if has(name) {
+                hparams.norm_eps = self.f32(&key(name))?;
+            }
+        }
+        if has("rope.freq_base") {
+            hparams.rope_theta = self.f32(&key("rope.freq_base"))?;
+        }
+        if has("attention.sliding_window") {
+            hparams.sliding_window = Some(self.usize(&key("attention.sliding_window"))?);
+        }
+        match arch {
+            Architecture::Gpt2 => hparams.rotary_pct = 0.0,
+            Architecture::GptNeoX if has("rope.dimension_count") => {
+                hparams.rotary_pct =
+                    self.usize(&key("rope.dimension_count"))? as f32 / hparams.head_dim() as f32;
+            }
+            _ => {}
+        }
+
+        Ok(hparams)
+    }
+}
+
+fn read_array<const N: usize>(r: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut buf = [0; N];
+    r.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn read_u32(r: &mut impl Read) -> io::Result<u32> {
+    Ok(u32::from_le_bytes(read_array(r)?))
+}
+
+fn read_u64(r: &mut impl Read) -> io::Result<u64> {
+    Ok(u64::from_le_bytes(read_array(r)?))
+}
+
+fn read_string(r: &mut impl Read) -> io::Result<BString> {
+    let len = read_u64(r)?;
+    let mut buf = Vec::new();
+    r.take(len).read_to_end(&mut buf)?;
+    if buf.len() as u64 != len {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "unexpected EOF",
+        ));
+    }
+    Ok(buf.into())
+}
+
+fn write_string(w: &mut impl Write, s: &[u8]) -> io::Result<()> {
+    w.write_all(&(s.len() as u64).to_le_bytes())?;
+    w.write_all(s)
+}
@@ -0,0 +1,535 @@
+use std::sync::{mpsc, Mutex};
+use std::thread;
+
+use half::f16;
+
+use crate::scratch::BufferPool;
+use crate::tensor::{extend_shape, Tensor, MAX_DIMS};
+
+/// Element-wise ops, which the executor fuses into a single pass when they're
+/// chained.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Unary {
+    Silu,
+    Scale(f32),
+}
+
+impl Unary {
+    fn apply(self, x: f32) -> f32 {
+        match self {
+            Unary::Silu => x * (1.0 / (1.0 + (-x).exp())),
+            Unary::Scale(scale) => x * scale,
+        }
+    }
+}
+
+#[derive(Clone)]
+enum Op {
+    Input(Tensor<f16, MAX_DIMS>),
+    /// A chain of element-wise ops, applied in order.
+    Map(Vec<Unary>),
+    RmsNorm,
+    Add,
+    Mul,
+    /// inputs: [w, x]
+    MatMul,
+    Dot,
+    Repeat,
+    /// inputs: [vt, q, k]
+    FlashAttn,
+}
+
+#[derive(Clone)]
+struct NodeData {
+    op: Op,
+    inputs: Vec<usize>,
+    /// The shape, extended to `MAX_DIMS` like the tensors the ops see.
+    shape: [usize; MAX_DIMS],
+}
+
+/// A handle to a lazily computed `DIMS`-dimensional `f16` tensor in a [`Graph`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Node<const DIMS: usize> {
+    id: usize,
+    shape: [usize; DIMS],
+}
+
+impl<const DIMS: usize> Node<DIMS> {
+    pub fn shape(&self) -> [usize; DIMS] {
+        self.shape
+    }
+}
+
+/// A computation recorded for later evaluation. Each method mirrors the eager
+/// op of the same name on [`Tensor`], but only adds a node to the graph. Mark
+/// the nodes you need with [`Graph::output`] and evaluate them with an
+/// [`Executor`].
+#[derive(Default)]
+pub struct Graph {
+    nodes: Vec<NodeData>,
+    outputs: Vec<usize>,
+}
+
+impl Graph {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn push<const DIMS: usize>(
+        &mut self,
+        op: Op,
+        inputs: Vec<usize>,
+        shape: [usize; DIMS],
+    ) -> Node<DIMS> {
+        let id = self.nodes.len();
+        self.nodes.push(NodeData {
+            op,
+            inputs,
+            shape: extend_shape(shape),
+        });
+        Node { id, shape }
+    }
+
+    pub fn input<const DIMS: usize>(&mut self, tensor: &Tensor<f16, DIMS>) -> Node<DIMS> {
+        let shape = tensor.shape();
+        self.push(
+            Op::Input(tensor.reshape(extend_shape(shape))),
+            vec![],
+            shape,
+        )
+    }
+
+    /// Marks a node to be returned by [`Executor::run`].
+    pub fn output<const DIMS: usize>(&mut self, x: Node<DIMS>) {
+        if !self.outputs.contains(&x.id) {
+            self.outputs.push(x.id);
+        }
+    }
+
+    pub fn silu<const DIMS: usize>(&mut self, x: Node<DIMS>) -> Node<DIMS> {
+        self.push(Op::Map(vec![Unary::Silu]), vec![x.id], x.shape)
+    }
+
+    pub fn scale<const DIMS: usize>(&mut self, x: Node<DIMS>, scale: f32) -> Node<DIMS> {
+        self.push(Op::Map(vec![Unary::Scale(scale)]), vec![x.id], x.shape)
+    }
+
+    pub fn rms_norm<const DIMS: usize>(&mut self, x: Node<DIMS>) -> Node<DIMS> {
+        self.push(Op::RmsNorm, vec![x.id], x.shape)
+    }
+
+    pub fn add<const DIMS: usize>(&mut self, a: Node<DIMS>, b: Node<DIMS>) -> Node<DIMS> {
+        assert_eq!(a.shape, b.shape);
+        self.push(Op::Add, vec![a.id, b.id], a.shape)
+    }
+
+    pub fn mul<const DIMS: usize>(&mut self, a: Node<DIMS>, b: Node<DIMS>) -> Node<DIMS> {
+        assert_eq!(a.shape, b.shape);
+        self.push(Op::Mul, vec![a.id, b.id], a.shape)
+    }
+
+    /// See [`Tensor::<f16, 2>::matmul`].
+    pub fn matmul<const DIMS: usize>(&mut self, w: Node<2>, x: Node<DIMS>) -> Node<DIMS> {
+        assert!(DIMS == 1 || DIMS == 2);
+        assert_eq!(w.shape[1], x.shape[DIMS - 1]);
+
+        let mut shape = x.shape;
+        shape[DIMS - 1] = w.shape[0];
+        self.push(Op::MatMul, vec![w.id, x.id], shape)
+    }
+
+    /// See [`Tensor::dot`].
+    pub fn dot<const DIMS: usize>(&mut self, a: Node<DIMS>, x: Node<DIMS>) -> Node<DIMS> {
+        assert!(DIMS >= 2);
+        assert_eq!(a.shape[..DIMS - 2], x.shape[..DIMS - 2]);
+        assert_eq!(a.shape[DIMS - 1], x.shape[DIMS - 1]);
+
+        let mut shape = a.shape;
+        shape[DIMS - 1] = x.shape[DIMS - 2];
+        self.push(Op::Dot, vec![a.id, x.id], shape)
+    }
+
+    pub fn repeat<const DIMS: usize, const DIMS2: usize>(
+        &mut self,
+        x: Node<DIMS>,
+        shape: [usize; DIMS2],
+    ) -> Node<DIMS2> {
+        assert!(DIMS <= DIMS2);
+        self.push(Op::Repeat, vec![x.id], shape)
+    }
+
+    /// See [`Tensor::<f16, 2>::flash_attn`].
+    pub fn flash_attn(&mut self, vt: Node<2>, q: Node<2>, k: Node<2>) -> Node<2> {
+        let [d, n] = vt.shape;
+        assert_eq!(q.shape, [n, d]);
+        assert_eq!(k.shape, [n, d]);
+        self.push(Op::FlashAttn, vec![vt.id, q.id, k.id], [n, d])
+    }
+
+    fn consumers(&self) -> Vec<usize> {
+        let mut consumers = vec![0; self.nodes.len()];
+        for node in &self.nodes {
+            for &i in &node.inputs {
+                consumers[i] += 1;
+            }
+        }
+        consumers
+    }
+
+    /// Merges chains of element-wise ops into single nodes, where the
+    /// intermediate results aren't used anywhere else.
+    fn fuse(&self) -> Graph {
+        let consumers = self.consumers();
+        let mut fused = Graph {
+            nodes: self.nodes.clone(),
+            outputs: self.outputs.clone(),
+        };
+
+        // Nodes only depend on earlier nodes, so by the time a node is visited
+        // its input has already absorbed the rest of the chain above it.
+        for i in 0..fused.nodes.len() {
+            let Op::Map(ops) = &fused.nodes[i].op else {
+                continue;
+            };
+            let x = fused.nodes[i].inputs[0];
+            if consumers[x] != 1 || self.outputs.contains(&x) {
+                continue;
+            }
+            if let Op::Map(prev) = &fused.nodes[x].op {
+                let chain = prev.iter().chain(ops).copied().collect();
+                fused.nodes[i].inputs = fused.nodes[x].inputs.clone();
+                fused.nodes[i].op = Op::Map(chain);
+            }
+        }
+
+        fused
+    }
+
+    /// The nodes the outputs depend on, with every node after its inputs.
+    /// This walks depth-first with a stack of its own, since a deep graph,
+    /// like an unrolled many-layer model, would overflow the thread's stack.
+    fn topological_order(&self) -> Vec<usize> {
+        let mut visited = vec![false; self.nodes.len()];
+        let mut order = Vec::new();
+        // Each entry is a node and how many of its inputs have been visited.
+        let mut stack = Vec::new();
+        for &i in &self.outputs {
+            if !visited[i] {
+                visited[i] = true;
+                stack.push((i, 0));
+            }
+            while let Some((i, next)) = stack.last_mut() {
+                match self.nodes[*i].inputs.get(*next) {
+                    Some(&x) => {
+                        *next += 1;
+                        if !visited[x] {
+                            visited[x] = true;
+                            stack.push((x, 0));
+                        }
+                    }
+                    None => {
+                        order.push(*i);
+                        stack.pop();
+                    }
+                }
+            }
+        }
+        order
+    }
+}
+
+/// Computes a single node from its inputs into `out`.
+fn compute(
+    node: &NodeData,
+    inputs: &[&Tensor<f16, MAX_DIMS>],
+    mut out: Tensor<f16, MAX_DIMS>,
+) -> Tensor<f16, MAX_DIMS> {
+    match &node.op {
+        Op::Input(_) => unreachable!(),
+        Op::Map(ops) => inputs[0].map_into(&mut out, |x| ops.iter().fold(x, |x, op| op.apply(x))),
+        Op::RmsNorm => inputs[0].rms_norm_into(&mut out),
+        Op::Add => inputs[0].add_into(inputs[1], &mut out),
+        Op::Mul => inputs[0].mul_into(inputs[1], &mut out),
+        Op::Dot => inputs[0].dot_into(inputs[1], &mut out),
+        Op::Repeat => inputs[0].repeat_into(&mut out),
+        Op::MatMul => {
+            let [.., m, n] = inputs[0].shape();
+            let p = inputs[1].shape()[..MAX_DIMS - 1].iter().product();
+
+            // Move the buffer rather than reshaping, which would share it.
+            let mut out_2d = Tensor::from_shared(out.into_shared(), [p, m]);
+            inputs[0]
+                .reshape([m, n])
+                .matmul_into(&inputs[1].reshape([p, n]), &mut out_2d);
+            out = Tensor::from_shared(out_2d.into_shared(), node.shape);
+        }
+        Op::FlashAttn => {
+            let [.., d, n] = inputs[0].shape();
+
+            let mut out_2d = Tensor::from_shared(out.into_shared(), [n, d]);
+            inputs[0].reshape([d, n]).flash_attn_into(
+                &inputs[1].reshape([n, d]),
+                &inputs[2].reshape([n, d]),
+                &mut out_2d,
+            );
+            out = Tensor::from_shared(out_2d.into_shared(), node.shape);
+        }
+    }
+    out
+}
+
+/// Evaluates [`Graph`]s. Intermediate buffers are taken from a pool and handed
+/// back as soon as their last consumer has run, so they're reused both within
+/// a run and across runs. Nodes whose inputs are ready are run in parallel.
+pub struct Executor {
+    pool: BufferPool<f16>,
+    threads: usize,
+}
+
+/// The values of a graph's output nodes.
+pub struct Outputs {
+    values: Vec<Option<Tensor<f16, MAX_DIMS>>>,
+}
+
+impl Outputs {
+    pub fn get<const DIMS: usize>(&self, x: Node<DIMS>) -> Tensor<f16, DIMS> {
+        self.values[x.id]
+            .as_ref()
+            .expect("node is not an output of the graph")
+            .reshape(x.shape)
+    }
+}
+
+impl Default for Executor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Executor {
+    pub fn new() -> Self {
+        let threads = thread::available_parallelism().map_or(1, |n| n.get());
+        Self::with_threads(threads)
+    }
+
+    pub fn with_threads(threads: usize) -> Self {
+        assert!(threads > 0);
+        Self {
+            pool: BufferPool::new(),
+            threads,
+        }
+    }
+
+    /// The number of buffers allocated for intermediates so far.
+    pub fn allocations(&self) -> usize {
+        self.pool.allocations()
+    }
+
+    pub fn run(&mut self, graph: &Graph) -> Outputs {
+        let graph = graph.fuse();
+        let order = graph.topological_order();
+
+        // Only count consumers that will actually run.
+        let mut remaining = vec![0; graph.nodes.len()];
+        let mut levels = vec![0; graph.nodes.len()];
+        for &i in &order {
+            for &x in &graph.nodes[i].inputs {
+                remaining[x] += 1;
+                levels[i] = levels[i].max(levels[x] + 1);
+            }
+        }
+
+        let mut values: Vec<Option<Tensor<f16, MAX_DIMS>>> = vec![None; graph.nodes.len()];
+        let max_level = order.iter().map(|&i| levels[i]).max().unwrap_or(0);
+
+        // The workers are started once per run and fed a level at a time. Each
+        // job carries its inputs, which share their buffers with `values`.
+        let (job_tx, job_rx) =
+            mpsc::channel::<(usize, Vec<Tensor<f16, MAX_DIMS>>, Tensor<f16, MAX_DIMS>)>();
+        let (done_tx, done_rx) = mpsc::channel();
+        let job_rx = Mutex::new(job_rx);
+        thread::scope(|s| {
+            for _ in 0..self.threads {
+                let (job_rx, done_tx, graph) = (&job_rx, done_tx.clone(), &graph);
+                s.spawn(move || loop {
+                    // The lock is only held while waiting for the next job.
+                    let Ok((i, inputs, out)) = job_rx.lock().unwrap().recv() else {
+                        break;
+                    };
+                    let out = compute(&graph.nodes[i], &inputs.iter().collect::<Vec<_>>(), out);
+                    // Drop the inputs before reporting back, so the buffers
+                    // are unshared by the time they might be recycled.
+                    drop(inputs);
+                    done_tx.send((i, out)).unwrap();
+                });
+            }
+
+            for level in 0..=max_level {
+                // Everything in a level only depends on earlier levels, so the
+                // nodes can be computed independently.
+                let mut jobs = 0;
+                for &i in order.iter().filter(|&&i| levels[i] == level) {
+                    let node = &graph.nodes[i];
+                    match &node.op {
+                        Op::Input(tensor) => values[i] = Some(tensor.clone()),
+                        _ => {
+                            let inputs = node
+                                .inputs
+                                .iter()
+                                .map(|&x| values[x].clone().unwrap())
+                                .collect();
+                            job_tx
+                                .send((i, inputs, self.pool.take(node.shape)))
+                                .unwrap();
+                            jobs += 1;
+                        }
+                    }
+                }
+
+                let done = done_rx.iter().take(jobs).collect::<Vec<_>>();
+                for (i, out) in done {
+                    for &x in &graph.nodes[i].inputs {
+                        remaining[x] -= 1;
+                        if remaining[x] == 0 && !graph.outputs.contains(&x) {
+                            if let Some(tensor) = values[x].take() {
+                                self.pool.recycle(tensor);
+                            }
+                        }
+                    }
+                    values[i] = Some(out);
+                }
+            }
+
+            // Closing the channel stops the workers.
+            drop(job_tx);
+        });
+
+        Outputs { values }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assert_tensor_close;
+
+    fn tensor<const DIMS: usize>(shape: [usize; DIMS], seed: usize) -> Tensor<f16, DIMS> {
+        let len = shape.iter().product::<usize>();
+        let data = (0..len)
+            .map(|i| f16::from_f32(((i * 7 + seed * 13) % 17) as f32 / 17.0 - 0.5))
+            .collect();
+        Tensor::new(data, shape)
+    }
+
+    #[test]
+    fn matches_eager() {
+        let x = tensor([5, 8], 1);
+        let w1 = tensor([6, 8], 2);
+        let w2 = tensor([6, 8], 3);
+
+        let eager = {
+            let h = x.rms_norm();
+            let mut a = w1.matmul(&h);
+            a.silu_inplace();
+            a.scale_inplace(0.5);
+            let b = w2.matmul(&h);
+            a.mul(&b).add(&b)
+        };
+
+        let mut g = Graph::new();
+        let gx = g.input(&x);
+        let gw1 = g.input(&w1);
+        let gw2 = g.input(&w2);
+        let h = g.rms_norm(gx);
+        let a = g.matmul(gw1, h);
+        let a = g.silu(a);
+        let a = g.scale(a, 0.5);
+        let b = g.matmul(gw2, h);
+        let ab = g.mul(a, b);
+        let y = g.add(ab, b);
+        g.output(y);
+
+        let mut executor = Executor::with_threads(2);
+        let lazy = executor.run(&g).get(y);
+        assert_tensor_close!(lazy, eager, rtol = 1e-2, atol = 1e-2);
+    }
+
+    #[test]
+    fn fuses_element_wise_chains() {
+        let x = tensor([4, 4], 1);
+
+        let mut g = Graph::new();
+        let gx = g.input(&x);
+        let a = g.silu(gx);
+        let b = g.scale(a, 2.0);
+        let c = g.silu(b);
+        g.output(c);
+
+        let fused = g.fuse();
+        let order = fused.topological_order();
+        assert_eq!(order.len(), 2);
+        assert!(matches!(&fused.nodes[c.id].op, Op::Map(ops) if ops.len() == 3));
+
+        let mut expected = x.silu();
+        expected.scale_inplace(2.0);
+        expected.silu_inplace();
+        assert_tensor_close!(
+            Executor::with_threads(1).run(&g).get(c),
+            expected,
+            rtol = 1e-2,
+            atol = 1e-2
+        );
+    }
+
+    #[test]
+    fn reuses_dead_buffers() {
+        let x = tensor([16], 1);
+
+        let mut g = Graph::new();
+        let mut cur = g.input(&x);
+        for _ in 0..8 {
+            cur = g.rms_norm(cur);
+        }
+        g.output(cur);
+
+        let mut executor = Executor::with_threads(1);
+        executor.run(&g);
+        // Each norm's input is dead once it has run, so two buffers suffice.
+        assert_eq!(executor.allocations(), 2);
+    }
+
+    #[test]
+    fn runs_deep_graphs() {
+        let x = tensor([4], 1);
+        let y = tensor([4], 2);
+
+        // Deep enough that visiting the nodes recursively would overflow the
+        // test thread's stack.
+        let mut g = Graph::new();
+        let gy = g.input(&y);
+        let mut cur = g.input(&x);
+        for _ in 0..100_000 {
+            cur = g.add(cur, gy);
+        }
+        g.output(cur);
+        assert_eq!(g.topological_order().len(), 100_002);
+
+        let mut g = Graph::new();
+        let gy = g.input(&y);
+        let mut cur = g.input(&x);
+        for _ in 0..1000 {
+            cur = g.add(cur, gy);
+        }
+        g.output(cur);
+        let mut expected = x.clone();
+        for _ in 0..1000 {
+            expected = expected.add(&y);
+        }
+        assert_tensor_close!(
+            Executor::with_threads(3).run(&g).get(cur),
+            expected,
+            rtol = 1e-2,
+            atol = 1e-1
+        );
+    }
+}
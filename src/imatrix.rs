@@ -0,0 +1,212 @@
+//! Importance matrices: how much each input channel of a weight matrix
+//! matters, measured as the mean square of the activations it's multiplied
+//! by over some calibration text. [`Quantized::with_importance`] uses them to
+//! spend a block's precision where the model will notice.
+//!
+//! [`Quantized::with_importance`]: crate::quant::Quantized::with_importance
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use half::f16;
+
+use crate::tensor::Tensor;
+use crate::weights::{NameTable, Weight};
+
+/// Running sums of the squared inputs of each weight matrix, recorded by
+/// [`Linear::matmul_into`](crate::model::Linear::matmul_into) while
+/// [`Scratch::activations`](crate::scratch::Scratch::activations) is set.
+#[derive(Default)]
+pub struct ActivationStats {
+    stats: HashMap<Weight, (Vec<f64>, usize)>,
+}
+
+impl ActivationStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the rows of `x`, the input of `weight`.
+    pub fn record(&mut self, weight: Weight, x: &Tensor<f16, 2>) {
+        let [n, cols] = x.shape();
+        let (sums, rows) = self
+            .stats
+            .entry(weight)
+            .or_insert_with(|| (vec![0.0; cols], 0));
+        assert_eq!(
+            sums.len(),
+            cols,
+            "{weight:?} has {} inputs, not {cols}",
+            sums.len()
+        );
+        for row in x.rows() {
+            for (sum, x) in sums.iter_mut().zip(row) {
+                let x = x.to_f64();
+                *sum += x * x;
+            }
+        }
+        *rows += n;
+    }
+
+    /// The mean squared inputs so far, named as `table` names the weights.
+    /// `chunks` is how many calibration chunks they were collected over.
+    pub fn to_imatrix(&self, table: &NameTable, chunks: usize, dataset: &str) -> Imatrix {
+        let importance = self
+            .stats
+            .iter()
+            .filter_map(|(&weight, (sums, rows))| {
+                let mean = sums
+                    .iter()
+                    .map(|sum| (sum / (*rows).max(1) as f64) as f32)
+                    .collect();
+                Some((table.name(weight)?, mean))
+            })
+            .collect();
+        Imatrix {
+            importance,
+            chunks,
+            dataset: dataset.to_string(),
+        }
+    }
+}
+
+/// The importance of each input channel of a model's weight matrices, keyed
+/// by the matrices' names.
+#[derive(Debug, PartialEq)]
+pub struct Imatrix {
+    pub importance: HashMap<String, Vec<f32>>,
+    /// The number of calibration chunks the importance was measured over.
+    pub chunks: usize,
+    /// The calibration text, usually its path.
+    pub dataset: String,
+}
+
+impl Imatrix {
+    /// Loads an importance matrix in llama.cpp's `imatrix.dat` format: a
+    /// count of entries, each a name, a call count and that many times the
+    /// mean squares, followed by the chunk count and the dataset's name.
+    pub fn load(p: impl AsRef<Path>) -> io::Result<Self> {
+        let mut r = BufReader::new(File::open(p)?);
+
+        let n_entries = read_len(&mut r)?;
+        let mut importance = HashMap::new();
+        let mut chunks = 0;
+        for _ in 0..n_entries {
+            let name = read_string(&mut r)?;
+            let (calls, len) = (read_len(&mut r)?, read_len(&mut r)?);
+            let mut values = vec![0.0; len];
+            for value in &mut values {
+                *value = f32::from_le_bytes(read_array(&mut r)?) / calls.max(1) as f32;
+            }
+            chunks = chunks.max(calls);
+            importance.insert(name, values);
+        }
+
+        // Older files stop after the entries.
+        let mut dataset = String::new();
+        let mut rest = Vec::new();
+        r.read_to_end(&mut rest)?;
+        if !rest.is_empty() {
+            let mut rest = &rest[..];
+            chunks = read_len(&mut rest)?;
+            dataset = read_string(&mut rest)?;
+        }
+
+        Ok(Self {
+            importance,
+            chunks,
+            dataset,
+        })
+    }
+
+    pub fn save(&self, p: impl AsRef<Path>) -> io::Result<()> {
+        let mut w = BufWriter::new(File::create(p)?);
+
+        let mut names = self.importance.keys().collect::<Vec<_>>();
+        names.sort();
+        write_len(&mut w, names.len())?;
+        for name in names {
+            let values = &self.importance[name];
+            write_len(&mut w, name.len())?;
+            w.write_all(name.as_bytes())?;
+            write_len(&mut w, self.chunks)?;
+            write_len(&mut w, values.len())?;
+            for value in values {
+                w.write_all(&(value * self.chunks as f32).to_le_bytes())?;
+            }
+        }
+        write_len(&mut w, self.chunks)?;
+        write_len(&mut w, self.dataset.len())?;
+        w.write_all(self.dataset.as_bytes())?;
+        w.flush()
+    }
+}
+
+fn read_array<const N: usize>(r: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut buf = [0; N];
+    r.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// Lengths and counts are stored as `i32`s.
+fn read_len(r: &mut impl Read) -> io::Result<usize> {
+    usize::try_from(i32::from_le_bytes(read_array(r)?))
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "negative length"))
+}
+
+fn read_string(r: &mut impl Read) -> io::Result<String> {
+    let mut buf = vec![0; read_len(r)?];
+    r.read_exact(&mut buf)?;
+    String::from_utf8(buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn write_len(w: &mut impl Write, len: usize) -> io::Result<()> {
+    let len = i32::try_from(len)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "length too large"))?;
+    w.write_all(&len.to_le_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::llama;
+    use crate::weights::LayerWeight;
+
+    #[test]
+    fn records_mean_squares() {
+        let mut stats = ActivationStats::new();
+        let wq = Weight::Layer(1, LayerWeight::Wq);
+        let x = |rows: &[[f32; 2]]| {
+            Tensor::new(
+                rows.iter().flatten().map(|&x| f16::from_f32(x)).collect(),
+                [rows.len(), 2],
+            )
+        };
+        stats.record(wq, &x(&[[1.0, -2.0], [3.0, 0.0]]));
+        stats.record(wq, &x(&[[-1.0, 4.0]]));
+        stats.record(Weight::Output, &x(&[[0.5, 0.5]]));
+
+        let imatrix = stats.to_imatrix(&llama::GGUF_NAMES, 2, "calibration.txt");
+        assert_eq!(
+            imatrix.importance["blk.1.attn_q.weight"],
+            [11.0 / 3.0, 20.0 / 3.0]
+        );
+        assert_eq!(imatrix.importance["output.weight"], [0.25, 0.25]);
+
+        let path = std::env::temp_dir().join(format!("nxml-imatrix-{}.dat", std::process::id()));
+        imatrix.save(&path).unwrap();
+        let loaded = Imatrix::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.chunks, 2);
+        assert_eq!(loaded.dataset, "calibration.txt");
+        for (name, values) in &imatrix.importance {
+            for (a, b) in values.iter().zip(&loaded.importance[name]) {
+                assert!((a - b).abs() < 1e-6, "{name}: {a} became {b}");
+            }
+        }
+    }
+}
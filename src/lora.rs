@@ -0,0 +1,440 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, Read},
+    mem,
+    path::Path,
+};
+
+use half::f16;
+use serde_json::Value;
+
+use crate::ggml::{self, Data};
+use crate::safetensors;
+use crate::scratch::Scratch;
+use crate::tensor::Tensor;
+
+/// The magic number of llama.cpp's LoRA adapter files, "ggla".
+const MAGIC: u32 = 0x67676c61;
+/// The data of each variable in an adapter file starts on a multiple of this.
+const ALIGN: u64 = 32;
+/// What PEFT puts in front of the name of each module it wraps.
+const PEFT_PREFIX: &str = "base_model.model.";
+
+/// How a model applies the updates of a LoRA adapter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoraMode {
+    /// Add the updates into the base weights while loading.
+    Merge,
+    /// Keep the base weights and apply the updates during each matmul.
+    OnTheFly,
+}
+
+/// A low-rank update `scale * b · a` to a `[out, in]` weight matrix, where `a`
+/// is `[rank, in]` and `b` is `[out, rank]`.
+pub struct LoraWeight {
+    pub a: Tensor<f16, 2>,
+    pub b: Tensor<f16, 2>,
+    pub scale: f32,
+}
+
+impl LoraWeight {
+    /// The shape of the weight matrix this updates.
+    pub fn target_shape(&self) -> [usize; 2] {
+        [self.b.shape()[0], self.a.shape()[1]]
+    }
+
+    /// Adds the update into `w`, so it no longer costs anything at inference.
+    pub fn merge_into(&self, w: &mut Tensor<f16, 2>) {
+        assert_eq!(w.shape(), self.target_shape());
+        let [_, cols] = w.shape();
+        let rank = self.a.shape()[0];
+
+        let (a, b) = (self.a.as_slice(), self.b.as_slice());
+        for (o, row) in w.make_mut().chunks_exact_mut(cols).enumerate() {
+            let b = &b[o * rank..(o + 1) * rank];
+            for (i, w) in row.iter_mut().enumerate() {
+                let delta = (0..rank)
+                    .map(|r| b[r].to_f32() * a[r * cols + i].to_f32())
+                    .sum::<f32>();
+                *w = f16::from_f32(w.to_f32() + self.scale * delta);
+            }
+        }
+    }
+
+    /// Like [`Tensor::<f16, 2>::matmul_into`] with `w` and the update merged
+    /// into it, but applies the update through the rank-sized bottleneck
+    /// instead, leaving `w` untouched.
+    pub fn matmul_into(
+        &self,
+        w: &Tensor<f16, 2>,
+        x: &Tensor<f16, 2>,
+        out: &mut Tensor<f16, 2>,
+        scratch: &mut Scratch,
+    ) {
+        assert_eq!(w.shape(), self.target_shape());
+        let n = x.shape()[0];
+
+        w.matmul_into(x, out);
+
+        let mut ax = scratch.f16.take([n, self.a.shape()[0]]);
+        self.a.matmul_into(x, &mut ax);
+        let mut bax = scratch.f16.take(out.shape());
+        self.b.matmul_into(&ax, &mut bax);
+        bax.scale_inplace(self.scale);
+        out.add_inplace(&bax);
+
+        scratch.f16.recycle(ax);
+        scratch.f16.recycle(bax);
+    }
+
+    /// Like [`LoraWeight::matmul_into`], for [`Tensor::<f16, 2>::dot_into`],
+    /// giving the transposed output.
+    pub fn dot_into(
+        &self,
+        w: &Tensor<f16, 2>,
+        x: &Tensor<f16, 2>,
+        out: &mut Tensor<f16, 2>,
+        scratch: &mut Scratch,
+    ) {
+        assert_eq!(w.shape(), self.target_shape());
+        let n = x.shape()[0];
+
+        w.dot_into(x, out);
+
+        // `b · (a · xᵀ)`, where `a · xᵀ` is the transpose of `x · aᵀ`.
+        let mut xa = scratch.f16.take([n, self.a.shape()[0]]);
+        self.a.matmul_into(x, &mut xa);
+        let mut bax = scratch.f16.take(out.shape());
+        self.b.dot_into(&xa, &mut bax);
+        bax.scale_inplace(self.scale);
+        out.add_inplace(&bax);
+
+        scratch.f16.recycle(xa);
+        scratch.f16.recycle(bax);
+    }
+}
+
+/// A LoRA adapter: low-rank updates to some of a model's weight matrices,
+/// keyed by the name of the matrix they update, e.g.
+/// `layers.0.attention.wq.weight`.
+pub struct Lora {
+    pub rank: usize,
+    pub alpha: usize,
+    pub weights: HashMap<String, LoraWeight>,
+}
+
+impl Lora {
+    /// Loads an adapter in llama.cpp's GGML LoRA format, where each target
+    /// has a `{name}.loraA` and a `{name}.loraB` variable. `loraA` is stored
+    /// transposed, as `[in, rank]`. Updates are scaled by `alpha / rank`.
+    pub fn load(p: impl AsRef<Path>) -> io::Result<Self> {
+        let mut f = File::open(p)?;
+
+        let mut header = [0; mem::size_of::<u32>() * 4];
+        f.read_exact(&mut header)?;
+        let header = header
+            .chunks_exact(mem::size_of::<u32>())
+            .map(|x| u32::from_le_bytes([x[0], x[1], x[2], x[3]]))
+            .collect::<Vec<_>>();
+
+        if header[0] != MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid magic"));
+        }
+        if header[1] != 1 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid version",
+            ));
+        }
+        let rank = header[2] as usize;
+        let alpha = header[3] as usize;
+        if rank == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "LoRA rank is zero",
+            ));
+        }
+        let scale = alpha as f32 / rank as f32;
+
+        let mut vars = ggml::read_vars(&mut f, ALIGN)?;
+        let mut targets = vars
+            .keys()
+            .filter_map(|name| name.strip_suffix(".loraA"))
+            .map(str::to_string)
+            .collect::<Vec<_>>();
+        targets.sort();
+
+        let mut weights = HashMap::new();
+        for target in targets {
+            let a = vars.remove(&format!("{target}.loraA")).unwrap();
+            let b = vars.remove(&format!("{target}.loraB")).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("{target} has no loraB"))
+            })?;
+            let (a, b) = (to_f16_matrix(a)?.transpose(), to_f16_matrix(b)?);
+
+            if a.shape()[0] != rank || b.shape()[1] != rank {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{target} doesn't have rank {rank}"),
+                ));
+            }
+            weights.insert(target, LoraWeight { a, b, scale });
+        }
+
+        if let Some(name) = vars.keys().next() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unexpected variable {name}"),
+            ));
+        }
+
+        Ok(Self {
+            rank,
+            alpha,
+            weights,
+        })
+    }
+
+    /// Loads an adapter saved by HuggingFace's PEFT: a directory with an
+    /// `adapter_config.json` giving `r` and `lora_alpha`, and an
+    /// `adapter_model.safetensors` with a `{module}.lora_A.weight` of
+    /// `[rank, in]` and a `{module}.lora_B.weight` of `[out, rank]` for each
+    /// target. Updates are keyed by the HuggingFace name of the matrix, e.g.
+    /// `model.layers.0.self_attn.q_proj.weight`, and scaled by
+    /// `alpha / rank`, or `alpha / sqrt(rank)` with `use_rslora`.
+    pub fn load_peft(dir: impl AsRef<Path>) -> io::Result<Self> {
+        let dir = dir.as_ref();
+        let config: Value = serde_json::from_slice(&fs::read(dir.join("adapter_config.json"))?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let usize_field = |key: &str| {
+            config
+                .get(key)
+                .and_then(Value::as_u64)
+                .map(|x| x as usize)
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("adapter_config.json has no whole number {key}"),
+                    )
+                })
+        };
+        let (rank, alpha) = (usize_field("r")?, usize_field("lora_alpha")?);
+        if rank == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "LoRA rank is zero",
+            ));
+        }
+        for key in ["rank_pattern", "alpha_pattern"] {
+            if config
+                .get(key)
+                .and_then(Value::as_object)
+                .is_some_and(|x| !x.is_empty())
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{key} is not supported"),
+                ));
+            }
+        }
+        let scale = match config.get("use_rslora") {
+            Some(Value::Bool(true)) => alpha as f32 / (rank as f32).sqrt(),
+            _ => alpha as f32 / rank as f32,
+        };
+
+        let mut vars = safetensors::load(dir.join("adapter_model.safetensors"))?;
+        let mut targets = vars
+            .keys()
+            .filter_map(|name| name.strip_suffix(".lora_A.weight"))
+            .map(str::to_string)
+            .collect::<Vec<_>>();
+        targets.sort();
+
+        let mut weights = HashMap::new();
+        for target in targets {
+            let a = vars.remove(&format!("{target}.lora_A.weight")).unwrap();
+            let b = vars
+                .remove(&format!("{target}.lora_B.weight"))
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("{target} has no lora_B"),
+                    )
+                })?;
+            let (a, b) = (to_f16_matrix(a)?, to_f16_matrix(b)?);
+
+            if a.shape()[0] != rank || b.shape()[1] != rank {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{target} doesn't have rank {rank}"),
+                ));
+            }
+            let name = target.strip_prefix(PEFT_PREFIX).unwrap_or(&target);
+            weights.insert(format!("{name}.weight"), LoraWeight { a, b, scale });
+        }
+
+        if let Some(name) = vars.keys().next() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unexpected variable {name}"),
+            ));
+        }
+
+        Ok(Self {
+            rank,
+            alpha,
+            weights,
+        })
+    }
+
+    /// Removes the update for the matrix named `name`, if there is one.
+    pub fn take(&mut self, name: &str) -> Option<LoraWeight> {
+        self.weights.remove(name)
+    }
+}
+
+fn to_f16_matrix(var: ggml::Var) -> io::Result<Tensor<f16, 2>> {
+    let shape: [usize; 2] = var.dims[..].try_into().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} is not a matrix", var.name),
+        )
+    })?;
+
+    Ok(match var.data {
+        Data::F16(data) => Tensor::from_buf(data, shape),
+        data => Tensor::new(
+            data.to_f32().into_iter().map(f16::from_f32).collect(),
+            shape,
+        ),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+    use crate::assert_tensor_close;
+    use crate::rng::Rng;
+
+    fn tensor(shape: [usize; 2], seed: u64) -> Tensor<f16, 2> {
+        Tensor::new(
+            Rng(seed * 2654435761 + 1).vec_f16(shape[0] * shape[1]),
+            shape,
+        )
+    }
+
+    #[test]
+    fn merged_matches_on_the_fly() {
+        let lora = LoraWeight {
+            a: tensor([2, 8], 1),
+            b: tensor([6, 2], 2),
+            scale: 0.5,
+        };
+        let w = tensor([6, 8], 3);
+        let x = tensor([3, 8], 4);
+
+        let mut on_the_fly = Tensor::zeros([3, 6]);
+        lora.matmul_into(&w, &x, &mut on_the_fly, &mut Scratch::new());
+
+        let mut on_the_fly_t = Tensor::zeros([6, 3]);
+        lora.dot_into(&w, &x, &mut on_the_fly_t, &mut Scratch::new());
+
+        let mut merged = w.clone();
+        lora.merge_into(&mut merged);
+        assert_tensor_close!(merged.matmul(&x), on_the_fly, rtol = 1e-2, atol = 1e-2);
+        assert_tensor_close!(merged.dot(&x), on_the_fly_t, rtol = 1e-2, atol = 1e-2);
+        assert!(!w.allclose(&merged, 1e-3, 1e-3));
+    }
+
+    #[test]
+    fn load() {
+        let path = std::env::temp_dir().join(format!("nxml-lora-{}.bin", std::process::id()));
+        let mut f = File::create(&path).unwrap();
+
+        let mut bytes = Vec::new();
+        for x in [MAGIC, 1, 2, 4] {
+            bytes.extend(x.to_le_bytes());
+        }
+        // `a` is [2, 3], stored transposed in f32, and `b` is [4, 2] in f16.
+        // The data of both is padded to `ALIGN`.
+        for (name, ftype, dims, data) in [
+            (
+                "w.loraA",
+                0u32,
+                [2u32, 3],
+                (0..6)
+                    .flat_map(|x| (x as f32).to_le_bytes())
+                    .collect::<Vec<_>>(),
+            ),
+            (
+                "w.loraB",
+                1,
+                [2, 4],
+                (0..8)
+                    .flat_map(|x| f16::from_f32(x as f32).to_le_bytes())
+                    .collect(),
+            ),
+        ] {
+            for x in [2, name.len() as u32, ftype, dims[0], dims[1]] {
+                bytes.extend(x.to_le_bytes());
+            }
+            bytes.extend(name.as_bytes());
+            bytes.resize(bytes.len().next_multiple_of(ALIGN as usize), 0);
+            bytes.extend(data);
+        }
+        f.write_all(&bytes).unwrap();
+        drop(f);
+
+        let mut lora = Lora::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!((lora.rank, lora.alpha), (2, 4));
+        let w = lora.take("w").unwrap();
+        assert_eq!(w.scale, 2.0);
+        assert_eq!(w.a.shape(), [2, 3]);
+        assert_eq!(
+            w.a.to_vec(),
+            [0.0, 2.0, 4.0, 1.0, 3.0, 5.0].map(f16::from_f32)
+        );
+        assert_eq!(w.b.shape(), [4, 2]);
+        assert_eq!(w.target_shape(), [4, 3]);
+        assert!(lora.take("w").is_none());
+    }
+
+    #[test]
+    fn load_peft() {
+        let dir = std::env::temp_dir().join(format!("nxml-peft-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = serde_json::json!({ "peft_type": "LORA", "r": 2, "lora_alpha": 8, "target_modules": ["q_proj"] });
+        std::fs::write(dir.join("adapter_config.json"), config.to_string()).unwrap();
+        let module = "base_model.model.model.layers.0.self_attn.q_proj";
+        let f32s = |n: usize| {
+            (0..n)
+                .flat_map(|x| (x as f32).to_le_bytes())
+                .collect::<Vec<_>>()
+        };
+        safetensors::tests::write(
+            &dir.join("adapter_model.safetensors"),
+            &[
+                (&format!("{module}.lora_A.weight"), "F32", &[2, 3], f32s(6)),
+                (&format!("{module}.lora_B.weight"), "F32", &[4, 2], f32s(8)),
+            ],
+        );
+
+        let mut lora = Lora::load_peft(&dir).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!((lora.rank, lora.alpha), (2, 8));
+        let w = lora.take("model.layers.0.self_attn.q_proj.weight").unwrap();
+        assert_eq!(w.scale, 4.0);
+        assert_eq!(
+            w.a.to_vec(),
+            [0.0, 1.0, 2.0, 3.0, 4.0, 5.0].map(f16::from_f32)
+        );
+        assert_eq!(w.target_shape(), [4, 3]);
+        assert!(lora.weights.is_empty());
+    }
+}
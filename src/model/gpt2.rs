@@ -0,0 +1,228 @@
+//! GPT-2: LayerNorm, learned position embeddings and a GELU feed-forward
+//! block, with the output tied to the token embeddings.
+
+use half::f16;
+
+use super::{advance, causal_attn_into, past_len, split_qkv, LayerNorm, Linear, Model};
+use crate::ggml::HParams;
+use crate::scratch::Scratch;
+use crate::tensor::{Attention, RopeStyle, Tensor};
+use crate::weights::{LayerWeight, NameTable, Weight, Weights};
+
+/// GPT-2, as named by HuggingFace transformers. Its `Conv1D` layers store
+/// matrices as `[in, out]`.
+pub static HF_NAMES: NameTable = NameTable {
+    global: &[
+        (Weight::TokEmbeddings, "wte.weight"),
+        (Weight::PosEmbeddings, "wpe.weight"),
+        (Weight::Norm, "ln_f.weight"),
+        (Weight::NormBias, "ln_f.bias"),
+    ],
+    layer: &[
+        (LayerWeight::AttnNorm, "h.{i}.ln_1.weight"),
+        (LayerWeight::AttnNormBias, "h.{i}.ln_1.bias"),
+        (LayerWeight::Wqkv, "h.{i}.attn.c_attn.weight"),
+        (LayerWeight::Bqkv, "h.{i}.attn.c_attn.bias"),
+        (LayerWeight::Wo, "h.{i}.attn.c_proj.weight"),
+        (LayerWeight::Bo, "h.{i}.attn.c_proj.bias"),
+        (LayerWeight::FfnNorm, "h.{i}.ln_2.weight"),
+        (LayerWeight::FfnNormBias, "h.{i}.ln_2.bias"),
+        (LayerWeight::W1, "h.{i}.mlp.c_fc.weight"),
+        (LayerWeight::B1, "h.{i}.mlp.c_fc.bias"),
+        (LayerWeight::W2, "h.{i}.mlp.c_proj.weight"),
+        (LayerWeight::B2, "h.{i}.mlp.c_proj.bias"),
+    ],
+    transposed: &[
+        LayerWeight::Wqkv,
+        LayerWeight::Wo,
+        LayerWeight::W1,
+        LayerWeight::W2,
+    ],
+    // The causal masks, saved as buffers.
+    ignored: &["h.{i}.attn.bias", "h.{i}.attn.masked_bias"],
+    rope_style: RopeStyle::NeoX,
+};
+
+/// GPT-2 with its language-modeling head, as `GPT2LMHeadModel` saves it:
+/// the same tensors under `transformer.`, with the tied head left out.
+pub static HF_LM_NAMES: NameTable = NameTable {
+    global: &[
+        (Weight::TokEmbeddings, "transformer.wte.weight"),
+        (Weight::PosEmbeddings, "transformer.wpe.weight"),
+        (Weight::Norm, "transformer.ln_f.weight"),
+        (Weight::NormBias, "transformer.ln_f.bias"),
+    ],
+    layer: &[
+        (LayerWeight::AttnNorm, "transformer.h.{i}.ln_1.weight"),
+        (LayerWeight::AttnNormBias, "transformer.h.{i}.ln_1.bias"),
+        (LayerWeight::Wqkv, "transformer.h.{i}.attn.c_attn.weight"),
+        (LayerWeight::Bqkv, "transformer.h.{i}.attn.c_attn.bias"),
+        (LayerWeight::Wo, "transformer.h.{i}.attn.c_proj.weight"),
+        (LayerWeight::Bo, "transformer.h.{i}.attn.c_proj.bias"),
+        (LayerWeight::FfnNorm, "transformer.h.{i}.ln_2.weight"),
+        (LayerWeight::FfnNormBias, "transformer.h.{i}.ln_2.bias"),
+        (LayerWeight::W1, "transformer.h.{i}.mlp.c_fc.weight"),
+        (LayerWeight::B1, "transformer.h.{i}.mlp.c_fc.bias"),
+        (LayerWeight::W2, "transformer.h.{i}.mlp.c_proj.weight"),
+        (LayerWeight::B2, "transformer.h.{i}.mlp.c_proj.bias"),
+    ],
+    transposed: &[
+        LayerWeight::Wqkv,
+        LayerWeight::Wo,
+        LayerWeight::W1,
+        LayerWeight::W2,
+    ],
+    ignored: &[
+        "transformer.h.{i}.attn.bias",
+        "transformer.h.{i}.attn.masked_bias",
+    ],
+    rope_style: RopeStyle::NeoX,
+};
+
+/// GPT-2, as named in GGUF files, with matrices stored as `[out, in]`.
+pub static GGUF_NAMES: NameTable = NameTable {
+    global: &[
+        (Weight::TokEmbeddings, "token_embd.weight"),
+        (Weight::PosEmbeddings, "position_embd.weight"),
+        (Weight::Norm, "output_norm.weight"),
+        (Weight::NormBias, "output_norm.bias"),
+    ],
+    layer: &[
+        (LayerWeight::AttnNorm, "blk.{i}.attn_norm.weight"),
+        (LayerWeight::AttnNormBias, "blk.{i}.attn_norm.bias"),
+        (LayerWeight::Wqkv, "blk.{i}.attn_qkv.weight"),
+        (LayerWeight::Bqkv, "blk.{i}.attn_qkv.bias"),
+        (LayerWeight::Wo, "blk.{i}.attn_output.weight"),
+        (LayerWeight::Bo, "blk.{i}.attn_output.bias"),
+        (LayerWeight::FfnNorm, "blk.{i}.ffn_norm.weight"),
+        (LayerWeight::FfnNormBias, "blk.{i}.ffn_norm.bias"),
+        (LayerWeight::W1, "blk.{i}.ffn_up.weight"),
+        (LayerWeight::B1, "blk.{i}.ffn_up.bias"),
+        (LayerWeight::W2, "blk.{i}.ffn_down.weight"),
+        (LayerWeight::B2, "blk.{i}.ffn_down.bias"),
+    ],
+    transposed: &[],
+    ignored: &[],
+    rope_style: RopeStyle::NeoX,
+};
+
+pub static NAMES: &[&NameTable] = &[&HF_NAMES, &HF_LM_NAMES, &GGUF_NAMES];
+
+struct Layer {
+    ln_1: LayerNorm,
+    c_attn: Linear,
+    c_proj: Linear,
+
+    ln_2: LayerNorm,
+    c_fc: Linear,
+    mlp_proj: Linear,
+}
+
+pub struct Gpt2 {
+    hparams: HParams,
+
+    wte: Tensor<f16, 2>,
+    wpe: Tensor<f16, 2>,
+    ln_f: LayerNorm,
+
+    layers: Vec<Layer>,
+}
+
+impl Gpt2 {
+    pub fn new(hparams: HParams, mut weights: Weights) -> Self {
+        let layers = (0..hparams.n_layers)
+            .map(|i| {
+                let w = |w| Weight::Layer(i, w);
+                Layer {
+                    ln_1: LayerNorm::take(
+                        &mut weights,
+                        w(LayerWeight::AttnNorm),
+                        w(LayerWeight::AttnNormBias),
+                    ),
+                    c_attn: weights.take_linear(w(LayerWeight::Wqkv), Some(w(LayerWeight::Bqkv))),
+                    c_proj: weights.take_linear(w(LayerWeight::Wo), Some(w(LayerWeight::Bo))),
+                    ln_2: LayerNorm::take(
+                        &mut weights,
+                        w(LayerWeight::FfnNorm),
+                        w(LayerWeight::FfnNormBias),
+                    ),
+                    c_fc: weights.take_linear(w(LayerWeight::W1), Some(w(LayerWeight::B1))),
+                    mlp_proj: weights.take_linear(w(LayerWeight::W2), Some(w(LayerWeight::B2))),
+                }
+            })
+            .collect();
+
+        Self {
+            wte: weights.take_f16(Weight::TokEmbeddings),
+            wpe: weights.take_f16(Weight::PosEmbeddings),
+            ln_f: LayerNorm::take(&mut weights, Weight::Norm, Weight::NormBias),
+            layers,
+            hparams,
+        }
+    }
+}
+
+impl Model for Gpt2 {
+    fn hparams(&self) -> &HParams {
+        &self.hparams
+    }
+
+    fn forward(&self, tokens: &Tensor<usize, 1>, scratch: &mut Scratch) -> Tensor<f16, 2> {
+        let hp = &self.hparams;
+        let [n] = tokens.shape();
+        let dim = hp.dim;
+        let past = past_len(scratch);
+        assert!(
+            past + n <= hp.n_ctx,
+            "GPT-2 has no position embedding past {}",
+            hp.n_ctx
+        );
+        let mut x = scratch.f16.take([n, dim]);
+        self.wte.get_rows_into(tokens, &mut x);
+        let mut pos = scratch.f16.take([n, dim]);
+        pos.make_mut()
+            .copy_from_slice(&self.wpe.as_slice()[past * dim..(past + n) * dim]);
+        x.add_inplace(&pos);
+        scratch.f16.recycle(pos);
+        let attn_shape = Attention {
+            n_heads: hp.n_heads,
+            n_kv_heads: hp.n_heads,
+            window: None,
+            past,
+        };
+
+        for (i, layer) in self.layers.iter().enumerate() {
+            let mut h = scratch.f16.take([n, dim]);
+            layer.ln_1.forward_into(&x, hp.norm_eps, &mut h);
+
+            let mut qkv = scratch.f16.take([n, 3 * dim]);
+            layer.c_attn.matmul_into(&h, &mut qkv, scratch);
+            let [q, k, v] = split_qkv(&qkv, scratch);
+            let mut attn = scratch.f16.take([n, dim]);
+            causal_attn_into(i, &q, &k, &v, attn_shape, &mut attn, scratch);
+            layer.c_proj.matmul_into(&attn, &mut h, scratch);
+            x.add_inplace(&h);
+
+            layer.ln_2.forward_into(&x, hp.norm_eps, &mut h);
+            let mut ff = scratch.f16.take([n, hp.n_ff]);
+            layer.c_fc.matmul_into(&h, &mut ff, scratch);
+            ff.gelu_inplace();
+            layer.mlp_proj.matmul_into(&ff, &mut h, scratch);
+            x.add_inplace(&h);
+
+            for t in [h, qkv, q, k, v, attn, ff] {
+                scratch.f16.recycle(t);
+            }
+        }
+
+        let mut h = scratch.f16.take([n, dim]);
+        self.ln_f.forward_into(&x, hp.norm_eps, &mut h);
+        let mut logits = scratch.f16.take([n, hp.vocab_size]);
+        self.wte.matmul_into(&h, &mut logits);
+        scratch.f16.recycle(h);
+        scratch.f16.recycle(x);
+        advance(scratch, n);
+
+        logits
+    }
+}
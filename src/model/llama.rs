@@ -0,0 +1,233 @@
+//! LLaMA, and Mistral, which adds grouped-query attention over a sliding
+//! window.
+
+use half::f16;
+
+use super::{advance, causal_attn_into, past_len, Linear, Model};
+use crate::ggml::HParams;
+use crate::scratch::Scratch;
+use crate::tensor::{Attention, RopeStyle, Tensor};
+use crate::weights::{LayerWeight, NameTable, Weight, Weights};
+
+/// LLaMA, as named by the original GGML conversion script.
+pub static GGML_NAMES: NameTable = NameTable {
+    global: &[
+        (Weight::TokEmbeddings, "tok_embeddings.weight"),
+        (Weight::Norm, "norm.weight"),
+        (Weight::Output, "output.weight"),
+    ],
+    layer: &[
+        (LayerWeight::AttnNorm, "layers.{i}.attention_norm.weight"),
+        (LayerWeight::Wq, "layers.{i}.attention.wq.weight"),
+        (LayerWeight::Wk, "layers.{i}.attention.wk.weight"),
+        (LayerWeight::Wv, "layers.{i}.attention.wv.weight"),
+        (LayerWeight::Wo, "layers.{i}.attention.wo.weight"),
+        (LayerWeight::FfnNorm, "layers.{i}.ffn_norm.weight"),
+        (LayerWeight::W1, "layers.{i}.feed_forward.w1.weight"),
+        (LayerWeight::W2, "layers.{i}.feed_forward.w2.weight"),
+        (LayerWeight::W3, "layers.{i}.feed_forward.w3.weight"),
+    ],
+    transposed: &[],
+    ignored: &[],
+    rope_style: RopeStyle::Interleaved,
+};
+
+/// LLaMA and Mistral, as named by HuggingFace transformers, which permutes
+/// the query and key projections for NeoX-style rotary embeddings.
+pub static HF_NAMES: NameTable = NameTable {
+    global: &[
+        (Weight::TokEmbeddings, "model.embed_tokens.weight"),
+        (Weight::Norm, "model.norm.weight"),
+        (Weight::Output, "lm_head.weight"),
+    ],
+    layer: &[
+        (
+            LayerWeight::AttnNorm,
+            "model.layers.{i}.input_layernorm.weight",
+        ),
+        (LayerWeight::Wq, "model.layers.{i}.self_attn.q_proj.weight"),
+        (LayerWeight::Wk, "model.layers.{i}.self_attn.k_proj.weight"),
+        (LayerWeight::Wv, "model.layers.{i}.self_attn.v_proj.weight"),
+        (LayerWeight::Wo, "model.layers.{i}.self_attn.o_proj.weight"),
+        (
+            LayerWeight::FfnNorm,
+            "model.layers.{i}.post_attention_layernorm.weight",
+        ),
+        (LayerWeight::W1, "model.layers.{i}.mlp.gate_proj.weight"),
+        (LayerWeight::W2, "model.layers.{i}.mlp.down_proj.weight"),
+        (LayerWeight::W3, "model.layers.{i}.mlp.up_proj.weight"),
+    ],
+    transposed: &[],
+    ignored: &["model.layers.{i}.self_attn.rotary_emb.inv_freq"],
+    rope_style: RopeStyle::NeoX,
+};
+
+/// LLaMA and Mistral, as named in GGUF files, with the query and key
+/// projections permuted back for interleaved rotary embeddings.
+pub static GGUF_NAMES: NameTable = NameTable {
+    global: &[
+        (Weight::TokEmbeddings, "token_embd.weight"),
+        (Weight::Norm, "output_norm.weight"),
+        (Weight::Output, "output.weight"),
+    ],
+    layer: &[
+        (LayerWeight::AttnNorm, "blk.{i}.attn_norm.weight"),
+        (LayerWeight::Wq, "blk.{i}.attn_q.weight"),
+        (LayerWeight::Wk, "blk.{i}.attn_k.weight"),
+        (LayerWeight::Wv, "blk.{i}.attn_v.weight"),
+        (LayerWeight::Wo, "blk.{i}.attn_output.weight"),
+        (LayerWeight::FfnNorm, "blk.{i}.ffn_norm.weight"),
+        (LayerWeight::W1, "blk.{i}.ffn_gate.weight"),
+        (LayerWeight::W2, "blk.{i}.ffn_down.weight"),
+        (LayerWeight::W3, "blk.{i}.ffn_up.weight"),
+    ],
+    transposed: &[],
+    ignored: &[],
+    rope_style: RopeStyle::Interleaved,
+};
+
+pub static NAMES: &[&NameTable] = &[&GGML_NAMES, &HF_NAMES, &GGUF_NAMES];
+
+struct Layer {
+    attn_norm: Tensor<f32, 1>,
+
+    wq: Linear,
+    wk: Linear,
+    wv: Linear,
+    wo: Linear,
+
+    ffn_norm: Tensor<f32, 1>,
+
+    w1: Linear,
+    w2: Linear,
+    w3: Linear,
+}
+
+pub struct Llama {
+    hparams: HParams,
+    rope_style: RopeStyle,
+
+    tok_embeddings: Tensor<f16, 2>,
+    norm: Tensor<f32, 1>,
+    output: Linear,
+
+    layers: Vec<Layer>,
+}
+
+impl Llama {
+    pub fn new(hparams: HParams, mut weights: Weights) -> Self {
+        let layers = (0..hparams.n_layers)
+            .map(|i| {
+                let mut linear = |w| weights.take_linear(Weight::Layer(i, w), None);
+                let (wq, wk, wv, wo) = (
+                    linear(LayerWeight::Wq),
+                    linear(LayerWeight::Wk),
+                    linear(LayerWeight::Wv),
+                    linear(LayerWeight::Wo),
+                );
+                let (w1, w2, w3) = (
+                    linear(LayerWeight::W1),
+                    linear(LayerWeight::W2),
+                    linear(LayerWeight::W3),
+                );
+
+                Layer {
+                    attn_norm: weights.take_f32(Weight::Layer(i, LayerWeight::AttnNorm)),
+                    wq,
+                    wk,
+                    wv,
+                    wo,
+                    ffn_norm: weights.take_f32(Weight::Layer(i, LayerWeight::FfnNorm)),
+                    w1,
+                    w2,
+                    w3,
+                }
+            })
+            .collect();
+
+        Self {
+            rope_style: weights.table().rope_style,
+            // Embeddings are looked up rather than multiplied, so their LoRA
+            // update is always merged.
+            tok_embeddings: weights.take_f16(Weight::TokEmbeddings),
+            norm: weights.take_f32(Weight::Norm),
+            output: weights.take_linear(Weight::Output, None),
+            layers,
+            hparams,
+        }
+    }
+}
+
+impl Model for Llama {
+    fn hparams(&self) -> &HParams {
+        &self.hparams
+    }
+
+    fn forward(&self, tokens: &Tensor<usize, 1>, scratch: &mut Scratch) -> Tensor<f16, 2> {
+        let hp = &self.hparams;
+        let [n] = tokens.shape();
+        let dim = hp.dim;
+        let mut x = scratch.f16.take([n, dim]);
+        self.tok_embeddings.get_rows_into(tokens, &mut x);
+        let head_dim = hp.head_dim();
+        let kv_dim = hp.n_kv_heads * head_dim;
+        let past = past_len(scratch);
+        let attn_shape = Attention {
+            n_heads: hp.n_heads,
+            n_kv_heads: hp.n_kv_heads,
+            window: hp.sliding_window,
+            past,
+        };
+
+        for (i, layer) in self.layers.iter().enumerate() {
+            let mut h = scratch.f16.take([n, dim]);
+            x.rms_norm_scaled_into(&layer.attn_norm, hp.norm_eps, &mut h);
+
+            let mut q = scratch.f16.take([n, dim]);
+            layer.wq.matmul_into(&h, &mut q, scratch);
+            let mut k = scratch.f16.take([n, kv_dim]);
+            layer.wk.matmul_into(&h, &mut k, scratch);
+            let mut v = scratch.f16.take([n, kv_dim]);
+            layer.wv.matmul_into(&h, &mut v, scratch);
+            q.rope_inplace(past, hp.n_heads, head_dim, hp.rope_theta, self.rope_style);
+            k.rope_inplace(
+                past,
+                hp.n_kv_heads,
+                head_dim,
+                hp.rope_theta,
+                self.rope_style,
+            );
+
+            let mut attn = scratch.f16.take([n, dim]);
+            causal_attn_into(i, &q, &k, &v, attn_shape, &mut attn, scratch);
+            layer.wo.matmul_into(&attn, &mut h, scratch);
+            x.add_inplace(&h);
+
+            // SwiGLU: `w2 · (silu(w1 · h) * (w3 · h))`.
+            x.rms_norm_scaled_into(&layer.ffn_norm, hp.norm_eps, &mut h);
+            let mut gate = scratch.f16.take([n, hp.n_ff]);
+            layer.w1.matmul_into(&h, &mut gate, scratch);
+            gate.silu_inplace();
+            let mut up = scratch.f16.take([n, hp.n_ff]);
+            layer.w3.matmul_into(&h, &mut up, scratch);
+            gate.mul_inplace(&up);
+            layer.w2.matmul_into(&gate, &mut h, scratch);
+            x.add_inplace(&h);
+
+            // Hand every intermediate back, so the next layer reuses them.
+            for t in [h, q, k, v, attn, gate, up] {
+                scratch.f16.recycle(t);
+            }
+        }
+
+        let mut h = scratch.f16.take([n, dim]);
+        x.rms_norm_scaled_into(&self.norm, hp.norm_eps, &mut h);
+        let mut logits = scratch.f16.take([n, hp.vocab_size]);
+        self.output.matmul_into(&h, &mut logits, scratch);
+        scratch.f16.recycle(h);
+        scratch.f16.recycle(x);
+        advance(scratch, n);
+
+        logits
+    }
+}
@@ -0,0 +1,495 @@
+//! Inference for the transformer architectures the loaders understand.
+
+use half::f16;
+
+use crate::ggml::HParams;
+use crate::lora::LoraWeight;
+use crate::scratch::Scratch;
+use crate::tensor::{Attention, Tensor};
+use crate::weights::{NameTable, Weight, Weights};
+
+pub mod gpt2;
+pub mod llama;
+pub mod neox;
+
+/// A model ready to run.
+pub trait Model: Send + Sync {
+    fn hparams(&self) -> &HParams;
+
+    /// The `[n, vocab_size]` logits for the token following each of the `n`
+    /// positions of `tokens`. With a [`KvCache`] in `scratch`, `tokens`
+    /// continue the positions in the cache, and are added to it. The logits
+    /// come from `scratch`, so handing them back once they're read lets the
+    /// next call reuse them.
+    fn forward(&self, tokens: &Tensor<usize, 1>, scratch: &mut Scratch) -> Tensor<f16, 2>;
+}
+
+/// The keys and values of every layer for the positions a model has already
+/// seen, so that decoding a token only runs the new position through it.
+pub struct KvCache {
+    /// `[n_ctx, n_kv_heads * head_dim]` for each layer.
+    k: Vec<Tensor<f16, 2>>,
+    v: Vec<Tensor<f16, 2>>,
+    len: usize,
+}
+
+impl KvCache {
+    /// An empty cache with room for `hparams.n_ctx` positions.
+    pub fn new(hparams: &HParams) -> Self {
+        let shape = [hparams.n_ctx, hparams.n_kv_heads * hparams.head_dim()];
+        Self {
+            k: (0..hparams.n_layers)
+                .map(|_| Tensor::zeros(shape))
+                .collect(),
+            v: (0..hparams.n_layers)
+                .map(|_| Tensor::zeros(shape))
+                .collect(),
+            len: 0,
+        }
+    }
+
+    /// The number of positions in the cache.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Forgets every position, to start a new sequence.
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+}
+
+/// The transformer variants there is a [`Model`] for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Architecture {
+    Llama,
+    /// LLaMA with grouped-query attention over a sliding window.
+    Mistral,
+    /// LayerNorm, learned position embeddings and a GELU feed-forward block.
+    Gpt2,
+    /// GPT-2's blocks with partial rotary embeddings and parallel residuals.
+    GptNeoX,
+}
+
+impl Architecture {
+    /// The architecture a HuggingFace config's `model_type` names.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "llama" => Some(Self::Llama),
+            "mistral" => Some(Self::Mistral),
+            "gpt2" => Some(Self::Gpt2),
+            "gpt_neox" => Some(Self::GptNeoX),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Llama => "llama",
+            Self::Mistral => "mistral",
+            Self::Gpt2 => "gpt2",
+            Self::GptNeoX => "gpt_neox",
+        }
+    }
+
+    /// The ways checkpoints of this architecture name their tensors.
+    pub fn name_tables(self) -> &'static [&'static NameTable] {
+        match self {
+            Self::Llama | Self::Mistral => llama::NAMES,
+            Self::Gpt2 => gpt2::NAMES,
+            Self::GptNeoX => neox::NAMES,
+        }
+    }
+
+    /// Builds the model from weights mapped with one of
+    /// [`Architecture::name_tables`].
+    pub fn build(self, hparams: HParams, weights: Weights) -> Box<dyn Model> {
+        assert_eq!(hparams.arch, self);
+        match self {
+            Self::Llama | Self::Mistral => Box::new(llama::Llama::new(hparams, weights)),
+            Self::Gpt2 => Box::new(gpt2::Gpt2::new(hparams, weights)),
+            Self::GptNeoX => Box::new(neox::GptNeoX::new(hparams, weights)),
+        }
+    }
+}
+
+/// A weight matrix with an optional bias, along with a LoRA update to apply
+/// on the fly, if any.
+pub struct Linear {
+    /// Which of the model's weights `w` is.
+    pub weight: Weight,
+    pub w: Tensor<f16, 2>,
+    pub bias: Option<Tensor<f32, 1>>,
+    pub lora: Option<LoraWeight>,
+}
+
+impl Linear {
+    /// Like [`Tensor::<f16, 2>::matmul_into`], adding the bias to each row.
+    /// Records `x` if `scratch` is collecting activations.
+    pub fn matmul_into(&self, x: &Tensor<f16, 2>, out: &mut Tensor<f16, 2>, scratch: &mut Scratch) {
+        if let Some(stats) = &mut scratch.activations {
+            stats.record(self.weight, x);
+        }
+        match &self.lora {
+            Some(lora) => lora.matmul_into(&self.w, x, out, scratch),
+            None => self.w.matmul_into(x, out),
+        }
+        if let Some(bias) = &self.bias {
+            out.add_row_inplace(bias);
+        }
+    }
+}
+
+/// A LayerNorm's scale and shift.
+struct LayerNorm {
+    w: Tensor<f32, 1>,
+    b: Tensor<f32, 1>,
+}
+
+impl LayerNorm {
+    fn take(weights: &mut Weights, w: Weight, b: Weight) -> Self {
+        Self {
+            w: weights.take_f32(w),
+            b: weights.take_f32(b),
+        }
+    }
+
+    fn forward_into(&self, x: &Tensor<f16, 2>, eps: f32, out: &mut Tensor<f16, 2>) {
+        x.layer_norm_into(&self.w, &self.b, eps, out);
+    }
+}
+
+/// Splits the `[n, 3 * dim]` output of a fused query, key and value
+/// projection, returning scratch tensors.
+fn split_qkv(qkv: &Tensor<f16, 2>, scratch: &mut Scratch) -> [Tensor<f16, 2>; 3] {
+    let [n, cols] = qkv.shape();
+    let dim = cols / 3;
+    [0, 1, 2].map(|i| {
+        let mut x = scratch.f16.take([n, dim]);
+        qkv.columns_into(i * dim, &mut x);
+        x
+    })
+}
+
+/// The number of positions before `tokens` in a forward pass.
+fn past_len(scratch: &Scratch) -> usize {
+    scratch.kv_cache.as_ref().map_or(0, KvCache::len)
+}
+
+/// [`Tensor::causal_attn_into`] for layer `layer`. With a [`KvCache`] in
+/// `scratch`, `k` and `v` are written to it after the `attn.past` positions
+/// already there, and the queries attend to all of them.
+fn causal_attn_into(
+    layer: usize,
+    q: &Tensor<f16, 2>,
+    k: &Tensor<f16, 2>,
+    v: &Tensor<f16, 2>,
+    attn: Attention,
+    out: &mut Tensor<f16, 2>,
+    scratch: &mut Scratch,
+) {
+    match &mut scratch.kv_cache {
+        None => q.causal_attn_into(k, v, attn, out, &mut scratch.f32),
+        Some(cache) => {
+            let [n, kv_dim] = k.shape();
+            let rows = attn.past * kv_dim..(attn.past + n) * kv_dim;
+            assert!(
+                rows.end <= cache.k[layer].as_slice().len(),
+                "the context is full"
+            );
+            cache.k[layer].make_mut()[rows.clone()].copy_from_slice(k.as_slice());
+            cache.v[layer].make_mut()[rows].copy_from_slice(v.as_slice());
+            q.causal_attn_into(
+                &cache.k[layer],
+                &cache.v[layer],
+                attn,
+                out,
+                &mut scratch.f32,
+            );
+        }
+    }
+}
+
+/// Counts the `n` positions a forward pass has added to the cache, if any.
+fn advance(scratch: &mut Scratch, n: usize) {
+    if let Some(cache) = &mut scratch.kv_cache {
+        cache.len += n;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::buffer::AlignedBuf;
+    use crate::ggml::{Data, ScalarType, Var};
+    use crate::imatrix::ActivationStats;
+    use crate::rng::Rng;
+
+    /// Random tensors for every weight `table` names, with norms around one.
+    fn random_vars(hparams: &HParams, table: &NameTable) -> HashMap<String, Var> {
+        let mut rng = Rng(0x2545f4914f6cdd1d);
+
+        table
+            .weights(hparams.n_layers)
+            .into_iter()
+            .map(|w| {
+                let mut dims = w.shape(hparams);
+                if matches!(w, Weight::Layer(_, w) if table.transposed.contains(&w)) {
+                    dims.reverse();
+                }
+                let len = dims.iter().product();
+                let data = match w.scalar_type(hparams) {
+                    ScalarType::F32 => Data::F32(AlignedBuf::from_slice(
+                        &(0..len)
+                            .map(|_| 1.0 + 0.1 * rng.float())
+                            .collect::<Vec<_>>(),
+                    )),
+                    _ => Data::F16(AlignedBuf::from_slice(
+                        &(0..len)
+                            .map(|_| f16::from_f32(0.5 * rng.float()))
+                            .collect::<Vec<_>>(),
+                    )),
+                };
+                let name = table.name(w).unwrap();
+                (name.clone(), Var { name, dims, data })
+            })
+            .collect()
+    }
+
+    fn random_model(hparams: HParams, table: &'static NameTable) -> Box<dyn Model> {
+        let weights = Weights::map(random_vars(&hparams, table), &hparams, table).unwrap();
+        hparams.arch.build(hparams, weights)
+    }
+
+    fn hparams(arch: Architecture) -> HParams {
+        HParams {
+            arch,
+            n_kv_heads: 1,
+            n_ctx: 16,
+            rotary_pct: 0.5,
+            ..HParams::llama(12, 8, 4, 2, 2)
+        }
+    }
+
+    #[test]
+    fn every_architecture_is_causal() {
+        let tokens = Tensor::from(vec![1, 5, 2, 11, 0, 3]);
+        let prefix = Tensor::from(vec![1, 5, 2]);
+
+        for arch in [
+            Architecture::Llama,
+            Architecture::Mistral,
+            Architecture::Gpt2,
+            Architecture::GptNeoX,
+        ] {
+            let mut hparams = hparams(arch);
+            if arch != Architecture::Llama && arch != Architecture::Mistral {
+                hparams.n_kv_heads = hparams.n_heads;
+            }
+            for &table in arch.name_tables() {
+                let model = random_model(hparams.clone(), table);
+                let mut scratch = Scratch::new();
+                let logits = model.forward(&tokens, &mut scratch);
+                assert_eq!(logits.shape(), [6, 12], "{arch:?}");
+
+                // Later tokens don't change the logits of earlier ones.
+                let logits = logits.to_vec();
+                let prefix = model.forward(&prefix, &mut scratch).to_vec();
+                assert_eq!(logits[..prefix.len()], prefix, "{arch:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn records_the_input_of_every_matrix() {
+        let tokens = Tensor::from(vec![1, 5, 2, 11]);
+        for arch in [
+            Architecture::Llama,
+            Architecture::Gpt2,
+            Architecture::GptNeoX,
+        ] {
+            let hparams = HParams {
+                n_kv_heads: 2,
+                ..hparams(arch)
+            };
+            let table = arch.name_tables()[0];
+            let model = random_model(hparams.clone(), table);
+            let mut scratch = Scratch {
+                activations: Some(ActivationStats::new()),
+                ..Scratch::new()
+            };
+            model.forward(&tokens, &mut scratch);
+            let imatrix = scratch.activations.unwrap().to_imatrix(table, 1, "");
+
+            // Everything but the embeddings, which are looked up rather than
+            // multiplied.
+            let matrices = table
+                .weights(hparams.n_layers)
+                .into_iter()
+                .filter(|w| {
+                    w.shape(&hparams).len() == 2
+                        && !matches!(w, Weight::TokEmbeddings | Weight::PosEmbeddings)
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(imatrix.importance.len(), matrices.len(), "{arch:?}");
+            for w in matrices {
+                let importance = &imatrix.importance[&table.name(w).unwrap()];
+                assert_eq!(importance.len(), w.shape(&hparams)[1], "{w:?}");
+                assert!(importance.iter().all(|&x| x > 0.0), "{w:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn sliding_window() {
+        let hparams = HParams {
+            n_layers: 1,
+            sliding_window: Some(2),
+            ..hparams(Architecture::Mistral)
+        };
+        let model = random_model(hparams, &llama::HF_NAMES);
+        let mut scratch = Scratch::new();
+
+        // With one layer, position 3 only sees positions 2 and 3.
+        let a = model.forward(&Tensor::from(vec![1, 5, 2, 11]), &mut scratch);
+        let b = model.forward(&Tensor::from(vec![7, 5, 2, 11]), &mut scratch);
+        let c = model.forward(&Tensor::from(vec![1, 5, 3, 11]), &mut scratch);
+        assert_eq!(a.to_vec()[36..], b.to_vec()[36..]);
+        assert_ne!(a.to_vec()[36..], c.to_vec()[36..]);
+    }
+
+    #[test]
+    fn steady_state_does_not_allocate() {
+        let model = random_model(hparams(Architecture::Llama), &llama::HF_NAMES);
+        let tokens = Tensor::from(vec![1, 5, 2, 11]);
+        let mut scratch = Scratch::new();
+
+        let logits = model.forward(&tokens, &mut scratch);
+        let buffer = logits.as_slice().as_ptr();
+        scratch.f16.recycle(logits);
+        let allocations = scratch.allocations();
+
+        // The second pass is served from the pool, down to its logits.
+        let logits = model.forward(&tokens, &mut scratch);
+        assert_eq!(logits.as_slice().as_ptr(), buffer);
+        scratch.f16.recycle(logits);
+        assert_eq!(scratch.allocations(), allocations);
+    }
+
+    #[test]
+    fn decodes_with_a_kv_cache() {
+        let tokens = [1, 5, 2, 11, 0, 3, 7];
+        for arch in [
+            Architecture::Llama,
+            Architecture::Mistral,
+            Architecture::Gpt2,
+            Architecture::GptNeoX,
+        ] {
+            let mut hparams = HParams {
+                sliding_window: Some(3),
+                ..hparams(arch)
+            };
+            if arch != Architecture::Llama && arch != Architecture::Mistral {
+                hparams.n_kv_heads = hparams.n_heads;
+            }
+            let model = random_model(hparams.clone(), arch.name_tables()[0]);
+            let expected = model
+                .forward(&Tensor::from(tokens.to_vec()), &mut Scratch::new())
+                .to_vec();
+
+            // The prompt at once, then a token at a time.
+            let mut scratch = Scratch {
+                kv_cache: Some(KvCache::new(&hparams)),
+                ..Scratch::new()
+            };
+            let mut logits = model
+                .forward(&Tensor::from(tokens[..3].to_vec()), &mut scratch)
+                .to_vec();
+            let mut allocations = 0;
+            for (i, &token) in tokens.iter().enumerate().skip(3) {
+                let next = model.forward(&Tensor::from(vec![token]), &mut scratch);
+                logits.extend(next.iter());
+                scratch.f16.recycle(next);
+                if i > 3 {
+                    assert_eq!(scratch.allocations(), allocations, "{arch:?}");
+                }
+                allocations = scratch.allocations();
+            }
+            assert_eq!(scratch.kv_cache.as_ref().unwrap().len(), tokens.len());
+
+            for (x, y) in logits.into_iter().zip(expected) {
+                assert!(
+                    (x.to_f32() - y.to_f32()).abs() < 1e-2,
+                    "{arch:?}: {x} != {y}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn converts_between_tables() {
+        let tokens = Tensor::from(vec![1, 5, 2, 11, 0, 3]);
+        // LLaMA rotates whole heads.
+        let hparams = HParams {
+            rotary_pct: 1.0,
+            ..hparams(Architecture::Llama)
+        };
+        let hf = Weights::map(
+            random_vars(&hparams, &llama::HF_NAMES),
+            &hparams,
+            &llama::HF_NAMES,
+        )
+        .unwrap();
+        let expected = hparams
+            .arch
+            .build(hparams.clone(), hf)
+            .forward(&tokens, &mut Scratch::new());
+
+        // Through GGUF's names and interleaved rotary embeddings, and back.
+        let chains: [&[&'static NameTable]; 3] = [
+            &[&llama::GGUF_NAMES],
+            &[&llama::GGML_NAMES, &llama::GGUF_NAMES],
+            &[&llama::GGUF_NAMES, &llama::HF_NAMES],
+        ];
+        for chain in chains {
+            let mut table = &llama::HF_NAMES;
+            let mut vars = random_vars(&hparams, table);
+            for &target in chain {
+                vars = Weights::map(vars, &hparams, table)
+                    .unwrap()
+                    .into_vars(target, &hparams);
+                table = target;
+            }
+
+            let weights = Weights::map(vars, &hparams, table).unwrap();
+            let logits = hparams
+                .arch
+                .build(hparams.clone(), weights)
+                .forward(&tokens, &mut Scratch::new());
+            for (x, y) in logits.to_vec().into_iter().zip(expected.to_vec()) {
+                assert!((x.to_f32() - y.to_f32()).abs() < 1e-2, "{x} != {y}");
+            }
+        }
+
+        // GPT-2's matrices are transposed back.
+        let hparams = HParams {
+            n_kv_heads: 2,
+            ..self::hparams(Architecture::Gpt2)
+        };
+        let vars = random_vars(&hparams, &gpt2::HF_NAMES);
+        let c_attn = vars["h.0.attn.c_attn.weight"].data.as_bytes().to_vec();
+        let vars = Weights::map(vars, &hparams, &gpt2::HF_NAMES)
+            .unwrap()
+            .into_vars(&gpt2::GGUF_NAMES, &hparams);
+        let vars = Weights::map(vars, &hparams, &gpt2::GGUF_NAMES)
+            .unwrap()
+            .into_vars(&gpt2::HF_NAMES, &hparams);
+        assert_eq!(vars["h.0.attn.c_attn.weight"].data.as_bytes(), c_attn);
+    }
+}
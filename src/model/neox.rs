@@ -0,0 +1,257 @@
+//! GPT-NeoX, as used by Pythia: GPT-2's blocks with rotary embeddings over
+//! part of each head, and attention and the feed-forward block computed in
+//! parallel from the same residual.
+
+use half::f16;
+
+use super::{advance, causal_attn_into, past_len, split_qkv, LayerNorm, Linear, Model};
+use crate::ggml::HParams;
+use crate::scratch::Scratch;
+use crate::tensor::{Attention, RopeStyle, Tensor, TensorElement};
+use crate::weights::{LayerWeight, NameTable, Weight, Weights};
+
+/// GPT-NeoX, as named by HuggingFace transformers.
+pub static HF_NAMES: NameTable = NameTable {
+    global: &[
+        (Weight::TokEmbeddings, "gpt_neox.embed_in.weight"),
+        (Weight::Norm, "gpt_neox.final_layer_norm.weight"),
+        (Weight::NormBias, "gpt_neox.final_layer_norm.bias"),
+        (Weight::Output, "embed_out.weight"),
+    ],
+    layer: &[
+        (
+            LayerWeight::AttnNorm,
+            "gpt_neox.layers.{i}.input_layernorm.weight",
+        ),
+        (
+            LayerWeight::AttnNormBias,
+            "gpt_neox.layers.{i}.input_layernorm.bias",
+        ),
+        (
+            LayerWeight::Wqkv,
+            "gpt_neox.layers.{i}.attention.query_key_value.weight",
+        ),
+        (
+            LayerWeight::Bqkv,
+            "gpt_neox.layers.{i}.attention.query_key_value.bias",
+        ),
+        (
+            LayerWeight::Wo,
+            "gpt_neox.layers.{i}.attention.dense.weight",
+        ),
+        (LayerWeight::Bo, "gpt_neox.layers.{i}.attention.dense.bias"),
+        (
+            LayerWeight::FfnNorm,
+            "gpt_neox.layers.{i}.post_attention_layernorm.weight",
+        ),
+        (
+            LayerWeight::FfnNormBias,
+            "gpt_neox.layers.{i}.post_attention_layernorm.bias",
+        ),
+        (
+            LayerWeight::W1,
+            "gpt_neox.layers.{i}.mlp.dense_h_to_4h.weight",
+        ),
+        (
+            LayerWeight::B1,
+            "gpt_neox.layers.{i}.mlp.dense_h_to_4h.bias",
+        ),
+        (
+            LayerWeight::W2,
+            "gpt_neox.layers.{i}.mlp.dense_4h_to_h.weight",
+        ),
+        (
+            LayerWeight::B2,
+            "gpt_neox.layers.{i}.mlp.dense_4h_to_h.bias",
+        ),
+    ],
+    transposed: &[],
+    // The causal masks and rotary frequencies, saved as buffers.
+    ignored: &[
+        "gpt_neox.layers.{i}.attention.bias",
+        "gpt_neox.layers.{i}.attention.masked_bias",
+        "gpt_neox.layers.{i}.attention.rotary_emb.inv_freq",
+    ],
+    rope_style: RopeStyle::NeoX,
+};
+
+/// GPT-NeoX, as named in GGUF files.
+pub static GGUF_NAMES: NameTable = NameTable {
+    global: &[
+        (Weight::TokEmbeddings, "token_embd.weight"),
+        (Weight::Norm, "output_norm.weight"),
+        (Weight::NormBias, "output_norm.bias"),
+        (Weight::Output, "output.weight"),
+    ],
+    layer: &[
+        (LayerWeight::AttnNorm, "blk.{i}.attn_norm.weight"),
+        (LayerWeight::AttnNormBias, "blk.{i}.attn_norm.bias"),
+        (LayerWeight::Wqkv, "blk.{i}.attn_qkv.weight"),
+        (LayerWeight::Bqkv, "blk.{i}.attn_qkv.bias"),
+        (LayerWeight::Wo, "blk.{i}.attn_output.weight"),
+        (LayerWeight::Bo, "blk.{i}.attn_output.bias"),
+        (LayerWeight::FfnNorm, "blk.{i}.ffn_norm.weight"),
+        (LayerWeight::FfnNormBias, "blk.{i}.ffn_norm.bias"),
+        (LayerWeight::W1, "blk.{i}.ffn_up.weight"),
+        (LayerWeight::B1, "blk.{i}.ffn_up.bias"),
+        (LayerWeight::W2, "blk.{i}.ffn_down.weight"),
+        (LayerWeight::B2, "blk.{i}.ffn_down.bias"),
+    ],
+    transposed: &[],
+    ignored: &[],
+    rope_style: RopeStyle::NeoX,
+};
+
+pub static NAMES: &[&NameTable] = &[&HF_NAMES, &GGUF_NAMES];
+
+struct Layer {
+    input_layernorm: LayerNorm,
+    query_key_value: Linear,
+    dense: Linear,
+
+    post_attention_layernorm: LayerNorm,
+    dense_h_to_4h: Linear,
+    dense_4h_to_h: Linear,
+}
+
+pub struct GptNeoX {
+    hparams: HParams,
+
+    embed_in: Tensor<f16, 2>,
+    final_layer_norm: LayerNorm,
+    embed_out: Linear,
+
+    layers: Vec<Layer>,
+}
+
+impl GptNeoX {
+    pub fn new(hparams: HParams, mut weights: Weights) -> Self {
+        let layers = (0..hparams.n_layers)
+            .map(|i| {
+                let w = |w| Weight::Layer(i, w);
+                let qkv = weights.take_linear(w(LayerWeight::Wqkv), Some(w(LayerWeight::Bqkv)));
+                Layer {
+                    input_layernorm: LayerNorm::take(
+                        &mut weights,
+                        w(LayerWeight::AttnNorm),
+                        w(LayerWeight::AttnNormBias),
+                    ),
+                    query_key_value: group_qkv(qkv, hparams.n_heads),
+                    dense: weights.take_linear(w(LayerWeight::Wo), Some(w(LayerWeight::Bo))),
+                    post_attention_layernorm: LayerNorm::take(
+                        &mut weights,
+                        w(LayerWeight::FfnNorm),
+                        w(LayerWeight::FfnNormBias),
+                    ),
+                    dense_h_to_4h: weights
+                        .take_linear(w(LayerWeight::W1), Some(w(LayerWeight::B1))),
+                    dense_4h_to_h: weights
+                        .take_linear(w(LayerWeight::W2), Some(w(LayerWeight::B2))),
+                }
+            })
+            .collect();
+
+        Self {
+            embed_in: weights.take_f16(Weight::TokEmbeddings),
+            final_layer_norm: LayerNorm::take(&mut weights, Weight::Norm, Weight::NormBias),
+            embed_out: weights.take_linear(Weight::Output, None),
+            layers,
+            hparams,
+        }
+    }
+}
+
+/// GPT-NeoX interleaves the fused projection by head, as `[n_heads, 3,
+/// head_dim]` outputs. Reorders its rows to all the queries, then all the
+/// keys, then all the values, like GPT-2's.
+fn group_qkv(linear: Linear, n_heads: usize) -> Linear {
+    fn regroup<T: TensorElement>(x: &Tensor<T, 2>, n_heads: usize) -> Tensor<T, 2> {
+        let [rows, cols] = x.shape();
+        let head_dim = rows / (3 * n_heads);
+        let src = x.as_slice();
+
+        let mut data = Vec::with_capacity(rows * cols);
+        for part in 0..3 {
+            for h in 0..n_heads {
+                let start = (h * 3 + part) * head_dim * cols;
+                data.extend_from_slice(&src[start..start + head_dim * cols]);
+            }
+        }
+        Tensor::new(data, [rows, cols])
+    }
+
+    Linear {
+        weight: linear.weight,
+        w: regroup(&linear.w, n_heads),
+        bias: linear.bias.map(|b| {
+            let [n] = b.shape();
+            regroup(&b.reshape([n, 1]), n_heads).reshape([n])
+        }),
+        lora: linear.lora.map(|mut lora| {
+            lora.b = regroup(&lora.b, n_heads);
+            lora
+        }),
+    }
+}
+
+impl Model for GptNeoX {
+    fn hparams(&self) -> &HParams {
+        &self.hparams
+    }
+
+    fn forward(&self, tokens: &Tensor<usize, 1>, scratch: &mut Scratch) -> Tensor<f16, 2> {
+        let hp = &self.hparams;
+        let [n] = tokens.shape();
+        let dim = hp.dim;
+        let mut x = scratch.f16.take([n, dim]);
+        self.embed_in.get_rows_into(tokens, &mut x);
+        let rot_dims = (hp.head_dim() as f32 * hp.rotary_pct) as usize;
+        let past = past_len(scratch);
+        let attn_shape = Attention {
+            n_heads: hp.n_heads,
+            n_kv_heads: hp.n_heads,
+            window: None,
+            past,
+        };
+
+        for (i, layer) in self.layers.iter().enumerate() {
+            let mut h = scratch.f16.take([n, dim]);
+            layer.input_layernorm.forward_into(&x, hp.norm_eps, &mut h);
+
+            let mut qkv = scratch.f16.take([n, 3 * dim]);
+            layer.query_key_value.matmul_into(&h, &mut qkv, scratch);
+            let [mut q, mut k, v] = split_qkv(&qkv, scratch);
+            q.rope_inplace(past, hp.n_heads, rot_dims, hp.rope_theta, RopeStyle::NeoX);
+            k.rope_inplace(past, hp.n_heads, rot_dims, hp.rope_theta, RopeStyle::NeoX);
+            let mut attn = scratch.f16.take([n, dim]);
+            causal_attn_into(i, &q, &k, &v, attn_shape, &mut attn, scratch);
+            let mut attn_out = scratch.f16.take([n, dim]);
+            layer.dense.matmul_into(&attn, &mut attn_out, scratch);
+
+            // The feed-forward block reads the same residual as attention.
+            layer
+                .post_attention_layernorm
+                .forward_into(&x, hp.norm_eps, &mut h);
+            let mut ff = scratch.f16.take([n, hp.n_ff]);
+            layer.dense_h_to_4h.matmul_into(&h, &mut ff, scratch);
+            ff.gelu_inplace();
+            layer.dense_4h_to_h.matmul_into(&ff, &mut h, scratch);
+            x.add_inplace(&attn_out);
+            x.add_inplace(&h);
+
+            for t in [h, qkv, q, k, v, attn, attn_out, ff] {
+                scratch.f16.recycle(t);
+            }
+        }
+
+        let mut h = scratch.f16.take([n, dim]);
+        self.final_layer_norm.forward_into(&x, hp.norm_eps, &mut h);
+        let mut logits = scratch.f16.take([n, hp.vocab_size]);
+        self.embed_out.matmul_into(&h, &mut logits, scratch);
+        scratch.f16.recycle(h);
+        scratch.f16.recycle(x);
+        advance(scratch, n);
+
+        logits
+    }
+}
@@ -1,6 +1,7 @@
+use crate::tensor::{FloatElement, MAX_DIMS};
 use half::f16;
+use std::ops::Range;
 use std::ptr;
-use crate::tensor::MAX_DIMS;
 
 fn to_strides(shape: [usize; MAX_DIMS]) -> [usize; MAX_DIMS] {
     let mut strides = [1; MAX_DIMS];
@@ -10,6 +11,7 @@ fn to_strides(shape: [usize; MAX_DIMS]) -> [usize; MAX_DIMS] {
     strides
 }
 
+/// `dst` may be `a`, to scale in place.
 pub unsafe fn scalev_raw_f16(a: *const f16, dst: *mut f16, n: usize, scale: f32) {
     for i in 0..n {
         let a = a.add(i).read();
@@ -18,6 +20,30 @@ pub unsafe fn scalev_raw_f16(a: *const f16, dst: *mut f16, n: usize, scale: f32)
     }
 }
 
+/// `dst` may be `a` or `b`.
+pub unsafe fn add_raw_f16(a: *const f16, b: *const f16, dst: *mut f16, n: usize) {
+    for i in 0..n {
+        let x = a.add(i).read().to_f32() + b.add(i).read().to_f32();
+        dst.add(i).write(f16::from_f32(x));
+    }
+}
+
+/// `dst` may be `a` or `b`.
+pub unsafe fn mul_raw_f16(a: *const f16, b: *const f16, dst: *mut f16, n: usize) {
+    for i in 0..n {
+        let x = a.add(i).read().to_f32() * b.add(i).read().to_f32();
+        dst.add(i).write(f16::from_f32(x));
+    }
+}
+
+/// Applies `f` to every element in `f32`. `dst` may be `a`.
+pub unsafe fn map_raw_f16(a: *const f16, dst: *mut f16, n: usize, f: impl Fn(f32) -> f32) {
+    for i in 0..n {
+        let x = f(a.add(i).read().to_f32());
+        dst.add(i).write(f16::from_f32(x));
+    }
+}
+
 pub unsafe fn dotv_raw_f32(a: *const f32, b: *const f32, n: usize) -> f32 {
     let mut sum = 0.0;
 
@@ -54,23 +80,26 @@ pub unsafe fn dotv_raw_f16_f32(a: *const f16, b: *const f32, n: usize) -> f32 {
     acc
 }
 
-// pub unsafe fn get_rows_raw<T, I: Into<usize>>(
-//     a: *const T,
-//     idxs: *const I,
-//     dst: *mut T,
-//     n: usize,
-//     stride: usize,
-//     indices: usize,
-// ) {
-//     assert!(n <= stride);
-//     for i in 0..indices {
-//         let idx = idxs.add(i).read();
-//         let src = a.add(idx.into() * stride);
-//         let dst = dst.add(i * stride);
-//         ptr::copy_nonoverlapping(src, dst, n);
-//     }
-// }
+/// Copies the rows of `a` (`rows` rows of `n` elements) selected by `idxs` to
+/// consecutive rows of `dst`.
+pub unsafe fn get_rows_raw<T, I: Copy + Into<usize>>(
+    a: *const T,
+    idxs: *const I,
+    dst: *mut T,
+    n: usize,
+    rows: usize,
+    indices: usize,
+) {
+    for i in 0..indices {
+        let idx = idxs.add(i).read().into();
+        assert!(idx < rows);
+        let src = a.add(idx * n);
+        let dst = dst.add(i * n);
+        ptr::copy_nonoverlapping(src, dst, n);
+    }
+}
 
+/// `dst` may be `a`, to normalize in place.
 pub unsafe fn rms_norm_f16(a: *const f16, dst: *mut f16, shape: [usize; MAX_DIMS]) {
     let strides = to_strides(shape);
 
@@ -89,7 +118,7 @@ pub unsafe fn rms_norm_f16(a: *const f16, dst: *mut f16, shape: [usize; MAX_DIMS
 }
 
 /// a_shape: [b1, b0, m, n]
-/// bT_shape: [b1, b0, p, n]
+/// bt_shape: [b1, b0, p, n]
 /// c_shape: [b1, b0, m, p]
 pub unsafe fn generic_dot_f16(
     a: *const f16,
@@ -102,7 +131,7 @@ pub unsafe fn generic_dot_f16(
     assert!(a_shape[0] == bt_shape[0]); // b1
     assert!(a_shape[1] == bt_shape[1]); // b0
 
-    assert!(a_shape[3] == bt_shape[2]); // n
+    assert!(a_shape[3] == bt_shape[3]); // n
 
     let a_strides = to_strides(a_shape);
     let b_strides = to_strides(bt_shape);
@@ -115,7 +144,7 @@ pub unsafe fn generic_dot_f16(
             // 0..m
             for k in 0..a_shape[2] {
                 // 0..p
-                for l in 0..bt_shape[3] {
+                for l in 0..bt_shape[2] {
                     let a = a.add(i * a_strides[0] + j * a_strides[1] + k * a_strides[2]);
                     let b = bt.add(i * b_strides[0] + j * b_strides[1] + l * b_strides[2]);
                     let c = c.add(i * c_strides[0] + j * c_strides[1] + k * c_strides[2] + l);
@@ -129,24 +158,24 @@ pub unsafe fn generic_dot_f16(
 }
 
 /// a_shape: [b1, b0, m, n]
-/// bT_shape: [b1, b0, p, n]
+/// bt_shape: [b1, b0, p, n]
 /// c_shape: [b1, b0, m, p]
 pub unsafe fn generic_dot_f32(
     a: *const f32,
-    bT: *const f32,
+    bt: *const f32,
     c: *mut f32,
     a_shape: [usize; MAX_DIMS],
-    bT_shape: [usize; MAX_DIMS],
+    bt_shape: [usize; MAX_DIMS],
 ) {
     // Check batch dimensions.
-    assert!(a_shape[0] == bT_shape[0]); // b1
-    assert!(a_shape[1] == bT_shape[1]); // b0
+    assert!(a_shape[0] == bt_shape[0]); // b1
+    assert!(a_shape[1] == bt_shape[1]); // b0
 
-    assert!(a_shape[3] == bT_shape[2]); // n
+    assert!(a_shape[3] == bt_shape[3]); // n
 
     let a_strides = to_strides(a_shape);
-    let b_strides = to_strides(bT_shape);
-    let c_strides = to_strides([a_shape[0], a_shape[1], a_shape[2], bT_shape[2]]);
+    let b_strides = to_strides(bt_shape);
+    let c_strides = to_strides([a_shape[0], a_shape[1], a_shape[2], bt_shape[2]]);
 
     for i in 0..a_shape[0] {
         for j in 0..a_shape[1] {
@@ -155,9 +184,9 @@ pub unsafe fn generic_dot_f32(
             // 0..m
             for k in 0..a_shape[2] {
                 // 0..p
-                for l in 0..bT_shape[3] {
+                for l in 0..bt_shape[2] {
                     let a = a.add(i * a_strides[0] + j * a_strides[1] + k * a_strides[2]);
-                    let b = bT.add(i * b_strides[0] + j * b_strides[1] + l * b_strides[2]);
+                    let b = bt.add(i * b_strides[0] + j * b_strides[1] + l * b_strides[2]);
                     let c = c.add(i * c_strides[0] + j * c_strides[1] + k * c_strides[2] + l);
 
                     let x = dotv_raw_f32(a, b, a_shape[3]);
@@ -169,24 +198,24 @@ pub unsafe fn generic_dot_f32(
 }
 
 /// a_shape: [b1, b0, m, n]
-/// bT_shape: [b1, b0, p, n]
+/// bt_shape: [b1, b0, p, n]
 /// c_shape: [b1, b0, m, p]
-pub unsafe fn generic_dot_f32_f16(
-    a: *const f32,
-    bT: *const f16,
+pub unsafe fn generic_dot_f16_f32(
+    a: *const f16,
+    bt: *const f32,
     c: *mut f16,
     a_shape: [usize; MAX_DIMS],
-    bT_shape: [usize; MAX_DIMS],
+    bt_shape: [usize; MAX_DIMS],
 ) {
     // Check batch dimensions.
-    assert!(a_shape[0] == bT_shape[0]); // b1
-    assert!(a_shape[1] == bT_shape[1]); // b0
+    assert!(a_shape[0] == bt_shape[0]); // b1
+    assert!(a_shape[1] == bt_shape[1]); // b0
 
-    assert!(a_shape[3] == bT_shape[2]); // n
+    assert!(a_shape[3] == bt_shape[3]); // n
 
     let a_strides = to_strides(a_shape);
-    let b_strides = to_strides(bT_shape);
-    let c_strides = to_strides([a_shape[0], a_shape[1], a_shape[2], bT_shape[2]]);
+    let b_strides = to_strides(bt_shape);
+    let c_strides = to_strides([a_shape[0], a_shape[1], a_shape[2], bt_shape[2]]);
 
     for i in 0..a_shape[0] {
         for j in 0..a_shape[1] {
@@ -195,12 +224,12 @@ pub unsafe fn generic_dot_f32_f16(
             // 0..m
             for k in 0..a_shape[2] {
                 // 0..p
-                for l in 0..bT_shape[3] {
+                for l in 0..bt_shape[2] {
                     let a = a.add(i * a_strides[0] + j * a_strides[1] + k * a_strides[2]);
-                    let b = bT.add(i * b_strides[0] + j * b_strides[1] + l * b_strides[2]);
+                    let b = bt.add(i * b_strides[0] + j * b_strides[1] + l * b_strides[2]);
                     let c = c.add(i * c_strides[0] + j * c_strides[1] + k * c_strides[2] + l);
 
-                    let x = dotv_raw_f16_f32(b, a, a_shape[3]);
+                    let x = dotv_raw_f16_f32(a, b, a_shape[3]);
                     c.write(f16::from_f32(x));
                 }
             }
@@ -208,6 +237,7 @@ pub unsafe fn generic_dot_f32_f16(
     }
 }
 
+/// `b` may be `a`, to apply in place.
 pub unsafe fn silu_raw_f16(a: *const f16, b: *mut f16, shape: [usize; MAX_DIMS]) {
     let strides = to_strides(shape);
 
@@ -243,13 +273,13 @@ fn softmax_inplace(x: &mut [f32]) {
 }
 
 pub unsafe fn flash_attn_raw_f16(
-    // [D, N]
+    // [N, D]
     q: *const f16,
-    // [D, N]
+    // [N, D]
     k: *const f16,
-    // [D, N]
+    // [D, N], i.e. transposed
     v: *const f16,
-    // [D, N]
+    // [N, D]
     o: *mut f16,
 
     n: usize,
@@ -285,7 +315,12 @@ pub unsafe fn flash_attn_raw_f16(
     }
 }
 
-pub unsafe fn repeat<T>(src: *const T, dst: *mut T, src_shape: [usize; MAX_DIMS], dst_shape:[usize; MAX_DIMS]) {
+pub unsafe fn repeat<T>(
+    src: *const T,
+    dst: *mut T,
+    src_shape: [usize; MAX_DIMS],
+    dst_shape: [usize; MAX_DIMS],
+) {
     assert!(dst_shape[0] % src_shape[0] == 0);
     assert!(dst_shape[1] % src_shape[1] == 0);
     assert!(dst_shape[2] % src_shape[2] == 0);
@@ -302,11 +337,1194 @@ pub unsafe fn repeat<T>(src: *const T, dst: *mut T, src_shape: [usize; MAX_DIMS]
                 let src_k = k % src_shape[2];
 
                 for l in 0..(dst_shape[3] / src_shape[3]) {
-                    let from = src.add(src_i * src_strides[0] + src_j * src_strides[1] + src_k * src_strides[2]);
-                    let to = dst.add(i * dst_strides[0] + j * dst_strides[1] + k * dst_strides[2] + l * src_shape[3]);
+                    let from = src.add(
+                        src_i * src_strides[0] + src_j * src_strides[1] + src_k * src_strides[2],
+                    );
+                    let to = dst.add(
+                        i * dst_strides[0]
+                            + j * dst_strides[1]
+                            + k * dst_strides[2]
+                            + l * src_shape[3],
+                    );
                     ptr::copy_nonoverlapping(from, to, src_shape[3]);
                 }
             }
         }
     }
 }
+
+/// Copies the slices `src_range` along `axis` in `src` to `dst`, starting at
+/// `dst_offset`. The shapes must agree on every other axis.
+pub unsafe fn copy_along_axis<T>(
+    src: *const T,
+    src_shape: [usize; MAX_DIMS],
+    src_range: Range<usize>,
+    dst: *mut T,
+    dst_shape: [usize; MAX_DIMS],
+    dst_offset: usize,
+    axis: usize,
+) {
+    let (outer, src_len, inner) = split_axis(src_shape, axis);
+    let dst_len = dst_shape[axis];
+    let len = src_range.len();
+
+    assert!(src_range.end <= src_len);
+    assert!(dst_offset + len <= dst_len);
+
+    for o in 0..outer {
+        let from = src.add((o * src_len + src_range.start) * inner);
+        let to = dst.add((o * dst_len + dst_offset) * inner);
+        ptr::copy_nonoverlapping(from, to, len * inner);
+    }
+}
+
+/// Splits a shape around `axis` into the number of lanes before the axis, the
+/// length of the axis, and the number of contiguous elements after it.
+fn split_axis(shape: [usize; MAX_DIMS], axis: usize) -> (usize, usize, usize) {
+    let outer = shape[..axis].iter().product();
+    let inner = shape[axis + 1..].iter().product();
+    (outer, shape[axis], inner)
+}
+
+/// Folds every lane along `axis` into a single value, accumulating in f32.
+/// dst_shape: shape with shape[axis] = 1
+unsafe fn reduce_raw<T: FloatElement>(
+    a: *const T,
+    dst: *mut T,
+    shape: [usize; MAX_DIMS],
+    axis: usize,
+    init: f32,
+    f: impl Fn(f32, f32) -> f32,
+    finish: impl Fn(f32) -> f32,
+) {
+    let (outer, len, inner) = split_axis(shape, axis);
+
+    for o in 0..outer {
+        for i in 0..inner {
+            let mut acc = init;
+            for l in 0..len {
+                acc = f(acc, a.add(o * len * inner + l * inner + i).read().to_f32());
+            }
+            dst.add(o * inner + i).write(T::from_f32(finish(acc)));
+        }
+    }
+}
+
+pub unsafe fn sum_raw<T: FloatElement>(
+    a: *const T,
+    dst: *mut T,
+    shape: [usize; MAX_DIMS],
+    axis: usize,
+) {
+    reduce_raw(a, dst, shape, axis, 0.0, |acc, x| acc + x, |acc| acc);
+}
+
+pub unsafe fn mean_raw<T: FloatElement>(
+    a: *const T,
+    dst: *mut T,
+    shape: [usize; MAX_DIMS],
+    axis: usize,
+) {
+    let len = shape[axis] as f32;
+    reduce_raw(a, dst, shape, axis, 0.0, |acc, x| acc + x, |acc| acc / len);
+}
+
+pub unsafe fn max_raw<T: FloatElement>(
+    a: *const T,
+    dst: *mut T,
+    shape: [usize; MAX_DIMS],
+    axis: usize,
+) {
+    reduce_raw(a, dst, shape, axis, f32::NEG_INFINITY, f32::max, |acc| acc);
+}
+
+pub unsafe fn min_raw<T: FloatElement>(
+    a: *const T,
+    dst: *mut T,
+    shape: [usize; MAX_DIMS],
+    axis: usize,
+) {
+    reduce_raw(a, dst, shape, axis, f32::INFINITY, f32::min, |acc| acc);
+}
+
+/// dst_shape: shape with shape[axis] = 1
+pub unsafe fn argmax_raw<T: FloatElement>(
+    a: *const T,
+    dst: *mut usize,
+    shape: [usize; MAX_DIMS],
+    axis: usize,
+) {
+    let (outer, len, inner) = split_axis(shape, axis);
+
+    for o in 0..outer {
+        for i in 0..inner {
+            let mut best = 0;
+            let mut best_x = f32::NEG_INFINITY;
+            for l in 0..len {
+                let x = a.add(o * len * inner + l * inner + i).read().to_f32();
+                // Strictly greater, so the first maximum wins.
+                if x > best_x {
+                    best = l;
+                    best_x = x;
+                }
+            }
+            dst.add(o * inner + i).write(best);
+        }
+    }
+}
+
+/// dst_shape: shape with shape[axis] = k
+pub unsafe fn topk_raw<T: FloatElement>(
+    a: *const T,
+    values: *mut T,
+    indices: *mut usize,
+    shape: [usize; MAX_DIMS],
+    axis: usize,
+    k: usize,
+) {
+    assert!(k <= shape[axis]);
+
+    let (outer, len, inner) = split_axis(shape, axis);
+    let mut lane = Vec::with_capacity(len);
+
+    for o in 0..outer {
+        for i in 0..inner {
+            lane.clear();
+            for l in 0..len {
+                lane.push((a.add(o * len * inner + l * inner + i).read(), l));
+            }
+
+            // A stable sort keeps equal values in index order.
+            lane.sort_by(|(x, _), (y, _)| y.to_f32().total_cmp(&x.to_f32()));
+
+            for (j, &(x, l)) in lane[..k].iter().enumerate() {
+                values.add(o * k * inner + j * inner + i).write(x);
+                indices.add(o * k * inner + j * inner + i).write(l);
+            }
+        }
+    }
+}
+
+// The kernels below back the forward passes in `model`. Activations are
+// `[tokens, features]` rows of `f16`, while norm weights and biases are `f32`.
+
+/// Normalizes each row by its root mean square, then scales it by `w`.
+/// `dst` may be `a`.
+pub unsafe fn rms_norm_scaled_f16(
+    a: *const f16,
+    w: *const f32,
+    dst: *mut f16,
+    rows: usize,
+    n: usize,
+    eps: f32,
+) {
+    for r in 0..rows {
+        let (a, dst) = (a.add(r * n), dst.add(r * n));
+        let scale = 1.0 / (dotv_raw_f16(a, a, n) / n as f32 + eps).sqrt();
+        for i in 0..n {
+            let x = a.add(i).read().to_f32() * scale * w.add(i).read();
+            dst.add(i).write(f16::from_f32(x));
+        }
+    }
+}
+
+/// Normalizes each row to zero mean and unit variance, then scales it by `w`
+/// and shifts it by `b`. `dst` may be `a`.
+pub unsafe fn layer_norm_f16(
+    a: *const f16,
+    w: *const f32,
+    b: *const f32,
+    dst: *mut f16,
+    rows: usize,
+    n: usize,
+    eps: f32,
+) {
+    for r in 0..rows {
+        let (a, dst) = (a.add(r * n), dst.add(r * n));
+        let row = (0..n).map(|i| a.add(i).read().to_f32());
+        let mean = row.clone().sum::<f32>() / n as f32;
+        let var = row.map(|x| (x - mean) * (x - mean)).sum::<f32>() / n as f32;
+        let scale = 1.0 / (var + eps).sqrt();
+
+        for i in 0..n {
+            let x = (a.add(i).read().to_f32() - mean) * scale;
+            dst.add(i)
+                .write(f16::from_f32(x * w.add(i).read() + b.add(i).read()));
+        }
+    }
+}
+
+/// Adds `b` to each of the `rows` rows of `a`, in place.
+pub unsafe fn add_row_f16_f32(a: *mut f16, b: *const f32, rows: usize, n: usize) {
+    for r in 0..rows {
+        for i in 0..n {
+            let a = a.add(r * n + i);
+            a.write(f16::from_f32(a.read().to_f32() + b.add(i).read()));
+        }
+    }
+}
+
+/// The tanh approximation of GELU, as used by GPT-2.
+pub fn gelu(x: f32) -> f32 {
+    const SQRT_2_OVER_PI: f32 = 0.797_884_6;
+    0.5 * x * (1.0 + (SQRT_2_OVER_PI * (x + 0.044715 * x * x * x)).tanh())
+}
+
+/// Rotary position embeddings, applied in place to a row of `n_heads` heads
+/// of `d` elements for each of `positions`. Only the first `rot_dims` elements
+/// of each head are rotated.
+///
+/// With `neox`, element `i` is paired with `i + rot_dims / 2`, as in GPT-NeoX
+/// and HuggingFace checkpoints. Otherwise, pairs are adjacent, as in the
+/// original LLaMA checkpoints.
+pub unsafe fn rope_f16(
+    x: *mut f16,
+    positions: Range<usize>,
+    n_heads: usize,
+    d: usize,
+    rot_dims: usize,
+    theta: f32,
+    neox: bool,
+) {
+    assert!(rot_dims <= d && rot_dims.is_multiple_of(2));
+    let half = rot_dims / 2;
+
+    for (row, r) in positions.enumerate() {
+        for h in 0..n_heads {
+            let head = x.add((row * n_heads + h) * d);
+            for i in 0..half {
+                let freq = theta.powf(-2.0 * i as f32 / rot_dims as f32);
+                let (sin, cos) = (r as f32 * freq).sin_cos();
+
+                let (a, b) = if neox {
+                    (head.add(i), head.add(i + half))
+                } else {
+                    (head.add(2 * i), head.add(2 * i + 1))
+                };
+                let (x0, x1) = (a.read().to_f32(), b.read().to_f32());
+                a.write(f16::from_f32(x0 * cos - x1 * sin));
+                b.write(f16::from_f32(x0 * sin + x1 * cos));
+            }
+        }
+    }
+}
+
+/// The shape of multi-head attention from `n` queries with heads of `d`
+/// elements, which follow `past` earlier positions. Query heads share key and
+/// value heads in groups of `n_heads / n_kv_heads`. With a `window`, each
+/// position only attends to itself and the `window - 1` positions before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AttnParams {
+    pub n: usize,
+    pub d: usize,
+    pub n_heads: usize,
+    pub n_kv_heads: usize,
+    pub window: Option<usize>,
+    pub past: usize,
+}
+
+/// Causal multi-head attention. `s` holds the scores of a query, and `acc` its
+/// output before it's rounded to f16.
+///
+/// q, o: [n, n_heads * d]
+/// k, v: [past + n, n_kv_heads * d], not transposed
+/// s: [past + n]
+/// acc: [d]
+pub unsafe fn causal_attn_f16(
+    q: *const f16,
+    k: *const f16,
+    v: *const f16,
+    o: *mut f16,
+    s: &mut [f32],
+    acc: &mut [f32],
+    params: AttnParams,
+) {
+    let AttnParams {
+        n,
+        d,
+        n_heads,
+        n_kv_heads,
+        window,
+        past,
+    } = params;
+    assert_eq!(n_heads % n_kv_heads, 0);
+    assert!(s.len() >= past + n && acc.len() == d);
+    let group = n_heads / n_kv_heads;
+    let scale = 1.0 / (d as f32).sqrt();
+    let (q_stride, kv_stride) = (n_heads * d, n_kv_heads * d);
+
+    for i in 0..n {
+        let pos = past + i;
+        let first = window.map_or(0, |w| (pos + 1).saturating_sub(w));
+        for h in 0..n_heads {
+            let (q, kv) = (q.add(i * q_stride + h * d), (h / group) * d);
+
+            let s = &mut s[first..=pos];
+            for (j, s) in (first..=pos).zip(s.iter_mut()) {
+                *s = scale * dotv_raw_f16(q, k.add(j * kv_stride + kv), d);
+            }
+            softmax_inplace(s);
+
+            acc.fill(0.0);
+            for (j, &p) in (first..=pos).zip(s.iter()) {
+                let v = v.add(j * kv_stride + kv);
+                for (c, acc) in acc.iter_mut().enumerate() {
+                    *acc = f32::mul_add(p, v.add(c).read().to_f32(), *acc);
+                }
+            }
+            for (c, &x) in acc.iter().enumerate() {
+                o.add(i * q_stride + h * d + c).write(f16::from_f32(x));
+            }
+        }
+    }
+}
+
+// The f32 kernels below back training, where the forward ops need to be exact
+// enough to check gradients against finite differences. Each `_backward`
+// kernel takes the gradient of the output, `dy`, and *adds* the gradients of
+// the inputs into `dx` etc., so that contributions from several uses sum up.
+
+/// `dst` may be `a` or `b`.
+pub unsafe fn add_raw_f32(a: *const f32, b: *const f32, dst: *mut f32, n: usize) {
+    for i in 0..n {
+        dst.add(i).write(a.add(i).read() + b.add(i).read());
+    }
+}
+
+/// `dst` may be `a` or `b`.
+pub unsafe fn mul_raw_f32(a: *const f32, b: *const f32, dst: *mut f32, n: usize) {
+    for i in 0..n {
+        dst.add(i).write(a.add(i).read() * b.add(i).read());
+    }
+}
+
+fn sigmoid(x: f32) -> f32 {
+    1.0 / (1.0 + (-x).exp())
+}
+
+pub unsafe fn silu_raw_f32(a: *const f32, dst: *mut f32, n: usize) {
+    for i in 0..n {
+        let x = a.add(i).read();
+        dst.add(i).write(x * sigmoid(x));
+    }
+}
+
+pub unsafe fn silu_backward_f32(x: *const f32, dy: *const f32, dx: *mut f32, n: usize) {
+    for i in 0..n {
+        let x = x.add(i).read();
+        let s = sigmoid(x);
+        let dx = dx.add(i);
+        dx.write(dx.read() + dy.add(i).read() * s * (1.0 + x * (1.0 - s)));
+    }
+}
+
+/// Normalizes each of the `rows` rows of `n` elements by its root mean square,
+/// with `eps` added to the mean square.
+pub unsafe fn rms_norm_f32(a: *const f32, dst: *mut f32, rows: usize, n: usize, eps: f32) {
+    for r in 0..rows {
+        let a = a.add(r * n);
+        let rms = (dotv_raw_f32(a, a, n) / n as f32 + eps).sqrt();
+        for i in 0..n {
+            dst.add(r * n + i).write(a.add(i).read() / rms);
+        }
+    }
+}
+
+/// With `y = x / rms(x)`, `dx = (dy - y * mean(dy * y)) / rms(x)`.
+pub unsafe fn rms_norm_backward_f32(
+    x: *const f32,
+    dy: *const f32,
+    dx: *mut f32,
+    rows: usize,
+    n: usize,
+    eps: f32,
+) {
+    for r in 0..rows {
+        let (x, dy, dx) = (x.add(r * n), dy.add(r * n), dx.add(r * n));
+        let rms = (dotv_raw_f32(x, x, n) / n as f32 + eps).sqrt();
+        let mean = dotv_raw_f32(dy, x, n) / rms / n as f32;
+        for i in 0..n {
+            let y = x.add(i).read() / rms;
+            let dx = dx.add(i);
+            dx.write(dx.read() + (dy.add(i).read() - y * mean) / rms);
+        }
+    }
+}
+
+/// Interleaved rotary position embeddings over whole heads, like `rope_f16`.
+/// Each rotation is orthogonal, so rotating the gradient back the other way,
+/// with `inverse`, is the backward pass.
+pub unsafe fn rope_f32(
+    x: *const f32,
+    dst: *mut f32,
+    rows: usize,
+    n_heads: usize,
+    d: usize,
+    theta: f32,
+    inverse: bool,
+) {
+    assert!(d.is_multiple_of(2));
+    let sign = if inverse { -1.0 } else { 1.0 };
+
+    for r in 0..rows {
+        for h in 0..n_heads {
+            let (x, dst) = (x.add((r * n_heads + h) * d), dst.add((r * n_heads + h) * d));
+            for i in 0..d / 2 {
+                let freq = theta.powf(-2.0 * i as f32 / d as f32);
+                let (sin, cos) = (sign * r as f32 * freq).sin_cos();
+
+                let (x0, x1) = (x.add(2 * i).read(), x.add(2 * i + 1).read());
+                dst.add(2 * i).write(x0 * cos - x1 * sin);
+                dst.add(2 * i + 1).write(x0 * sin + x1 * cos);
+            }
+        }
+    }
+}
+
+/// Softmax over each of the `rows` rows of `n` elements.
+pub unsafe fn softmax_rows_f32(a: *const f32, dst: *mut f32, rows: usize, n: usize) {
+    ptr::copy(a, dst, rows * n);
+    for r in 0..rows {
+        softmax_inplace(std::slice::from_raw_parts_mut(dst.add(r * n), n));
+    }
+}
+
+/// With `y = softmax(x)`, `dx = y * (dy - sum(dy * y))`. Takes `y` rather than
+/// `x`.
+pub unsafe fn softmax_rows_backward_f32(
+    y: *const f32,
+    dy: *const f32,
+    dx: *mut f32,
+    rows: usize,
+    n: usize,
+) {
+    for r in 0..rows {
+        let (y, dy, dx) = (y.add(r * n), dy.add(r * n), dx.add(r * n));
+        let t = dotv_raw_f32(y, dy, n);
+        for i in 0..n {
+            let dx = dx.add(i);
+            dx.write(dx.read() + y.add(i).read() * (dy.add(i).read() - t));
+        }
+    }
+}
+
+/// An input of a backward kernel, along with where its gradient is added.
+#[derive(Clone, Copy)]
+pub struct Grad {
+    pub x: *const f32,
+    pub dx: *mut f32,
+}
+
+/// x: [n, k], w: [m, k], dy: [n, m]
+/// Forward is `y = x · wᵀ`, see `generic_dot_f32`.
+pub unsafe fn matmul_backward_f32(x: Grad, w: Grad, dy: *const f32, n: usize, k: usize, m: usize) {
+    for i in 0..n {
+        for o in 0..m {
+            let g = dy.add(i * m + o).read();
+            for j in 0..k {
+                // dx = dy · w
+                let dx = x.dx.add(i * k + j);
+                dx.write(f32::mul_add(g, w.x.add(o * k + j).read(), dx.read()));
+                // dw = dyᵀ · x
+                let dw = w.dx.add(o * k + j);
+                dw.write(f32::mul_add(g, x.x.add(i * k + j).read(), dw.read()));
+            }
+        }
+    }
+}
+
+/// The attention scores of query `i` against every key, as probabilities.
+/// Keys after `i` are masked out when `causal`.
+/// There are as many keys as `p` is long.
+unsafe fn attention_probs_f32(
+    q: *const f32,
+    k: *const f32,
+    i: usize,
+    d: usize,
+    causal: bool,
+    p: &mut [f32],
+) {
+    let scale = 1.0 / (d as f32).sqrt();
+    for (j, p) in p.iter_mut().enumerate() {
+        *p = if causal && j > i {
+            f32::NEG_INFINITY
+        } else {
+            scale * dotv_raw_f32(q.add(i * d), k.add(j * d), d)
+        };
+    }
+    softmax_inplace(p);
+}
+
+/// q, k, v, o: [n, d]
+/// Unlike `flash_attn_raw_f16`, `v` isn't transposed.
+pub unsafe fn attention_f32(
+    q: *const f32,
+    k: *const f32,
+    v: *const f32,
+    o: *mut f32,
+    n: usize,
+    d: usize,
+    causal: bool,
+) {
+    let mut p = vec![0.0; n];
+    for i in 0..n {
+        attention_probs_f32(q, k, i, d, causal, &mut p);
+        for c in 0..d {
+            let o = o.add(i * d + c);
+            o.write(0.0);
+            for (j, &p) in p.iter().enumerate() {
+                o.write(f32::mul_add(p, v.add(j * d + c).read(), o.read()));
+            }
+        }
+    }
+}
+
+/// q, k, v, dy: [n, d]
+pub unsafe fn attention_backward_f32(
+    q: Grad,
+    k: Grad,
+    v: Grad,
+    dy: *const f32,
+    n: usize,
+    d: usize,
+    causal: bool,
+) {
+    let scale = 1.0 / (d as f32).sqrt();
+    let mut p = vec![0.0; n];
+    let mut dp = vec![0.0; n];
+
+    for i in 0..n {
+        // The probabilities are recomputed rather than kept from the forward
+        // pass, as in flash attention.
+        attention_probs_f32(q.x, k.x, i, d, causal, &mut p);
+
+        for (j, (&p, dp)) in p.iter().zip(dp.iter_mut()).enumerate() {
+            *dp = dotv_raw_f32(dy.add(i * d), v.x.add(j * d), d);
+            for c in 0..d {
+                let dv = v.dx.add(j * d + c);
+                dv.write(f32::mul_add(p, dy.add(i * d + c).read(), dv.read()));
+            }
+        }
+
+        let t = p.iter().zip(&dp).map(|(p, dp)| p * dp).sum::<f32>();
+        for (j, (&p, &dp)) in p.iter().zip(&dp).enumerate() {
+            let ds = scale * p * (dp - t);
+            for c in 0..d {
+                let dq = q.dx.add(i * d + c);
+                dq.write(f32::mul_add(ds, k.x.add(j * d + c).read(), dq.read()));
+                let dk = k.dx.add(j * d + c);
+                dk.write(f32::mul_add(ds, q.x.add(i * d + c).read(), dk.read()));
+            }
+        }
+    }
+}
+
+/// Adds each row of `dy` into the row of `dw` (`rows` rows of `n` elements)
+/// it was gathered from by `get_rows_raw`.
+pub unsafe fn get_rows_backward_f32<I: Copy + Into<usize>>(
+    idxs: *const I,
+    dy: *const f32,
+    dw: *mut f32,
+    n: usize,
+    rows: usize,
+    indices: usize,
+) {
+    for i in 0..indices {
+        let idx = idxs.add(i).read().into();
+        assert!(idx < rows);
+        add_raw_f32(dw.add(idx * n), dy.add(i * n), dw.add(idx * n), n);
+    }
+}
+
+/// The mean over rows of `-log(softmax(logits)[target])`, for `rows` rows of
+/// `n` logits and one target class per row.
+pub unsafe fn cross_entropy_f32(
+    logits: *const f32,
+    targets: *const usize,
+    rows: usize,
+    n: usize,
+) -> f32 {
+    let mut loss = 0.0;
+    for r in 0..rows {
+        let row = std::slice::from_raw_parts(logits.add(r * n), n);
+        let max = row.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        let log_sum = row.iter().map(|&x| (x - max).exp()).sum::<f32>().ln() + max;
+        let target = targets.add(r).read();
+        assert!(target < n);
+        loss += log_sum - row[target];
+    }
+    loss / rows as f32
+}
+
+/// `dlogits = dy * (softmax(logits) - onehot(target)) / rows`, where `dy` is
+/// the gradient of the mean loss.
+pub unsafe fn cross_entropy_backward_f32(
+    logits: *const f32,
+    targets: *const usize,
+    dy: f32,
+    dx: *mut f32,
+    rows: usize,
+    n: usize,
+) {
+    let scale = dy / rows as f32;
+    let mut probs = vec![0.0; n];
+    for r in 0..rows {
+        softmax_rows_f32(logits.add(r * n), probs.as_mut_ptr(), 1, n);
+        probs[targets.add(r).read()] -= 1.0;
+        for (i, p) in probs.iter().enumerate() {
+            let dx = dx.add(r * n + i);
+            dx.write(dx.read() + p * scale);
+        }
+    }
+}
+
+/// The number of elements in a k-quant super-block.
+pub const QK_K: usize = 256;
+
+/// Writes the `QK_K` elements of the k-quant super-block at `x` to `y`.
+pub type DecodeSuperBlock = unsafe fn(x: *const u8, y: *mut f32);
+
+unsafe fn read_f16(x: *const u8) -> f32 {
+    f16::from_le_bytes([x.read(), x.add(1).read()]).to_f32()
+}
+
+/// The 6-bit scale and minimum `j` of the eight packed into the 12 bytes at
+/// `s` by Q4_K and Q5_K.
+unsafe fn scale_min_k4(j: usize, s: *const u8) -> (f32, f32) {
+    let s = |i: usize| s.add(i).read();
+    let (sc, m) = if j < 4 {
+        (s(j) & 63, s(j + 4) & 63)
+    } else {
+        (
+            (s(j + 4) & 0xf) | ((s(j - 4) >> 6) << 4),
+            (s(j + 4) >> 4) | ((s(j) >> 6) << 4),
+        )
+    };
+    (sc as f32, m as f32)
+}
+
+/// Q2_K: 16 sub-blocks of 16 2-bit values, each with a 4-bit scale and
+/// minimum, followed by the `f16` scales of those. 84 bytes.
+pub unsafe fn decode_q2_k(x: *const u8, y: *mut f32) {
+    let (scales, qs) = (x, x.add(16));
+    let (d, dmin) = (read_f16(x.add(80)), read_f16(x.add(82)));
+
+    let mut y = y;
+    let mut is = 0;
+    for n in 0..QK_K / 128 {
+        let q = qs.add(32 * n);
+        for shift in [0, 2, 4, 6] {
+            for half in [0, 16] {
+                let sc = scales.add(is).read();
+                is += 1;
+                let (dl, ml) = (d * (sc & 0xf) as f32, dmin * (sc >> 4) as f32);
+                for l in 0..16 {
+                    let q = (q.add(half + l).read() >> shift) & 3;
+                    y.write(dl * q as f32 - ml);
+                    y = y.add(1);
+                }
+            }
+        }
+    }
+}
+
+/// Q3_K: 256 2-bit values with their high bits in a separate mask, and 16
+/// 6-bit scales offset by 32, followed by their `f16` scale. 110 bytes.
+pub unsafe fn decode_q3_k(x: *const u8, y: *mut f32) {
+    let (hmask, qs, packed) = (x, x.add(32), x.add(96));
+    let d = read_f16(x.add(108));
+
+    // The low 4 bits of the scales are in the first 8 bytes, scales 8 to 15
+    // in the high nibbles, and the high 2 bits in the last 4.
+    let scale = |k: usize| {
+        let low = (packed.add(k % 8).read() >> (4 * (k / 8))) & 0xf;
+        let high = (packed.add(8 + k % 4).read() >> (2 * (k / 4))) & 3;
+        (low | (high << 4)) as i32 - 32
+    };
+
+    let mut y = y;
+    let (mut is, mut m) = (0, 1u8);
+    for n in 0..QK_K / 128 {
+        let q = qs.add(32 * n);
+        for shift in [0, 2, 4, 6] {
+            for half in [0, 16] {
+                let dl = d * scale(is) as f32;
+                is += 1;
+                for l in 0..16 {
+                    let low = ((q.add(half + l).read() >> shift) & 3) as i32;
+                    let high = if hmask.add(half + l).read() & m != 0 {
+                        0
+                    } else {
+                        4
+                    };
+                    y.write(dl * (low - high) as f32);
+                    y = y.add(1);
+                }
+            }
+            m <<= 1;
+        }
+    }
+}
+
+/// Q4_K: 8 sub-blocks of 32 4-bit values, each with a 6-bit scale and
+/// minimum, after the `f16` scales of those. 144 bytes.
+pub unsafe fn decode_q4_k(x: *const u8, y: *mut f32) {
+    let (d, dmin) = (read_f16(x), read_f16(x.add(2)));
+    let (scales, qs) = (x.add(4), x.add(16));
+
+    let mut y = y;
+    for j in 0..QK_K / 64 {
+        let q = qs.add(32 * j);
+        for (is, shift) in [(2 * j, 0), (2 * j + 1, 4)] {
+            let (sc, m) = scale_min_k4(is, scales);
+            let (dl, ml) = (d * sc, dmin * m);
+            for l in 0..32 {
+                y.write(dl * ((q.add(l).read() >> shift) & 0xf) as f32 - ml);
+                y = y.add(1);
+            }
+        }
+    }
+}
+
+/// Q5_K: Q4_K with a fifth bit for each value. 176 bytes.
+pub unsafe fn decode_q5_k(x: *const u8, y: *mut f32) {
+    let (d, dmin) = (read_f16(x), read_f16(x.add(2)));
+    let (scales, qh, qs) = (x.add(4), x.add(16), x.add(48));
+
+    let mut y = y;
+    for j in 0..QK_K / 64 {
+        let q = qs.add(32 * j);
+        for (is, shift) in [(2 * j, 0), (2 * j + 1, 4)] {
+            let (sc, m) = scale_min_k4(is, scales);
+            let (dl, ml) = (d * sc, dmin * m);
+            for l in 0..32 {
+                let low = (q.add(l).read() >> shift) & 0xf;
+                let high = (qh.add(l).read() >> is) & 1;
+                y.write(dl * (low | (high << 4)) as f32 - ml);
+                y = y.add(1);
+            }
+        }
+    }
+}
+
+/// Q6_K: 16 sub-blocks of 16 6-bit values offset by 32, split into their
+/// low 4 and high 2 bits, each with an 8-bit scale, followed by the `f16`
+/// scale of those. 210 bytes.
+pub unsafe fn decode_q6_k(x: *const u8, y: *mut f32) {
+    let d = read_f16(x.add(208));
+
+    for n in 0..QK_K / 128 {
+        let (ql, qh, sc) = (
+            x.add(64 * n),
+            x.add(128 + 32 * n),
+            x.add(192 + 8 * n) as *const i8,
+        );
+        let y = y.add(128 * n);
+        for l in 0..32 {
+            let is = l / 16;
+            let (ql0, ql1, qh) = (ql.add(l).read(), ql.add(l + 32).read(), qh.add(l).read());
+            let q = [
+                (ql0 & 0xf) | ((qh & 3) << 4),
+                (ql1 & 0xf) | (((qh >> 2) & 3) << 4),
+                (ql0 >> 4) | (((qh >> 4) & 3) << 4),
+                (ql1 >> 4) | (((qh >> 6) & 3) << 4),
+            ];
+            for (k, q) in q.into_iter().enumerate() {
+                let scale = sc.add(is + 2 * k).read() as f32;
+                y.add(l + 32 * k).write(d * scale * (q as i32 - 32) as f32);
+            }
+        }
+    }
+}
+
+/// Dequantizes `n` elements of k-quant super-blocks of `block_size` bytes.
+pub unsafe fn dequantize_k_raw(
+    x: *const u8,
+    y: *mut f32,
+    n: usize,
+    block_size: usize,
+    decode: DecodeSuperBlock,
+) {
+    assert_eq!(n % QK_K, 0);
+    for i in 0..n / QK_K {
+        decode(x.add(i * block_size), y.add(i * QK_K));
+    }
+}
+
+/// The dot product of `n` k-quantized elements with `n` `f32`s, decoding a
+/// super-block at a time.
+pub unsafe fn dot_k_raw_f32(
+    x: *const u8,
+    y: *const f32,
+    n: usize,
+    block_size: usize,
+    decode: DecodeSuperBlock,
+) -> f32 {
+    assert_eq!(n % QK_K, 0);
+    let mut block = [0.0; QK_K];
+    let mut sum = 0.0;
+    for i in 0..n / QK_K {
+        decode(x.add(i * block_size), block.as_mut_ptr());
+        sum += dotv_raw_f32(block.as_ptr(), y.add(i * QK_K), QK_K);
+    }
+    sum
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assert_tensor_close;
+    use crate::rng::Rng;
+    use crate::tensor::Tensor;
+
+    /// A random shape with small batch dimensions, where the last two
+    /// dimensions are biased towards sizes around the chunk boundaries.
+    fn random_shape(rng: &mut Rng, max: usize) -> [usize; MAX_DIMS] {
+        let mut shape = [rng.usize(1..4), rng.usize(1..4), 1, 1];
+        for x in shape[2..].iter_mut() {
+            *x = match rng.usize(0..4) {
+                0 => rng.usize(1..max + 1),
+                _ => [1, 2, 3, 15, 16, 17, 31, 33, 63, 65][rng.usize(0..10)].min(max),
+            };
+        }
+        shape
+    }
+
+    fn to_f64(x: &[f16]) -> Vec<f64> {
+        x.iter().map(|x| x.to_f64()).collect()
+    }
+
+    fn tensor(data: Vec<f64>, shape: [usize; MAX_DIMS]) -> Tensor<f32, MAX_DIMS> {
+        Tensor::new(data.into_iter().map(|x| x as f32).collect(), shape)
+    }
+
+    fn tensor_f16(data: Vec<f16>, shape: [usize; MAX_DIMS]) -> Tensor<f32, MAX_DIMS> {
+        tensor(to_f64(&data), shape)
+    }
+
+    fn ref_dot(a: &[f64], b: &[f64]) -> f64 {
+        a.iter().zip(b).map(|(a, b)| a * b).sum()
+    }
+
+    /// a: [.., m, n], bt: [.., p, n] -> [.., m, p]
+    fn ref_generic_dot(a: &[f64], bt: &[f64], a_shape: [usize; MAX_DIMS], p: usize) -> Vec<f64> {
+        let [b1, b0, m, n] = a_shape;
+        let mut c = vec![0.0; b1 * b0 * m * p];
+        for batch in 0..b1 * b0 {
+            for i in 0..m {
+                for j in 0..p {
+                    let a = &a[(batch * m + i) * n..][..n];
+                    let b = &bt[(batch * p + j) * n..][..n];
+                    c[(batch * m + i) * p + j] = ref_dot(a, b);
+                }
+            }
+        }
+        c
+    }
+
+    #[test]
+    fn dotv() {
+        let mut rng = Rng(1);
+        for n in [0, 1, 15, 16, 17, 31, 32, 33, 100, 1000] {
+            let a = rng.vec_f16(n);
+            let b = rng.vec_f16(n);
+            let b32 = rng.vec_f32(n);
+            let expected = ref_dot(&to_f64(&a), &to_f64(&b));
+
+            let x = unsafe { dotv_raw_f16(a.as_ptr(), b.as_ptr(), n) };
+            assert_tensor_close!(
+                Tensor::from(vec![x]),
+                Tensor::from(vec![expected as f32]),
+                rtol = 1e-4,
+                atol = 1e-4
+            );
+
+            let x = unsafe { dotv_raw_f16_f32(a.as_ptr(), b32.as_ptr(), n) };
+            let expected = ref_dot(
+                &to_f64(&a),
+                &b32.iter().map(|&x| x as f64).collect::<Vec<_>>(),
+            );
+            assert_tensor_close!(
+                Tensor::from(vec![x]),
+                Tensor::from(vec![expected as f32]),
+                rtol = 1e-4,
+                atol = 1e-4
+            );
+
+            let a32 = rng.vec_f32(n);
+            let x = unsafe { dotv_raw_f32(a32.as_ptr(), b32.as_ptr(), n) };
+            let expected = a32
+                .iter()
+                .zip(&b32)
+                .map(|(&a, &b)| a as f64 * b as f64)
+                .sum::<f64>();
+            assert_tensor_close!(
+                Tensor::from(vec![x]),
+                Tensor::from(vec![expected as f32]),
+                rtol = 1e-4,
+                atol = 1e-4
+            );
+        }
+    }
+
+    #[test]
+    fn generic_dot() {
+        let mut rng = Rng(2);
+        for _ in 0..20 {
+            let a_shape = random_shape(&mut rng, 70);
+            let p = rng.usize(1..41);
+            let bt_shape = [a_shape[0], a_shape[1], p, a_shape[3]];
+            let c_shape = [a_shape[0], a_shape[1], a_shape[2], p];
+            let c_len = c_shape.iter().product();
+
+            let a = rng.vec_f16(a_shape.iter().product());
+            let bt = rng.vec_f16(bt_shape.iter().product());
+            let expected = tensor(
+                ref_generic_dot(&to_f64(&a), &to_f64(&bt), a_shape, p),
+                c_shape,
+            );
+
+            let mut c = vec![f16::ZERO; c_len];
+            unsafe { generic_dot_f16(a.as_ptr(), bt.as_ptr(), c.as_mut_ptr(), a_shape, bt_shape) };
+            assert_tensor_close!(tensor_f16(c, c_shape), expected, rtol = 1e-2, atol = 1e-2);
+
+            let bt32 = rng.vec_f32(bt_shape.iter().product());
+            let bt32_f64 = bt32.iter().map(|&x| x as f64).collect::<Vec<_>>();
+            let expected = tensor(ref_generic_dot(&to_f64(&a), &bt32_f64, a_shape, p), c_shape);
+
+            let mut c = vec![f16::ZERO; c_len];
+            unsafe {
+                generic_dot_f16_f32(a.as_ptr(), bt32.as_ptr(), c.as_mut_ptr(), a_shape, bt_shape)
+            };
+            assert_tensor_close!(tensor_f16(c, c_shape), expected, rtol = 1e-2, atol = 1e-2);
+
+            let a32 = rng.vec_f32(a_shape.iter().product());
+            let a32_f64 = a32.iter().map(|&x| x as f64).collect::<Vec<_>>();
+            let expected = tensor(ref_generic_dot(&a32_f64, &bt32_f64, a_shape, p), c_shape);
+
+            let mut c = vec![0.0; c_len];
+            unsafe {
+                generic_dot_f32(
+                    a32.as_ptr(),
+                    bt32.as_ptr(),
+                    c.as_mut_ptr(),
+                    a_shape,
+                    bt_shape,
+                )
+            };
+            assert_tensor_close!(Tensor::new(c, c_shape), expected, rtol = 1e-4, atol = 1e-4);
+        }
+    }
+
+    #[test]
+    fn silu() {
+        let mut rng = Rng(3);
+        for _ in 0..20 {
+            let shape = random_shape(&mut rng, 70);
+            let a = rng.vec_f16(shape.iter().product());
+            let expected = to_f64(&a)
+                .into_iter()
+                .map(|x| x / (1.0 + (-x).exp()))
+                .collect();
+
+            let mut b = vec![f16::ZERO; a.len()];
+            unsafe { silu_raw_f16(a.as_ptr(), b.as_mut_ptr(), shape) };
+            assert_tensor_close!(
+                tensor_f16(b, shape),
+                tensor(expected, shape),
+                rtol = 1e-3,
+                atol = 1e-3
+            );
+        }
+    }
+
+    #[test]
+    fn rms_norm() {
+        let mut rng = Rng(4);
+        for _ in 0..20 {
+            let shape = random_shape(&mut rng, 70);
+            let a = rng.vec_f16(shape.iter().product());
+            let expected = to_f64(&a)
+                .chunks_exact(shape[3])
+                .flat_map(|row| {
+                    let rms = (ref_dot(row, row) / row.len() as f64).sqrt();
+                    row.iter().map(move |x| x / rms)
+                })
+                .collect();
+
+            let mut b = vec![f16::ZERO; a.len()];
+            unsafe { rms_norm_f16(a.as_ptr(), b.as_mut_ptr(), shape) };
+            assert_tensor_close!(
+                tensor_f16(b, shape),
+                tensor(expected, shape),
+                rtol = 1e-2,
+                atol = 1e-2
+            );
+        }
+    }
+
+    #[test]
+    fn flash_attn() {
+        let mut rng = Rng(5);
+        for _ in 0..10 {
+            let n = rng.usize(1..41);
+            let d = rng.usize(1..71);
+
+            let q = rng.vec_f16(n * d);
+            let k = rng.vec_f16(n * d);
+            let vt = rng.vec_f16(d * n);
+            let (q64, k64, vt64) = (to_f64(&q), to_f64(&k), to_f64(&vt));
+
+            let scale = 1.0 / (d as f64).sqrt();
+            let mut expected = vec![0.0; n * d];
+            for i in 0..n {
+                let s = (0..n)
+                    .map(|j| scale * ref_dot(&q64[i * d..][..d], &k64[j * d..][..d]))
+                    .collect::<Vec<_>>();
+                let max = s.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+                let sum = s.iter().map(|x| (x - max).exp()).sum::<f64>();
+                for j in 0..d {
+                    expected[i * d + j] = (0..n)
+                        .map(|t| (s[t] - max).exp() / sum * vt64[j * n + t])
+                        .sum();
+                }
+            }
+
+            let mut o = vec![f16::ZERO; n * d];
+            unsafe {
+                flash_attn_raw_f16(
+                    q.as_ptr(),
+                    k.as_ptr(),
+                    vt.as_ptr(),
+                    o.as_mut_ptr(),
+                    n,
+                    d,
+                    d,
+                    d,
+                    n,
+                    d,
+                )
+            };
+            assert_tensor_close!(
+                tensor_f16(o, [1, 1, n, d]),
+                tensor(expected, [1, 1, n, d]),
+                rtol = 1e-2,
+                atol = 1e-2
+            );
+        }
+    }
+
+    #[test]
+    fn repeat() {
+        let mut rng = Rng(6);
+        for _ in 0..20 {
+            let src_shape = random_shape(&mut rng, 20);
+            let mut reps = [1; MAX_DIMS];
+            for r in reps.iter_mut() {
+                *r = rng.usize(1..4);
+            }
+            let dst_shape = [0, 1, 2, 3].map(|i| src_shape[i] * reps[i]);
+            let src = rng.vec_f32(src_shape.iter().product());
+
+            let mut expected = Vec::with_capacity(dst_shape.iter().product());
+            for i in 0..dst_shape[0] {
+                for j in 0..dst_shape[1] {
+                    for k in 0..dst_shape[2] {
+                        for l in 0..dst_shape[3] {
+                            let idx = [i, j, k, l];
+                            let src_idx = [0, 1, 2, 3].map(|a| idx[a] % src_shape[a]);
+                            let offset = ((src_idx[0] * src_shape[1] + src_idx[1]) * src_shape[2]
+                                + src_idx[2])
+                                * src_shape[3]
+                                + src_idx[3];
+                            expected.push(src[offset]);
+                        }
+                    }
+                }
+            }
+
+            let mut dst = vec![0.0; expected.len()];
+            unsafe { super::repeat(src.as_ptr(), dst.as_mut_ptr(), src_shape, dst_shape) };
+            assert_eq!(dst, expected);
+        }
+    }
+
+    #[test]
+    fn reductions() {
+        let mut rng = Rng(7);
+        for _ in 0..20 {
+            let shape = random_shape(&mut rng, 40);
+            let axis = rng.usize(0..4);
+            let a = rng.vec_f16(shape.iter().product());
+            let a64 = to_f64(&a);
+
+            let (outer, len, inner) = split_axis(shape, axis);
+            let lane = |o: usize, i: usize| {
+                (0..len)
+                    .map(|l| a64[(o * len + l) * inner + i])
+                    .collect::<Vec<_>>()
+            };
+
+            let mut out_shape = shape;
+            out_shape[axis] = 1;
+            let mut sum = Vec::new();
+            let mut max = Vec::new();
+            let mut argmax = Vec::new();
+            for o in 0..outer {
+                for i in 0..inner {
+                    let lane = lane(o, i);
+                    sum.push(lane.iter().sum::<f64>());
+                    max.push(lane.iter().copied().fold(f64::NEG_INFINITY, f64::max));
+                    argmax.push(
+                        (0..len).fold(0, |best, l| if lane[l] > lane[best] { l } else { best }),
+                    );
+                }
+            }
+
+            let mut dst = vec![f16::ZERO; outer * inner];
+            unsafe { sum_raw(a.as_ptr(), dst.as_mut_ptr(), shape, axis) };
+            assert_tensor_close!(
+                tensor_f16(dst.clone(), out_shape),
+                tensor(sum, out_shape),
+                rtol = 1e-2,
+                atol = 1e-2
+            );
+
+            unsafe { max_raw(a.as_ptr(), dst.as_mut_ptr(), shape, axis) };
+            assert_tensor_close!(
+                tensor_f16(dst, out_shape),
+                tensor(max, out_shape),
+                rtol = 0.0,
+                atol = 0.0
+            );
+
+            let mut dst = vec![0; outer * inner];
+            unsafe { argmax_raw(a.as_ptr(), dst.as_mut_ptr(), shape, axis) };
+            assert_eq!(dst, argmax);
+
+            let k = rng.usize(1..len + 1);
+            let mut values = vec![f16::ZERO; outer * k * inner];
+            let mut indices = vec![0; outer * k * inner];
+            unsafe {
+                topk_raw(
+                    a.as_ptr(),
+                    values.as_mut_ptr(),
+                    indices.as_mut_ptr(),
+                    shape,
+                    axis,
+                    k,
+                )
+            };
+            for o in 0..outer {
+                for i in 0..inner {
+                    let mut expected = lane(o, i).into_iter().enumerate().collect::<Vec<_>>();
+                    expected.sort_by(|(_, x), (_, y)| y.total_cmp(x));
+                    for j in 0..k {
+                        assert_eq!(indices[(o * k + j) * inner + i], expected[j].0);
+                        assert_eq!(values[(o * k + j) * inner + i].to_f64(), expected[j].1);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,229 @@
+use std::f32::consts::PI;
+
+use crate::autograd::{Gradients, Tape, Var};
+
+/// Updates parameters recorded as leaves on a [`Tape`] from their gradients.
+pub trait Optimizer {
+    /// Takes one step with learning rate `lr` on every parameter in `params`
+    /// that has a gradient. `params` should be the same, in the same order,
+    /// on every step, since any state is kept per position.
+    fn step(&mut self, tape: &mut Tape, params: &[Var], grads: &Gradients, lr: f32);
+}
+
+/// Stochastic gradient descent, with optional momentum.
+#[derive(Default)]
+pub struct Sgd {
+    pub momentum: f32,
+    velocity: Vec<Vec<f32>>,
+}
+
+impl Sgd {
+    pub fn new(momentum: f32) -> Self {
+        Self {
+            momentum,
+            velocity: Vec::new(),
+        }
+    }
+}
+
+impl Optimizer for Sgd {
+    fn step(&mut self, tape: &mut Tape, params: &[Var], grads: &Gradients, lr: f32) {
+        self.velocity.resize_with(params.len(), Vec::new);
+
+        for (&p, velocity) in params.iter().zip(&mut self.velocity) {
+            let Some(g) = grads.get(p) else {
+                continue;
+            };
+            let g = g.as_slice();
+            if velocity.is_empty() {
+                velocity.resize(g.len(), 0.0);
+            }
+
+            let p = tape.value_mut(p).make_mut();
+            for ((p, &g), v) in p.iter_mut().zip(g).zip(velocity.iter_mut()) {
+                *v = self.momentum * *v + g;
+                *p -= lr * *v;
+            }
+        }
+    }
+}
+
+/// Adam with decoupled weight decay (Loshchilov & Hutter, 2019).
+pub struct AdamW {
+    pub beta1: f32,
+    pub beta2: f32,
+    pub eps: f32,
+    pub weight_decay: f32,
+    steps: i32,
+    /// The running first and second moments of each parameter's gradient.
+    moments: Vec<(Vec<f32>, Vec<f32>)>,
+}
+
+impl AdamW {
+    pub fn new(weight_decay: f32) -> Self {
+        Self {
+            beta1: 0.9,
+            beta2: 0.999,
+            eps: 1e-8,
+            weight_decay,
+            steps: 0,
+            moments: Vec::new(),
+        }
+    }
+}
+
+impl Optimizer for AdamW {
+    fn step(&mut self, tape: &mut Tape, params: &[Var], grads: &Gradients, lr: f32) {
+        self.moments.resize_with(params.len(), Default::default);
+        self.steps += 1;
+        let bias1 = 1.0 - self.beta1.powi(self.steps);
+        let bias2 = 1.0 - self.beta2.powi(self.steps);
+
+        for (&p, (m, v)) in params.iter().zip(&mut self.moments) {
+            let Some(g) = grads.get(p) else {
+                continue;
+            };
+            let g = g.as_slice();
+            if m.is_empty() {
+                m.resize(g.len(), 0.0);
+                v.resize(g.len(), 0.0);
+            }
+
+            let p = tape.value_mut(p).make_mut();
+            for (i, p) in p.iter_mut().enumerate() {
+                m[i] = self.beta1 * m[i] + (1.0 - self.beta1) * g[i];
+                v[i] = self.beta2 * v[i] + (1.0 - self.beta2) * g[i] * g[i];
+
+                let m_hat = m[i] / bias1;
+                let v_hat = v[i] / bias2;
+                *p -= lr * (m_hat / (v_hat.sqrt() + self.eps) + self.weight_decay * *p);
+            }
+        }
+    }
+}
+
+/// Scales the gradients of `params` down so their combined L2 norm is at
+/// most `max_norm`. Returns the norm before clipping.
+pub fn clip_grad_norm(grads: &mut Gradients, params: &[Var], max_norm: f32) -> f32 {
+    let norm = params
+        .iter()
+        .filter_map(|&p| grads.get(p))
+        .flat_map(|g| g.as_slice())
+        .map(|g| g * g)
+        .sum::<f32>()
+        .sqrt();
+
+    if norm > max_norm {
+        let scale = max_norm / norm;
+        for &p in params {
+            if let Some(g) = grads.get_mut(p) {
+                g.map_inplace(|g| g * scale);
+            }
+        }
+    }
+    norm
+}
+
+/// A learning rate that warms up linearly from zero to `max_lr` over
+/// `warmup_steps`, then follows a cosine down to `min_lr` at `total_steps`,
+/// and stays there.
+pub struct CosineSchedule {
+    pub max_lr: f32,
+    pub min_lr: f32,
+    pub warmup_steps: usize,
+    pub total_steps: usize,
+}
+
+impl CosineSchedule {
+    /// The learning rate for the zero-based `step`.
+    pub fn lr(&self, step: usize) -> f32 {
+        if step < self.warmup_steps {
+            return self.max_lr * (step + 1) as f32 / self.warmup_steps as f32;
+        }
+        if step >= self.total_steps {
+            return self.min_lr;
+        }
+
+        let progress =
+            (step - self.warmup_steps) as f32 / (self.total_steps - self.warmup_steps) as f32;
+        let cosine = 0.5 * (1.0 + (PI * progress).cos());
+        self.min_lr + (self.max_lr - self.min_lr) * cosine
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tensor::Tensor;
+
+    /// Minimizes `sum((x - target)²)` from zero and returns the final `x`.
+    fn minimize(optimizer: &mut impl Optimizer, lr: f32, steps: usize) -> Vec<f32> {
+        let mut tape = Tape::new();
+        let x = tape.leaf(Tensor::zeros([1, 3]));
+        let neg_target = tape.leaf(Tensor::new(vec![-1.0, 2.0, -3.0], [1, 3]));
+
+        for _ in 0..steps {
+            let d = tape.add(x, neg_target);
+            let d2 = tape.mul(d, d);
+            let loss = tape.sum(d2);
+            let grads = tape.backward(loss);
+            optimizer.step(&mut tape, &[x], &grads, lr);
+            tape.clear_ops();
+        }
+        tape.value(x).to_vec()
+    }
+
+    #[test]
+    fn converges() {
+        let expected = [1.0, -2.0, 3.0];
+        for (x, expected) in minimize(&mut Sgd::new(0.5), 0.1, 100).iter().zip(expected) {
+            assert!((x - expected).abs() < 1e-3, "sgd: {x} != {expected}");
+        }
+        for (x, expected) in minimize(&mut AdamW::new(0.0), 0.1, 500)
+            .iter()
+            .zip(expected)
+        {
+            assert!((x - expected).abs() < 1e-2, "adamw: {x} != {expected}");
+        }
+
+        // Weight decay pulls the solution towards zero.
+        let decayed = minimize(&mut AdamW::new(0.5), 0.1, 500);
+        assert!(decayed
+            .iter()
+            .zip(expected)
+            .all(|(x, e)| x.abs() < e.abs() - 0.05));
+    }
+
+    #[test]
+    fn clipping() {
+        let mut tape = Tape::new();
+        let x = tape.leaf(Tensor::new(vec![3.0, 4.0], [1, 2]));
+        let y = tape.leaf(Tensor::new(vec![0.0], [1, 1]));
+        let loss = tape.sum(x);
+
+        let mut grads = tape.backward(loss);
+        assert_eq!(clip_grad_norm(&mut grads, &[x, y], 1.0), 2f32.sqrt());
+        let clipped = grads.get(x).unwrap().to_vec();
+        assert!((clipped[0] - 0.5f32.sqrt()).abs() < 1e-6);
+
+        // Gradients within the limit are left alone.
+        assert!((clip_grad_norm(&mut grads, &[x, y], 10.0) - 1.0).abs() < 1e-6);
+        assert_eq!(grads.get(x).unwrap().to_vec(), clipped);
+    }
+
+    #[test]
+    fn cosine_schedule() {
+        let schedule = CosineSchedule {
+            max_lr: 1.0,
+            min_lr: 0.1,
+            warmup_steps: 10,
+            total_steps: 110,
+        };
+        assert_eq!(schedule.lr(0), 0.1);
+        assert_eq!(schedule.lr(9), 1.0);
+        assert_eq!(schedule.lr(10), 1.0);
+        assert!((schedule.lr(60) - 0.55).abs() < 1e-6);
+        assert_eq!(schedule.lr(110), 0.1);
+        assert_eq!(schedule.lr(1000), 0.1);
+    }
+}
@@ -0,0 +1,698 @@
+//! GGML's block quantization: each block of `BLOCK_LEN` consecutive elements
+//! of a row is stored as small integers sharing an `f16` scale, and for the
+//! `_1` types an `f16` minimum.
+//!
+//! The k-quants group `SUPER_BLOCK_LEN` elements into a super-block of
+//! sub-blocks, whose scales are themselves quantized against an `f16` scale.
+//! They can be read and used, but not written.
+
+use half::f16;
+
+use crate::ggml::ScalarType;
+use crate::ops::{self, DecodeSuperBlock};
+
+/// The number of elements in a block. Rows must be a whole number of blocks.
+pub const BLOCK_LEN: usize = 32;
+/// The number of elements in a k-quant super-block.
+pub const SUPER_BLOCK_LEN: usize = ops::QK_K;
+
+impl ScalarType {
+    pub fn is_quantized(self) -> bool {
+        !matches!(self, Self::F32 | Self::F16 | Self::BF16)
+    }
+
+    fn is_k_quant(self) -> bool {
+        matches!(
+            self,
+            Self::Q2_K | Self::Q3_K | Self::Q4_K | Self::Q5_K | Self::Q6_K
+        )
+    }
+
+    /// The number of elements stored together, which rows must be a whole
+    /// number of.
+    pub fn block_len(self) -> usize {
+        match self {
+            Self::F32 | Self::F16 | Self::BF16 => 1,
+            ty if ty.is_k_quant() => SUPER_BLOCK_LEN,
+            _ => BLOCK_LEN,
+        }
+    }
+
+    /// The number of bytes `len` elements take up. `len` must be a multiple
+    /// of the [`block_len`](Self::block_len).
+    pub fn size_of(self, len: usize) -> usize {
+        let block_size = match self {
+            Self::F32 => 4,
+            Self::F16 | Self::BF16 => 2,
+            Self::Q4_0 => 2 + BLOCK_LEN / 2,
+            Self::Q4_1 => 4 + BLOCK_LEN / 2,
+            Self::Q5_0 => 6 + BLOCK_LEN / 2,
+            Self::Q5_1 => 8 + BLOCK_LEN / 2,
+            Self::Q8_0 => 2 + BLOCK_LEN,
+            Self::Q2_K => 84,
+            Self::Q3_K => 110,
+            Self::Q4_K => 144,
+            Self::Q5_K => 176,
+            Self::Q6_K => 210,
+        };
+        let block_len = self.block_len();
+        assert_eq!(
+            len % block_len,
+            0,
+            "{len} elements are not a whole number of blocks"
+        );
+        len / block_len * block_size
+    }
+
+    fn decode_super_block(self) -> DecodeSuperBlock {
+        match self {
+            Self::Q2_K => ops::decode_q2_k,
+            Self::Q3_K => ops::decode_q3_k,
+            Self::Q4_K => ops::decode_q4_k,
+            Self::Q5_K => ops::decode_q5_k,
+            Self::Q6_K => ops::decode_q6_k,
+            ty => panic!("{ty:?} is not a k-quant"),
+        }
+    }
+}
+
+/// Block-quantized elements, laid out as GGML stores them.
+pub struct Quantized {
+    ty: ScalarType,
+    len: usize,
+    bytes: Vec<u8>,
+}
+
+impl Quantized {
+    /// Quantizes `x`, whose length must be a multiple of [`BLOCK_LEN`].
+    ///
+    /// Panics if `ty` is a k-quant.
+    pub fn new(ty: ScalarType, x: &[f32]) -> Self {
+        assert!(
+            ty.is_quantized() && !ty.is_k_quant(),
+            "can't quantize to {ty:?}"
+        );
+        let mut bytes = vec![0; ty.size_of(x.len())];
+        for (x, block) in x
+            .chunks_exact(BLOCK_LEN)
+            .zip(bytes.chunks_exact_mut(ty.size_of(BLOCK_LEN)))
+        {
+            quantize_block(ty, x.try_into().unwrap(), block);
+        }
+        Self {
+            ty,
+            len: x.len(),
+            bytes,
+        }
+    }
+
+    /// Quantizes `x` as rows of `importance.len()` elements, choosing each
+    /// block's scale to keep the error down in the columns `importance`
+    /// weights most, at the cost of the rest. `importance` must be a whole
+    /// number of blocks, and `x` a whole number of rows.
+    ///
+    /// Panics if `ty` is a k-quant.
+    pub fn with_importance(ty: ScalarType, x: &[f32], importance: &[f32]) -> Self {
+        assert!(
+            ty.is_quantized() && !ty.is_k_quant(),
+            "can't quantize to {ty:?}"
+        );
+        let row_len = importance.len();
+        assert!(
+            row_len > 0 && row_len.is_multiple_of(BLOCK_LEN) && x.len().is_multiple_of(row_len)
+        );
+
+        let block_size = ty.size_of(BLOCK_LEN);
+        let mut bytes = vec![0; ty.size_of(x.len())];
+        for (x, row) in x
+            .chunks_exact(row_len)
+            .zip(bytes.chunks_exact_mut(ty.size_of(row_len)))
+        {
+            let blocks = x
+                .chunks_exact(BLOCK_LEN)
+                .zip(importance.chunks_exact(BLOCK_LEN));
+            for ((x, w), block) in blocks.zip(row.chunks_exact_mut(block_size)) {
+                quantize_block_weighted(ty, x.try_into().unwrap(), w.try_into().unwrap(), block);
+            }
+        }
+        Self {
+            ty,
+            len: x.len(),
+            bytes,
+        }
+    }
+
+    /// Wraps `len` elements' worth of blocks, as read from a file.
+    pub fn from_bytes(ty: ScalarType, len: usize, bytes: Vec<u8>) -> Self {
+        assert!(ty.is_quantized(), "{ty:?} is not a quantized type");
+        assert_eq!(bytes.len(), ty.size_of(len));
+        Self { ty, len, bytes }
+    }
+
+    pub fn scalar_type(&self) -> ScalarType {
+        self.ty
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn dequantize(&self) -> Vec<f32> {
+        let mut out = vec![0.0; self.len];
+        if self.ty.is_k_quant() {
+            let block_size = self.ty.size_of(SUPER_BLOCK_LEN);
+            let decode = self.ty.decode_super_block();
+            unsafe {
+                ops::dequantize_k_raw(
+                    self.bytes.as_ptr(),
+                    out.as_mut_ptr(),
+                    self.len,
+                    block_size,
+                    decode,
+                )
+            };
+            return out;
+        }
+
+        let block_size = self.ty.size_of(BLOCK_LEN);
+        for (block, out) in self
+            .bytes
+            .chunks_exact(block_size)
+            .zip(out.chunks_exact_mut(BLOCK_LEN))
+        {
+            dequantize_block(self.ty, block, out.try_into().unwrap());
+        }
+        out
+    }
+
+    /// The dot product of `x` with the `x.len()` elements starting at
+    /// `offset`, which must both be whole numbers of blocks.
+    pub fn dot(&self, offset: usize, x: &[f32]) -> f32 {
+        let block_len = self.ty.block_len();
+        assert!(offset.is_multiple_of(block_len) && x.len().is_multiple_of(block_len));
+        assert!(offset + x.len() <= self.len);
+        let bytes = &self.bytes[self.ty.size_of(offset)..];
+
+        if self.ty.is_k_quant() {
+            let block_size = self.ty.size_of(SUPER_BLOCK_LEN);
+            let decode = self.ty.decode_super_block();
+            return unsafe {
+                ops::dot_k_raw_f32(bytes.as_ptr(), x.as_ptr(), x.len(), block_size, decode)
+            };
+        }
+
+        let block_size = self.ty.size_of(BLOCK_LEN);
+        let mut y = [0.0; BLOCK_LEN];
+        let mut sum = 0.0;
+        for (block, x) in bytes
+            .chunks_exact(block_size)
+            .zip(x.chunks_exact(BLOCK_LEN))
+        {
+            dequantize_block(self.ty, block, &mut y);
+            sum += unsafe { ops::dotv_raw_f32(y.as_ptr(), x.as_ptr(), BLOCK_LEN) };
+        }
+        sum
+    }
+}
+
+/// The element of `x` with the largest magnitude, keeping its sign.
+fn signed_max(x: &[f32]) -> f32 {
+    x.iter()
+        .copied()
+        .fold(0.0, |max, x| if x.abs() > max.abs() { x } else { max })
+}
+
+fn min_max(x: &[f32]) -> (f32, f32) {
+    x.iter()
+        .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), &x| {
+            (min.min(x), max.max(x))
+        })
+}
+
+fn inverse(d: f32) -> f32 {
+    if d == 0.0 {
+        0.0
+    } else {
+        1.0 / d
+    }
+}
+
+/// Stores 4-bit values, the first half of the block in the low nibbles and
+/// the second half in the high nibbles, and returns the fifth bits, if any,
+/// packed into a `u32`.
+fn pack_nibbles(q: &[u8; BLOCK_LEN], out: &mut [u8]) -> u32 {
+    let mut high = 0;
+    for j in 0..BLOCK_LEN / 2 {
+        let (a, b) = (q[j], q[j + BLOCK_LEN / 2]);
+        out[j] = (a & 0xf) | ((b & 0xf) << 4);
+        high |= ((a as u32 >> 4) & 1) << j;
+        high |= ((b as u32 >> 4) & 1) << (j + BLOCK_LEN / 2);
+    }
+    high
+}
+
+fn unpack_nibbles(qs: &[u8], high: u32) -> [u8; BLOCK_LEN] {
+    let mut q = [0; BLOCK_LEN];
+    for j in 0..BLOCK_LEN / 2 {
+        q[j] = (qs[j] & 0xf) | ((((high >> j) & 1) as u8) << 4);
+        q[j + BLOCK_LEN / 2] = (qs[j] >> 4) | ((((high >> (j + BLOCK_LEN / 2)) & 1) as u8) << 4);
+    }
+    q
+}
+
+fn read_f16(bytes: &[u8], offset: usize) -> f32 {
+    f16::from_le_bytes([bytes[offset], bytes[offset + 1]]).to_f32()
+}
+
+/// The stored value that element values are counted from: blocks hold
+/// `(q - offset) * d + min`.
+fn offset(ty: ScalarType) -> i32 {
+    match ty {
+        ScalarType::Q4_0 => 8,
+        ScalarType::Q5_0 => 16,
+        _ => 0,
+    }
+}
+
+/// The scale and minimum GGML's reference implementation picks for a block.
+/// The minimum is zero for the types that don't store one.
+fn block_params(ty: ScalarType, x: &[f32; BLOCK_LEN]) -> (f32, f32) {
+    match ty {
+        // Symmetric around zero, with the largest magnitude mapped to the
+        // most negative value.
+        ScalarType::Q4_0 | ScalarType::Q5_0 => (signed_max(x) / -(offset(ty) as f32), 0.0),
+        ScalarType::Q4_1 | ScalarType::Q5_1 => {
+            let levels = if ty == ScalarType::Q4_1 { 16.0 } else { 32.0 };
+            let (min, max) = min_max(x);
+            ((max - min) / (levels - 1.0), min)
+        }
+        ScalarType::Q8_0 => (
+            x.iter().fold(0.0f32, |max, x| max.max(x.abs())) / 127.0,
+            0.0,
+        ),
+        ty => unreachable!("{ty:?} is not quantized in blocks"),
+    }
+}
+
+/// Rounds `x` to the values `ty` stores against scale `d` and minimum `min`.
+fn quantize_values(ty: ScalarType, x: &[f32; BLOCK_LEN], d: f32, min: f32) -> [i32; BLOCK_LEN] {
+    let id = inverse(d);
+    x.map(|x| match ty {
+        ScalarType::Q4_0 | ScalarType::Q5_0 => {
+            let offset = offset(ty) as f32;
+            ((x * id + offset + 0.5) as i8).clamp(0, 2 * offset as i8 - 1) as i32
+        }
+        ScalarType::Q4_1 => (((x - min) * id + 0.5) as i8).clamp(0, 15) as i32,
+        ScalarType::Q5_1 => (((x - min) * id + 0.5) as i8).clamp(0, 31) as i32,
+        ScalarType::Q8_0 => (x * id).round() as i8 as i32,
+        ty => unreachable!("{ty:?} is not quantized in blocks"),
+    })
+}
+
+fn write_block(ty: ScalarType, d: f32, min: f32, q: &[i32; BLOCK_LEN], out: &mut [u8]) {
+    out[..2].copy_from_slice(&f16::from_f32(d).to_le_bytes());
+    if ty == ScalarType::Q8_0 {
+        for (out, &q) in out[2..].iter_mut().zip(q) {
+            *out = q as i8 as u8;
+        }
+        return;
+    }
+
+    let q = q.map(|q| q as u8);
+    match ty {
+        ScalarType::Q4_0 => {
+            pack_nibbles(&q, &mut out[2..]);
+        }
+        ScalarType::Q5_0 => {
+            let high = pack_nibbles(&q, &mut out[6..]);
+            out[2..6].copy_from_slice(&high.to_le_bytes());
+        }
+        ScalarType::Q4_1 => {
+            out[2..4].copy_from_slice(&f16::from_f32(min).to_le_bytes());
+            pack_nibbles(&q, &mut out[4..]);
+        }
+        ScalarType::Q5_1 => {
+            out[2..4].copy_from_slice(&f16::from_f32(min).to_le_bytes());
+            let high = pack_nibbles(&q, &mut out[8..]);
+            out[4..8].copy_from_slice(&high.to_le_bytes());
+        }
+        ty => unreachable!("{ty:?} is not quantized in blocks"),
+    }
+}
+
+/// Quantizes one block the way GGML's reference implementation does, so
+/// files match the ones it writes.
+fn quantize_block(ty: ScalarType, x: &[f32; BLOCK_LEN], out: &mut [u8]) {
+    let (d, min) = block_params(ty, x);
+    write_block(ty, d, min, &quantize_values(ty, x, d, min), out);
+}
+
+/// How far either side of the reference scale [`quantize_block_weighted`]
+/// looks, in steps of `SCALE_STEP` of it.
+const SCALE_STEPS: i32 = 8;
+const SCALE_STEP: f32 = 0.025;
+
+/// Like [`quantize_block`], but picks the scale and minimum with the smallest
+/// squared error weighted by `w`. Tries scales around the reference one,
+/// refitting each to the values it rounds to by weighted least squares.
+fn quantize_block_weighted(
+    ty: ScalarType,
+    x: &[f32; BLOCK_LEN],
+    w: &[f32; BLOCK_LEN],
+    out: &mut [u8],
+) {
+    // The error with the scale and minimum as they're stored.
+    let error = |d: f32, min: f32, q: &[i32; BLOCK_LEN]| {
+        let (d, min) = (f16::from_f32(d).to_f32(), f16::from_f32(min).to_f32());
+        (0..BLOCK_LEN)
+            .map(|j| {
+                let e = x[j] - ((q[j] - offset(ty)) as f32 * d + min);
+                w[j] * e * e
+            })
+            .sum::<f32>()
+    };
+
+    let (d0, min0) = block_params(ty, x);
+    let q = quantize_values(ty, x, d0, min0);
+    let mut best = (error(d0, min0, &q), d0, min0, q);
+    for step in -SCALE_STEPS..=SCALE_STEPS {
+        let d = d0 * (1.0 + step as f32 * SCALE_STEP);
+        let (d, min) = refit(ty, x, w, &quantize_values(ty, x, d, min0)).unwrap_or((d, min0));
+        let q = quantize_values(ty, x, d, min);
+        let e = error(d, min, &q);
+        if e < best.0 {
+            best = (e, d, min, q);
+        }
+    }
+
+    let (_, d, min, q) = best;
+    write_block(ty, d, min, &q, out);
+}
+
+/// The scale, and the minimum if `ty` has one, that minimize the error of
+/// `q` weighted by `w`, or `None` if they aren't determined.
+fn refit(
+    ty: ScalarType,
+    x: &[f32; BLOCK_LEN],
+    w: &[f32; BLOCK_LEN],
+    q: &[i32; BLOCK_LEN],
+) -> Option<(f32, f32)> {
+    let (mut sw, mut swq, mut swqq, mut swx, mut swxq) = (0.0, 0.0, 0.0, 0.0, 0.0);
+    for j in 0..BLOCK_LEN {
+        let q = (q[j] - offset(ty)) as f32;
+        sw += w[j];
+        swq += w[j] * q;
+        swqq += w[j] * q * q;
+        swx += w[j] * x[j];
+        swxq += w[j] * x[j] * q;
+    }
+
+    if matches!(ty, ScalarType::Q4_1 | ScalarType::Q5_1) {
+        let det = swqq * sw - swq * swq;
+        (det > 0.0).then(|| {
+            (
+                (sw * swxq - swq * swx) / det,
+                (swqq * swx - swq * swxq) / det,
+            )
+        })
+    } else {
+        (swqq > 0.0).then(|| (swxq / swqq, 0.0))
+    }
+}
+
+fn dequantize_block(ty: ScalarType, block: &[u8], out: &mut [f32; BLOCK_LEN]) {
+    let d = read_f16(block, 0);
+    let (q, offset, min) = match ty {
+        ScalarType::Q4_0 => (unpack_nibbles(&block[2..], 0), 8.0, 0.0),
+        ScalarType::Q4_1 => (unpack_nibbles(&block[4..], 0), 0.0, read_f16(block, 2)),
+        ScalarType::Q5_0 => {
+            let high = u32::from_le_bytes(block[2..6].try_into().unwrap());
+            (unpack_nibbles(&block[6..], high), 16.0, 0.0)
+        }
+        ScalarType::Q5_1 => {
+            let high = u32::from_le_bytes(block[4..8].try_into().unwrap());
+            (unpack_nibbles(&block[8..], high), 0.0, read_f16(block, 2))
+        }
+        ScalarType::Q8_0 => {
+            for (out, &q) in out.iter_mut().zip(&block[2..]) {
+                *out = q as i8 as f32 * d;
+            }
+            return;
+        }
+        ty => unreachable!("{ty:?} is not quantized in blocks"),
+    };
+    for (out, q) in out.iter_mut().zip(q) {
+        *out = (q as f32 - offset) * d + min;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::Rng;
+
+    const TYPES: [ScalarType; 5] = [
+        ScalarType::Q4_0,
+        ScalarType::Q4_1,
+        ScalarType::Q5_0,
+        ScalarType::Q5_1,
+        ScalarType::Q8_0,
+    ];
+
+    fn random(len: usize) -> Vec<f32> {
+        Rng(0x2545f4914f6cdd1d).vec_f32(len)
+    }
+
+    #[test]
+    fn error_is_bounded() {
+        let x = random(4 * BLOCK_LEN);
+        for ty in TYPES {
+            let q = Quantized::new(ty, &x);
+            assert_eq!(q.as_bytes().len(), ty.size_of(x.len()));
+
+            for (block, y) in x.chunks(BLOCK_LEN).zip(q.dequantize().chunks(BLOCK_LEN)) {
+                let (min, max) = min_max(block);
+                let amax = max.abs().max(min.abs());
+                // The symmetric types clamp one end of the range to a whole
+                // step short.
+                let error = match ty {
+                    ScalarType::Q4_0 => amax / 8.0,
+                    ScalarType::Q5_0 => amax / 16.0,
+                    ScalarType::Q4_1 => (max - min) / 30.0,
+                    ScalarType::Q5_1 => (max - min) / 62.0,
+                    _ => amax / 254.0,
+                };
+                // Allow for the scale and minimum being rounded to f16.
+                let error = error * 1.01 + 1e-3;
+                for (a, b) in block.iter().zip(y) {
+                    assert!((a - b).abs() <= error, "{ty:?}: {a} became {b}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn matches_ggml_layout() {
+        // -8 to 7, then its negation.
+        let x = (0..BLOCK_LEN)
+            .map(|i| {
+                if i < 16 {
+                    i as f32 - 8.0
+                } else {
+                    8.0 - (i - 16) as f32
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let q4 = Quantized::new(ScalarType::Q4_0, &x);
+        // The largest magnitude is -8, so the scale is 1 and each value is
+        // stored offset by 8, the second half in the high nibbles.
+        assert_eq!(read_f16(q4.as_bytes(), 0), 1.0);
+        assert_eq!(q4.as_bytes()[2], 0xf0);
+        assert_eq!(q4.as_bytes()[3], 0xf1);
+        assert_eq!(q4.dequantize()[..16], x[..16]);
+
+        let q8 = Quantized::new(ScalarType::Q8_0, &x);
+        assert_eq!(q8.as_bytes()[2] as i8, -127);
+        assert_eq!(q8.as_bytes()[2 + 16] as i8, 127);
+
+        // Zero blocks dequantize to zeros.
+        for ty in TYPES {
+            assert_eq!(
+                Quantized::new(ty, &[0.0; BLOCK_LEN]).dequantize(),
+                [0.0; BLOCK_LEN]
+            );
+        }
+    }
+
+    /// A k-quant super-block of `ty`, with `fill` writing its bytes.
+    fn super_block(ty: ScalarType, fill: impl FnOnce(&mut [u8])) -> Quantized {
+        let mut bytes = vec![0; ty.size_of(SUPER_BLOCK_LEN)];
+        fill(&mut bytes);
+        Quantized::from_bytes(ty, SUPER_BLOCK_LEN, bytes)
+    }
+
+    fn put_f16(bytes: &mut [u8], offset: usize, x: f32) {
+        bytes[offset..offset + 2].copy_from_slice(&f16::from_f32(x).to_le_bytes());
+    }
+
+    /// Checks elements of super-blocks worked out by hand from the layouts in
+    /// GGML's `dequantize_row_q*_K`.
+    #[test]
+    fn decodes_k_quants() {
+        // Sub-block scale k and minimum 1, 2-bit values 0, 1, 2 and 3 at
+        // shifts 0, 2, 4 and 6.
+        let q2 = super_block(ScalarType::Q2_K, |b| {
+            for (k, b) in b[..16].iter_mut().enumerate() {
+                *b = k as u8 | (1 << 4);
+            }
+            b[16..80].fill(0b11_10_01_00);
+            put_f16(b, 80, 0.5);
+            put_f16(b, 82, 0.25);
+        });
+        let y = q2.dequantize();
+        // Element 37 is in the second sub-block of the second shift.
+        assert_eq!(
+            [y[0], y[37], y[200], y[255]],
+            [
+                -0.25,
+                0.5 * 2.0 - 0.25,
+                0.5 * 24.0 - 0.25,
+                0.5 * 45.0 - 0.25
+            ]
+        );
+
+        // Sub-block scale k, with the high bit of element 16 clear.
+        let q3 = super_block(ScalarType::Q3_K, |b| {
+            b[..32].fill(0xff);
+            b[16] = 0xfe;
+            b[32..96].fill(0b11_10_01_00);
+            for k in 0..8 {
+                b[96 + k] = k as u8 | ((k as u8 + 8) << 4);
+            }
+            b[104..108].fill(0b10_10_10_10);
+            put_f16(b, 108, 0.5);
+        });
+        let y = q3.dequantize();
+        assert_eq!(
+            [y[16], y[200], y[255]],
+            [0.5 * -4.0, 0.5 * 12.0 * 2.0, 0.5 * 15.0 * 3.0]
+        );
+
+        // Scales 1, 2, 3, 4, 21, 5, 5, 5 and minimums 2, 2, 2, 2, 3, 3, 3, 3.
+        let scales = |b: &mut [u8]| {
+            b[..4].copy_from_slice(&[0x41, 2, 3, 4]);
+            b[4..8].fill(2);
+            b[8..12].fill(0x35);
+        };
+        let q4 = super_block(ScalarType::Q4_K, |b| {
+            put_f16(b, 0, 0.5);
+            put_f16(b, 2, 0.25);
+            scales(&mut b[4..]);
+            b[16..].fill(0x73);
+        });
+        let y = q4.dequantize();
+        assert_eq!([y[0], y[40], y[128], y[255]], [1.0, 6.5, 30.75, 16.75]);
+
+        // The fifth bits of even elements of the first and seventh sub-blocks
+        // are set.
+        let q5 = super_block(ScalarType::Q5_K, |b| {
+            put_f16(b, 0, 0.5);
+            put_f16(b, 2, 0.25);
+            scales(&mut b[4..]);
+            for l in (0..32).step_by(2) {
+                b[16 + l] = 0b0100_0001;
+            }
+            b[48..].fill(0x73);
+        });
+        let y = q5.dequantize();
+        assert_eq!([y[0], y[1], y[40], y[192]], [9.0, 1.0, 6.5, 46.75]);
+
+        // Sub-block scales k - 8, 6-bit values 10, 26, 37 and 53 before the
+        // offset of 32.
+        let q6 = super_block(ScalarType::Q6_K, |b| {
+            b[..128].fill(0x5a);
+            b[128..192].fill(0b11_10_01_00);
+            for k in 0..16 {
+                b[192 + k] = (k as i8 - 8) as u8;
+            }
+            put_f16(b, 208, 0.5);
+        });
+        let y = q6.dequantize();
+        assert_eq!([y[0], y[50], y[100], y[200]], [88.0, 15.0, -21.0, 10.0]);
+    }
+
+    #[test]
+    fn dot_matches_dequantized() {
+        let x = random(2 * SUPER_BLOCK_LEN);
+        let mut bytes = random(ScalarType::Q4_K.size_of(x.len()))
+            .into_iter()
+            .map(|x| (x.to_bits() >> 12) as u8)
+            .collect::<Vec<_>>();
+        // Keep the scales finite.
+        for block in bytes.chunks_mut(ScalarType::Q4_K.size_of(SUPER_BLOCK_LEN)) {
+            put_f16(block, 0, 0.01);
+            put_f16(block, 2, 0.02);
+        }
+        let k = Quantized::from_bytes(ScalarType::Q4_K, x.len(), bytes);
+        let q = Quantized::new(ScalarType::Q5_1, &x);
+
+        for q in [k, q] {
+            let y = q.dequantize();
+            let expected = x.iter().zip(&y).map(|(a, b)| a * b).sum::<f32>();
+            assert!((q.dot(0, &x) - expected).abs() < 1e-3 * expected.abs().max(1.0));
+
+            let half = SUPER_BLOCK_LEN;
+            let expected = x[..half]
+                .iter()
+                .zip(&y[half..])
+                .map(|(a, b)| a * b)
+                .sum::<f32>();
+            assert!((q.dot(half, &x[..half]) - expected).abs() < 1e-3 * expected.abs().max(1.0));
+        }
+    }
+
+    #[test]
+    fn importance_lowers_weighted_error() {
+        let row_len = 2 * BLOCK_LEN;
+        let x = random(8 * row_len);
+        // A few outlier channels matter far more than the rest.
+        let importance = (0..row_len)
+            .map(|j| if j % 7 == 0 { 100.0 } else { 1.0 })
+            .collect::<Vec<_>>();
+        let weighted_error = |y: &[f32], importance: &[f32]| {
+            x.iter()
+                .zip(y)
+                .enumerate()
+                .map(|(i, (a, b))| importance[i % row_len] * (a - b) * (a - b))
+                .sum::<f32>()
+        };
+
+        for ty in TYPES {
+            let plain = Quantized::new(ty, &x).dequantize();
+            let weighted = Quantized::with_importance(ty, &x, &importance);
+            assert_eq!(weighted.as_bytes().len(), ty.size_of(x.len()));
+            let weighted = weighted.dequantize();
+            assert!(
+                weighted_error(&weighted, &importance) < 0.9 * weighted_error(&plain, &importance),
+                "{ty:?}"
+            );
+
+            // Uniform importance never does worse than the reference.
+            let ones = vec![1.0; row_len];
+            let uniform = Quantized::with_importance(ty, &x, &ones).dequantize();
+            assert!(
+                weighted_error(&uniform, &ones) <= weighted_error(&plain, &ones) * 1.0001,
+                "{ty:?}"
+            );
+        }
+    }
+}
@@ -0,0 +1,38 @@
+//! A small xorshift generator, so tests, benchmarks and training runs are
+//! reproducible without pulling in a dependency. Its numbers are easy to
+//! predict, so it's no use for anything that must not be guessed.
+
+use std::ops::Range;
+
+use half::f16;
+
+/// Marsaglia's xorshift64. The seed must not be zero, which it would never
+/// leave.
+pub struct Rng(pub u64);
+
+impl Rng {
+    pub fn u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Uniform in `[-1, 1)`.
+    pub fn float(&mut self) -> f32 {
+        (self.u64() >> 40) as f32 / (1u64 << 23) as f32 - 1.0
+    }
+
+    /// Nearly uniform in `range`, which must not be empty.
+    pub fn usize(&mut self, range: Range<usize>) -> usize {
+        range.start + (self.u64() % range.len() as u64) as usize
+    }
+
+    pub fn vec_f32(&mut self, n: usize) -> Vec<f32> {
+        (0..n).map(|_| self.float()).collect()
+    }
+
+    pub fn vec_f16(&mut self, n: usize) -> Vec<f16> {
+        (0..n).map(|_| f16::from_f32(self.float())).collect()
+    }
+}
@@ -0,0 +1,395 @@
+//! HuggingFace's safetensors format: an 8-byte little-endian header length, a
+//! JSON header giving each tensor's dtype, row-major shape and byte range, and
+//! then the tensors' data.
+
+use std::{collections::HashMap, fs::File, io, path::Path, sync::Arc};
+
+use memmap2::Mmap;
+use serde::Deserialize;
+
+use crate::ggml::{Data, Ggml, HParams, Mapped, ScalarType, Var};
+use crate::sentencepiece::SentencePiece;
+use crate::tokenizer::Tokenizer;
+
+/// The index of a checkpoint split across several files.
+const INDEX: &str = "model.safetensors.index.json";
+/// The file of a checkpoint that isn't split.
+const SINGLE: &str = "model.safetensors";
+
+#[derive(Deserialize)]
+struct Header {
+    #[serde(rename = "__metadata__")]
+    _metadata: Option<HashMap<String, String>>,
+    #[serde(flatten)]
+    tensors: HashMap<String, TensorInfo>,
+}
+
+#[derive(Deserialize)]
+struct TensorInfo {
+    dtype: String,
+    shape: Vec<usize>,
+    data_offsets: [usize; 2],
+}
+
+#[derive(Deserialize)]
+struct Index {
+    weight_map: HashMap<String, String>,
+}
+
+/// Loads the tensors of a checkpoint, from a `.safetensors` file, a
+/// `model.safetensors.index.json` listing the files of a sharded checkpoint,
+/// or a directory containing either.
+///
+/// The tensors are left in the mapped files, in the scalar types they're
+/// stored as, and only copied out when a model takes them from its
+/// [`Weights`](crate::weights::Weights).
+pub fn load(p: impl AsRef<Path>) -> io::Result<HashMap<String, Var>> {
+    let p = p.as_ref();
+    if p.is_dir() {
+        return if p.join(INDEX).exists() {
+            load_sharded(&p.join(INDEX))
+        } else {
+            load_file(&p.join(SINGLE))
+        };
+    }
+
+    if p.extension().is_some_and(|ext| ext == "json") {
+        load_sharded(p)
+    } else {
+        load_file(p)
+    }
+}
+
+/// Whether `p` names a checkpoint [`load`] reads, rather than a GGML file.
+pub fn is_checkpoint(p: impl AsRef<Path>) -> bool {
+    let p = p.as_ref();
+    p.is_dir()
+        || p.extension()
+            .is_some_and(|ext| ext == "safetensors" || ext == "json")
+}
+
+/// Loads a checkpoint's tensors as [`load`] does, along with the
+/// hyperparameters in the `config.json` beside them.
+pub fn load_model(p: impl AsRef<Path>) -> io::Result<Ggml> {
+    let p = p.as_ref();
+    let hparams = HParams::from_config(dir(p).join("config.json"))?;
+    Ok(Ggml {
+        hparams,
+        vars: load(p)?,
+    })
+}
+
+/// Loads the tokenizer beside a checkpoint: its `tokenizer.json`, or
+/// failing that a SentencePiece `tokenizer.model`.
+pub fn load_tokenizer(p: impl AsRef<Path>) -> io::Result<Tokenizer> {
+    let dir = dir(p.as_ref());
+    if dir.join("tokenizer.json").exists() {
+        Tokenizer::from_json(dir.join("tokenizer.json"))
+    } else {
+        SentencePiece::load(dir.join("tokenizer.model"))?.try_into()
+    }
+}
+
+/// The directory a checkpoint's files are in.
+fn dir(p: &Path) -> &Path {
+    if p.is_dir() {
+        p
+    } else {
+        p.parent().unwrap_or(Path::new("."))
+    }
+}
+
+fn load_sharded(index: &Path) -> io::Result<HashMap<String, Var>> {
+    let Index { weight_map } = serde_json::from_reader(File::open(index)?).map_err(invalid_data)?;
+    let dir = dir(index);
+
+    let mut shards = weight_map.values().collect::<Vec<_>>();
+    shards.sort();
+    shards.dedup();
+
+    let mut vars = HashMap::new();
+    for shard in shards {
+        for (name, var) in load_file(&dir.join(shard))? {
+            if weight_map.get(&name) != Some(shard) {
+                return Err(invalid_data(format!(
+                    "{name} is in {shard}, but the index doesn't list it there"
+                )));
+            }
+            vars.insert(name, var);
+        }
+    }
+
+    if let Some(name) = weight_map
+        .keys()
+        .filter(|name| !vars.contains_key(*name))
+        .min()
+    {
+        return Err(invalid_data(format!(
+            "{name} is not in {}",
+            weight_map[name]
+        )));
+    }
+
+    Ok(vars)
+}
+
+fn load_file(p: &Path) -> io::Result<HashMap<String, Var>> {
+    let f = File::open(p)?;
+    // Safety: the file may not change while it's mapped, which is for as
+    // long as any of its tensors are alive.
+    let mmap = Arc::new(unsafe { Mmap::map(&f)? });
+
+    let header_len = mmap
+        .get(..8)
+        .map(|x| u64::from_le_bytes(x.try_into().unwrap()) as usize)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "unexpected EOF"))?;
+    let header = mmap
+        .get(8..8usize.saturating_add(header_len))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "unexpected EOF"))?;
+    let header: Header = serde_json::from_slice(header).map_err(invalid_data)?;
+    let data_start = 8 + header_len;
+
+    let mut vars = HashMap::new();
+    for (name, info) in header.tensors {
+        let ty = match info.dtype.as_str() {
+            "F32" => ScalarType::F32,
+            "F16" => ScalarType::F16,
+            "BF16" => ScalarType::BF16,
+            dtype => {
+                return Err(invalid_data(format!(
+                    "{name} has unsupported dtype {dtype}"
+                )))
+            }
+        };
+        let [start, end] = info.data_offsets;
+        let len = info.shape.iter().product::<usize>();
+        if start > end || end > mmap.len() - data_start || end - start != ty.size_of(len) {
+            return Err(invalid_data(format!("{name} has invalid data offsets")));
+        }
+
+        let data = Data::Mapped(Mapped::new(
+            mmap.clone(),
+            data_start + start..data_start + end,
+            ty,
+        ));
+        vars.insert(
+            name.clone(),
+            Var {
+                name,
+                dims: info.shape,
+                data,
+            },
+        );
+    }
+
+    Ok(vars)
+}
+
+fn invalid_data(e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{fs, path::PathBuf};
+
+    use half::{bf16, f16};
+
+    use super::*;
+    use crate::buffer::AlignedBuf;
+    use crate::model::llama;
+    use crate::scratch::Scratch;
+    use crate::tensor::Tensor;
+    use crate::weights::Weights;
+
+    /// Writes a safetensors file of `(name, dtype, shape, bytes)` tensors.
+    pub(crate) fn write(path: &Path, tensors: &[(&str, &str, &[usize], Vec<u8>)]) {
+        let mut header = serde_json::Map::new();
+        header.insert("__metadata__".into(), serde_json::json!({ "format": "pt" }));
+        let mut data = Vec::<u8>::new();
+        for (name, dtype, shape, bytes) in tensors {
+            let offsets = [data.len(), data.len() + bytes.len()];
+            header.insert(
+                name.to_string(),
+                serde_json::json!({ "dtype": dtype, "shape": shape, "data_offsets": offsets }),
+            );
+            data.extend(bytes);
+        }
+
+        let header = serde_json::to_vec(&header).unwrap();
+        let mut file = (header.len() as u64).to_le_bytes().to_vec();
+        file.extend(header);
+        file.extend(data);
+        fs::write(path, file).unwrap();
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("nxml-{name}-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn values(var: &Var) -> Vec<f32> {
+        var.data.to_f32()
+    }
+
+    #[test]
+    fn keeps_dtypes() {
+        let dir = temp_dir("safetensors");
+        let path = dir.join("model.safetensors");
+        let xs = [1.0f32, -2.0, 0.5, 3.0, 0.25, -1.5];
+        write(
+            &path,
+            &[
+                (
+                    "f32",
+                    "F32",
+                    &[2, 3],
+                    xs.iter().flat_map(|x| x.to_le_bytes()).collect(),
+                ),
+                (
+                    "f16",
+                    "F16",
+                    &[6],
+                    xs.iter()
+                        .flat_map(|&x| f16::from_f32(x).to_le_bytes())
+                        .collect(),
+                ),
+                (
+                    "bf16",
+                    "BF16",
+                    &[3, 2],
+                    xs.iter()
+                        .flat_map(|&x| bf16::from_f32(x).to_le_bytes())
+                        .collect(),
+                ),
+            ],
+        );
+
+        let vars = load(&dir).unwrap();
+        assert_eq!(vars.len(), 3);
+        for (name, dims, scalar_type) in [
+            ("f32", vec![2, 3], ScalarType::F32),
+            ("f16", vec![6], ScalarType::F16),
+            ("bf16", vec![3, 2], ScalarType::BF16),
+        ] {
+            let var = &vars[name];
+            assert_eq!(var.dims, dims, "{name}");
+            assert_eq!(var.data.scalar_type(), scalar_type, "{name}");
+            assert_eq!(values(var), xs, "{name}");
+        }
+
+        drop(vars);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn loads_model() {
+        let dir = temp_dir("safetensors-model");
+        let config = serde_json::json!({
+            "model_type": "llama",
+            "vocab_size": 12,
+            "hidden_size": 8,
+            "num_attention_heads": 2,
+            "num_key_value_heads": 1,
+            "num_hidden_layers": 2,
+            "intermediate_size": 16,
+            "max_position_embeddings": 16,
+            "rms_norm_eps": 1e-5,
+        });
+        fs::write(dir.join("config.json"), config.to_string()).unwrap();
+        let hparams = HParams::from_config(dir.join("config.json")).unwrap();
+
+        // Matrices in bf16 and norms in f32, as HuggingFace saves them.
+        let table = &llama::HF_NAMES;
+        let weights = table
+            .weights(hparams.n_layers)
+            .into_iter()
+            .map(|w| {
+                let (name, shape) = (table.name(w).unwrap(), w.shape(&hparams));
+                let xs = (0..shape.iter().product())
+                    .map(|i: usize| (i * 7919 % 1000) as f32 / 1000.0 - 0.5);
+                let (dtype, bytes) = match shape.len() {
+                    1 => ("F32", xs.flat_map(|x| (1.0 + x).to_le_bytes()).collect()),
+                    _ => (
+                        "BF16",
+                        xs.flat_map(|x| bf16::from_f32(x).to_le_bytes()).collect(),
+                    ),
+                };
+                (name, dtype, shape, bytes)
+            })
+            .collect::<Vec<(String, &str, Vec<usize>, Vec<u8>)>>();
+        let tensors = weights
+            .iter()
+            .map(|(name, dtype, shape, bytes)| (&name[..], *dtype, &shape[..], bytes.clone()));
+        write(&dir.join(SINGLE), &tensors.collect::<Vec<_>>());
+
+        let Ggml { hparams, vars } = load_model(&dir).unwrap();
+        assert_eq!(
+            (hparams.n_heads, hparams.n_kv_heads, hparams.n_ff),
+            (2, 1, 16)
+        );
+        assert!(vars.values().all(|var| matches!(var.data, Data::Mapped(_))));
+
+        // The same values, copied out of the file.
+        let copied = vars
+            .values()
+            .map(|var| {
+                let data = Data::F32(AlignedBuf::from_slice(&var.data.to_f32()));
+                (
+                    var.name.clone(),
+                    Var {
+                        name: var.name.clone(),
+                        dims: var.dims.clone(),
+                        data,
+                    },
+                )
+            })
+            .collect();
+
+        let tokens = Tensor::from(vec![1, 5, 2, 11]);
+        let logits = [vars, copied].map(|vars| {
+            let weights = Weights::map(vars, &hparams, table).unwrap();
+            hparams
+                .arch
+                .build(hparams.clone(), weights)
+                .forward(&tokens, &mut Scratch::new())
+                .to_vec()
+        });
+        assert_eq!(logits[0], logits[1]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sharded() {
+        let dir = temp_dir("safetensors-sharded");
+        let shards = [
+            "model-00001-of-00002.safetensors",
+            "model-00002-of-00002.safetensors",
+        ];
+        write(&dir.join(shards[0]), &[("a", "F32", &[2], vec![0; 8])]);
+        write(&dir.join(shards[1]), &[("b", "F16", &[1, 2], vec![0; 4])]);
+
+        let index = |weight_map: serde_json::Value| {
+            fs::write(
+                dir.join(INDEX),
+                serde_json::json!({ "metadata": {}, "weight_map": weight_map }).to_string(),
+            )
+            .unwrap();
+            load(&dir)
+        };
+
+        let vars = index(serde_json::json!({ "a": shards[0], "b": shards[1] })).unwrap();
+        assert_eq!(vars["a"].dims, [2]);
+        assert_eq!(vars["b"].dims, [1, 2]);
+
+        // Every tensor must be where the index says it is.
+        assert!(index(serde_json::json!({ "a": shards[0], "b": shards[0] })).is_err());
+        assert!(
+            index(serde_json::json!({ "a": shards[0], "b": shards[1], "c": shards[1] })).is_err()
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
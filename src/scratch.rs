@@ -0,0 +1,121 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use half::f16;
+
+use crate::buffer::AlignedBuf;
+use crate::imatrix::ActivationStats;
+use crate::model::KvCache;
+use crate::tensor::{Tensor, TensorElement};
+
+/// A pool of buffers keyed by their length, for tensors that only live for part
+/// of a forward pass. Once every intermediate of a token has been handed back
+/// with [`BufferPool::recycle`], the next token with the same shapes is served
+/// entirely from the pool, without touching the heap.
+pub struct BufferPool<T> {
+    free: HashMap<usize, Vec<Arc<AlignedBuf<T>>>>,
+    allocations: usize,
+}
+
+impl<T: TensorElement> BufferPool<T> {
+    pub fn new() -> Self {
+        Self {
+            free: HashMap::new(),
+            allocations: 0,
+        }
+    }
+
+    /// Takes a tensor of the given shape from the pool, allocating one if there
+    /// is no free buffer of the right size. The contents are left over from
+    /// the buffer's previous use, so this is meant for the outputs of `_into`
+    /// ops, which overwrite every element.
+    pub fn take<const DIMS: usize>(&mut self, shape: [usize; DIMS]) -> Tensor<T, DIMS> {
+        let len = shape.iter().product::<usize>();
+
+        match self.free.get_mut(&len).and_then(|free| free.pop()) {
+            Some(data) => Tensor::from_shared(data, shape),
+            None => {
+                self.allocations += 1;
+                Tensor::zeros(shape)
+            }
+        }
+    }
+
+    /// Returns a tensor's buffer to the pool. If the buffer is still shared
+    /// with another tensor, it's left to that tensor instead.
+    pub fn recycle<const DIMS: usize>(&mut self, tensor: Tensor<T, DIMS>) {
+        let mut data = tensor.into_shared();
+        if Arc::get_mut(&mut data).is_some() {
+            self.free.entry(data.len()).or_default().push(data);
+        }
+    }
+
+    /// The number of buffers the pool has had to allocate so far.
+    pub fn allocations(&self) -> usize {
+        self.allocations
+    }
+}
+
+impl<T: TensorElement> Default for BufferPool<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The pools a forward pass borrows its intermediates from.
+#[derive(Default)]
+pub struct Scratch {
+    pub f16: BufferPool<f16>,
+    pub f32: BufferPool<f32>,
+    /// Where to record the inputs of each weight matrix, when collecting an
+    /// importance matrix.
+    pub activations: Option<ActivationStats>,
+    /// The keys and values of earlier positions, when decoding a token at a
+    /// time.
+    pub kv_cache: Option<KvCache>,
+}
+
+impl Scratch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn allocations(&self) -> usize {
+        self.f16.allocations() + self.f32.allocations()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn steady_state_reuses_buffers() {
+        let mut scratch = Scratch::new();
+        let w = Tensor::<f16, 2>::zeros([8, 4]);
+        let x = Tensor::<f16, 2>::zeros([3, 4]);
+
+        for _ in 0..3 {
+            let mut norm = scratch.f16.take([3, 4]);
+            x.rms_norm_into(&mut norm);
+            let mut y = scratch.f16.take([3, 8]);
+            w.matmul_into(&norm, &mut y);
+            scratch.f16.recycle(norm);
+            scratch.f16.recycle(y);
+        }
+
+        assert_eq!(scratch.allocations(), 2);
+    }
+
+    #[test]
+    fn shared_buffers_are_not_recycled() {
+        let mut pool = BufferPool::<f32>::new();
+        let t = pool.take([4]);
+        let shared = t.clone();
+        pool.recycle(t);
+
+        let _ = pool.take([4]);
+        assert_eq!(pool.allocations(), 2);
+        drop(shared);
+    }
+}
@@ -0,0 +1,658 @@
+//! SentencePiece's `tokenizer.model`: a `ModelProto` protobuf holding the
+//! pieces with their scores and types, the trainer's settings, and how text
+//! is normalized before it's split into pieces.
+//!
+//! Only the fields tokenizing needs are read. The rest, like the self-test
+//! data, are skipped.
+
+use std::{fs, io, path::Path};
+
+use unicode_normalization::UnicodeNormalization;
+
+use crate::tokenizer::{Aligned, Token, TokenType, Tokenizer, Vocab};
+
+/// The algorithm the pieces were trained with, which decides how text is
+/// split into them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModelType {
+    Unigram,
+    Bpe,
+    Word,
+    Char,
+}
+
+/// How text is normalized before it's split into pieces.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NormalizerSpec {
+    /// The name of the normalization rule, e.g. `nmt_nfkc` or `identity`.
+    pub name: String,
+    /// The compiled rule, a trie of the strings to replace.
+    pub precompiled_charsmap: Vec<u8>,
+    /// Whether to add a space to the start of the text, so the first word is
+    /// split like the rest.
+    pub add_dummy_prefix: bool,
+    /// Whether to strip leading and trailing whitespace and collapse runs
+    /// of it.
+    pub remove_extra_whitespaces: bool,
+    /// Whether to replace spaces with `▁` (U+2581).
+    pub escape_whitespaces: bool,
+}
+
+impl Default for NormalizerSpec {
+    /// The defaults in `sentencepiece_model.proto`.
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            precompiled_charsmap: Vec::new(),
+            add_dummy_prefix: true,
+            remove_extra_whitespaces: true,
+            escape_whitespaces: true,
+        }
+    }
+}
+
+impl NormalizerSpec {
+    /// LLaMA's normalization, for vocabularies loaded without their spec: no
+    /// rule, a dummy prefix, and spaces escaped but otherwise kept.
+    pub fn llama() -> Self {
+        Self {
+            name: "identity".into(),
+            remove_extra_whitespaces: false,
+            ..Default::default()
+        }
+    }
+
+    /// Normalizes `text` as SentencePiece does before splitting it into
+    /// pieces.
+    pub fn normalize(&self, text: &str) -> String {
+        self.normalize_aligned(&Aligned::new(text, 0)).text
+    }
+
+    pub(crate) fn normalize_aligned(&self, text: &Aligned) -> Aligned {
+        // Without the compiled rule, NFKC is the closest we have.
+        let nfkc;
+        let mut source = text;
+        if self.precompiled_charsmap.is_empty() && self.name.contains("nfkc") {
+            nfkc = text.map(|s| s.nfkc().collect());
+            source = &nfkc;
+        }
+
+        let mut pos = 0;
+        if self.remove_extra_whitespaces {
+            while let Some((" ", len)) = self.normalize_prefix(&source.text[pos..]) {
+                pos += len;
+            }
+        }
+        let mut out = Aligned::empty();
+        if pos == source.len() {
+            return out;
+        }
+
+        let space = if self.escape_whitespaces {
+            "\u{2581}"
+        } else {
+            " "
+        };
+        if self.add_dummy_prefix {
+            out.insert_str(space, source.span(pos..pos + 1).start);
+        }
+        let mut previous_space = self.remove_extra_whitespaces;
+        while let Some((mut normalized, len)) = self.normalize_prefix(&source.text[pos..]) {
+            let from = source.spans(pos..pos + len);
+            pos += len;
+            // Collapse runs of spaces.
+            if previous_space {
+                normalized = normalized.trim_start_matches(' ');
+            }
+            if !normalized.is_empty() {
+                if self.escape_whitespaces && normalized.contains(' ') {
+                    out.push_str(&normalized.replace(' ', space), from);
+                } else {
+                    out.push_str(normalized, from);
+                }
+                previous_space = self.remove_extra_whitespaces && normalized.ends_with(' ');
+            }
+        }
+        if self.remove_extra_whitespaces {
+            while out.text.ends_with(space) {
+                out.truncate(out.len() - space.len());
+            }
+        }
+        out
+    }
+
+    /// What the start of `input` normalizes to, and how many bytes of it
+    /// that consumes: the longest match in the compiled rule, or else the
+    /// first character as it is.
+    fn normalize_prefix<'a>(&'a self, input: &'a str) -> Option<(&'a str, usize)> {
+        let c = input.chars().next()?;
+        Some(
+            self.charsmap_match(input.as_bytes())
+                .unwrap_or((&input[..c.len_utf8()], c.len_utf8())),
+        )
+    }
+
+    /// The longest prefix of `input` in the compiled rule, which is a
+    /// Darts-clone double-array trie (prefixed with its length) mapping
+    /// strings to offsets into the NUL-terminated replacements after it.
+    fn charsmap_match(&self, input: &[u8]) -> Option<(&str, usize)> {
+        let map = &self.precompiled_charsmap;
+        let trie_len = u32::from_le_bytes(map.get(..4)?.try_into().unwrap()) as usize;
+        let trie = map.get(4..4 + trie_len)?;
+        let replacements = &map[4 + trie_len..];
+        let unit = |i: usize| {
+            Some(u32::from_le_bytes(
+                trie.get(4 * i..4 * i + 4)?.try_into().unwrap(),
+            ))
+        };
+        let offset = |unit: u32| ((unit >> 10) << ((unit & (1 << 9)) >> 6)) as usize;
+
+        let mut longest = None;
+        let mut pos = offset(unit(0)?);
+        for (i, &byte) in input.iter().enumerate() {
+            pos ^= byte as usize;
+            let u = unit(pos)?;
+            // The label, which includes the leaf bit so leaves never match.
+            if u & ((1 << 31) | 0xff) != byte as u32 {
+                break;
+            }
+            pos ^= offset(u);
+            if (u >> 8) & 1 == 1 {
+                longest = Some(((unit(pos)? & ((1 << 31) - 1)) as usize, i + 1));
+            }
+        }
+
+        let (value, len) = longest?;
+        let replacement = replacements.get(value..)?;
+        let end = replacement.iter().position(|&b| b == 0)?;
+        Some((std::str::from_utf8(&replacement[..end]).ok()?, len))
+    }
+}
+
+/// A SentencePiece model.
+pub struct SentencePiece {
+    pub vocab: Vocab,
+    pub model_type: ModelType,
+    pub normalizer: NormalizerSpec,
+    /// Whether text no piece covers is split into byte pieces rather than
+    /// mapped to the unknown piece.
+    pub byte_fallback: bool,
+    pub unk_id: Option<usize>,
+    pub bos_id: Option<usize>,
+    pub eos_id: Option<usize>,
+    pub pad_id: Option<usize>,
+}
+
+impl SentencePiece {
+    /// Loads a `tokenizer.model` file.
+    pub fn load(p: impl AsRef<Path>) -> io::Result<Self> {
+        Self::from_bytes(&fs::read(p)?)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        let mut tokens = Vec::new();
+        let mut trainer = None;
+        let mut normalizer = NormalizerSpec::default();
+        for field in fields(bytes) {
+            match field? {
+                (1, Field::Bytes(piece)) => tokens.push(read_piece(piece)?),
+                (2, Field::Bytes(spec)) => trainer = Some(spec),
+                (3, Field::Bytes(spec)) => normalizer = read_normalizer(spec)?,
+                (1..=3, _) => return Err(invalid("model")),
+                _ => {}
+            }
+        }
+
+        // The ids default to the first three pieces, and no padding.
+        let mut model_type = ModelType::Unigram;
+        let mut byte_fallback = false;
+        let (mut unk_id, mut bos_id, mut eos_id, mut pad_id) = (0, 1, 2, -1);
+        for field in fields(trainer.unwrap_or_default()) {
+            match field? {
+                (3, Field::Varint(x)) => {
+                    model_type = match x {
+                        1 => ModelType::Unigram,
+                        2 => ModelType::Bpe,
+                        3 => ModelType::Word,
+                        4 => ModelType::Char,
+                        _ => return Err(invalid("model type")),
+                    }
+                }
+                (35, Field::Varint(x)) => byte_fallback = x != 0,
+                (40, Field::Varint(x)) => unk_id = x as i32,
+                (41, Field::Varint(x)) => bos_id = x as i32,
+                (42, Field::Varint(x)) => eos_id = x as i32,
+                (43, Field::Varint(x)) => pad_id = x as i32,
+                (3 | 35 | 40..=43, _) => return Err(invalid("trainer spec")),
+                _ => {}
+            }
+        }
+
+        let vocab = Vocab::new(tokens);
+        // Negative ids mean there's no such piece.
+        let id = |id: i32| match usize::try_from(id) {
+            Ok(id) if id >= vocab.id_to_token.len() => Err(invalid("special piece id")),
+            id => Ok(id.ok()),
+        };
+        Ok(Self {
+            model_type,
+            normalizer,
+            byte_fallback,
+            unk_id: id(unk_id)?,
+            bos_id: id(bos_id)?,
+            eos_id: id(eos_id)?,
+            pad_id: id(pad_id)?,
+            vocab,
+        })
+    }
+}
+
+/// Only BPE models can be tokenized, since [`Tokenizer`] merges pieces by
+/// score. Unigram models, which are split by likelihood, fail to convert.
+impl TryFrom<SentencePiece> for Tokenizer {
+    type Error = io::Error;
+
+    fn try_from(sp: SentencePiece) -> io::Result<Self> {
+        if sp.model_type != ModelType::Bpe {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{:?} models are not supported, only BPE", sp.model_type),
+            ));
+        }
+        let mut tokenizer = Tokenizer::new(sp.vocab).with_special_ids(sp.bos_id, sp.eos_id);
+        tokenizer.normalizer = sp.normalizer;
+        tokenizer.unk = sp.unk_id;
+        if !sp.byte_fallback {
+            tokenizer.byte_ids = [None; 256];
+        }
+        Ok(tokenizer)
+    }
+}
+
+fn read_piece(bytes: &[u8]) -> io::Result<Token> {
+    let mut token = Token {
+        token: Default::default(),
+        score: 0.0,
+        ty: TokenType::Normal,
+    };
+    for field in fields(bytes) {
+        match field? {
+            (1, Field::Bytes(piece)) => token.token = piece.into(),
+            (2, Field::Fixed32(score)) => token.score = f32::from_bits(score),
+            (3, Field::Varint(ty)) => {
+                token.ty = u32::try_from(ty)
+                    .ok()
+                    .and_then(TokenType::from_id)
+                    .ok_or_else(|| invalid("piece type"))?;
+            }
+            (1..=3, _) => return Err(invalid("piece")),
+            _ => {}
+        }
+    }
+    Ok(token)
+}
+
+fn read_normalizer(bytes: &[u8]) -> io::Result<NormalizerSpec> {
+    let mut spec = NormalizerSpec::default();
+    for field in fields(bytes) {
+        match field? {
+            (1, Field::Bytes(name)) => {
+                spec.name =
+                    String::from_utf8(name.to_vec()).map_err(|_| invalid("normalizer name"))?;
+            }
+            (2, Field::Bytes(charsmap)) => spec.precompiled_charsmap = charsmap.to_vec(),
+            (3, Field::Varint(x)) => spec.add_dummy_prefix = x != 0,
+            (4, Field::Varint(x)) => spec.remove_extra_whitespaces = x != 0,
+            (5, Field::Varint(x)) => spec.escape_whitespaces = x != 0,
+            (1..=5, _) => return Err(invalid("normalizer spec")),
+            _ => {}
+        }
+    }
+    Ok(spec)
+}
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("invalid {what}"))
+}
+
+/// A protobuf field's value, by wire type.
+enum Field<'a> {
+    Varint(u64),
+    Fixed64,
+    Bytes(&'a [u8]),
+    Fixed32(u32),
+}
+
+/// The `(number, value)` fields of a protobuf message, in order.
+fn fields(mut data: &[u8]) -> impl Iterator<Item = io::Result<(u64, Field<'_>)>> {
+    std::iter::from_fn(move || (!data.is_empty()).then(|| read_field(&mut data)))
+}
+
+fn read_field<'a>(data: &mut &'a [u8]) -> io::Result<(u64, Field<'a>)> {
+    let key = read_varint(data)?;
+    let field = match key & 7 {
+        0 => Field::Varint(read_varint(data)?),
+        1 => {
+            take(data, 8)?;
+            Field::Fixed64
+        }
+        2 => {
+            let len = usize::try_from(read_varint(data)?).map_err(|_| invalid("length"))?;
+            Field::Bytes(take(data, len)?)
+        }
+        5 => Field::Fixed32(u32::from_le_bytes(take(data, 4)?.try_into().unwrap())),
+        // Groups were deprecated before SentencePiece existed.
+        _ => return Err(invalid("wire type")),
+    };
+    Ok((key >> 3, field))
+}
+
+fn read_varint(data: &mut &[u8]) -> io::Result<u64> {
+    let mut x = 0;
+    for shift in (0..64).step_by(7) {
+        let byte = take(data, 1)?[0];
+        x |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(x);
+        }
+    }
+    Err(invalid("varint"))
+}
+
+fn take<'a>(data: &mut &'a [u8], len: usize) -> io::Result<&'a [u8]> {
+    if data.len() < len {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "unexpected EOF",
+        ));
+    }
+    let (head, tail) = data.split_at(len);
+    *data = tail;
+    Ok(head)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::tokenizer::EncodeOptions;
+
+    fn varint(out: &mut Vec<u8>, mut x: u64) {
+        while x >= 0x80 {
+            out.push(x as u8 | 0x80);
+            x >>= 7;
+        }
+        out.push(x as u8);
+    }
+
+    fn int_field(out: &mut Vec<u8>, number: u64, x: i64) {
+        varint(out, number << 3);
+        varint(out, x as u64);
+    }
+
+    fn bytes_field(out: &mut Vec<u8>, number: u64, bytes: &[u8]) {
+        varint(out, (number << 3) | 2);
+        varint(out, bytes.len() as u64);
+        out.extend_from_slice(bytes);
+    }
+
+    fn piece(piece: &str, score: f32, ty: Option<TokenType>) -> Vec<u8> {
+        let mut out = Vec::new();
+        bytes_field(&mut out, 1, piece.as_bytes());
+        varint(&mut out, (2 << 3) | 5);
+        out.extend_from_slice(&score.to_le_bytes());
+        if let Some(ty) = ty {
+            int_field(&mut out, 3, ty as i64);
+        }
+        out
+    }
+
+    #[test]
+    fn reads_model_proto() {
+        let mut model = Vec::new();
+        for (p, score, ty) in [
+            ("<unk>", 0.0, Some(TokenType::Unknown)),
+            ("<s>", 0.0, Some(TokenType::Control)),
+            ("</s>", 0.0, Some(TokenType::Control)),
+            ("<0x41>", 0.0, Some(TokenType::Byte)),
+            ("▁hello", -1.5, None),
+        ] {
+            bytes_field(&mut model, 1, &piece(p, score, ty));
+        }
+
+        let mut trainer = Vec::new();
+        int_field(&mut trainer, 3, 2);
+        int_field(&mut trainer, 35, 1);
+        int_field(&mut trainer, 42, 4);
+        // Negative int32s take ten bytes.
+        int_field(&mut trainer, 43, -1);
+        // A fixed64 field this reader doesn't know about.
+        varint(&mut trainer, (100 << 3) | 1);
+        trainer.extend_from_slice(&[0; 8]);
+        bytes_field(&mut model, 2, &trainer);
+
+        let mut normalizer = Vec::new();
+        bytes_field(&mut normalizer, 1, b"identity");
+        int_field(&mut normalizer, 3, 0);
+        bytes_field(&mut model, 3, &normalizer);
+        bytes_field(&mut model, 4, b"self-test data");
+
+        let sp = SentencePiece::from_bytes(&model).unwrap();
+        assert_eq!(sp.model_type, ModelType::Bpe);
+        assert!(sp.byte_fallback);
+        assert_eq!(
+            (sp.unk_id, sp.bos_id, sp.eos_id, sp.pad_id),
+            (Some(0), Some(1), Some(4), None)
+        );
+        assert_eq!(
+            sp.normalizer,
+            NormalizerSpec {
+                name: "identity".into(),
+                add_dummy_prefix: false,
+                ..Default::default()
+            }
+        );
+
+        let tokens = &sp.vocab.id_to_token;
+        assert_eq!(tokens.len(), 5);
+        assert_eq!(
+            tokens.iter().map(|t| t.ty).collect::<Vec<_>>(),
+            [
+                TokenType::Unknown,
+                TokenType::Control,
+                TokenType::Control,
+                TokenType::Byte,
+                TokenType::Normal
+            ]
+        );
+        assert_eq!(
+            (tokens[4].token.as_slice(), tokens[4].score),
+            ("▁hello".as_bytes(), -1.5)
+        );
+        assert_eq!(sp.vocab.token_to_id.get("<0x41>"), Some(3));
+    }
+
+    /// A compiled rule replacing prefixes of `key`, keyed by their length:
+    /// a Darts-clone trie with a block of 256 units for each byte of it.
+    fn charsmap(key: &str, replacements: &[(usize, &str)]) -> Vec<u8> {
+        let base = |depth: usize| 256 * (depth + 1);
+        let mut units = vec![0u32; base(key.len() + 1)];
+        let mut strings = Vec::new();
+        units[0] = (base(0) as u32) << 10;
+        for (i, b) in key.bytes().enumerate() {
+            let pos = base(i) ^ b as usize;
+            let replacement = replacements.iter().find(|&&(len, _)| len == i + 1);
+            units[pos] =
+                ((pos ^ base(i + 1)) as u32) << 10 | (replacement.is_some() as u32) << 8 | b as u32;
+            if let Some((_, replacement)) = replacement {
+                units[base(i + 1)] = 1 << 31 | strings.len() as u32;
+                strings.extend_from_slice(replacement.as_bytes());
+                strings.push(0);
+            }
+        }
+
+        let mut map = ((units.len() * 4) as u32).to_le_bytes().to_vec();
+        map.extend(units.iter().flat_map(|u| u.to_le_bytes()));
+        map.extend(strings);
+        map
+    }
+
+    #[test]
+    fn converts_bpe_models() {
+        let sp = |model_type, byte_fallback| {
+            let pieces = [
+                ("<unk>", TokenType::Unknown),
+                ("<s>", TokenType::Control),
+                ("</s>", TokenType::Control),
+                ("<0x41>", TokenType::Byte),
+                ("▁b", TokenType::Normal),
+            ];
+            SentencePiece {
+                vocab: Vocab::new(
+                    pieces
+                        .iter()
+                        .map(|&(p, ty)| Token {
+                            token: p.into(),
+                            score: 0.0,
+                            ty,
+                        })
+                        .collect(),
+                ),
+                model_type,
+                normalizer: NormalizerSpec::llama(),
+                byte_fallback,
+                unk_id: Some(0),
+                bos_id: Some(1),
+                eos_id: Some(2),
+                pad_id: None,
+            }
+        };
+        let encode = |sp| Tokenizer::try_from(sp).unwrap().encode("bA").to_vec();
+
+        assert_eq!(encode(sp(ModelType::Bpe, true)), [1, 4, 3]);
+        // Without byte fallback, even pieces with byte tokens are unknown.
+        assert_eq!(encode(sp(ModelType::Bpe, false)), [1, 4, 0]);
+        assert!(Tokenizer::try_from(sp(ModelType::Unigram, true)).is_err());
+    }
+
+    /// A file from the fixture directory, which `generate.py` there fills in
+    /// with the `sentencepiece` package.
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/sentencepiece")
+            .join(name)
+    }
+
+    /// The model the reference trainer makes with `generate.py`'s settings.
+    #[test]
+    #[ignore = "needs tokenizer.model from tests/fixtures/sentencepiece/generate.py"]
+    fn converts_reference_model() {
+        let sp = SentencePiece::load(fixture("tokenizer.model")).unwrap();
+        assert_eq!(sp.model_type, ModelType::Bpe);
+        assert_eq!(sp.normalizer.name, "nmt_nfkc");
+        assert!(sp.byte_fallback);
+        assert_eq!(
+            (sp.unk_id, sp.bos_id, sp.eos_id, sp.pad_id),
+            (Some(0), Some(1), Some(2), None)
+        );
+        // The byte pieces come right after the three special ones.
+        let tokens = &sp.vocab.id_to_token;
+        assert!(tokens.len() <= 400);
+        for b in 0..256 {
+            assert_eq!(tokens[3 + b].token, format!("<0x{b:02X}>"));
+            assert_eq!(tokens[3 + b].ty, TokenType::Byte);
+        }
+
+        let tokenizer = Tokenizer::try_from(sp).unwrap();
+        assert_eq!((tokenizer.bos_id(), tokenizer.eos_id()), (Some(1), Some(2)));
+        assert_eq!(tokenizer.encode("").to_vec(), [1]);
+    }
+
+    /// The ids the reference tokenizer gives a model it trained on a corpus
+    /// sample. `generate.py` in the fixture directory makes the model and the
+    /// ids, with the `sentencepiece` package.
+    #[test]
+    #[ignore = "needs tokenizer.model and expected.json from tests/fixtures/sentencepiece/generate.py"]
+    fn matches_reference_ids() {
+        let tokenizer =
+            Tokenizer::try_from(SentencePiece::load(fixture("tokenizer.model")).unwrap()).unwrap();
+        let expected: serde_json::Value =
+            serde_json::from_slice(&fs::read(fixture("expected.json")).unwrap()).unwrap();
+
+        let options = EncodeOptions {
+            add_bos: false,
+            ..Default::default()
+        };
+        for case in expected.as_array().unwrap() {
+            let text = case["text"].as_str().unwrap();
+            let ids = case["ids"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|id| id.as_u64().unwrap() as usize);
+            assert_eq!(
+                tokenizer.encode_with(text, options).to_vec(),
+                ids.collect::<Vec<_>>(),
+                "{text:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn normalizes_like_sentencepiece() {
+        let llama = NormalizerSpec::llama();
+        assert_eq!(llama.normalize("Hello  world "), "▁Hello▁▁world▁");
+        assert_eq!(llama.normalize(""), "");
+        let unescaped = NormalizerSpec {
+            escape_whitespaces: false,
+            ..NormalizerSpec::llama()
+        };
+        assert_eq!(unescaped.normalize("a b"), " a b");
+
+        // The longest match in the rule wins, and spaces it makes are
+        // collapsed with the text's.
+        let nmt = NormalizerSpec {
+            name: "nmt_nfkc".into(),
+            precompiled_charsmap: charsmap("ae", &[(1, "A"), (2, "æ ")]),
+            ..Default::default()
+        };
+        assert_eq!(nmt.normalize("  ae  ab "), "▁æ▁Ab");
+        assert_eq!(nmt.normalize("   "), "");
+
+        // Without a compiled rule, NFKC stands in for it.
+        let nfkc = NormalizerSpec {
+            name: "nmt_nfkc".into(),
+            ..Default::default()
+        };
+        assert_eq!(nfkc.normalize("ｈｉ　there"), "▁hi▁there");
+    }
+
+    #[test]
+    fn rejects_bad_models() {
+        let mut model = Vec::new();
+        bytes_field(&mut model, 1, &piece("a", 0.0, None));
+        model.pop();
+        assert_eq!(
+            SentencePiece::from_bytes(&model).err().unwrap().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+
+        let mut model = Vec::new();
+        bytes_field(&mut model, 1, &piece("a", 0.0, Some(TokenType::Normal)));
+        let mut trainer = Vec::new();
+        int_field(&mut trainer, 41, 7);
+        bytes_field(&mut model, 2, &trainer);
+        assert_eq!(
+            SentencePiece::from_bytes(&model).err().unwrap().to_string(),
+            "invalid special piece id"
+        );
+
+        let mut model = Vec::new();
+        int_field(&mut model, 1, 3);
+        assert_eq!(
+            SentencePiece::from_bytes(&model).err().unwrap().to_string(),
+            "invalid model"
+        );
+    }
+}
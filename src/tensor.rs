@@ -1,13 +1,16 @@
-use crate::ops;
+use crate::buffer::AlignedBuf;
+use crate::ops::{self, AttnParams};
+use crate::scratch::BufferPool;
 use half::f16;
 use std::fmt;
+use std::ops::Index;
 use std::sync::Arc;
 
 pub const MAX_DIMS: usize = 4;
 
 #[derive(Clone)]
 pub struct Tensor<T, const DIMS: usize> {
-    data: Arc<Box<[T]>>,
+    data: Arc<AlignedBuf<T>>,
     shape: [usize; DIMS],
 }
 
@@ -27,6 +30,28 @@ impl TensorElement for usize {
     const ZERO: Self = 0;
 }
 
+/// Floating point elements, which are accumulated in `f32` by reductions.
+pub trait FloatElement: TensorElement {
+    fn to_f32(self) -> f32;
+    fn from_f32(x: f32) -> Self;
+}
+impl FloatElement for f16 {
+    fn to_f32(self) -> f32 {
+        f16::to_f32(self)
+    }
+    fn from_f32(x: f32) -> Self {
+        f16::from_f32(x)
+    }
+}
+impl FloatElement for f32 {
+    fn to_f32(self) -> f32 {
+        self
+    }
+    fn from_f32(x: f32) -> Self {
+        x
+    }
+}
+
 pub trait ValidTensorDims {}
 impl<T> ValidTensorDims for Tensor<T, 1> {}
 impl<T> ValidTensorDims for Tensor<T, 2> {}
@@ -39,45 +64,196 @@ impl<T: fmt::Debug, const DIMS: usize> fmt::Debug for Tensor<T, DIMS> {
     }
 }
 
-fn extend_shape<const DIMS: usize>(shape: [usize; DIMS]) -> [usize; MAX_DIMS] {
+/// Tensors with more elements than this are summarised with ellipses.
+const DISPLAY_THRESHOLD: usize = 1000;
+/// The number of leading and trailing items shown along each summarised axis.
+const DISPLAY_EDGE_ITEMS: usize = 3;
+
+fn fmt_axis<T: fmt::Display>(
+    f: &mut fmt::Formatter,
+    data: &[T],
+    shape: &[usize],
+    indent: usize,
+    summarise: bool,
+) -> fmt::Result {
+    let Some((&len, inner_shape)) = shape.split_first() else {
+        return fmt::Display::fmt(&data[0], f);
+    };
+    let stride = inner_shape.iter().product::<usize>();
+
+    let indices = if summarise && len > 2 * DISPLAY_EDGE_ITEMS {
+        (0..DISPLAY_EDGE_ITEMS)
+            .map(Some)
+            .chain([None])
+            .chain((len - DISPLAY_EDGE_ITEMS..len).map(Some))
+            .collect::<Vec<_>>()
+    } else {
+        (0..len).map(Some).collect()
+    };
+
+    write!(f, "[")?;
+    for (n, i) in indices.into_iter().enumerate() {
+        if n > 0 {
+            if inner_shape.is_empty() {
+                write!(f, ", ")?;
+            } else {
+                write!(f, ",")?;
+                for _ in 0..inner_shape.len() {
+                    writeln!(f)?;
+                }
+                write!(f, "{:indent$}", "", indent = indent + 1)?;
+            }
+        }
+
+        match i {
+            Some(i) => fmt_axis(
+                f,
+                &data[i * stride..(i + 1) * stride],
+                inner_shape,
+                indent + 1,
+                summarise,
+            )?,
+            None => write!(f, "...")?,
+        }
+    }
+    write!(f, "]")
+}
+
+/// Pretty-prints the values of the tensor as nested lists, in the style of
+/// NumPy. Formatting options such as precision are applied to each element.
+impl<T: fmt::Display, const DIMS: usize> fmt::Display for Tensor<T, DIMS> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let summarise = self.data.len() > DISPLAY_THRESHOLD;
+        fmt_axis(f, &self.data, &self.shape, 0, summarise)
+    }
+}
+
+pub(crate) fn extend_shape<const DIMS: usize>(shape: [usize; DIMS]) -> [usize; MAX_DIMS] {
     let mut o = [1; MAX_DIMS];
-    o[MAX_DIMS-DIMS..].copy_from_slice(&shape);
+    o[MAX_DIMS - DIMS..].copy_from_slice(&shape);
     o
 }
 
 impl<T: TensorElement, const DIMS: usize> Tensor<T, DIMS> {
     pub fn new(data: Vec<T>, shape: [usize; DIMS]) -> Self {
+        Self::from_buf(data.into(), shape)
+    }
+
+    pub fn from_buf(data: AlignedBuf<T>, shape: [usize; DIMS]) -> Self {
         assert_eq!(shape.iter().product::<usize>(), data.len());
 
         Self {
-            data: Arc::new(data.into_boxed_slice()),
+            data: Arc::new(data),
             shape,
         }
     }
 
     pub fn zeros(shape: [usize; DIMS]) -> Self {
-        Self::new(vec![T::ZERO; shape.iter().product::<usize>()], shape)
+        Self::from_buf(AlignedBuf::zeroed(shape.iter().product::<usize>()), shape)
     }
 
     pub fn shape(&self) -> [usize; DIMS] {
         self.shape
     }
 
-    pub fn repeat<const DIMS2: usize>(&self, shape: [usize; DIMS2]) -> Tensor<T, DIMS2> {
-        assert!(DIMS <= DIMS2);
+    /// Returns the element at `index`, or `None` if it is out of bounds.
+    pub fn get(&self, index: [usize; DIMS]) -> Option<T> {
+        let mut offset = 0;
+        for (&i, &len) in index.iter().zip(&self.shape) {
+            if i >= len {
+                return None;
+            }
+            offset = offset * len + i;
+        }
+        Some(self.data[offset])
+    }
 
+    /// The elements of the tensor in row-major order.
+    pub fn as_slice(&self) -> &[T] {
+        &self.data
+    }
+
+    pub fn to_vec(&self) -> Vec<T> {
+        self.data.to_vec()
+    }
+
+    /// Iterates over the elements of the tensor in row-major order.
+    pub fn iter(&self) -> std::slice::Iter<'_, T> {
+        self.data.iter()
+    }
+
+    /// Iterates over the rows of the tensor, i.e. the contiguous slices along
+    /// the last axis.
+    pub fn rows(&self) -> std::slice::ChunksExact<'_, T> {
+        self.data.chunks_exact(self.shape[DIMS - 1].max(1))
+    }
+
+    pub fn repeat<const DIMS2: usize>(&self, shape: [usize; DIMS2]) -> Tensor<T, DIMS2> {
         let mut o = Tensor::zeros(shape);
+        self.repeat_into(&mut o);
+        o
+    }
+
+    /// Like [`Tensor::repeat`], writing into `out`, whose shape is the shape
+    /// to repeat to.
+    pub fn repeat_into<const DIMS2: usize>(&self, out: &mut Tensor<T, DIMS2>) {
+        assert!(DIMS <= DIMS2);
 
         unsafe {
             ops::repeat(
                 self.data.as_ptr(),
-                Arc::get_mut(&mut o.data).unwrap().as_mut_ptr(),
+                out.output_ptr(),
                 extend_shape(self.shape),
-                extend_shape(shape),
+                extend_shape(out.shape),
             )
         }
+    }
 
-        o
+    /// Mutable access to the elements, copying them first if they're shared
+    /// with another tensor (e.g. a clone or a reshape).
+    pub fn make_mut(&mut self) -> &mut [T] {
+        &mut Arc::make_mut(&mut self.data)[..]
+    }
+
+    /// Mutable access to the elements, or `None` if they're shared with
+    /// another tensor, for callers who would rather not pay for a copy.
+    pub fn try_mut(&mut self) -> Option<&mut [T]> {
+        Arc::get_mut(&mut self.data).map(|data| &mut data[..])
+    }
+
+    pub fn fill(&mut self, value: T) {
+        self.output().fill(value);
+    }
+
+    /// Applies `f` to every element in place.
+    pub fn map_inplace(&mut self, f: impl Fn(T) -> T) {
+        for x in self.make_mut() {
+            *x = f(*x);
+        }
+    }
+
+    /// Mutable access for an op that overwrites every element. If the data is
+    /// shared, the tensor is given a fresh buffer rather than a copy.
+    fn output(&mut self) -> &mut [T] {
+        if Arc::get_mut(&mut self.data).is_none() {
+            self.data = Arc::new(AlignedBuf::zeroed(self.data.len()));
+        }
+        Arc::get_mut(&mut self.data).unwrap()
+    }
+
+    /// A pointer to write an op's output through, see [`Tensor::output`].
+    fn output_ptr(&mut self) -> *mut T {
+        self.output().as_mut_ptr()
+    }
+
+    /// Reuses `data` as the storage of a tensor. `data` must not be shared.
+    pub(crate) fn from_shared(data: Arc<AlignedBuf<T>>, shape: [usize; DIMS]) -> Self {
+        assert_eq!(shape.iter().product::<usize>(), data.len());
+        Self { data, shape }
+    }
+
+    pub(crate) fn into_shared(self) -> Arc<AlignedBuf<T>> {
+        self.data
     }
 
     pub fn reshape<const DIMS2: usize>(&self, shape: [usize; DIMS2]) -> Tensor<T, DIMS2> {
@@ -87,6 +263,286 @@ impl<T: TensorElement, const DIMS: usize> Tensor<T, DIMS> {
             shape,
         }
     }
+
+    /// Concatenates `tensors` along `axis`. Every other axis must match.
+    pub fn concat(tensors: &[&Self], axis: usize) -> Self {
+        assert!(!tensors.is_empty());
+        assert!(axis < DIMS);
+
+        let mut shape = tensors[0].shape;
+        shape[axis] = tensors.iter().map(|t| t.shape[axis]).sum();
+
+        let mut o = Self::zeros(shape);
+        Self::concat_into(&mut o, tensors, axis, 0);
+        o
+    }
+
+    /// Writes the concatenation of `tensors` along `axis` into `dst`, starting
+    /// at `offset` along that axis. This lets a preallocated buffer such as a
+    /// KV cache be appended to without reallocating.
+    pub fn concat_into(dst: &mut Self, tensors: &[&Self], axis: usize, offset: usize) {
+        assert!(axis < DIMS);
+
+        let dst_shape = dst.shape;
+        // The rest of `dst` has to be kept, so this copies if it's shared.
+        let dst_ptr = dst.make_mut().as_mut_ptr();
+
+        let mut offset = offset;
+        for t in tensors {
+            for (i, (&len, &dst_len)) in t.shape.iter().zip(&dst_shape).enumerate() {
+                assert!(i == axis || len == dst_len);
+            }
+            assert!(offset + t.shape[axis] <= dst_shape[axis]);
+
+            unsafe {
+                ops::copy_along_axis(
+                    t.data.as_ptr(),
+                    extend_shape(t.shape),
+                    0..t.shape[axis],
+                    dst_ptr,
+                    extend_shape(dst_shape),
+                    offset,
+                    axis + MAX_DIMS - DIMS,
+                );
+            }
+
+            offset += t.shape[axis];
+        }
+    }
+
+    /// Stacks `tensors` along a new axis inserted at `axis`.
+    pub fn stack<const DIMS2: usize>(tensors: &[&Self], axis: usize) -> Tensor<T, DIMS2> {
+        assert_eq!(DIMS + 1, DIMS2);
+        assert!(axis < DIMS2);
+
+        let expanded = tensors
+            .iter()
+            .map(|t| {
+                let mut shape = [1; DIMS2];
+                shape[..axis].copy_from_slice(&t.shape[..axis]);
+                shape[axis + 1..].copy_from_slice(&t.shape[axis..]);
+                t.reshape(shape)
+            })
+            .collect::<Vec<_>>();
+
+        Tensor::concat(&expanded.iter().collect::<Vec<_>>(), axis)
+    }
+
+    /// Splits the tensor along `axis` into pieces of the given `sizes`, which
+    /// must add up to the length of that axis.
+    pub fn split(&self, sizes: &[usize], axis: usize) -> Vec<Self> {
+        assert!(axis < DIMS);
+        assert_eq!(sizes.iter().sum::<usize>(), self.shape[axis]);
+
+        let mut offset = 0;
+        sizes
+            .iter()
+            .map(|&size| {
+                let mut shape = self.shape;
+                shape[axis] = size;
+                let mut o = Self::zeros(shape);
+
+                unsafe {
+                    ops::copy_along_axis(
+                        self.data.as_ptr(),
+                        extend_shape(self.shape),
+                        offset..offset + size,
+                        o.output_ptr(),
+                        extend_shape(shape),
+                        0,
+                        axis + MAX_DIMS - DIMS,
+                    );
+                }
+
+                offset += size;
+                o
+            })
+            .collect()
+    }
+
+    /// Splits the tensor along `axis` into `n` pieces of equal size, except
+    /// for the last one, which may be smaller.
+    pub fn chunk(&self, n: usize, axis: usize) -> Vec<Self> {
+        assert!(n > 0);
+        assert!(axis < DIMS);
+
+        let len = self.shape[axis];
+        let size = len.div_ceil(n);
+        let sizes = (0..len)
+            .step_by(size.max(1))
+            .map(|start| size.min(len - start))
+            .collect::<Vec<_>>();
+
+        self.split(&sizes, axis)
+    }
+}
+
+/// Reductions along a single axis. The reduced axis is kept with a length of 1
+/// (or `k` for `topk`), so the result has the same number of dimensions.
+impl<T: FloatElement, const DIMS: usize> Tensor<T, DIMS> {
+    fn reduced_shape(&self, axis: usize, len: usize) -> [usize; DIMS] {
+        assert!(axis < DIMS);
+        let mut shape = self.shape;
+        shape[axis] = len;
+        shape
+    }
+
+    pub fn sum(&self, axis: usize) -> Self {
+        let mut o = Self::zeros(self.reduced_shape(axis, 1));
+
+        unsafe {
+            ops::sum_raw(
+                self.data.as_ptr(),
+                o.output_ptr(),
+                extend_shape(self.shape),
+                axis + MAX_DIMS - DIMS,
+            );
+        }
+
+        o
+    }
+
+    pub fn mean(&self, axis: usize) -> Self {
+        let mut o = Self::zeros(self.reduced_shape(axis, 1));
+
+        unsafe {
+            ops::mean_raw(
+                self.data.as_ptr(),
+                o.output_ptr(),
+                extend_shape(self.shape),
+                axis + MAX_DIMS - DIMS,
+            );
+        }
+
+        o
+    }
+
+    pub fn max(&self, axis: usize) -> Self {
+        let mut o = Self::zeros(self.reduced_shape(axis, 1));
+
+        unsafe {
+            ops::max_raw(
+                self.data.as_ptr(),
+                o.output_ptr(),
+                extend_shape(self.shape),
+                axis + MAX_DIMS - DIMS,
+            );
+        }
+
+        o
+    }
+
+    pub fn min(&self, axis: usize) -> Self {
+        let mut o = Self::zeros(self.reduced_shape(axis, 1));
+
+        unsafe {
+            ops::min_raw(
+                self.data.as_ptr(),
+                o.output_ptr(),
+                extend_shape(self.shape),
+                axis + MAX_DIMS - DIMS,
+            );
+        }
+
+        o
+    }
+
+    pub fn argmax(&self, axis: usize) -> Tensor<usize, DIMS> {
+        let mut o = Tensor::zeros(self.reduced_shape(axis, 1));
+
+        unsafe {
+            ops::argmax_raw(
+                self.data.as_ptr(),
+                o.output_ptr(),
+                extend_shape(self.shape),
+                axis + MAX_DIMS - DIMS,
+            );
+        }
+
+        o
+    }
+
+    /// Returns the `k` largest values along `axis` in descending order, along
+    /// with their indices. Ties are broken in favour of the lower index.
+    pub fn topk(&self, axis: usize, k: usize) -> (Self, Tensor<usize, DIMS>) {
+        assert!(k <= self.shape[axis]);
+
+        let shape = self.reduced_shape(axis, k);
+        let mut values = Self::zeros(shape);
+        let mut indices = Tensor::zeros(shape);
+
+        unsafe {
+            ops::topk_raw(
+                self.data.as_ptr(),
+                values.output_ptr(),
+                indices.output_ptr(),
+                extend_shape(self.shape),
+                axis + MAX_DIMS - DIMS,
+                k,
+            );
+        }
+
+        (values, indices)
+    }
+}
+
+impl<T: TensorElement, const DIMS: usize> Index<[usize; DIMS]> for Tensor<T, DIMS> {
+    type Output = T;
+
+    fn index(&self, index: [usize; DIMS]) -> &T {
+        let mut offset = 0;
+        for (&i, &len) in index.iter().zip(&self.shape) {
+            assert!(
+                i < len,
+                "index {index:?} out of bounds for shape {:?}",
+                self.shape
+            );
+            offset = offset * len + i;
+        }
+        &self.data[offset]
+    }
+}
+
+impl<'a, T, const DIMS: usize> IntoIterator for &'a Tensor<T, DIMS> {
+    type Item = &'a T;
+    type IntoIter = std::slice::Iter<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.data.iter()
+    }
+}
+
+impl<T: FloatElement, const DIMS: usize> Tensor<T, DIMS> {
+    /// Whether both tensors have the same shape and every pair of elements
+    /// satisfies `|a - b| <= atol + rtol * |b|`, as in NumPy.
+    pub fn allclose(&self, other: &Self, rtol: f32, atol: f32) -> bool {
+        self.shape == other.shape
+            && self.data.iter().zip(other.data.iter()).all(|(&a, &b)| {
+                let (a, b) = (a.to_f32(), b.to_f32());
+                (a - b).abs() <= atol + rtol * b.abs()
+            })
+    }
+}
+
+/// Asserts that two float tensors are [`allclose`](Tensor::allclose), printing
+/// both on failure. The tolerances default to `rtol = 1e-5, atol = 1e-8`.
+#[macro_export]
+macro_rules! assert_tensor_close {
+    ($left:expr, $right:expr $(,)?) => {
+        $crate::assert_tensor_close!($left, $right, rtol = 1e-5, atol = 1e-8)
+    };
+    ($left:expr, $right:expr, rtol = $rtol:expr, atol = $atol:expr $(,)?) => {
+        match (&$left, &$right) {
+            (left, right) => {
+                if !left.allclose(right, $rtol, $atol) {
+                    panic!(
+                        "assertion failed: tensors are not close (rtol = {}, atol = {})\n  left: {:?} {}\n right: {:?} {}",
+                        $rtol, $atol, left, left, right, right,
+                    );
+                }
+            }
+        }
+    };
 }
 
 impl<T: TensorElement> From<Vec<T>> for Tensor<T, 1> {
@@ -99,132 +555,535 @@ impl<T: TensorElement> From<Vec<T>> for Tensor<T, 1> {
 impl<const DIMS: usize> Tensor<f16, DIMS> {
     pub fn silu(&self) -> Self {
         let mut y = Self::zeros(self.shape);
+        self.silu_into(&mut y);
+        y
+    }
+
+    pub fn silu_into(&self, out: &mut Self) {
+        assert_eq!(self.shape, out.shape);
 
         unsafe {
             ops::silu_raw_f16(
                 self.data.as_ptr(),
-                Arc::get_mut(&mut y.data).unwrap().as_mut_ptr(),
+                out.output_ptr(),
                 extend_shape(self.shape),
             );
         }
+    }
+
+    pub fn silu_inplace(&mut self) {
+        let shape = extend_shape(self.shape);
+        let ptr = self.make_mut().as_mut_ptr();
+
+        unsafe {
+            ops::silu_raw_f16(ptr, ptr, shape);
+        }
+    }
 
-        y
+    pub fn gelu_inplace(&mut self) {
+        let n = self.data.len();
+        let ptr = self.make_mut().as_mut_ptr();
+
+        unsafe {
+            ops::map_raw_f16(ptr, ptr, n, ops::gelu);
+        }
     }
 
     pub fn rms_norm(&self) -> Self {
         let mut o = Self::zeros(self.shape);
+        self.rms_norm_into(&mut o);
+        o
+    }
+
+    pub fn rms_norm_into(&self, out: &mut Self) {
+        assert_eq!(self.shape, out.shape);
 
         unsafe {
             ops::rms_norm_f16(
                 self.data.as_ptr(),
-                Arc::get_mut(&mut o.data).unwrap().as_mut_ptr(),
+                out.output_ptr(),
                 extend_shape(self.shape),
             );
         }
+    }
+
+    pub fn rms_norm_inplace(&mut self) {
+        let shape = extend_shape(self.shape);
+        let ptr = self.make_mut().as_mut_ptr();
+
+        unsafe {
+            ops::rms_norm_f16(ptr, ptr, shape);
+        }
+    }
+
+    pub fn scale_inplace(&mut self, scale: f32) {
+        let n = self.data.len();
+        let ptr = self.make_mut().as_mut_ptr();
+
+        unsafe {
+            ops::scalev_raw_f16(ptr, ptr, n, scale);
+        }
+    }
+
+    /// Element-wise `self + x`. Both tensors have the same shape.
+    pub fn add(&self, x: &Self) -> Self {
+        let mut o = Self::zeros(self.shape);
+        self.add_into(x, &mut o);
+        o
+    }
+
+    pub fn add_into(&self, x: &Self, out: &mut Self) {
+        assert_eq!(self.shape, x.shape);
+        assert_eq!(self.shape, out.shape);
+
+        unsafe {
+            ops::add_raw_f16(
+                self.data.as_ptr(),
+                x.data.as_ptr(),
+                out.output_ptr(),
+                self.data.len(),
+            );
+        }
+    }
+
+    pub fn add_inplace(&mut self, x: &Self) {
+        assert_eq!(self.shape, x.shape);
+
+        let n = self.data.len();
+        let ptr = self.make_mut().as_mut_ptr();
+        unsafe {
+            ops::add_raw_f16(ptr, x.data.as_ptr(), ptr, n);
+        }
+    }
+
+    /// Element-wise `self * x`. Both tensors have the same shape.
+    pub fn mul(&self, x: &Self) -> Self {
+        let mut o = Self::zeros(self.shape);
+        self.mul_into(x, &mut o);
+        o
+    }
+
+    pub fn mul_into(&self, x: &Self, out: &mut Self) {
+        assert_eq!(self.shape, x.shape);
+        assert_eq!(self.shape, out.shape);
+
+        unsafe {
+            ops::mul_raw_f16(
+                self.data.as_ptr(),
+                x.data.as_ptr(),
+                out.output_ptr(),
+                self.data.len(),
+            );
+        }
+    }
+
+    pub fn mul_inplace(&mut self, x: &Self) {
+        assert_eq!(self.shape, x.shape);
+
+        let n = self.data.len();
+        let ptr = self.make_mut().as_mut_ptr();
+        unsafe {
+            ops::mul_raw_f16(ptr, x.data.as_ptr(), ptr, n);
+        }
+    }
+
+    /// Applies `f` to every element, computing in `f32`.
+    pub(crate) fn map_into(&self, out: &mut Self, f: impl Fn(f32) -> f32) {
+        assert_eq!(self.shape, out.shape);
+
+        unsafe {
+            ops::map_raw_f16(self.data.as_ptr(), out.output_ptr(), self.data.len(), f);
+        }
+    }
+
+    /// Batched `self · xᵀ` over the last two axes, where `self` is `[.., m, n]`
+    /// and `x` is `[.., p, n]`, giving `[.., m, p]`.
+    pub fn dot(&self, x: &Self) -> Self {
+        assert!(DIMS >= 2);
 
+        let mut shape = self.shape;
+        shape[DIMS - 1] = x.shape[DIMS - 2];
+        let mut o = Self::zeros(shape);
+        self.dot_into(x, &mut o);
         o
     }
 
-    pub fn dot<const DIMS2: usize>(&self, x: &Tensor<f16, DIMS2>) -> Tensor<f16, DIMS2> {
-        let mut o = Tensor<f16, DIMS2>::zeros([]);
+    pub fn dot_into(&self, x: &Self, out: &mut Self) {
+        assert!(DIMS >= 2);
+        assert_eq!(self.shape[..DIMS - 2], x.shape[..DIMS - 2]);
+        assert_eq!(self.shape[DIMS - 1], x.shape[DIMS - 1]);
+        assert_eq!(out.shape[..DIMS - 1], self.shape[..DIMS - 1]);
+        assert_eq!(out.shape[DIMS - 1], x.shape[DIMS - 2]);
 
+        unsafe {
+            ops::generic_dot_f16(
+                self.data.as_ptr(),
+                x.data.as_ptr(),
+                out.output_ptr(),
+                extend_shape(self.shape),
+                extend_shape(x.shape),
+            );
+        }
     }
 }
 
 impl<T: TensorElement> Tensor<T, 2> {
-    pub fn get_rows<I: Into<usize>>(&self, idxs: Tensor<I, 1>) -> Self {
-        // let mut o = Self::zeros([self.shape[0], idxs.shape[0]]);
+    /// Gathers the rows selected by `idxs`, e.g. the embeddings of a sequence
+    /// of tokens.
+    pub fn get_rows<I: Copy + Into<usize>>(&self, idxs: &Tensor<I, 1>) -> Self {
+        let mut o = Self::zeros([idxs.shape[0], self.shape[1]]);
+        self.get_rows_into(idxs, &mut o);
+        o
+    }
 
-        // unsafe {
-        //     ops::get_rows_raw(
-        //         self.data.as_ptr(),
-        //         idxs.data.as_ptr(),
-        //         Arc::get_mut(&mut o.data).unwrap().as_mut_ptr(),
-        //         self.shape[0],
-        //         self.shape[0],
-        //         idxs.shape[0],
-        //     );
-        // }
+    pub fn get_rows_into<I: Copy + Into<usize>>(&self, idxs: &Tensor<I, 1>, out: &mut Self) {
+        assert_eq!(out.shape, [idxs.shape[0], self.shape[1]]);
 
-        // o
-        todo!()
+        unsafe {
+            ops::get_rows_raw(
+                self.data.as_ptr(),
+                idxs.data.as_ptr(),
+                out.output_ptr(),
+                self.shape[1],
+                self.shape[0],
+                idxs.shape[0],
+            );
+        }
+    }
+
+    /// Swaps the two axes, copying the data.
+    pub fn transpose(&self) -> Self {
+        let [rows, cols] = self.shape;
+        let mut o = Self::zeros([cols, rows]);
+
+        let out = o.make_mut();
+        for (r, row) in self.rows().enumerate() {
+            for (c, &x) in row.iter().enumerate() {
+                out[c * rows + r] = x;
+            }
+        }
+
+        o
+    }
+
+    /// Copies the `out.shape()[1]` columns starting at `offset` into `out`,
+    /// e.g. to split a fused projection.
+    pub fn columns_into(&self, offset: usize, out: &mut Self) {
+        let [rows, cols] = out.shape;
+        assert_eq!(self.shape[0], rows);
+        assert!(offset + cols <= self.shape[1]);
+
+        for (src, dst) in self.rows().zip(out.output().chunks_exact_mut(cols.max(1))) {
+            dst.copy_from_slice(&src[offset..offset + cols]);
+        }
     }
 }
 
 impl Tensor<f16, 2> {
+    /// Multiplies each row of `x` by this `[m, n]` weight matrix, i.e.
+    /// computes `x · selfᵀ`. `x` is either `[n]` or `[p, n]`, and the result is
+    /// `[m]` or `[p, m]` respectively.
     pub fn matmul<const DIMS: usize>(&self, x: &Tensor<f16, DIMS>) -> Tensor<f16, DIMS> {
-        assert!(DIMS <= 2);
-        assert_eq!(self.shape[0], x.shape[0]);
+        assert!(DIMS == 1 || DIMS == 2);
 
         let mut shape = x.shape;
-        shape[0] = self.shape[0];
-        let mut y = dbg!(Tensor::<f16, DIMS>::zeros(shape));
+        shape[DIMS - 1] = self.shape[0];
+        let mut y = Tensor::<f16, DIMS>::zeros(shape);
+        self.matmul_into(x, &mut y);
+        y
+    }
+
+    pub fn matmul_into<const DIMS: usize>(
+        &self,
+        x: &Tensor<f16, DIMS>,
+        out: &mut Tensor<f16, DIMS>,
+    ) {
+        assert!(DIMS == 1 || DIMS == 2);
+        assert_eq!(self.shape[1], x.shape[DIMS - 1]);
+        assert_eq!(out.shape[..DIMS - 1], x.shape[..DIMS - 1]);
+        assert_eq!(out.shape[DIMS - 1], self.shape[0]);
 
         unsafe {
-            ops::matmul_raw_f16(
-                self.data.as_ptr(),
+            ops::generic_dot_f16(
                 x.data.as_ptr(),
-                Arc::get_mut(&mut y.data).unwrap().as_mut_ptr(),
-                self.shape[1],
-                self.shape[0],
-                x.shape.get(1).copied().unwrap_or(1),
-                self.shape[0],
-                x.shape[0],
+                self.data.as_ptr(),
+                out.output_ptr(),
+                extend_shape(x.shape),
+                extend_shape(self.shape),
             );
         }
-
-        y
     }
 
+    /// Attention over `N` positions with a head size of `D`. `q` and `k` are
+    /// `[N, D]`, while the values (`self`) are transposed to `[D, N]`. The
+    /// output is `[N, D]`.
     pub fn flash_attn(&self, q: Self, k: Self) -> Self {
-        assert_eq!(self.shape[0], q.shape[0]); // D
-        assert_eq!(self.shape[0], k.shape[0]);
-        assert_eq!(self.shape[1], q.shape[1]); // N
-        assert_eq!(self.shape[1], k.shape[1]);
+        let [d, n] = self.shape;
+        let mut o = Self::zeros([n, d]);
+        self.flash_attn_into(&q, &k, &mut o);
+        o
+    }
 
-        let mut o = Self::zeros(self.shape);
+    pub fn flash_attn_into(&self, q: &Self, k: &Self, out: &mut Self) {
+        let [d, n] = self.shape;
+        assert_eq!(q.shape, [n, d]);
+        assert_eq!(k.shape, [n, d]);
+        assert_eq!(out.shape, [n, d]);
 
         unsafe {
             ops::flash_attn_raw_f16(
                 q.data.as_ptr(),
                 k.data.as_ptr(),
                 self.data.as_ptr(),
-                Arc::get_mut(&mut o.data).unwrap().as_mut_ptr(),
-                self.shape[1],
-                self.shape[0],
-                self.shape[1],
-                k.shape[1],
-                self.shape[1],
-                o.shape[1],
+                out.output_ptr(),
+                n,
+                d,
+                d,
+                d,
+                n,
+                d,
             );
         }
+    }
 
-        o
+    /// Normalizes each row by its root mean square, then scales it by `w`.
+    pub fn rms_norm_scaled_into(&self, w: &Tensor<f32, 1>, eps: f32, out: &mut Self) {
+        let [rows, n] = self.shape;
+        assert_eq!(w.shape, [n]);
+        assert_eq!(out.shape, self.shape);
+
+        unsafe {
+            ops::rms_norm_scaled_f16(
+                self.data.as_ptr(),
+                w.data.as_ptr(),
+                out.output_ptr(),
+                rows,
+                n,
+                eps,
+            );
+        }
     }
+
+    /// Normalizes each row to zero mean and unit variance, then scales it by
+    /// `w` and shifts it by `b`.
+    pub fn layer_norm_into(
+        &self,
+        w: &Tensor<f32, 1>,
+        b: &Tensor<f32, 1>,
+        eps: f32,
+        out: &mut Self,
+    ) {
+        let [rows, n] = self.shape;
+        assert_eq!(w.shape, [n]);
+        assert_eq!(b.shape, [n]);
+        assert_eq!(out.shape, self.shape);
+
+        unsafe {
+            ops::layer_norm_f16(
+                self.data.as_ptr(),
+                w.data.as_ptr(),
+                b.data.as_ptr(),
+                out.output_ptr(),
+                rows,
+                n,
+                eps,
+            );
+        }
+    }
+
+    /// Adds `b` to every row, e.g. a bias.
+    pub fn add_row_inplace(&mut self, b: &Tensor<f32, 1>) {
+        let [rows, n] = self.shape;
+        assert_eq!(b.shape, [n]);
+
+        let ptr = self.make_mut().as_mut_ptr();
+        unsafe {
+            ops::add_row_f16_f32(ptr, b.data.as_ptr(), rows, n);
+        }
+    }
+
+    /// Applies rotary position embeddings to rows of `n_heads` heads, where
+    /// row `i` is at position `pos + i`. Only the first `rot_dims` elements of
+    /// each head are rotated.
+    pub fn rope_inplace(
+        &mut self,
+        pos: usize,
+        n_heads: usize,
+        rot_dims: usize,
+        theta: f32,
+        style: RopeStyle,
+    ) {
+        let [rows, n] = self.shape;
+        assert_eq!(n % n_heads, 0);
+
+        let ptr = self.make_mut().as_mut_ptr();
+        unsafe {
+            ops::rope_f16(
+                ptr,
+                pos..pos + rows,
+                n_heads,
+                n / n_heads,
+                rot_dims,
+                theta,
+                style == RopeStyle::NeoX,
+            );
+        }
+    }
+
+    /// Causal multi-head attention with `self` as the `[n, n_heads * d]`
+    /// queries, which follow `attn.past` earlier positions. The first
+    /// `attn.past + n` rows of `k` and `v` hold the keys and values of all of
+    /// those positions, `n_kv_heads * d` wide. The scores and sums are kept in
+    /// buffers from `pool`.
+    pub fn causal_attn_into(
+        &self,
+        k: &Self,
+        v: &Self,
+        attn: Attention,
+        out: &mut Self,
+        pool: &mut BufferPool<f32>,
+    ) {
+        let Attention {
+            n_heads,
+            n_kv_heads,
+            window,
+            past,
+        } = attn;
+        let [n, q_dim] = self.shape;
+        assert_eq!(q_dim % n_heads, 0);
+        let d = q_dim / n_heads;
+        let [kv_rows, kv_dim] = k.shape;
+        assert!(kv_rows >= past + n && kv_dim == n_kv_heads * d);
+        assert_eq!(v.shape, k.shape);
+        assert_eq!(out.shape, self.shape);
+
+        let mut scores = pool.take([kv_rows]);
+        let mut acc = pool.take([d]);
+        unsafe {
+            ops::causal_attn_f16(
+                self.data.as_ptr(),
+                k.data.as_ptr(),
+                v.data.as_ptr(),
+                out.output_ptr(),
+                scores.output(),
+                acc.output(),
+                AttnParams {
+                    n,
+                    d,
+                    n_heads,
+                    n_kv_heads,
+                    window,
+                    past,
+                },
+            );
+        }
+        pool.recycle(scores);
+        pool.recycle(acc);
+    }
+}
+
+/// How the heads of [`Tensor::causal_attn_into`] attend. Query heads share
+/// key and value heads in groups of `n_heads / n_kv_heads`, and the queries
+/// follow `past` positions whose keys and values come first. With a `window`,
+/// each position only attends to the `window` positions up to and including
+/// itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Attention {
+    pub n_heads: usize,
+    pub n_kv_heads: usize,
+    pub window: Option<usize>,
+    pub past: usize,
+}
+
+/// How rotary position embeddings pair up the elements of a head.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RopeStyle {
+    /// Adjacent elements, as in the original LLaMA checkpoints.
+    Interleaved,
+    /// The first half of the rotated elements with the second half, as in
+    /// GPT-NeoX and HuggingFace checkpoints.
+    NeoX,
 }
 
 impl Tensor<f32, 2> {
+    /// Like [`Tensor::<f16, 2>::matmul`], for an `f32` weight matrix.
     pub fn matmul<const DIMS: usize>(&self, x: &Tensor<f16, DIMS>) -> Tensor<f16, DIMS> {
-        assert!(DIMS <= 2);
-        assert_eq!(self.shape[0], x.shape[0]);
+        assert!(DIMS == 1 || DIMS == 2);
 
         let mut shape = x.shape;
-        shape[0] = self.shape[0];
+        shape[DIMS - 1] = self.shape[0];
         let mut y = Tensor::<f16, DIMS>::zeros(shape);
+        self.matmul_into(x, &mut y);
+        y
+    }
+
+    pub fn matmul_into<const DIMS: usize>(
+        &self,
+        x: &Tensor<f16, DIMS>,
+        out: &mut Tensor<f16, DIMS>,
+    ) {
+        assert!(DIMS == 1 || DIMS == 2);
+        assert_eq!(self.shape[1], x.shape[DIMS - 1]);
+        assert_eq!(out.shape[..DIMS - 1], x.shape[..DIMS - 1]);
+        assert_eq!(out.shape[DIMS - 1], self.shape[0]);
 
         unsafe {
-            ops::matmul_raw_f32_f16(
-                self.data.as_ptr(),
+            ops::generic_dot_f16_f32(
                 x.data.as_ptr(),
-                Arc::get_mut(&mut y.data).unwrap().as_mut_ptr(),
-                self.shape[1],
-                self.shape[0],
-                x.shape.get(1).copied().unwrap_or(1),
-                self.shape[0],
-                x.shape[0],
+                self.data.as_ptr(),
+                out.output_ptr(),
+                extend_shape(x.shape),
+                extend_shape(self.shape),
             );
         }
+    }
+}
 
-        y
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn copy_on_write() {
+        let mut a = Tensor::<f32, 2>::new(vec![1.0, 2.0, 3.0, 4.0], [2, 2]);
+        let b = a.clone();
+        let c = a.reshape([4]);
+
+        assert!(a.try_mut().is_none());
+        a.make_mut()[0] = 5.0;
+        a.map_inplace(|x| x * 2.0);
+
+        assert_eq!(a.as_slice(), [10.0, 4.0, 6.0, 8.0]);
+        assert_eq!(b.as_slice(), [1.0, 2.0, 3.0, 4.0]);
+        assert_eq!(c.as_slice(), [1.0, 2.0, 3.0, 4.0]);
+        assert!(a.try_mut().is_some());
+    }
+
+    #[test]
+    fn outputs_into_shared_tensors() {
+        let x = Tensor::<f16, 1>::new(vec![f16::from_f32(1.0); 4], [4]);
+        let mut out = Tensor::zeros([4]);
+        let shared = out.clone();
+
+        x.silu_into(&mut out);
+        assert!(shared.iter().all(|&y| y == f16::ZERO));
+
+        let mut y = x.clone();
+        y.silu_inplace();
+        assert_tensor_close!(y, out);
+        assert!(x.iter().all(|&y| y == f16::from_f32(1.0)));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn concat_into_shared_cache() {
+        let mut cache = Tensor::<f32, 2>::zeros([3, 2]);
+        let row = Tensor::new(vec![1.0, 2.0], [1, 2]);
+        Tensor::concat_into(&mut cache, &[&row], 0, 0);
+
+        let snapshot = cache.clone();
+        Tensor::concat_into(&mut cache, &[&row, &row], 0, 1);
+
+        assert_eq!(cache.as_slice(), [1.0, 2.0, 1.0, 2.0, 1.0, 2.0]);
+        assert_eq!(snapshot.as_slice(), [1.0, 2.0, 0.0, 0.0, 0.0, 0.0]);
+    }
+}
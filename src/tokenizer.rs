@@ -1,23 +1,88 @@
-use std::collections::{BinaryHeap, HashMap};
+use std::{collections::BinaryHeap, ops::Range, thread};
 
-use bstr::{BStr, BString};
+use bstr::BString;
 use ordered_float::OrderedFloat;
+use unicode_normalization::char::canonical_combining_class;
 
+use crate::sentencepiece::NormalizerSpec;
 use crate::tensor::Tensor;
+use crate::tokenizer_json::Pipeline;
+use crate::trie::{State, Trie};
 
+/// What a token stands for, numbered as SentencePiece and GGUF number them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TokenType {
+    #[default]
+    Normal = 1,
+    Unknown = 2,
+    /// Markers like BOS and EOS, which never come from text.
+    Control = 3,
+    /// Pieces that are always kept whole.
+    UserDefined = 4,
+    Unused = 5,
+    /// A single byte, for text no other piece covers, named like `<0x0A>`.
+    Byte = 6,
+}
+
+impl TokenType {
+    pub fn from_id(id: u32) -> Option<Self> {
+        Some(match id {
+            1 => Self::Normal,
+            2 => Self::Unknown,
+            3 => Self::Control,
+            4 => Self::UserDefined,
+            5 => Self::Unused,
+            6 => Self::Byte,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Clone)]
 pub struct Token {
     pub token: BString,
     pub score: f32,
+    pub ty: TokenType,
 }
 
+#[derive(Clone)]
 pub struct Vocab {
-    pub token_to_id: HashMap<BString, usize>,
+    pub token_to_id: Trie,
     pub id_to_token: Vec<Token>,
+    /// The BOS and EOS tokens, where the vocabulary's source names them.
+    pub bos: Option<usize>,
+    pub eos: Option<usize>,
+    /// The merges of a byte-level BPE, as `left right` in order of rank. A
+    /// vocabulary merged by score has none.
+    pub merges: Vec<BString>,
+}
+
+impl Vocab {
+    /// A vocabulary of `tokens`, numbered in order. If a token appears more
+    /// than once, looking it up finds the first.
+    pub fn new(tokens: Vec<Token>) -> Self {
+        let token_to_id = Trie::new(
+            tokens
+                .iter()
+                .enumerate()
+                .map(|(i, token)| (token.token.as_slice(), i)),
+        );
+        Self {
+            token_to_id,
+            id_to_token: tokens,
+            bos: None,
+            eos: None,
+            merges: Vec::new(),
+        }
+    }
 }
 
 struct Symbol {
     start_byte: usize,
     end_byte: usize,
+    /// Where the symbol's bytes lead in the vocabulary, so merging it with
+    /// the next only has to look up the next one's bytes.
+    state: Option<State>,
     prev: Option<usize>,
     next: Option<usize>,
 }
@@ -34,6 +99,7 @@ struct Bigram {
     right: Option<usize>,
     score: f32,
     n: usize,
+    state: State,
 }
 
 impl Eq for Bigram {}
@@ -52,18 +118,356 @@ impl PartialOrd for Bigram {
     }
 }
 
+/// Text that was changed from some original, with the span of the original
+/// each of its bytes came from.
+pub(crate) struct Aligned {
+    pub(crate) text: String,
+    spans: Vec<(usize, usize)>,
+}
+
+impl Aligned {
+    /// `text` unchanged, as found at byte `offset` of the original.
+    pub(crate) fn new(text: &str, offset: usize) -> Self {
+        Self {
+            text: text.to_string(),
+            spans: (offset..offset + text.len()).map(|i| (i, i + 1)).collect(),
+        }
+    }
+
+    pub(crate) fn empty() -> Self {
+        Self {
+            text: String::new(),
+            spans: Vec::new(),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.text.len()
+    }
+
+    /// Appends `s`, made from text whose bytes came from `from`. If it's as
+    /// long as that text, its bytes are taken to match one for one, and
+    /// otherwise each came from all of it.
+    pub(crate) fn push_str(&mut self, s: &str, from: &[(usize, usize)]) {
+        self.text.push_str(s);
+        if s.len() == from.len() {
+            self.spans.extend_from_slice(from);
+        } else {
+            let span = (from[0].0, from[from.len() - 1].1);
+            self.spans.resize(self.spans.len() + s.len(), span);
+        }
+    }
+
+    /// Appends `s`, which came from nowhere, just before byte `at` of the
+    /// original.
+    pub(crate) fn insert_str(&mut self, s: &str, at: usize) {
+        self.push_str(s, &[(at, at)]);
+    }
+
+    pub(crate) fn truncate(&mut self, len: usize) {
+        self.text.truncate(len);
+        self.spans.truncate(len);
+    }
+
+    /// Where each byte in `range` of the text came from.
+    pub(crate) fn spans(&self, range: Range<usize>) -> &[(usize, usize)] {
+        &self.spans[range]
+    }
+
+    /// The span of the original that `range` of the text came from.
+    pub(crate) fn span(&self, range: Range<usize>) -> Range<usize> {
+        match &self.spans[range] {
+            [] => 0..0,
+            spans => spans[0].0..spans[spans.len() - 1].1,
+        }
+    }
+
+    pub(crate) fn slice(&self, range: Range<usize>) -> Self {
+        Self {
+            text: self.text[range.clone()].to_string(),
+            spans: self.spans[range].to_vec(),
+        }
+    }
+
+    /// Where the original text starts, for text inserted before it.
+    pub(crate) fn start(&self) -> usize {
+        self.spans.first().map_or(0, |span| span.0)
+    }
+
+    /// Applies `f`, which may change text like Unicode normalization does.
+    /// The text is changed a run at a time, each a character and the
+    /// combining marks after it, so changes stay aligned to their runs where
+    /// that gives the same text as changing it whole.
+    pub(crate) fn map(&self, f: impl Fn(&str) -> String) -> Self {
+        let mut bounds = (self.text.char_indices())
+            .filter(|&(i, c)| i == 0 || canonical_combining_class(c) == 0)
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+        bounds.push(self.len());
+
+        let whole = f(&self.text);
+        let mut out = Self::empty();
+        for run in bounds.windows(2) {
+            let changed = f(&self.text[run[0]..run[1]]);
+            if !changed.is_empty() {
+                out.push_str(&changed, &self.spans[run[0]..run[1]]);
+            }
+        }
+        if out.text != whole {
+            out = Self::empty();
+            if !whole.is_empty() {
+                out.push_str(&whole, &self.spans);
+            }
+        }
+        out
+    }
+}
+
+/// Token ids with the bytes of the text each came from.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Encoding {
+    pub ids: Vec<usize>,
+    /// The byte range of the text each token came from. Tokens added around
+    /// it, like BOS, get empty ranges where they're added, and byte tokens
+    /// each get their byte.
+    pub offsets: Vec<Range<usize>>,
+    /// Whether each token should be attended to, which padding shouldn't.
+    pub attention_mask: Vec<bool>,
+}
+
+impl Encoding {
+    pub(crate) fn push(&mut self, id: usize, offsets: Range<usize>) {
+        self.ids.push(id);
+        self.offsets.push(offsets);
+        self.attention_mask.push(true);
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    /// Keeps the first `len` tokens.
+    pub fn truncate(&mut self, len: usize) {
+        self.ids.truncate(len);
+        self.offsets.truncate(len);
+        self.attention_mask.truncate(len);
+    }
+
+    /// Appends `pad_id` until there are `len` tokens, masked out and with
+    /// empty offsets at the end of the text.
+    pub fn pad(&mut self, len: usize, pad_id: usize) {
+        let end = self.offsets.last().map_or(0, |offsets| offsets.end);
+        while self.len() < len {
+            self.ids.push(pad_id);
+            self.offsets.push(end..end);
+            self.attention_mask.push(false);
+        }
+    }
+}
+
+/// How [`Tokenizer::encode_with`] treats special tokens.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EncodeOptions {
+    pub add_bos: bool,
+    pub add_eos: bool,
+    /// Whether control tokens written in the text, like `</s>`, become those
+    /// tokens. Otherwise they're split into pieces like any other text, so
+    /// user input can't forge them.
+    pub parse_special: bool,
+}
+
+impl Default for EncodeOptions {
+    fn default() -> Self {
+        Self {
+            add_bos: true,
+            add_eos: false,
+            parse_special: false,
+        }
+    }
+}
+
+/// How [`Tokenizer::encode_batch`] encodes and shapes each text.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BatchOptions {
+    pub encode: EncodeOptions,
+    /// The most tokens to keep of each text. The rest, including any EOS, are
+    /// cut off.
+    pub max_len: Option<usize>,
+    /// Pads every encoding with this token to `max_len` if it's set, and to
+    /// the longest encoding otherwise.
+    pub pad_id: Option<usize>,
+}
+
 pub struct Tokenizer {
-    vocab: Vocab,
+    pub(crate) vocab: Vocab,
+    pub(crate) bos: Option<usize>,
+    pub(crate) eos: Option<usize>,
+    pub(crate) unk: Option<usize>,
+    /// The `<0xXX>` token of each byte, for pieces the vocabulary lacks.
+    pub(crate) byte_ids: [Option<usize>; 256],
+    /// Control and user-defined tokens, which are matched in the text before
+    /// it's split into pieces.
+    specials: Vec<usize>,
+    /// How the text between special tokens is normalized.
+    pub(crate) normalizer: NormalizerSpec,
+    /// How a `tokenizer.json` splits text, used instead of merging by score.
+    pub(crate) json: Option<Box<Pipeline>>,
 }
 
 impl Tokenizer {
+    /// A tokenizer for `vocab`, normalizing text like LLaMA's. BOS and EOS
+    /// are the ones `vocab` names, or else found by their SentencePiece
+    /// names, `<s>` and `</s>`, as is `<unk>`.
     pub fn new(vocab: Vocab) -> Self {
-        Self { vocab }
+        let id = |token: &str| vocab.token_to_id.get(token);
+        let unk = vocab
+            .id_to_token
+            .iter()
+            .position(|t| t.ty == TokenType::Unknown)
+            .or_else(|| id("<unk>"));
+        let byte_ids = std::array::from_fn(|b| id(&format!("<0x{b:02X}>")));
+        let specials = (0..vocab.id_to_token.len())
+            .filter(|&i| {
+                let token = &vocab.id_to_token[i];
+                matches!(token.ty, TokenType::Control | TokenType::UserDefined)
+                    && !token.token.is_empty()
+            })
+            .collect();
+        Self {
+            bos: vocab.bos.or_else(|| id("<s>")),
+            eos: vocab.eos.or_else(|| id("</s>")),
+            unk,
+            byte_ids,
+            specials,
+            normalizer: NormalizerSpec::llama(),
+            json: None,
+            vocab,
+        }
+    }
+
+    /// Uses `bos` and `eos` rather than the tokens named `<s>` and `</s>`,
+    /// for models whose metadata names them.
+    pub fn with_special_ids(self, bos: Option<usize>, eos: Option<usize>) -> Self {
+        Self { bos, eos, ..self }
     }
 
-    /// Not sure if this even makes sense
+    pub fn vocab(&self) -> &Vocab {
+        &self.vocab
+    }
+
+    pub fn bos_id(&self) -> Option<usize> {
+        self.bos
+    }
+
+    pub fn eos_id(&self) -> Option<usize> {
+        self.eos
+    }
+
+    /// Encodes `text` after a BOS token, with special tokens in it split
+    /// like any other text.
     pub fn encode(&self, text: &str) -> Tensor<usize, 1> {
-        let mut output = vec![1];
+        self.encode_with(text, EncodeOptions::default())
+    }
+
+    pub fn encode_with(&self, text: &str, options: EncodeOptions) -> Tensor<usize, 1> {
+        self.encode_with_offsets(text, options).ids.into()
+    }
+
+    /// Encodes `text`, keeping track of which bytes of it each token came
+    /// from.
+    pub fn encode_with_offsets(&self, text: &str, options: EncodeOptions) -> Encoding {
+        let mut output = Encoding::default();
+        if let (true, Some(bos)) = (options.add_bos, self.bos) {
+            output.push(bos, 0..0);
+        }
+
+        if let Some(json) = &self.json {
+            json.encode(&self.vocab, text, options.parse_special, &mut output);
+        } else {
+            let mut start = 0;
+            let mut i = 0;
+            while i < text.len() {
+                match self.special_at(text, i, options.parse_special) {
+                    Some(id) => {
+                        self.encode_text(&text[start..i], start, &mut output);
+                        let len = self.vocab.id_to_token[id].token.len();
+                        output.push(id, i..i + len);
+                        i += len;
+                        start = i;
+                    }
+                    None => i += text[i..].chars().next().unwrap().len_utf8(),
+                }
+            }
+            self.encode_text(&text[start..], start, &mut output);
+        }
+
+        if let (true, Some(eos)) = (options.add_eos, self.eos) {
+            output.push(eos, text.len()..text.len());
+        }
+        output
+    }
+
+    /// Encodes `texts` on as many threads as there are cores.
+    pub fn encode_batch(&self, texts: &[&str], options: BatchOptions) -> Vec<Encoding> {
+        let threads = thread::available_parallelism().map_or(1, |n| n.get());
+        let mut encodings = vec![Encoding::default(); texts.len()];
+        let chunk_size = texts.len().div_ceil(threads).max(1);
+        thread::scope(|s| {
+            for (texts, encodings) in texts
+                .chunks(chunk_size)
+                .zip(encodings.chunks_mut(chunk_size))
+            {
+                s.spawn(move || {
+                    for (text, encoding) in texts.iter().zip(encodings) {
+                        *encoding = self.encode_with_offsets(text, options.encode);
+                        if let Some(max_len) = options.max_len {
+                            encoding.truncate(max_len);
+                        }
+                    }
+                });
+            }
+        });
+
+        if let Some(pad_id) = options.pad_id {
+            let len = options
+                .max_len
+                .unwrap_or_else(|| encodings.iter().map(Encoding::len).max().unwrap_or(0));
+            for encoding in &mut encodings {
+                encoding.pad(len, pad_id);
+            }
+        }
+        encodings
+    }
+
+    /// The longest special token at byte `i` of `text`. User-defined tokens
+    /// always match, and control tokens only if `parse_special` is set.
+    fn special_at(&self, text: &str, i: usize, parse_special: bool) -> Option<usize> {
+        let rest = &text.as_bytes()[i..];
+        self.specials
+            .iter()
+            .copied()
+            .filter(|&id| {
+                let token = &self.vocab.id_to_token[id];
+                (parse_special || token.ty == TokenType::UserDefined)
+                    && rest.starts_with(&token.token)
+            })
+            .max_by_key(|&id| self.vocab.id_to_token[id].token.len())
+    }
+
+    /// Normalizes `text`, found at byte `offset` of the input, and merges its
+    /// characters into pieces by score.
+    fn encode_text(&self, text: &str, offset: usize, output: &mut Encoding) {
+        let aligned = self
+            .normalizer
+            .normalize_aligned(&Aligned::new(text, offset));
+        let text = &aligned.text;
+        if text.is_empty() {
+            return;
+        }
 
         let mut symbols = text
             .char_indices()
@@ -79,6 +483,10 @@ impl Tokenizer {
                 Symbol {
                     start_byte,
                     end_byte: start_byte + c_len,
+                    state: self
+                        .vocab
+                        .token_to_id
+                        .walk(Trie::ROOT, &text.as_bytes()[start_byte..start_byte + c_len]),
                     prev: if i == 0 { None } else { Some(i - 1) },
                     next,
                 }
@@ -104,6 +512,7 @@ impl Tokenizer {
 
             // Merge the right symbol into the left one.
             symbols[l].end_byte = symbols[r].end_byte;
+            symbols[l].state = Some(bigram.state);
             symbols[r].end_byte = symbols[r].start_byte;
 
             // Remove the right symbol from the linked list.
@@ -118,12 +527,30 @@ impl Tokenizer {
 
         let mut i = 0;
         loop {
-            let token = &text[symbols[i].start_byte..symbols[i].end_byte];
-            if let Some(&id) = self.vocab.token_to_id.get(BStr::new(token)) {
-                output.push(id);
+            let (start, end) = (symbols[i].start_byte, symbols[i].end_byte);
+            let token = &text[start..end];
+            if let Some(id) = symbols[i]
+                .state
+                .and_then(|state| self.vocab.token_to_id.value(state))
+            {
+                output.push(id, aligned.span(start..end));
             } else {
-                for c in token.bytes() {
-                    output.push(c as usize + 3);
+                // A piece with bytes that lack tokens is unknown as a whole.
+                match token
+                    .bytes()
+                    .map(|b| self.byte_ids[b as usize])
+                    .collect::<Option<Vec<_>>>()
+                {
+                    Some(bytes) => {
+                        for (j, id) in bytes.into_iter().enumerate() {
+                            output.push(id, aligned.span(start + j..start + j + 1));
+                        }
+                    }
+                    None => {
+                        if let Some(unk) = self.unk {
+                            output.push(unk, aligned.span(start..end));
+                        }
+                    }
                 }
             }
 
@@ -133,12 +560,6 @@ impl Tokenizer {
                 break;
             }
         }
-
-        for id in output.iter() {
-            println!("token: \"{}\"", self.vocab.id_to_token[*id].token);
-        }
-
-        output.into()
     }
 
     fn try_add_bigram(
@@ -153,21 +574,174 @@ impl Tokenizer {
             if let (Some(left_sym), Some(right_sym)) =
                 (symbols.get(left_sym_idx), symbols.get(right_sym_idx))
             {
-                let merged_text = &text[left_sym.start_byte..right_sym.end_byte];
-                if let Some(score) = self
-                    .vocab
-                    .token_to_id
-                    .get(BStr::new(merged_text))
-                    .map(|id| self.vocab.id_to_token[*id].score)
+                let trie = &self.vocab.token_to_id;
+                let right_text = &text.as_bytes()[right_sym.start_byte..right_sym.end_byte];
+                let state = left_sym
+                    .state
+                    .and_then(|state| trie.walk(state, right_text));
+                if let Some((state, id)) = state.and_then(|state| Some((state, trie.value(state)?)))
                 {
                     bigrams.push(Bigram {
                         left: Some(left_sym_idx),
                         right: Some(right_sym_idx),
-                        score,
+                        score: self.vocab.id_to_token[id].score,
                         n: left_sym.size() + right_sym.size(),
+                        state,
                     });
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(token: &str, score: f32, ty: TokenType) -> Token {
+        Token {
+            token: token.into(),
+            score,
+            ty,
+        }
+    }
+
+    #[test]
+    fn handles_special_tokens() {
+        // Byte tokens after the rest, so they aren't at `byte + 3`.
+        let mut tokens = vec![
+            token("a", -1.0, TokenType::Normal),
+            token("b", -1.0, TokenType::Normal),
+            token("ab", -0.5, TokenType::Normal),
+            token("<unk>", 0.0, TokenType::Unknown),
+            token("<s>", 0.0, TokenType::Control),
+            token("</s>", 0.0, TokenType::Control),
+            token("[INST]", 0.0, TokenType::UserDefined),
+        ];
+        let n = tokens.len();
+        tokens.extend((0..=255u8).map(|b| token(&format!("<0x{b:02X}>"), 0.0, TokenType::Byte)));
+        let mut tokenizer = Tokenizer::new(Vocab::new(tokens));
+        tokenizer.normalizer.add_dummy_prefix = false;
+        assert_eq!((tokenizer.bos_id(), tokenizer.eos_id()), (Some(4), Some(5)));
+
+        let encode = |text, add_bos, add_eos, parse_special| {
+            tokenizer
+                .encode_with(
+                    text,
+                    EncodeOptions {
+                        add_bos,
+                        add_eos,
+                        parse_special,
+                    },
+                )
+                .to_vec()
+        };
+        assert_eq!(tokenizer.encode("ab").to_vec(), [4, 2]);
+        assert_eq!(encode("aba", false, true, false), [2, 0, 5]);
+        // Control tokens are only parsed when asked, and user-defined ones
+        // always are.
+        let bytes = |s: &str| s.bytes().map(|b| n + b as usize).collect::<Vec<_>>();
+        assert_eq!(
+            encode("[INST]a</s>", false, false, false),
+            [&[6, 0][..], &bytes("</s>")].concat()
+        );
+        assert_eq!(encode("[INST]a</s>", false, false, true), [6, 0, 5]);
+
+        let tokens = vec![
+            token("a", 0.0, TokenType::Normal),
+            token("<unk>", 0.0, TokenType::Unknown),
+        ];
+        let tokenizer = Tokenizer::new(Vocab::new(tokens));
+        assert_eq!((tokenizer.bos_id(), tokenizer.eos_id()), (None, None));
+        // Neither `▁` nor `c` has a token.
+        assert_eq!(tokenizer.encode("ac").to_vec(), [1, 0, 1]);
+
+        // Vocabularies that name their BOS and EOS, like Llama 3's in GGUF.
+        let tokens = ["▁a", "<|begin_of_text|>", "<|end_of_text|>"];
+        let vocab = Vocab {
+            bos: Some(1),
+            eos: Some(2),
+            ..Vocab::new(
+                tokens
+                    .iter()
+                    .map(|t| token(t, 0.0, TokenType::Control))
+                    .collect(),
+            )
+        };
+        let tokenizer = Tokenizer::new(vocab);
+        assert_eq!((tokenizer.bos_id(), tokenizer.eos_id()), (Some(1), Some(2)));
+        assert_eq!(tokenizer.encode("a").to_vec(), [1, 0]);
+    }
+
+    #[test]
+    fn tracks_offsets() {
+        let mut tokens = vec![
+            token("<s>", 0.0, TokenType::Control),
+            token("</s>", 0.0, TokenType::Control),
+            token("▁", -2.0, TokenType::Normal),
+            token("a", -2.0, TokenType::Normal),
+            token("▁a", -1.0, TokenType::Normal),
+        ];
+        tokens.extend((0..=255u8).map(|b| token(&format!("<0x{b:02X}>"), 0.0, TokenType::Byte)));
+        let tokenizer = Tokenizer::new(Vocab::new(tokens));
+
+        // The dummy prefix comes from nowhere, escaped spaces from their
+        // spaces, and byte tokens each from their byte.
+        let options = EncodeOptions {
+            add_bos: true,
+            add_eos: true,
+            parse_special: true,
+        };
+        let encoding = tokenizer.encode_with_offsets("a é</s>", options);
+        assert_eq!(encoding.ids, [0, 4, 2, 5 + 0xc3, 5 + 0xa9, 1, 1]);
+        assert_eq!(encoding.offsets, [0..0, 0..1, 1..2, 2..3, 3..4, 4..8, 8..8]);
+    }
+
+    #[test]
+    fn encodes_batches() {
+        let tokens = ["<unk>", "<s>", "</s>", "▁", "a", "b", "▁a", "▁ab", "<pad>"];
+        let tokenizer = Tokenizer::new(Vocab::new(
+            tokens
+                .iter()
+                .map(|t| token(t, -1.0, TokenType::Normal))
+                .collect(),
+        ));
+        // More texts than threads, so each thread gets several.
+        let texts = ["a", "ab a", "", "b b b b"].repeat(16);
+        let encode = |options| tokenizer.encode_batch(&texts, options);
+
+        let encodings = encode(BatchOptions::default());
+        for (text, encoding) in texts.iter().zip(&encodings) {
+            assert_eq!(
+                *encoding,
+                tokenizer.encode_with_offsets(text, EncodeOptions::default())
+            );
+        }
+
+        let encodings = encode(BatchOptions {
+            pad_id: Some(8),
+            ..Default::default()
+        });
+        assert!(encodings.iter().all(|e| e.len() == 9));
+        assert_eq!(encodings[1].ids, [1, 7, 6, 8, 8, 8, 8, 8, 8]);
+        assert_eq!(
+            encodings[1].attention_mask,
+            [true, true, true, false, false, false, false, false, false]
+        );
+        assert_eq!(encodings[1].offsets[3..5], [4..4, 4..4]);
+
+        let encodings = encode(BatchOptions {
+            max_len: Some(2),
+            pad_id: Some(8),
+            ..Default::default()
+        });
+        assert_eq!(
+            encodings[..4]
+                .iter()
+                .map(|e| e.ids.clone())
+                .collect::<Vec<_>>(),
+            [[1, 6], [1, 7], [1, 8], [1, 3]]
+        );
+        assert_eq!(encodings[2].attention_mask, [true, false]);
+    }
+}
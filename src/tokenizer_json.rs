@@ -0,0 +1,1215 @@
+//! HuggingFace tokenizers' `tokenizer.json`, for models whose vocabulary
+//! is a BPE with ranked merges, like GPT-2's, Mistral's and Llama 3's.
+//!
+//! Text goes through the same steps as in the `tokenizers` library: added
+//! tokens are split out of it, the rest is normalized and pre-tokenized into
+//! words, each word is merged into tokens, and the post-processor adds
+//! special tokens around the result. The common normalizers and
+//! pre-tokenizers are supported, and loading fails on any other.
+
+use std::{collections::HashMap, fs, io, ops::Range, path::Path, sync::RwLock};
+
+use fancy_regex::Regex;
+use serde_json::{Map, Value};
+use unicode_normalization::UnicodeNormalization;
+
+use crate::tokenizer::{Aligned, Encoding, Token, TokenType, Tokenizer, Vocab};
+
+/// GPT-2's pre-tokenization: contractions, then runs of letters, numbers
+/// and other characters, each with at most one leading space.
+const GPT2_PATTERN: &str =
+    r"'s|'t|'re|'ve|'m|'ll|'d| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+(?!\S)|\s+";
+
+impl Tokenizer {
+    /// Loads a HuggingFace `tokenizer.json`.
+    pub fn from_json(p: impl AsRef<Path>) -> io::Result<Self> {
+        Self::from_json_str(&fs::read_to_string(p)?)
+    }
+
+    pub fn from_json_str(json: &str) -> io::Result<Self> {
+        let json: Value = serde_json::from_str(json)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let model = json
+            .get("model")
+            .ok_or_else(|| invalid("tokenizer has no model"))?;
+        match model.get("type").and_then(Value::as_str) {
+            Some("BPE") => {}
+            // Files from older versions of the library don't name the model.
+            None if model.get("merges").is_some() => {}
+            Some(ty) => return Err(invalid(format!("unsupported model {ty}"))),
+            None => return Err(invalid("unsupported model")),
+        }
+        for key in ["continuing_subword_prefix", "end_of_word_suffix"] {
+            if !matches!(model.get(key).and_then(Value::as_str), None | Some("")) {
+                return Err(invalid(format!("unsupported {key}")));
+            }
+        }
+
+        let added = match json.get("added_tokens") {
+            None | Some(Value::Null) => Vec::new(),
+            Some(Value::Array(added)) => added
+                .iter()
+                .map(AddedToken::parse)
+                .collect::<io::Result<_>>()?,
+            Some(_) => return Err(invalid("invalid added_tokens")),
+        };
+        let unk_token = model.get("unk_token").and_then(Value::as_str);
+        let byte_fallback = flag(model, "byte_fallback", false);
+        let mut vocab = vocab(object(model, "vocab")?, &added, unk_token, byte_fallback)?;
+        let id = |token: &str| vocab.token_to_id.get(token);
+
+        let (mut merges, mut ranked) = (HashMap::new(), Vec::new());
+        let list = model
+            .get("merges")
+            .and_then(Value::as_array)
+            .ok_or_else(|| invalid("invalid merges"))?;
+        for (rank, merge) in list.iter().enumerate() {
+            // Either "left right" or, in newer files, ["left", "right"].
+            let (left, right) = match merge {
+                Value::String(merge) => merge.split_once(' '),
+                Value::Array(pair) => match &pair[..] {
+                    [Value::String(left), Value::String(right)] => {
+                        Some((left.as_str(), right.as_str()))
+                    }
+                    _ => None,
+                },
+                _ => None,
+            }
+            .ok_or_else(|| invalid("invalid merge"))?;
+            ranked.push(format!("{left} {right}").into());
+            let ids = (id(left), id(right), id(&format!("{left}{right}")));
+            let (Some(left), Some(right), Some(merged)) = ids else {
+                return Err(invalid(format!(
+                    "merge of tokens not in the vocabulary: {left} {right}"
+                )));
+            };
+            merges.entry((left, right)).or_insert((rank, merged));
+        }
+
+        let bpe = Bpe {
+            merges,
+            unk: unk_token.and_then(id),
+            fuse_unk: flag(model, "fuse_unk", false),
+            byte_ids: if byte_fallback {
+                (0..=255u8).map(|b| id(&format!("<0x{b:02X}>"))).collect()
+            } else {
+                Vec::new()
+            },
+            ignore_merges: flag(model, "ignore_merges", false),
+            cache: RwLock::new(HashMap::new()),
+        };
+
+        let mut first_bytes = [false; 256];
+        for token in &added {
+            first_bytes[token.content.as_bytes()[0] as usize] = true;
+        }
+        let (prefix, suffix) = match json.get("post_processor") {
+            None | Some(Value::Null) => (Vec::new(), Vec::new()),
+            Some(processor) => template(processor)?,
+        };
+        // The tokens around the text are taken as BOS and EOS.
+        if prefix.len() > 1 || suffix.len() > 1 {
+            return Err(invalid(
+                "unsupported post-processor with several tokens around the text",
+            ));
+        }
+        let pipeline = Pipeline {
+            added,
+            first_bytes,
+            normalizer: optional(&json, "normalizer", Normalizer::parse)?,
+            pre_tokenizer: optional(&json, "pre_tokenizer", PreTokenizer::parse)?,
+            bpe,
+        };
+        if pipeline
+            .pre_tokenizer
+            .as_ref()
+            .is_some_and(PreTokenizer::is_byte_level)
+        {
+            vocab.merges = ranked;
+        }
+        let mut tokenizer =
+            Self::new(vocab).with_special_ids(prefix.first().copied(), suffix.first().copied());
+        tokenizer.json = Some(Box::new(pipeline));
+        Ok(tokenizer)
+    }
+}
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+fn object<'a>(value: &'a Value, key: &str) -> io::Result<&'a Map<String, Value>> {
+    value
+        .get(key)
+        .and_then(Value::as_object)
+        .ok_or_else(|| invalid(format!("invalid {key}")))
+}
+
+fn string<'a>(value: &'a Value, key: &str) -> io::Result<&'a str> {
+    value
+        .get(key)
+        .and_then(Value::as_str)
+        .ok_or_else(|| invalid(format!("invalid {key}")))
+}
+
+/// A boolean field, which may be missing or `null`.
+fn flag(value: &Value, key: &str, default: bool) -> bool {
+    value.get(key).and_then(Value::as_bool).unwrap_or(default)
+}
+
+/// A field that may be missing or `null`.
+fn optional<T>(
+    value: &Value,
+    key: &str,
+    parse: impl FnOnce(&Value) -> io::Result<T>,
+) -> io::Result<Option<T>> {
+    match value.get(key) {
+        None | Some(Value::Null) => Ok(None),
+        Some(value) => parse(value).map(Some),
+    }
+}
+
+/// The model's vocabulary with the added tokens, which may extend it.
+fn vocab(
+    model: &Map<String, Value>,
+    added: &[AddedToken],
+    unk_token: Option<&str>,
+    byte_fallback: bool,
+) -> io::Result<Vocab> {
+    // Added tokens may reuse ids from the model, so this only bounds the ids;
+    // those past the last one claimed are dropped below.
+    let len = model.len() + added.len();
+    let mut tokens = vec![None; len];
+    let mut insert = |id: usize, token: &str, ty| {
+        let slot = tokens
+            .get_mut(id)
+            .ok_or_else(|| invalid(format!("token id {id} out of range")))?;
+        *slot = Some(Token {
+            token: token.into(),
+            score: 0.0,
+            ty,
+        });
+        Ok::<_, io::Error>(())
+    };
+
+    for (token, id) in model {
+        let id = id.as_u64().ok_or_else(|| invalid("invalid vocab"))? as usize;
+        let ty = if Some(token.as_str()) == unk_token {
+            TokenType::Unknown
+        } else if byte_fallback && is_byte_token(token) {
+            TokenType::Byte
+        } else {
+            TokenType::Normal
+        };
+        insert(id, token, ty)?;
+    }
+    for token in added {
+        let ty = if token.special {
+            TokenType::Control
+        } else {
+            TokenType::UserDefined
+        };
+        insert(token.id, &token.content, ty)?;
+    }
+
+    while tokens.last().is_some_and(Option::is_none) {
+        tokens.pop();
+    }
+    let tokens = tokens
+        .into_iter()
+        .enumerate()
+        .map(|(id, token)| token.ok_or_else(|| invalid(format!("no token has id {id}"))))
+        .collect::<io::Result<_>>()?;
+    Ok(Vocab::new(tokens))
+}
+
+/// Whether `token` is named like a byte-fallback token, e.g. `<0x0A>`.
+fn is_byte_token(token: &str) -> bool {
+    token.len() == 6
+        && token.starts_with("<0x")
+        && token.ends_with('>')
+        && token[3..5].bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// The special tokens the post-processor puts before and after the text.
+fn template(processor: &Value) -> io::Result<(Vec<usize>, Vec<usize>)> {
+    match string(processor, "type")? {
+        "TemplateProcessing" => {
+            let special = object(processor, "special_tokens")?;
+            let (mut prefix, mut suffix) = (Vec::new(), Vec::new());
+            let mut seen_text = false;
+            let single = processor
+                .get("single")
+                .and_then(Value::as_array)
+                .ok_or_else(|| invalid("invalid single"))?;
+            for piece in single {
+                if piece.get("Sequence").is_some() {
+                    seen_text = true;
+                    continue;
+                }
+                let token = piece
+                    .get("SpecialToken")
+                    .ok_or_else(|| invalid("invalid template"))?;
+                let name = string(token, "id")?;
+                let ids = special
+                    .get(name)
+                    .and_then(|t| t.get("ids"))
+                    .and_then(Value::as_array)
+                    .ok_or_else(|| invalid(format!("unknown special token {name}")))?;
+                for id in ids {
+                    let id = id
+                        .as_u64()
+                        .ok_or_else(|| invalid("invalid special token id"))?
+                        as usize;
+                    if seen_text { &mut suffix } else { &mut prefix }.push(id);
+                }
+            }
+            Ok((prefix, suffix))
+        }
+        "Sequence" => {
+            let processors = processor
+                .get("processors")
+                .and_then(Value::as_array)
+                .ok_or_else(|| invalid("invalid processors"))?;
+            let (mut prefix, mut suffix) = (Vec::new(), Vec::new());
+            for processor in processors {
+                let (p, s) = template(processor)?;
+                prefix.extend(p);
+                suffix.extend(s);
+            }
+            Ok((prefix, suffix))
+        }
+        // Only adjusts offsets.
+        "ByteLevel" => Ok((Vec::new(), Vec::new())),
+        ty => Err(invalid(format!("unsupported post-processor {ty}"))),
+    }
+}
+
+/// A token added on top of the model's vocabulary, which is split out of the
+/// text before anything else and never merged.
+struct AddedToken {
+    id: usize,
+    content: String,
+    /// Only matches whole words.
+    single_word: bool,
+    /// Takes the whitespace on its left or right with it.
+    lstrip: bool,
+    rstrip: bool,
+    special: bool,
+}
+
+impl AddedToken {
+    fn parse(token: &Value) -> io::Result<Self> {
+        let token = Self {
+            id: token
+                .get("id")
+                .and_then(Value::as_u64)
+                .ok_or_else(|| invalid("invalid added token id"))? as usize,
+            content: string(token, "content")?.to_string(),
+            single_word: flag(token, "single_word", false),
+            lstrip: flag(token, "lstrip", false),
+            rstrip: flag(token, "rstrip", false),
+            special: flag(token, "special", false),
+        };
+        if token.content.is_empty() {
+            return Err(invalid("empty added token"));
+        }
+        Ok(token)
+    }
+}
+
+/// A string or regex to find in text.
+enum Pattern {
+    String(String),
+    Regex(Regex),
+}
+
+impl Pattern {
+    fn parse(pattern: &Value) -> io::Result<Self> {
+        if let Some(s) = pattern.get("String").and_then(Value::as_str) {
+            Ok(Self::String(s.to_string()))
+        } else if let Some(regex) = pattern.get("Regex").and_then(Value::as_str) {
+            Ok(Self::Regex(Regex::new(regex).map_err(|e| {
+                invalid(format!("invalid regex {regex}: {e}"))
+            })?))
+        } else {
+            Err(invalid("invalid pattern"))
+        }
+    }
+
+    /// The byte ranges of the non-empty matches in `text`.
+    fn find(&self, text: &str) -> Vec<(usize, usize)> {
+        match self {
+            Self::String(s) if s.is_empty() => Vec::new(),
+            Self::String(s) => text
+                .match_indices(s.as_str())
+                .map(|(i, m)| (i, i + m.len()))
+                .collect(),
+            // A regex that backtracks too much stops matching.
+            Self::Regex(regex) => regex
+                .find_iter(text)
+                .map_while(Result::ok)
+                .filter(|m| !m.as_str().is_empty())
+                .map(|m| (m.start(), m.end()))
+                .collect(),
+        }
+    }
+
+    fn replace(&self, text: &Aligned, content: &str) -> Aligned {
+        let mut out = Aligned::empty();
+        let mut last = 0;
+        for (start, end) in self.find(&text.text) {
+            if start > last {
+                out.push_str(&text.text[last..start], text.spans(last..start));
+            }
+            if !content.is_empty() {
+                out.push_str(content, text.spans(start..end));
+            }
+            last = end;
+        }
+        if last < text.len() {
+            out.push_str(&text.text[last..], text.spans(last..text.len()));
+        }
+        out
+    }
+}
+
+enum Normalizer {
+    Sequence(Vec<Normalizer>),
+    Prepend(String),
+    Replace(Pattern, String),
+    Nfc,
+    Nfd,
+    Nfkc,
+    Nfkd,
+    Lowercase,
+    Strip { left: bool, right: bool },
+}
+
+impl Normalizer {
+    fn parse(normalizer: &Value) -> io::Result<Self> {
+        Ok(match string(normalizer, "type")? {
+            "Sequence" => Self::Sequence(
+                normalizer
+                    .get("normalizers")
+                    .and_then(Value::as_array)
+                    .ok_or_else(|| invalid("invalid normalizers"))?
+                    .iter()
+                    .map(Self::parse)
+                    .collect::<io::Result<_>>()?,
+            ),
+            "Prepend" => Self::Prepend(string(normalizer, "prepend")?.to_string()),
+            "Replace" => Self::Replace(
+                Pattern::parse(
+                    normalizer
+                        .get("pattern")
+                        .ok_or_else(|| invalid("invalid pattern"))?,
+                )?,
+                string(normalizer, "content")?.to_string(),
+            ),
+            "NFC" => Self::Nfc,
+            "NFD" => Self::Nfd,
+            "NFKC" => Self::Nfkc,
+            "NFKD" => Self::Nfkd,
+            "Lowercase" => Self::Lowercase,
+            "Strip" => Self::Strip {
+                left: flag(normalizer, "strip_left", true),
+                right: flag(normalizer, "strip_right", true),
+            },
+            ty => return Err(invalid(format!("unsupported normalizer {ty}"))),
+        })
+    }
+
+    fn normalize(&self, text: Aligned) -> Aligned {
+        match self {
+            Self::Sequence(normalizers) => {
+                normalizers.iter().fold(text, |text, n| n.normalize(text))
+            }
+            Self::Prepend(prefix) if !text.text.is_empty() => prepend(prefix, &text),
+            Self::Prepend(_) => text,
+            Self::Replace(pattern, content) => pattern.replace(&text, content),
+            Self::Nfc => text.map(|s| s.nfc().collect()),
+            Self::Nfd => text.map(|s| s.nfd().collect()),
+            Self::Nfkc => text.map(|s| s.nfkc().collect()),
+            Self::Nfkd => text.map(|s| s.nfkd().collect()),
+            Self::Lowercase => text.map(str::to_lowercase),
+            Self::Strip { left, right } => {
+                let start = if *left {
+                    text.len() - text.text.trim_start().len()
+                } else {
+                    0
+                };
+                let end = if *right {
+                    text.text.trim_end().len()
+                } else {
+                    text.len()
+                };
+                text.slice(start..end.max(start))
+            }
+        }
+    }
+}
+
+/// `prefix` followed by `text`.
+fn prepend(prefix: &str, text: &Aligned) -> Aligned {
+    let mut out = Aligned::empty();
+    out.insert_str(prefix, text.start());
+    out.push_str(&text.text, text.spans(0..text.len()));
+    out
+}
+
+/// What a split does with the parts of the text the pattern matches.
+#[derive(Clone, Copy)]
+enum Behavior {
+    Removed,
+    Isolated,
+    MergedWithPrevious,
+    MergedWithNext,
+    Contiguous,
+}
+
+/// When Metaspace puts its replacement in front of a word.
+#[derive(Clone, Copy, PartialEq)]
+enum PrependScheme {
+    Always,
+    /// Only at the start of the text.
+    First,
+    Never,
+}
+
+enum PreTokenizer {
+    Sequence(Vec<PreTokenizer>),
+    /// Maps each byte to a printable character, after splitting like GPT-2
+    /// if `regex` is set.
+    ByteLevel {
+        add_prefix_space: bool,
+        regex: Option<Pattern>,
+    },
+    Split {
+        pattern: Pattern,
+        behavior: Behavior,
+        invert: bool,
+    },
+    /// Replaces spaces with `replacement`, splitting before each.
+    Metaspace {
+        replacement: char,
+        prepend: PrependScheme,
+        split: bool,
+    },
+}
+
+impl PreTokenizer {
+    /// Whether this maps bytes to characters, making the vocabulary's tokens
+    /// byte-level.
+    fn is_byte_level(&self) -> bool {
+        match self {
+            Self::Sequence(pre_tokenizers) => pre_tokenizers.iter().any(Self::is_byte_level),
+            Self::ByteLevel { .. } => true,
+            _ => false,
+        }
+    }
+
+    fn parse(pre_tokenizer: &Value) -> io::Result<Self> {
+        Ok(match string(pre_tokenizer, "type")? {
+            "Sequence" => Self::Sequence(
+                pre_tokenizer
+                    .get("pretokenizers")
+                    .and_then(Value::as_array)
+                    .ok_or_else(|| invalid("invalid pretokenizers"))?
+                    .iter()
+                    .map(Self::parse)
+                    .collect::<io::Result<_>>()?,
+            ),
+            "ByteLevel" => Self::ByteLevel {
+                add_prefix_space: flag(pre_tokenizer, "add_prefix_space", true),
+                regex: flag(pre_tokenizer, "use_regex", true)
+                    .then(|| Regex::new(GPT2_PATTERN).map(Pattern::Regex).unwrap()),
+            },
+            "Split" => Self::Split {
+                pattern: Pattern::parse(
+                    pre_tokenizer
+                        .get("pattern")
+                        .ok_or_else(|| invalid("invalid pattern"))?,
+                )?,
+                behavior: match string(pre_tokenizer, "behavior")? {
+                    "Removed" => Behavior::Removed,
+                    "Isolated" => Behavior::Isolated,
+                    "MergedWithPrevious" => Behavior::MergedWithPrevious,
+                    "MergedWithNext" => Behavior::MergedWithNext,
+                    "Contiguous" => Behavior::Contiguous,
+                    behavior => {
+                        return Err(invalid(format!("unsupported split behavior {behavior}")))
+                    }
+                },
+                invert: flag(pre_tokenizer, "invert", false),
+            },
+            "Metaspace" => {
+                let mut replacement = string(pre_tokenizer, "replacement")?.chars();
+                let (Some(replacement), None) = (replacement.next(), replacement.next()) else {
+                    return Err(invalid("invalid replacement"));
+                };
+                // Older files only have `add_prefix_space`.
+                let prepend = match pre_tokenizer.get("prepend_scheme").and_then(Value::as_str) {
+                    Some("always") => PrependScheme::Always,
+                    Some("first") => PrependScheme::First,
+                    Some("never") => PrependScheme::Never,
+                    Some(scheme) => {
+                        return Err(invalid(format!("unsupported prepend_scheme {scheme}")))
+                    }
+                    None if flag(pre_tokenizer, "add_prefix_space", true) => PrependScheme::Always,
+                    None => PrependScheme::Never,
+                };
+                Self::Metaspace {
+                    replacement,
+                    prepend,
+                    split: flag(pre_tokenizer, "split", true),
+                }
+            }
+            "Digits" => Self::Split {
+                pattern: Pattern::Regex(Regex::new(r"\p{N}").unwrap()),
+                behavior: if flag(pre_tokenizer, "individual_digits", false) {
+                    Behavior::Isolated
+                } else {
+                    Behavior::Contiguous
+                },
+                invert: false,
+            },
+            ty => return Err(invalid(format!("unsupported pre-tokenizer {ty}"))),
+        })
+    }
+
+    /// Splits `words` further. `at_start` is whether the first word starts
+    /// the text.
+    fn pre_tokenize(&self, words: Vec<Aligned>, at_start: bool) -> Vec<Aligned> {
+        match self {
+            Self::Sequence(pre_tokenizers) => pre_tokenizers
+                .iter()
+                .fold(words, |words, p| p.pre_tokenize(words, at_start)),
+            Self::ByteLevel {
+                add_prefix_space,
+                regex,
+            } => {
+                let chars = bytes_to_unicode();
+                let mut out = Vec::new();
+                for mut word in words {
+                    if *add_prefix_space && !word.text.starts_with(' ') {
+                        word = prepend(" ", &word);
+                    }
+                    let parts = match regex {
+                        Some(regex) => split(&word.text, regex, Behavior::Isolated, false),
+                        None => std::iter::once(0..word.len()).collect(),
+                    };
+                    for part in parts {
+                        let mut mapped = Aligned::empty();
+                        for i in part {
+                            let c = chars[word.text.as_bytes()[i] as usize];
+                            mapped.push_str(c.encode_utf8(&mut [0; 4]), word.spans(i..i + 1));
+                        }
+                        out.push(mapped);
+                    }
+                }
+                out
+            }
+            Self::Split {
+                pattern,
+                behavior,
+                invert,
+            } => words
+                .iter()
+                .flat_map(|word| {
+                    split(&word.text, pattern, *behavior, *invert)
+                        .into_iter()
+                        .map(|r| word.slice(r))
+                })
+                .collect(),
+            Self::Metaspace {
+                replacement,
+                prepend: scheme,
+                split: split_words,
+            } => {
+                let replacement = replacement.to_string();
+                let mut out = Vec::new();
+                for (i, word) in words.iter().enumerate() {
+                    let mut replaced = Aligned::empty();
+                    for (j, c) in word.text.char_indices() {
+                        let end = j + c.len_utf8();
+                        let c = if c == ' ' {
+                            &replacement
+                        } else {
+                            &word.text[j..end]
+                        };
+                        replaced.push_str(c, word.spans(j..end));
+                    }
+                    let first = at_start && i == 0;
+                    if (*scheme == PrependScheme::Always
+                        || (*scheme == PrependScheme::First && first))
+                        && !replaced.text.starts_with(&replacement)
+                    {
+                        replaced = prepend(&replacement, &replaced);
+                    }
+                    if *split_words {
+                        let pattern = Pattern::String(replacement.clone());
+                        let parts =
+                            split(&replaced.text, &pattern, Behavior::MergedWithNext, false);
+                        out.extend(parts.into_iter().map(|r| replaced.slice(r)));
+                    } else {
+                        out.push(replaced);
+                    }
+                }
+                out
+            }
+        }
+    }
+}
+
+/// Splits `text` around the matches of `pattern`, or around everything else
+/// if `invert` is set, as `tokenizers`' `Split` does, into the ranges of the
+/// parts.
+fn split(text: &str, pattern: &Pattern, behavior: Behavior, invert: bool) -> Vec<Range<usize>> {
+    let mut parts = Vec::new();
+    let mut last = 0;
+    for (start, end) in pattern.find(text) {
+        if start > last {
+            parts.push((last, start, invert));
+        }
+        parts.push((start, end, !invert));
+        last = end;
+    }
+    if last < text.len() {
+        parts.push((last, text.len(), invert));
+    }
+
+    // The ranges to keep, merging matches into their neighbours as asked.
+    let mut ranges: Vec<Range<usize>> = Vec::new();
+    let mut previous_match = false;
+    match behavior {
+        Behavior::Removed => ranges.extend(
+            parts
+                .iter()
+                .filter(|p| !p.2)
+                .map(|&(start, end, _)| start..end),
+        ),
+        Behavior::Isolated => ranges.extend(parts.iter().map(|&(start, end, _)| start..end)),
+        Behavior::MergedWithPrevious => {
+            for (start, end, is_match) in parts {
+                match ranges.last_mut() {
+                    Some(last) if is_match && !previous_match => last.end = end,
+                    _ => ranges.push(start..end),
+                }
+                previous_match = is_match;
+            }
+        }
+        Behavior::MergedWithNext => {
+            for (start, end, is_match) in parts.into_iter().rev() {
+                match ranges.last_mut() {
+                    Some(last) if is_match && !previous_match => last.start = start,
+                    _ => ranges.push(start..end),
+                }
+                previous_match = is_match;
+            }
+            ranges.reverse();
+        }
+        Behavior::Contiguous => {
+            for (start, end, is_match) in parts {
+                match ranges.last_mut() {
+                    Some(last) if is_match && previous_match => last.end = end,
+                    _ => ranges.push(start..end),
+                }
+                previous_match = is_match;
+            }
+        }
+    }
+    ranges
+}
+
+/// GPT-2's map from bytes to printable characters: printable Latin-1
+/// characters stand for themselves, and the rest are shifted past 255.
+pub fn bytes_to_unicode() -> [char; 256] {
+    let printable = |b: u8| matches!(b, b'!'..=b'~' | 0xa1..=0xac | 0xae..=0xff);
+    let mut chars = ['\0'; 256];
+    let mut n = 0;
+    for b in 0..=255u8 {
+        chars[b as usize] = if printable(b) {
+            b as char
+        } else {
+            n += 1;
+            char::from_u32(255 + n).unwrap()
+        };
+    }
+    chars
+}
+
+/// How many words [`Bpe`] remembers the merges of. Once it's full, others
+/// are merged every time.
+const CACHE_CAPACITY: usize = 10_000;
+
+/// A word's tokens, and the bytes of it each covers.
+type Symbols = Vec<(usize, usize, usize)>;
+
+/// Merges the characters of each word by rank.
+struct Bpe {
+    /// The rank of each merge, and the token it makes.
+    merges: HashMap<(usize, usize), (usize, usize)>,
+    unk: Option<usize>,
+    /// Whether consecutive unknown characters become one unknown token.
+    fuse_unk: bool,
+    /// The `<0xXX>` tokens for characters the vocabulary lacks, if the model
+    /// falls back to bytes.
+    byte_ids: Vec<Option<usize>>,
+    /// Whether words in the vocabulary are taken whole, without merging.
+    ignore_merges: bool,
+    /// The merged symbols of words seen before, since text repeats a lot of
+    /// them.
+    cache: RwLock<HashMap<String, Symbols>>,
+}
+
+impl Bpe {
+    fn encode_word(&self, vocab: &Vocab, word: &Aligned, output: &mut Encoding) {
+        if self.ignore_merges {
+            if let Some(id) = vocab.token_to_id.get(&word.text) {
+                output.push(id, word.span(0..word.len()));
+                return;
+            }
+        }
+
+        let cached = self.cache.read().unwrap().get(&word.text).cloned();
+        let symbols = cached.unwrap_or_else(|| {
+            let symbols = self.merge(self.symbols(vocab, &word.text));
+            // Other threads may be reading it, and it's only a cache.
+            if let Ok(mut cache) = self.cache.try_write() {
+                if cache.len() < CACHE_CAPACITY {
+                    cache.insert(word.text.clone(), symbols.clone());
+                }
+            }
+            symbols
+        });
+        for (id, start, end) in symbols {
+            output.push(id, word.span(start..end));
+        }
+    }
+
+    /// The tokens of each character of `word`, before merging.
+    fn symbols(&self, vocab: &Vocab, word: &str) -> Symbols {
+        let mut symbols = Symbols::new();
+        let mut previous_unk = false;
+        for (i, c) in word.char_indices() {
+            let end = i + c.len_utf8();
+            let c = &word[i..end];
+            if let Some(id) = vocab.token_to_id.get(c) {
+                symbols.push((id, i, end));
+                previous_unk = false;
+                continue;
+            }
+            let bytes = c
+                .bytes()
+                .map(|b| self.byte_ids.get(b as usize).copied().flatten())
+                .collect::<Option<Vec<_>>>();
+            if let Some(bytes) = bytes {
+                symbols.extend(
+                    bytes
+                        .into_iter()
+                        .enumerate()
+                        .map(|(j, id)| (id, i + j, i + j + 1)),
+                );
+                previous_unk = false;
+            } else if let Some(unk) = self.unk {
+                // Characters without a token or an unknown token are dropped.
+                match symbols.last_mut() {
+                    Some(last) if self.fuse_unk && previous_unk => last.2 = end,
+                    _ => symbols.push((unk, i, end)),
+                }
+                previous_unk = true;
+            }
+        }
+        symbols
+    }
+
+    /// Applies the lowest ranked merge of adjacent symbols until none apply,
+    /// leftmost first among equal ranks.
+    fn merge(&self, mut symbols: Symbols) -> Symbols {
+        use std::{cmp::Reverse, collections::BinaryHeap};
+
+        let n = symbols.len();
+        let mut next = (1..=n).collect::<Vec<_>>();
+        let mut prev = (0..n).map(|i| i.wrapping_sub(1)).collect::<Vec<_>>();
+        let mut alive = vec![true; n];
+
+        let mut heap = BinaryHeap::new();
+        let push = |heap: &mut BinaryHeap<_>, symbols: &Symbols, i: usize, j: usize| {
+            if let Some(&(rank, merged)) = self.merges.get(&(symbols[i].0, symbols[j].0)) {
+                heap.push(Reverse((rank, i, symbols[i].0, symbols[j].0, merged)));
+            }
+        };
+        for i in 1..n {
+            push(&mut heap, &symbols, i - 1, i);
+        }
+
+        while let Some(Reverse((_, i, left, right, merged))) = heap.pop() {
+            // Skip pairs an earlier merge changed.
+            let j = next[i];
+            if !alive[i] || j >= n || symbols[i].0 != left || symbols[j].0 != right {
+                continue;
+            }
+            symbols[i] = (merged, symbols[i].1, symbols[j].2);
+            alive[j] = false;
+            next[i] = next[j];
+            if next[i] < n {
+                prev[next[i]] = i;
+                push(&mut heap, &symbols, i, next[i]);
+            }
+            if prev[i] < n {
+                push(&mut heap, &symbols, prev[i], i);
+            }
+        }
+
+        symbols
+            .into_iter()
+            .zip(alive)
+            .filter(|&(_, alive)| alive)
+            .map(|(symbol, _)| symbol)
+            .collect()
+    }
+}
+
+/// Everything a `tokenizer.json` does to text besides looking up tokens.
+pub(crate) struct Pipeline {
+    added: Vec<AddedToken>,
+    /// The first bytes of the added tokens, to skip positions quickly.
+    first_bytes: [bool; 256],
+    normalizer: Option<Normalizer>,
+    pre_tokenizer: Option<PreTokenizer>,
+    bpe: Bpe,
+}
+
+impl Pipeline {
+    /// Encodes `text` into `output`, leaving special added tokens in it as
+    /// text unless `parse_special` is set.
+    pub(crate) fn encode(
+        &self,
+        vocab: &Vocab,
+        text: &str,
+        parse_special: bool,
+        output: &mut Encoding,
+    ) {
+        let mut start = 0;
+        let mut i = 0;
+        while i < text.len() {
+            let Some(token) = self.added_at(text, i, parse_special) else {
+                i += text[i..].chars().next().unwrap().len_utf8();
+                continue;
+            };
+
+            let (mut begin, mut end) = (i, i + token.content.len());
+            if token.lstrip {
+                begin = start + text[start..begin].trim_end().len();
+            }
+            if token.rstrip {
+                end = text.len() - text[end..].trim_start().len();
+            }
+            self.encode_text(vocab, &text[start..begin], start, output);
+            output.push(token.id, i..i + token.content.len());
+            start = end;
+            i = end;
+        }
+        self.encode_text(vocab, &text[start..], start, output);
+    }
+
+    /// The longest added token at byte `i` of `text`.
+    fn added_at(&self, text: &str, i: usize, parse_special: bool) -> Option<&AddedToken> {
+        if !self.first_bytes[text.as_bytes()[i] as usize] {
+            return None;
+        }
+        let is_word = |c: Option<char>| c.is_some_and(|c| c.is_alphanumeric() || c == '_');
+        self.added
+            .iter()
+            .filter(|t| (parse_special || !t.special) && text[i..].starts_with(&t.content))
+            .filter(|t| {
+                let end = i + t.content.len();
+                !t.single_word
+                    || (!is_word(text[..i].chars().next_back())
+                        && !is_word(text[end..].chars().next()))
+            })
+            .max_by_key(|t| t.content.len())
+    }
+
+    /// Encodes the text between added tokens, found at byte `offset` of the
+    /// whole text.
+    fn encode_text(&self, vocab: &Vocab, text: &str, offset: usize, output: &mut Encoding) {
+        if text.is_empty() {
+            return;
+        }
+        let mut text = Aligned::new(text, offset);
+        if let Some(normalizer) = &self.normalizer {
+            text = normalizer.normalize(text);
+        }
+        let words = match &self.pre_tokenizer {
+            Some(pre_tokenizer) => pre_tokenizer.pre_tokenize(vec![text], offset == 0),
+            None => vec![text],
+        };
+        for word in words {
+            self.bpe.encode_word(vocab, &word, output);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::tokenizer::EncodeOptions;
+
+    /// A byte-level BPE over GPT-2's byte alphabet, with `merges` and
+    /// `words` on top, and `model` merged into the model's settings.
+    fn byte_level(
+        merges: &[&str],
+        words: &[&str],
+        pre_tokenizer: Value,
+        model: Value,
+    ) -> Tokenizer {
+        let mut vocab = Map::new();
+        for c in bytes_to_unicode() {
+            vocab.insert(c.to_string(), json!(vocab.len()));
+        }
+        for token in merges
+            .iter()
+            .map(|merge| merge.replace(' ', ""))
+            .chain(words.iter().map(|w| w.to_string()))
+        {
+            vocab.insert(token, json!(vocab.len()));
+        }
+        let mut json = json!({
+            "added_tokens": [{"id": vocab.len(), "content": "<|endoftext|>", "special": true}],
+            "normalizer": null,
+            "pre_tokenizer": pre_tokenizer,
+            "post_processor": {"type": "ByteLevel", "trim_offsets": false},
+            "model": {"type": "BPE", "vocab": vocab, "merges": merges},
+        });
+        for (key, value) in model.as_object().unwrap() {
+            json["model"][key] = value.clone();
+        }
+        Tokenizer::from_json_str(&json.to_string()).unwrap()
+    }
+
+    fn tokens(tokenizer: &Tokenizer, text: &str) -> Vec<String> {
+        let ids = tokenizer
+            .encode_with(
+                text,
+                EncodeOptions {
+                    parse_special: true,
+                    ..Default::default()
+                },
+            )
+            .to_vec();
+        ids.into_iter()
+            .map(|id| tokenizer.vocab.id_to_token[id].token.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn maps_bytes_like_gpt2() {
+        let chars = bytes_to_unicode();
+        assert_eq!(
+            (
+                chars[b' ' as usize],
+                chars[b'\n' as usize],
+                chars[b'A' as usize]
+            ),
+            ('Ġ', 'Ċ', 'A')
+        );
+        assert_eq!((chars[0], chars[0xad]), ('Ā', 'Ń'));
+    }
+
+    #[test]
+    fn merges_by_rank() {
+        let gpt2 = json!({"type": "ByteLevel", "add_prefix_space": false, "use_regex": true});
+        let merges = [
+            "h e", "l l", "e l", "he ll", "hell o", "Ġ w", "o r", "Ġw or",
+        ];
+        let tokenizer = byte_level(&merges, &[], gpt2, json!({}));
+        assert_eq!(tokenizer.vocab.merges, merges);
+
+        // "l l" outranks "e l", so "hell" is "he" + "ll" rather than
+        // "h" + "el" + "l".
+        assert_eq!(
+            tokens(&tokenizer, "hello world!<|endoftext|>"),
+            ["hello", "Ġwor", "l", "d", "!", "<|endoftext|>"]
+        );
+        assert_eq!(tokens(&tokenizer, "élan\n"), ["Ã", "©", "l", "a", "n", "Ċ"]);
+        // GPT-2 splits runs of spaces before a word, leaving it one.
+        assert_eq!(
+            tokens(&tokenizer, "a   world"),
+            ["a", "Ġ", "Ġ", "Ġwor", "l", "d"]
+        );
+
+        let encoding = tokenizer.encode_with_offsets("hello wé", EncodeOptions::default());
+        assert_eq!(encoding.offsets, [0..5, 5..7, 7..8, 8..9]);
+
+        // Words seen before are merged from the cache, keeping the offsets
+        // of where they are this time.
+        let bpe = &tokenizer.json.as_ref().unwrap().bpe;
+        assert!(bpe.cache.read().unwrap().contains_key("Ġw\u{c3}\u{a9}"));
+        let encoding = tokenizer.encode_with_offsets("wé wé", EncodeOptions::default());
+        assert_eq!(encoding.offsets, [0..1, 1..2, 2..3, 3..5, 5..6, 6..7]);
+    }
+
+    #[test]
+    fn aligns_normalized_text() {
+        let normalizer = Normalizer::Sequence(vec![
+            Normalizer::Nfd,
+            Normalizer::Replace(Pattern::String("e\u{301}".into()), "E".into()),
+            Normalizer::Lowercase,
+            Normalizer::Strip {
+                left: true,
+                right: false,
+            },
+        ]);
+        // "é" decomposes into "e" and U+0301, which both came from its two
+        // bytes, and the replacement of both takes both.
+        let text = normalizer.normalize(Aligned::new(" Aéb", 10));
+        assert_eq!(text.text, "aeb");
+        assert_eq!(
+            (text.span(0..1), text.span(1..2), text.span(2..3)),
+            (11..12, 12..14, 14..15)
+        );
+    }
+
+    #[test]
+    fn splits_like_llama3() {
+        let pattern = r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+";
+        let llama3 = json!({"type": "Sequence", "pretokenizers": [
+            {"type": "Split", "pattern": {"Regex": pattern}, "behavior": "Isolated", "invert": false},
+            {"type": "ByteLevel", "add_prefix_space": false, "trim_offsets": true, "use_regex": false},
+        ]});
+        let merges = ["1 2", "12 3", "Ġ a", "Ġa b"];
+        let tokenizer = byte_level(&merges, &["Ġabc"], llama3.clone(), json!({}));
+        // Numbers are split into groups of three digits.
+        assert_eq!(tokens(&tokenizer, "12345 ab"), ["123", "4", "5", "Ġab"]);
+        assert_eq!(tokens(&tokenizer, " abc"), ["Ġab", "c"]);
+        // With ignore_merges, words in the vocabulary are taken whole even
+        // where the merges can't make them.
+        let tokenizer = byte_level(&merges, &["Ġabc"], llama3, json!({"ignore_merges": true}));
+        assert_eq!(tokens(&tokenizer, " abc"), ["Ġabc"]);
+    }
+
+    #[test]
+    fn falls_back_to_bytes_like_mistral() {
+        let mut vocab = json!({"<unk>": 0, "<s>": 1, "</s>": 2});
+        for b in 0..=255u8 {
+            vocab[format!("<0x{b:02X}>")] = json!(3 + b as usize);
+        }
+        for (i, token) in ["▁", "h", "i", "▁h", "▁hi", "!"].into_iter().enumerate() {
+            vocab[token] = json!(259 + i);
+        }
+        let json = json!({
+            "added_tokens": [
+                {"id": 0, "content": "<unk>", "special": true},
+                {"id": 1, "content": "<s>", "special": true},
+                {"id": 2, "content": "</s>", "special": true},
+            ],
+            "normalizer": {"type": "Sequence", "normalizers": [
+                {"type": "Prepend", "prepend": "▁"},
+                {"type": "Replace", "pattern": {"String": " "}, "content": "▁"},
+            ]},
+            "pre_tokenizer": null,
+            "post_processor": {
+                "type": "TemplateProcessing",
+                "single": [{"SpecialToken": {"id": "<s>", "type_id": 0}}, {"Sequence": {"id": "A", "type_id": 0}}],
+                "pair": [],
+                "special_tokens": {"<s>": {"id": "<s>", "ids": [1], "tokens": ["<s>"]}},
+            },
+            "model": {
+                "type": "BPE", "unk_token": "<unk>", "fuse_unk": true, "byte_fallback": true,
+                "vocab": vocab, "merges": ["▁ h", "▁h i"],
+            },
+        });
+        let tokenizer = Tokenizer::from_json_str(&json.to_string()).unwrap();
+        assert_eq!(tokenizer.vocab.id_to_token[1].ty, TokenType::Control);
+        assert_eq!(tokenizer.vocab.id_to_token[3].ty, TokenType::Byte);
+        // Without a byte-level pre-tokenizer, GGUF describes it by scores.
+        assert!(tokenizer.vocab.merges.is_empty());
+
+        // Text after a special token gets its own prefix, as in `tokenizers`.
+        let expected = ["<s>", "▁hi", "▁", "<0xC3>", "<0xA9>", "!", "</s>", "▁hi"];
+        assert_eq!(tokens(&tokenizer, "hi é!</s>hi"), expected);
+        let options = EncodeOptions {
+            parse_special: true,
+            ..Default::default()
+        };
+        let encoding = tokenizer.encode_with_offsets("hi é!</s>hi", options);
+        assert_eq!(
+            encoding.offsets,
+            [0..0, 0..2, 2..3, 3..4, 4..5, 5..6, 6..10, 10..12]
+        );
+        // Unless asked, special tokens in the text are split like the rest.
+        let bytes = "</s>".bytes().map(|b| 3 + b as usize);
+        assert_eq!(
+            tokenizer.encode("</s>").to_vec(),
+            [1, 259].into_iter().chain(bytes).collect::<Vec<_>>()
+        );
+        assert_eq!((tokenizer.bos_id(), tokenizer.eos_id()), (Some(1), None));
+    }
+
+    #[test]
+    fn splits_like_tokenizers() {
+        let text = "a,b,,c";
+        let comma = Pattern::String(",".into());
+        let cases = [
+            (Behavior::Removed, vec!["a", "b", "c"]),
+            (Behavior::Isolated, vec!["a", ",", "b", ",", ",", "c"]),
+            (Behavior::MergedWithPrevious, vec!["a,", "b,", ",", "c"]),
+            (Behavior::MergedWithNext, vec!["a", ",b", ",", ",c"]),
+            (Behavior::Contiguous, vec!["a", ",", "b", ",,", "c"]),
+        ];
+        let parts = |behavior, invert| {
+            split(text, &comma, behavior, invert)
+                .into_iter()
+                .map(|r| &text[r])
+                .collect::<Vec<_>>()
+        };
+        for (behavior, expected) in cases {
+            assert_eq!(parts(behavior, false), expected);
+        }
+        assert_eq!(parts(Behavior::Removed, true), [",", ",", ","]);
+    }
+
+    #[test]
+    fn rejects_unsupported_files() {
+        for (json, error) in [
+            (
+                json!({"model": {"type": "Unigram", "vocab": []}}),
+                "unsupported model Unigram",
+            ),
+            (
+                json!({"model": {"type": "BPE", "vocab": {"a": 0}, "merges": ["a b"]}}),
+                "merge of tokens not in the vocabulary: a b",
+            ),
+            (
+                json!({"model": {"type": "BPE", "vocab": {"a": 0, "b": 5}, "merges": []}}),
+                "token id 5 out of range",
+            ),
+            (
+                json!({
+                    "added_tokens": [{"id": 0, "content": "a", "special": false}],
+                    "model": {"type": "BPE", "vocab": {"a": 0, "c": 2}, "merges": []},
+                }),
+                "no token has id 1",
+            ),
+            (
+                json!({
+                    "pre_tokenizer": {"type": "BertPreTokenizer"},
+                    "model": {"type": "BPE", "vocab": {}, "merges": []},
+                }),
+                "unsupported pre-tokenizer BertPreTokenizer",
+            ),
+        ] {
+            assert_eq!(
+                Tokenizer::from_json_str(&json.to_string())
+                    .err()
+                    .unwrap()
+                    .to_string(),
+                error
+            );
+        }
+    }
+}
@@ -0,0 +1,200 @@
+//! A double-array trie over token bytes: each state is one entry in a flat
+//! array, and following a byte from it is an add and a compare. Walking it a
+//! byte at a time lets the tokenizer extend a match without rehashing the
+//! bytes it's already seen.
+
+use std::ops::Range;
+
+/// A state of a [`Trie`]: the bytes walked from the root to get to it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct State(u32);
+
+#[derive(Clone, Copy)]
+struct Unit {
+    /// Where the children are: following `byte` leads to `base + byte`.
+    base: u32,
+    /// The parent, to tell whether a unit is the child it was looked up as.
+    check: u32,
+    /// One more than the id of the key ending here, or 0 if none does.
+    value: u32,
+}
+
+const FREE: Unit = Unit {
+    base: 0,
+    check: u32::MAX,
+    value: 0,
+};
+
+/// Maps byte strings to ids.
+#[derive(Clone)]
+pub struct Trie {
+    units: Vec<Unit>,
+    len: usize,
+}
+
+impl Trie {
+    pub const ROOT: State = State(0);
+
+    /// A trie of `keys`. If a key appears more than once, the first id wins.
+    pub fn new<'a>(keys: impl IntoIterator<Item = (&'a [u8], usize)>) -> Self {
+        let mut keys = keys.into_iter().collect::<Vec<_>>();
+        // Stable, so the first of equal keys stays first.
+        keys.sort_by_key(|&(key, _)| key);
+        keys.dedup_by_key(|&mut (key, _)| key);
+
+        let mut units = vec![Unit {
+            check: u32::MAX - 1,
+            ..FREE
+        }];
+        // Where to start looking for room, before which everything's taken.
+        let mut first_free = 1;
+        let mut stack = vec![(0, 0..keys.len(), 0)];
+        let mut children = Vec::<(u8, Range<usize>)>::new();
+        while let Some((state, mut range, depth)) = stack.pop() {
+            if range.start < range.end && keys[range.start].0.len() == depth {
+                units[state].value = keys[range.start].1 as u32 + 1;
+                range.start += 1;
+            }
+
+            children.clear();
+            for i in range {
+                let byte = keys[i].0[depth];
+                match children.last_mut() {
+                    Some((last, range)) if *last == byte => range.end = i + 1,
+                    _ => children.push((byte, i..i + 1)),
+                }
+            }
+            if children.is_empty() {
+                continue;
+            }
+
+            // The lowest base at which every child has room.
+            let first = children[0].0 as usize;
+            let mut pos = first_free.max(first + 1);
+            let base = loop {
+                let base = pos - first;
+                let free = |byte: u8| {
+                    units
+                        .get(base + byte as usize)
+                        .is_none_or(|u| u.check == FREE.check)
+                };
+                if children.iter().all(|&(byte, _)| free(byte)) {
+                    break base;
+                }
+                pos += 1;
+            };
+            if units.len() < base + 256 {
+                units.resize(base + 256, FREE);
+            }
+            units[state].base = base as u32;
+            for (byte, range) in children.drain(..) {
+                let child = base + byte as usize;
+                units[child].check = state as u32;
+                stack.push((child, range, depth + 1));
+            }
+            while units.get(first_free).is_some_and(|u| u.check != FREE.check) {
+                first_free += 1;
+            }
+        }
+
+        // Nothing follows the last states, so the room kept after them for
+        // children is never used.
+        while units.len() > 1 && units[units.len() - 1].check == FREE.check {
+            units.pop();
+        }
+        units.shrink_to_fit();
+        Self {
+            units,
+            len: keys.len(),
+        }
+    }
+
+    /// The number of distinct keys.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, key: impl AsRef<[u8]>) -> Option<usize> {
+        self.value(self.walk(Self::ROOT, key.as_ref())?)
+    }
+
+    /// The state after following `bytes` from `state`, if any key continues
+    /// that way.
+    pub fn walk(&self, mut state: State, bytes: &[u8]) -> Option<State> {
+        for &byte in bytes {
+            state = self.step(state, byte)?;
+        }
+        Some(state)
+    }
+
+    pub fn step(&self, state: State, byte: u8) -> Option<State> {
+        let next = self.units[state.0 as usize].base as usize + byte as usize;
+        match self.units.get(next) {
+            Some(unit) if unit.check == state.0 => Some(State(next as u32)),
+            _ => None,
+        }
+    }
+
+    /// The id of the key that ends at `state`, if one does.
+    pub fn value(&self, state: State) -> Option<usize> {
+        self.units[state.0 as usize]
+            .value
+            .checked_sub(1)
+            .map(|id| id as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::rng::Rng;
+
+    #[test]
+    fn finds_keys() {
+        // Mostly short keys over a few bytes, so many share prefixes, and
+        // some over all of them.
+        let mut rng = Rng(7);
+        let all = (0..=255).collect::<Vec<u8>>();
+        let keys = (0..5000)
+            .map(|i| {
+                let alphabet = if i % 10 == 0 { &all[..] } else { b"abcd" };
+                let len = 1 + rng.u64() as usize % 6;
+                (0..len)
+                    .map(|_| alphabet[rng.u64() as usize % alphabet.len()])
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let trie = Trie::new(keys.iter().enumerate().map(|(id, key)| (&key[..], id)));
+
+        let mut expected = HashMap::new();
+        for (id, key) in keys.iter().enumerate() {
+            expected.entry(&key[..]).or_insert(id);
+        }
+        assert_eq!(trie.len(), expected.len());
+        for key in &keys {
+            assert_eq!(trie.get(key), Some(expected[&key[..]]));
+        }
+        for _ in 0..5000 {
+            let key = (0..1 + rng.u64() % 8)
+                .map(|_| (rng.u64() % 4) as u8 + b'a')
+                .collect::<Vec<_>>();
+            assert_eq!(trie.get(&key), expected.get(&key[..]).copied());
+        }
+        assert_eq!(trie.get(b""), None);
+
+        // Walking part of a key and then the rest gets to the same place.
+        let (key, id) = (&keys[3], expected[&keys[3][..]]);
+        let state = trie.walk(Trie::ROOT, &key[..1]).unwrap();
+        assert_eq!(trie.value(trie.walk(state, &key[1..]).unwrap()), Some(id));
+
+        let empty = Trie::new([]);
+        assert!(empty.is_empty());
+        assert_eq!(empty.get(b"a"), None);
+    }
+}
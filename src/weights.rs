@@ -0,0 +1,606 @@
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    fmt, io,
+};
+
+use half::f16;
+
+use crate::buffer::AlignedBuf;
+use crate::ggml::{Data, HParams, ScalarType, Var};
+use crate::lora::{Lora, LoraMode, LoraWeight};
+use crate::model::Linear;
+use crate::tensor::{RopeStyle, Tensor, TensorElement};
+
+/// A weight of one of a model's layers. Not every architecture has every
+/// weight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LayerWeight {
+    AttnNorm,
+    AttnNormBias,
+    Wq,
+    Wk,
+    Wv,
+    /// The query, key and value projections in one matrix.
+    Wqkv,
+    Bqkv,
+    Wo,
+    Bo,
+    FfnNorm,
+    FfnNormBias,
+    /// The gate projection of a gated feed-forward block, or the up
+    /// projection of an ungated one.
+    W1,
+    B1,
+    /// The down projection of the feed-forward block.
+    W2,
+    B2,
+    /// The up projection of a gated feed-forward block.
+    W3,
+}
+
+/// A weight of a model, independent of what a checkpoint calls it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Weight {
+    TokEmbeddings,
+    PosEmbeddings,
+    Norm,
+    NormBias,
+    Output,
+    Layer(usize, LayerWeight),
+}
+
+impl Weight {
+    /// The shape the weight must have, in row-major order. Matrices are
+    /// `[out, in]`.
+    pub fn shape(self, hparams: &HParams) -> Vec<usize> {
+        let (dim, n_ff, vocab) = (hparams.dim, hparams.n_ff, hparams.vocab_size);
+        let kv_dim = hparams.n_kv_heads * hparams.head_dim();
+        match self {
+            Self::TokEmbeddings | Self::Output => vec![vocab, dim],
+            Self::PosEmbeddings => vec![hparams.n_ctx, dim],
+            Self::Norm | Self::NormBias => vec![dim],
+            Self::Layer(_, w) => match w {
+                LayerWeight::AttnNorm
+                | LayerWeight::AttnNormBias
+                | LayerWeight::FfnNorm
+                | LayerWeight::FfnNormBias
+                | LayerWeight::Bo
+                | LayerWeight::B2 => vec![dim],
+                LayerWeight::Wq | LayerWeight::Wo => vec![dim, dim],
+                LayerWeight::Wk | LayerWeight::Wv => vec![kv_dim, dim],
+                LayerWeight::Wqkv => vec![3 * dim, dim],
+                LayerWeight::Bqkv => vec![3 * dim],
+                LayerWeight::W1 | LayerWeight::W3 => vec![n_ff, dim],
+                LayerWeight::B1 => vec![n_ff],
+                LayerWeight::W2 => vec![dim, n_ff],
+            },
+        }
+    }
+
+    /// Norms and biases are kept in `f32`, and matrices in `f16`.
+    /// Checkpoints may store vectors in `f16` and matrices in any type, which
+    /// are converted as they're taken.
+    pub fn scalar_type(self, hparams: &HParams) -> ScalarType {
+        if self.shape(hparams).len() == 1 {
+            ScalarType::F32
+        } else {
+            ScalarType::F16
+        }
+    }
+}
+
+/// What a checkpoint format calls each weight of an architecture. Layer names
+/// contain `{i}` in place of the layer's index.
+pub struct NameTable {
+    /// The first entry is the token embeddings, which identify the table.
+    pub global: &'static [(Weight, &'static str)],
+    pub layer: &'static [(LayerWeight, &'static str)],
+    /// Layer weights stored as `[in, out]`, like GPT-2's `Conv1D`, which are
+    /// transposed while mapping.
+    pub transposed: &'static [LayerWeight],
+    /// Layer tensors that aren't weights, such as precomputed masks.
+    pub ignored: &'static [&'static str],
+    /// How the query and key projections lay out the elements rotary
+    /// position embeddings pair up.
+    pub rope_style: RopeStyle,
+}
+
+impl NameTable {
+    /// The table among `tables` whose token embeddings are among `names`.
+    pub fn detect<'a>(
+        tables: &[&'static Self],
+        names: impl IntoIterator<Item = &'a str>,
+    ) -> Option<&'static Self> {
+        let names = names.into_iter().collect::<HashSet<_>>();
+        tables
+            .iter()
+            .copied()
+            .find(|table| names.contains(table.global[0].1))
+    }
+
+    /// Every weight of a model with `n_layers` layers.
+    pub fn weights(&self, n_layers: usize) -> Vec<Weight> {
+        let mut weights = self.global.iter().map(|&(w, _)| w).collect::<Vec<_>>();
+        for i in 0..n_layers {
+            weights.extend(self.layer.iter().map(|&(w, _)| Weight::Layer(i, w)));
+        }
+        weights
+    }
+
+    /// What a checkpoint calls `weight`, if the architecture has it.
+    pub fn name(&self, weight: Weight) -> Option<String> {
+        match weight {
+            Weight::Layer(i, w) => {
+                let (_, name) = self.layer.iter().find(|(x, _)| *x == w)?;
+                Some(name.replace("{i}", &i.to_string()))
+            }
+            w => self
+                .global
+                .iter()
+                .find(|(x, _)| *x == w)
+                .map(|(_, name)| name.to_string()),
+        }
+    }
+}
+
+/// Everything wrong with a checkpoint's tensors, found by [`Weights::map`].
+#[derive(Debug, Default)]
+pub struct MappingError {
+    pub missing: Vec<String>,
+    pub unused: Vec<String>,
+    /// Tensors with the wrong shape or scalar type, with what's wrong.
+    pub mismatched: Vec<String>,
+}
+
+impl MappingError {
+    fn is_empty(&self) -> bool {
+        self.missing.is_empty() && self.unused.is_empty() && self.mismatched.is_empty()
+    }
+}
+
+impl fmt::Display for MappingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "the checkpoint doesn't match the model:")?;
+        for name in &self.missing {
+            writeln!(f, "  missing: {name}")?;
+        }
+        for name in &self.unused {
+            writeln!(f, "  unused: {name}")?;
+        }
+        for problem in &self.mismatched {
+            writeln!(f, "  {problem}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for MappingError {}
+
+impl From<MappingError> for io::Error {
+    fn from(e: MappingError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+/// A checkpoint's tensors, keyed by the weight they are, along with any LoRA
+/// updates to them.
+pub struct Weights {
+    table: &'static NameTable,
+    vars: HashMap<Weight, Var>,
+    lora: HashMap<Weight, LoraWeight>,
+    lora_mode: LoraMode,
+}
+
+impl Weights {
+    /// Finds every weight of the model described by `hparams` in `vars` under
+    /// the names in `table`, and checks its shape and scalar type. Every
+    /// problem is reported at once, as is any tensor that isn't a weight.
+    pub fn map(
+        mut vars: HashMap<String, Var>,
+        hparams: &HParams,
+        table: &'static NameTable,
+    ) -> Result<Self, MappingError> {
+        let mut error = MappingError::default();
+        let mut weights = HashMap::new();
+
+        for weight in table.weights(hparams.n_layers) {
+            let name = table.name(weight).unwrap();
+            let Some(mut var) = vars.remove(&name) else {
+                error.missing.push(name);
+                continue;
+            };
+            if matches!(weight, Weight::Layer(_, w) if table.transposed.contains(&w)) {
+                var = transpose(var);
+            }
+
+            let shape = weight.shape(hparams);
+            if var.dims != shape {
+                error
+                    .mismatched
+                    .push(format!("{name} is {:?}, expected {shape:?}", var.dims));
+            }
+            let (scalar_type, expected) = (var.data.scalar_type(), weight.scalar_type(hparams));
+            let convertible = match expected {
+                ScalarType::F32 => matches!(scalar_type, ScalarType::F16 | ScalarType::BF16),
+                _ => {
+                    matches!(scalar_type, ScalarType::F32 | ScalarType::BF16)
+                        || scalar_type.is_quantized()
+                }
+            };
+            if scalar_type != expected && !convertible {
+                error
+                    .mismatched
+                    .push(format!("{name} is {scalar_type:?}, expected {expected:?}"));
+            }
+            weights.insert(weight, var);
+        }
+
+        for i in 0..hparams.n_layers {
+            for name in table.ignored {
+                vars.remove(&name.replace("{i}", &i.to_string()));
+            }
+        }
+        error.unused = vars.into_keys().collect();
+        error.unused.sort();
+
+        if error.is_empty() {
+            Ok(Self {
+                table,
+                vars: weights,
+                lora: HashMap::new(),
+                lora_mode: LoraMode::Merge,
+            })
+        } else {
+            Err(error)
+        }
+    }
+
+    pub fn table(&self) -> &'static NameTable {
+        self.table
+    }
+
+    /// Applies the updates of a LoRA adapter to the weights as they're taken,
+    /// after checking that each one updates a matrix of the right shape.
+    pub fn with_lora(mut self, mut lora: Lora, mode: LoraMode) -> io::Result<Self> {
+        let mut errors = Vec::new();
+        for (&weight, var) in &self.vars {
+            let Some(update) = lora.take(&var.name) else {
+                continue;
+            };
+            if var.dims != update.target_shape() {
+                errors.push(format!(
+                    "{} is {:?}, but the adapter updates {:?}",
+                    var.name,
+                    var.dims,
+                    update.target_shape()
+                ));
+            }
+            self.lora.insert(weight, update);
+        }
+        errors.extend(
+            lora.weights
+                .into_keys()
+                .map(|name| format!("{name} is not a weight of the model")),
+        );
+
+        if errors.is_empty() {
+            self.lora_mode = mode;
+            Ok(self)
+        } else {
+            errors.sort();
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                errors.join("\n"),
+            ))
+        }
+    }
+
+    /// Takes a matrix out of the checkpoint, dequantized if need be, with any
+    /// LoRA update merged into it.
+    ///
+    /// Panics if `weight` is not a matrix or was already taken.
+    pub fn take_f16(&mut self, weight: Weight) -> Tensor<f16, 2> {
+        let mut var = self.vars.remove(&weight).expect("weight already taken");
+        if !matches!(var.data, Data::F16(_)) {
+            var.data = Data::F16(to_f16(&var.data));
+        }
+        let mut w = var
+            .as_tensor_f16()
+            .unwrap_or_else(|var| panic!("{} is not an f16 matrix", var.name));
+        if let Some(update) = self.lora.remove(&weight) {
+            update.merge_into(&mut w);
+        }
+        w
+    }
+
+    /// Takes a norm or a bias out of the checkpoint.
+    ///
+    /// Panics if `weight` is a matrix or was already taken.
+    pub fn take_f32(&mut self, weight: Weight) -> Tensor<f32, 1> {
+        let mut var = self.vars.remove(&weight).expect("weight already taken");
+        if !matches!(var.data, Data::F32(_)) {
+            var.data = Data::F32(AlignedBuf::from_slice(&var.data.to_f32()));
+        }
+        var.as_tensor_f32()
+            .unwrap_or_else(|var| panic!("{} is not an f32 vector", var.name))
+    }
+
+    /// Takes a matrix out of the checkpoint, along with its bias, if any. Its
+    /// LoRA update is merged or kept to apply on the fly, as chosen in
+    /// [`Weights::with_lora`].
+    pub fn take_linear(&mut self, weight: Weight, bias: Option<Weight>) -> Linear {
+        let lora = match self.lora_mode {
+            LoraMode::Merge => None,
+            LoraMode::OnTheFly => self.lora.remove(&weight),
+        };
+        Linear {
+            weight,
+            w: self.take_f16(weight),
+            bias: bias.map(|b| self.take_f32(b)),
+            lora,
+        }
+    }
+
+    /// Names the weights as `table` does, to save them in another format.
+    /// Matrices are transposed back if `table` stores them as `[in, out]`,
+    /// the query and key projections are permuted for `table`'s rotary
+    /// embeddings, and any LoRA updates are merged.
+    ///
+    /// Panics if `table` doesn't name every weight.
+    pub fn into_vars(mut self, table: &NameTable, hparams: &HParams) -> HashMap<String, Var> {
+        let rot_dims = (hparams.head_dim() as f32 * hparams.rotary_pct) as usize;
+        let weights = self.vars.keys().copied().collect::<Vec<_>>();
+
+        weights
+            .into_iter()
+            .map(|weight| {
+                let name = table
+                    .name(weight)
+                    .unwrap_or_else(|| panic!("the table has no name for {weight:?}"));
+                let mut var = if self.lora.contains_key(&weight) {
+                    let w = self.take_f16(weight);
+                    Var {
+                        name: String::new(),
+                        dims: w.shape().to_vec(),
+                        data: Data::F16(AlignedBuf::from_slice(w.as_slice())),
+                    }
+                } else {
+                    self.vars.remove(&weight).unwrap()
+                };
+
+                let n_heads = match weight {
+                    Weight::Layer(_, LayerWeight::Wq) => Some(hparams.n_heads),
+                    Weight::Layer(_, LayerWeight::Wk) => Some(hparams.n_kv_heads),
+                    _ => None,
+                };
+                if let Some(n_heads) = n_heads {
+                    var = permute_rope(
+                        var,
+                        n_heads,
+                        rot_dims,
+                        self.table.rope_style,
+                        table.rope_style,
+                    );
+                }
+                if matches!(weight, Weight::Layer(_, w) if table.transposed.contains(&w)) {
+                    var = transpose(var);
+                }
+
+                var.name = name.clone();
+                (name, var)
+            })
+            .collect()
+    }
+}
+
+/// Reorders the rows of each head of a query or key projection that rotary
+/// embeddings pair up, from `from`'s layout to `to`'s.
+fn permute_rope(var: Var, n_heads: usize, rot_dims: usize, from: RopeStyle, to: RopeStyle) -> Var {
+    if from == to || rot_dims == 0 {
+        return var;
+    }
+    let [rows, cols] = <[usize; 2]>::try_from(&var.dims[..]).expect("not a matrix");
+    let head_dim = rows / n_heads;
+    let half = rot_dims / 2;
+
+    fn permute<T: TensorElement>(
+        src: &[T],
+        rows: usize,
+        cols: usize,
+        source_row: impl Fn(usize) -> usize,
+    ) -> AlignedBuf<T> {
+        let data = (0..rows)
+            .flat_map(|r| &src[source_row(r) * cols..(source_row(r) + 1) * cols])
+            .copied()
+            .collect::<Vec<_>>();
+        AlignedBuf::from_slice(&data)
+    }
+    let source_row = |r: usize| {
+        let (head, i) = (r / head_dim, r % head_dim);
+        let i = match (i < rot_dims, to) {
+            (false, _) => i,
+            // The pair (i, i + half) becomes (2i, 2i + 1).
+            (true, RopeStyle::Interleaved) => i / 2 + (i % 2) * half,
+            (true, RopeStyle::NeoX) => 2 * (i % half) + i / half,
+        };
+        head * head_dim + i
+    };
+
+    let data = match &var.data {
+        Data::F32(data) => Data::F32(permute(data, rows, cols, source_row)),
+        Data::F16(data) => Data::F16(permute(data, rows, cols, source_row)),
+        Data::Mapped(data) if data.scalar_type() == ScalarType::F32 => {
+            Data::F32(permute(&data.to_f32(), rows, cols, source_row))
+        }
+        Data::Quantized(_) | Data::Mapped(_) => {
+            Data::F16(permute(&to_f16(&var.data), rows, cols, source_row))
+        }
+    };
+    Var { data, ..var }
+}
+
+fn to_f16(data: &Data) -> AlignedBuf<f16> {
+    AlignedBuf::from_slice(
+        &data
+            .to_f32()
+            .into_iter()
+            .map(f16::from_f32)
+            .collect::<Vec<_>>(),
+    )
+}
+
+/// Swaps the axes of a matrix.
+fn transpose(var: Var) -> Var {
+    let Ok(dims) = <[usize; 2]>::try_from(&var.dims[..]) else {
+        return var;
+    };
+    let data = match var.data {
+        Data::F32(data) => Data::F32(AlignedBuf::from_slice(
+            Tensor::from_buf(data, dims).transpose().as_slice(),
+        )),
+        Data::F16(data) => Data::F16(AlignedBuf::from_slice(
+            Tensor::from_buf(data, dims).transpose().as_slice(),
+        )),
+        Data::Mapped(data) if data.scalar_type() == ScalarType::F32 => Data::F32(
+            AlignedBuf::from_slice(Tensor::new(data.to_f32(), dims).transpose().as_slice()),
+        ),
+        data @ (Data::Quantized(_) | Data::Mapped(_)) => Data::F16(AlignedBuf::from_slice(
+            Tensor::from_buf(to_f16(&data), dims).transpose().as_slice(),
+        )),
+    };
+    Var {
+        name: var.name,
+        dims: vec![dims[1], dims[0]],
+        data,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{gpt2, llama};
+    use crate::quant::Quantized;
+
+    fn hparams() -> HParams {
+        HParams::llama(10, 6, 4, 2, 2)
+    }
+
+    fn var(name: String, dims: Vec<usize>, scalar_type: ScalarType) -> (String, Var) {
+        let len = dims.iter().product();
+        let data = match scalar_type {
+            ScalarType::F32 => Data::F32(AlignedBuf::zeroed(len)),
+            ScalarType::F16 => Data::F16(AlignedBuf::zeroed(len)),
+            ty => Data::Quantized(Quantized::new(ty, &vec![0.0; len])),
+        };
+        (name.clone(), Var { name, dims, data })
+    }
+
+    fn checkpoint(table: &NameTable, hparams: &HParams) -> HashMap<String, Var> {
+        table
+            .weights(hparams.n_layers)
+            .into_iter()
+            .map(|w| {
+                let mut shape = w.shape(hparams);
+                if matches!(w, Weight::Layer(_, w) if table.transposed.contains(&w)) {
+                    shape.reverse();
+                }
+                var(table.name(w).unwrap(), shape, w.scalar_type(hparams))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn maps_every_naming() {
+        let hparams = hparams();
+        for table in llama::NAMES {
+            let vars = checkpoint(table, &hparams);
+            assert!(std::ptr::eq(
+                NameTable::detect(llama::NAMES, vars.keys().map(String::as_str)).unwrap(),
+                *table
+            ));
+
+            let mut weights = Weights::map(vars, &hparams, table).unwrap();
+            // n_ff is 2/3 of 24, rounded up to a multiple of 4.
+            assert_eq!(
+                weights.take_f16(Weight::Layer(1, LayerWeight::W2)).shape(),
+                [6, 16]
+            );
+            assert_eq!(weights.take_f32(Weight::Norm).shape(), [6]);
+        }
+
+        // Quantized matrices and f16 norms are converted.
+        let mut vars = checkpoint(&llama::GGUF_NAMES, &hparams);
+        vars.extend([
+            var(
+                "blk.0.ffn_down.weight".to_string(),
+                vec![6, 16],
+                ScalarType::Q8_0,
+            ),
+            var("output_norm.weight".to_string(), vec![6], ScalarType::F16),
+        ]);
+        let mut weights = Weights::map(vars, &hparams, &llama::GGUF_NAMES).unwrap();
+        assert_eq!(
+            weights.take_f16(Weight::Layer(0, LayerWeight::W2)).shape(),
+            [6, 16]
+        );
+        assert_eq!(weights.take_f32(Weight::Norm).shape(), [6]);
+
+        // HuggingFace's GPT-2 matrices are transposed, and its masks are
+        // skipped.
+        let hparams = HParams {
+            n_ff: 24,
+            ..hparams
+        };
+        for table in gpt2::NAMES {
+            let mut vars = checkpoint(table, &hparams);
+            assert!(std::ptr::eq(
+                NameTable::detect(gpt2::NAMES, vars.keys().map(String::as_str)).unwrap(),
+                *table
+            ));
+
+            for mask in table.ignored {
+                vars.extend([var(mask.replace("{i}", "1"), vec![4, 4], ScalarType::F32)]);
+            }
+            let mut weights = Weights::map(vars, &hparams, table).unwrap();
+            assert_eq!(
+                weights.take_f16(Weight::Layer(0, LayerWeight::W1)).shape(),
+                [24, 6]
+            );
+        }
+    }
+
+    #[test]
+    fn reports_every_problem() {
+        let hparams = hparams();
+        let mut vars = checkpoint(&llama::GGML_NAMES, &hparams);
+        vars.remove("output.weight");
+        vars.remove("layers.1.attention.wk.weight");
+        vars.extend([
+            var("rope.freqs".to_string(), vec![3], ScalarType::F32),
+            var(
+                "layers.0.attention.wq.weight".to_string(),
+                vec![6, 5],
+                ScalarType::F16,
+            ),
+            var("norm.weight".to_string(), vec![32], ScalarType::Q8_0),
+        ]);
+
+        let error = Weights::map(vars, &hparams, &llama::GGML_NAMES)
+            .err()
+            .unwrap();
+        assert_eq!(
+            error.missing,
+            ["output.weight", "layers.1.attention.wk.weight"]
+        );
+        assert_eq!(error.unused, ["rope.freqs"]);
+        assert_eq!(
+            error.mismatched,
+            [
+                "norm.weight is [32], expected [6]",
+                "norm.weight is Q8_0, expected F32",
+                "layers.0.attention.wq.weight is [6, 5], expected [6, 6]",
+            ]
+        );
+    }
+}
@@ -0,0 +1,39 @@
+"""Trains the fixture model on corpus.txt and records the reference ids.
+
+Needs the `sentencepiece` package. Run from this directory:
+
+    python3 generate.py
+
+It writes `tokenizer.model`, a small BPE model with byte fallback, and
+`expected.json`, the ids the reference tokenizer gives each line of the
+corpus and a few texts it didn't see.
+"""
+
+import json
+
+import sentencepiece as spm
+
+spm.SentencePieceTrainer.train(
+    input="corpus.txt",
+    model_prefix="tokenizer",
+    model_type="bpe",
+    vocab_size=400,
+    byte_fallback=True,
+    character_coverage=0.98,
+    normalization_rule_name="nmt_nfkc",
+    hard_vocab_limit=False,
+)
+
+sp = spm.SentencePieceProcessor(model_file="tokenizer.model")
+with open("corpus.txt", encoding="utf-8") as f:
+    texts = f.read().splitlines()
+texts += [
+    "",
+    " ",
+    "unseen words: zyzzyva, qwerty",
+    "Mixed 🦀 and 日本 with   spaces\tand tabs",
+    "</s> and <s> are split like any text",
+]
+
+with open("expected.json", "w", encoding="utf-8") as f:
+    json.dump([{"text": text, "ids": sp.encode(text)} for text in texts], f, ensure_ascii=False, indent=1)